@@ -0,0 +1,45 @@
+#!/bin/sh
+
+# Runs a single liquidator scan against a local test validator.
+#
+# FIXTURES_DIR holds the programs to deploy as <PROGRAM_ID>.so, and the
+# accounts to load as <PUBKEY>.json, in the format written by
+# `solana account --output json`. Remaining arguments are passed to
+# zo-keeper before the subcommand, e.g. --payer.
+
+if [ $# -lt 1 ]; then
+    echo >&2 "Usage: $(basename "$0") <FIXTURES_DIR> [ZO_KEEPER ARGS]..."
+    exit 1
+fi
+
+readonly FIXTURES="$1"
+shift
+
+set -eu
+
+args=""
+for f in "$FIXTURES"/*.so; do
+    if [ -e "$f" ]; then
+        args="$args --bpf-program $(basename "$f" .so) $f"
+    fi
+done
+for f in "$FIXTURES"/*.json; do
+    if [ -e "$f" ]; then
+        args="$args --account $(basename "$f" .json) $f"
+    fi
+done
+
+# shellcheck disable=SC2086
+solana-test-validator --reset --quiet $args &
+readonly VALIDATOR=$!
+trap 'kill $VALIDATOR' EXIT
+
+until solana --url localhost cluster-version >/dev/null 2>&1; do
+    sleep 1
+done
+
+cargo run -- \
+    --rpc-url http://127.0.0.1:8899 \
+    --ws-url ws://127.0.0.1:8900 \
+    "$@" \
+    liquidator --single-scan
@@ -2,6 +2,8 @@
 pub enum Error {
     #[error("Skipped oracles {}", .0.join(", "))]
     OraclesSkipped(Vec<String>),
+    #[error("Invalid configuration: {}", .0.join(", "))]
+    InvalidConfig(Vec<String>),
 
     // Library errors
     #[error("{0}: {0:?}")]
@@ -18,4 +20,8 @@ pub enum Error {
     Db(#[from] mongodb::error::Error),
     #[error("{0}")]
     Var(#[from] std::env::VarError),
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Liquidator(#[from] crate::liquidator::ErrorCode),
 }
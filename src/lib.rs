@@ -11,3 +11,4 @@ mod utils;
 
 pub use error::*;
 pub use state::*;
+pub use utils::AccountSetCache;
@@ -8,25 +8,49 @@
  * then deal with compression.
 */
 use crate::liquidator::{
-    error::ErrorCode, liquidation, margin_utils::*, utils::*,
+    error::ErrorCode, jito::JitoSender, liquidation, margin_utils::*,
+    math::checked_to_i64, metrics, pnl, strategy::MarginHealth, utils::*,
+    LiquidatorConfig,
 };
 
 use serum_dex::state::{
     Market as SerumMarket, MarketState as SerumMarketState,
 };
-use solana_sdk::pubkey::Pubkey;
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey};
 use std::{
     cell::RefCell,
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     ops::Deref,
     sync::{Arc, Mutex, MutexGuard},
 };
-use tracing::{error, error_span, info, warn};
+use tracing::{debug, error, error_span, info, warn};
 use zo_abi::{
     dex::ZoDexMarket as MarketState, Cache, Control, FractionType, Margin,
-    State, MAX_MARKETS,
+    State, Symbol, WrappedI80F48,
 };
 
+#[derive(Clone, Copy, Debug)]
+pub enum Exposure {
+    Market(usize),
+    Collateral(usize),
+}
+
+/// Clears an account's in-flight mark when its send resolves, including
+/// by a panic.
+struct InFlightGuard {
+    in_flight: Arc<Mutex<HashSet<Pubkey>>>,
+    key: Pubkey,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if let Ok(mut in_flight) = self.in_flight.lock() {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
 // Let's start with a simple hashtable
 // It has to be sharable.
 pub struct AccountTable {
@@ -58,16 +82,39 @@ pub struct AccountTable {
     payer_control_key: Pubkey,
     payer_control: Control,
 
-    worker_count: u8,
-    worker_index: u8,
+    // Fees and confirmation every send is made with
+    retry_policy: RetryPolicy,
+
+    // When margin accounts created after startup were first seen
+    first_seen: HashMap<Pubkey, std::time::Instant>,
+
+    // When accounts skipped for a stale oracle were last reported
+    stale_skips: HashMap<Pubkey, std::time::Instant>,
+
+    // Oracle prices seen across cache updates, to detect frozen feeds
+    freeze_counts: FreezeCounts,
+
+    // Liquidatable accounts that no liquidation path can act on
+    stuck: HashMap<Pubkey, StuckReason>,
+
+    // Latest slot seen by the listener, and its value at the last scan
+    last_slot: u64,
+    last_scan_slot: u64,
+
+    // Time until the next scan, including any backoff
+    scan_interval: std::time::Duration,
+
+    // Accounts with a liquidation or cancel being sent
+    in_flight: Arc<Mutex<HashSet<Pubkey>>>,
+
+    config: LiquidatorConfig,
 }
 
 impl AccountTable {
     pub fn new(
         st: &crate::AppState,
-        worker_index: u8,
-        worker_count: u8,
-    ) -> Self {
+        config: LiquidatorConfig,
+    ) -> Result<Self, ErrorCode> {
         // This fetches all on-chain accounts for a start
         // Assumes that the dex is started, i.e. there's a cache
         // Also need to load market state info.
@@ -80,38 +127,107 @@ impl AccountTable {
         .0;
         let payer_margin = get_type_from_account::<Margin>(
             &payer_margin_key,
-            &mut st
-                .rpc
-                .get_account(&payer_margin_key)
-                .expect("Could not get payer margin account"),
-        );
+            &mut st.rpc.get_account(&payer_margin_key)?,
+        )?;
         let payer_control_key = payer_margin.control;
         let payer_control = get_type_from_account::<Control>(
             &payer_control_key,
-            &mut st.rpc.get_account(&payer_control_key).unwrap(),
-        );
+            &mut st.rpc.get_account(&payer_control_key)?,
+        )?;
 
-        let margin_table: HashMap<_, _> =
-            load_program_accounts::<Margin>(&st.rpc, &zo_abi::ID)
-                .unwrap()
-                .into_iter()
-                .filter(|(_, a)| {
-                    is_right_remainder(&a.control, worker_count, worker_index)
-                })
-                .collect();
+        // Scan at confirmed to see margin changes sooner.
+        let commitment = CommitmentConfig::confirmed();
+        let max_len = st.max_program_accounts;
 
-        let control_table: HashMap<_, _> =
-            load_program_accounts::<Control>(&st.rpc, &zo_abi::ID)
-                .unwrap()
+        // With several workers, only this worker's share of margins is
+        // fetched in full, picked out by their headers.
+        let margin_table: HashMap<_, _> = match config.worker_count {
+            1 => load_program_accounts::<Margin>(
+                &st.rpc,
+                &st.account_sets,
+                &zo_abi::ID,
+                commitment,
+                max_len,
+            ),
+            _ => {
+                let keys: Vec<_> = load_margin_headers(
+                    &st.rpc,
+                    &zo_abi::ID,
+                    commitment,
+                    max_len,
+                )?
                 .into_iter()
-                .filter(|(k, _)| {
-                    is_right_remainder(&k, worker_count, worker_index)
+                .filter(|(_, h)| {
+                    is_right_remainder(
+                        &h.control,
+                        config.worker_count,
+                        config.worker_index,
+                    )
                 })
+                .map(|(k, _)| k)
                 .collect();
+                load_accounts::<Margin>(&st.rpc, &keys)
+            }
+        }?
+        .into_iter()
+        .filter(|(_, a)| {
+            is_right_remainder(
+                &a.control,
+                config.worker_count,
+                config.worker_index,
+            )
+        })
+        .collect();
+
+        let control_table: HashMap<_, _> = load_program_accounts::<Control>(
+            &st.rpc,
+            &st.account_sets,
+            &zo_abi::ID,
+            commitment,
+            max_len,
+        )?
+        .into_iter()
+        .filter(|(k, _)| {
+            is_right_remainder(&k, config.worker_count, config.worker_index)
+        })
+        .collect();
+
+        if let Err(e) = check_oracles_sorted(&st.zo_cache) {
+            error!("Oracle prices may be wrong: {:?}", e);
+        }
 
         let market_state: Vec<_> =
             st.load_dex_markets().map(|(_, m)| m).collect();
 
+        let mut retry_policy = RetryPolicy::new(
+            config.priority_fees,
+            config.confirm_timeout.map(|timeout| Confirmation {
+                rpc: Arc::new(RpcClient::new_with_commitment(
+                    st.cluster.url().to_string(),
+                    config.confirm_commitment,
+                )),
+                timeout,
+                commitment: config.confirm_commitment,
+            }),
+        );
+        // Keepers paying from different accounts retry out of step.
+        retry_policy.jitter_seed =
+            u64::from_le_bytes(payer.to_bytes()[..8].try_into().unwrap());
+
+        for index in zero_imf_markets(&st.zo_state) {
+            let exposed = control_table
+                .values()
+                .filter(|c| {
+                    let oo = &c.open_orders_agg[index];
+                    oo.pos_size != 0 || oo.coin_on_bids != 0 || oo.coin_on_asks != 0
+                })
+                .count();
+            warn!(
+                "Market {} has a zero base_imf, {} accounts have unmargined exposure",
+                index, exposed
+            );
+        }
+
         let mut serum_markets: HashMap<usize, _> = HashMap::new();
         let mut serum_vault_signers: HashMap<usize, _> = HashMap::new();
 
@@ -120,15 +236,13 @@ impl AccountTable {
                 continue;
             }
 
-            let serum_oo_account = st
-                .rpc
-                .get_account(&collateral_info.serum_open_orders)
-                .unwrap();
+            let serum_oo_account =
+                st.rpc.get_account(&collateral_info.serum_open_orders)?;
 
             let serum_market_address =
                 Pubkey::new(&serum_oo_account.data[13..45]);
             let mut serum_market_account =
-                st.rpc.get_account(&serum_market_address).unwrap();
+                st.rpc.get_account(&serum_market_address)?;
             let serum_market_account_info = get_account_info(
                 &serum_market_address,
                 &mut serum_market_account,
@@ -139,7 +253,9 @@ impl AccountTable {
                 &zo_abi::SERUM_DEX_PID,
                 true,
             )
-            .unwrap();
+            .map_err(|_| {
+                ErrorCode::AccountDeserializeFailure(serum_market_address)
+            })?;
             let market = market_state.deref();
 
             serum_markets.insert(i, *market);
@@ -156,7 +272,7 @@ impl AccountTable {
             serum_vault_signers.insert(i, vault_signer);
         }
 
-        Self {
+        Ok(Self {
             margin_table,
             control_table,
             cache: st.zo_cache,
@@ -172,32 +288,449 @@ impl AccountTable {
             payer_margin,
             payer_control_key,
             payer_control,
-            worker_count,
-            worker_index,
-        }
+            retry_policy,
+            first_seen: HashMap::new(),
+            stale_skips: HashMap::new(),
+            freeze_counts: FreezeCounts::new(),
+            stuck: HashMap::new(),
+            last_slot: 0,
+            last_scan_slot: 0,
+            scan_interval: std::time::Duration::ZERO,
+            in_flight: Arc::new(Mutex::new(HashSet::new())),
+            config,
+        })
     }
 
-    pub fn refresh_accounts(&mut self, st: &crate::AppState) {
-        *self = Self::new(st, self.worker_index, self.worker_count);
+    /// Refetches every account, keeping the current table if that
+    /// fails.
+    pub fn refresh_accounts(
+        &mut self,
+        st: &crate::AppState,
+    ) -> Result<(), ErrorCode> {
+        let fresh = Self::new(st, self.config.clone())?;
+
+        let mut first_seen = std::mem::take(&mut self.first_seen);
+        first_seen.retain(|_, t| t.elapsed() < self.config.new_account_grace);
+        let stale_skips = std::mem::take(&mut self.stale_skips);
+        let freeze_counts = std::mem::take(&mut self.freeze_counts);
+        let stuck = std::mem::take(&mut self.stuck);
+        let (last_slot, last_scan_slot, scan_interval) =
+            (self.last_slot, self.last_scan_slot, self.scan_interval);
+        let in_flight = self.in_flight.clone();
+
+        *self = fresh;
+        self.first_seen = first_seen;
+        self.stale_skips = stale_skips;
+        self.freeze_counts = freeze_counts;
+        self.stuck = stuck;
+        self.last_slot = last_slot;
+        self.last_scan_slot = last_scan_slot;
+        self.scan_interval = scan_interval;
+        self.in_flight = in_flight;
+        Ok(())
     }
 
     pub fn update_margin(&mut self, key: Pubkey, account: Margin) {
         if is_right_remainder(
             &account.control,
-            self.worker_count,
-            self.worker_index,
+            self.config.worker_count,
+            self.config.worker_index,
         ) {
-            self.margin_table.insert(key, account);
+            if self.margin_table.insert(key, account).is_none() {
+                self.first_seen.insert(key, std::time::Instant::now());
+            }
+        }
+    }
+
+    /// Whether the account is under `empty_account_threshold` and can't
+    /// become liquidatable, in which case sweeps skip it.
+    pub fn is_empty_account(&self, margin: &Margin) -> bool {
+        let threshold = match self.config.empty_account_threshold {
+            Some(threshold) => threshold,
+            None => return false,
+        };
+
+        match self.get_control_from_margin(margin) {
+            Some((_, control)) => is_empty_account(
+                margin,
+                control,
+                &self.state,
+                &self.cache,
+                threshold,
+            )
+            .unwrap_or(false),
+            None => false,
+        }
+    }
+
+    /// Whether a send for the account is still in flight, in which case
+    /// it isn't flagged again until the send resolves.
+    pub fn is_in_flight(&self, key: &Pubkey) -> bool {
+        match self.in_flight.lock() {
+            Ok(in_flight) => {
+                !self.config.rescan_in_flight && in_flight.contains(key)
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Marks the account as in flight until the guard is dropped.
+    fn mark_in_flight(&self, key: Pubkey) -> InFlightGuard {
+        if let Ok(mut in_flight) = self.in_flight.lock() {
+            in_flight.insert(key);
+        }
+        InFlightGuard {
+            in_flight: self.in_flight.clone(),
+            key,
+        }
+    }
+
+    /// Whether the margin account was created too recently to be
+    /// liquidated, as it may still be in the middle of being funded.
+    pub fn is_in_grace_period(&self, key: &Pubkey) -> bool {
+        match self.first_seen.get(key) {
+            Some(t) => t.elapsed() < self.config.new_account_grace,
+            None => false,
+        }
+    }
+
+    /// The oracles that accounts in the table depend on and that are
+    /// older than the configured maximum age.
+    pub fn stale_oracle_symbols(&self) -> BTreeSet<Symbol> {
+        let max_age = match self.config.max_oracle_age {
+            Some(d) => d.as_secs(),
+            None => return BTreeSet::new(),
+        };
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let accounts = self.margin_table.values().filter_map(|margin| {
+            let (_, control) = self.get_control_from_margin(margin)?;
+            Some((margin, control))
+        });
+        let symbols = required_symbols(accounts, &self.state);
+        stale_symbols(&symbols, &self.cache, now, max_age)
+    }
+
+    /// Oracles whose price has been frozen for the configured number of
+    /// updates.
+    pub fn frozen_oracle_symbols(&self) -> BTreeSet<Symbol> {
+        match self.config.frozen_oracle_updates {
+            Some(updates) => frozen_symbols(&self.freeze_counts, updates),
+            None => BTreeSet::new(),
+        }
+    }
+
+    /// Checks that none of the oracles the account depends on is stale
+    /// or frozen.
+    pub fn check_oracle_ages(
+        &self,
+        margin: &Margin,
+        stale: &BTreeSet<Symbol>,
+        frozen: &BTreeSet<Symbol>,
+    ) -> Result<(), ErrorCode> {
+        let control = match self.get_control_from_margin(margin) {
+            Some((_, control)) => control,
+            None => return Ok(()),
+        };
+
+        let symbols = account_symbols(margin, control, &self.state);
+        if symbols.iter().any(|symbol| stale.contains(symbol)) {
+            return Err(ErrorCode::StaleOracle);
+        }
+        if symbols.iter().any(|symbol| frozen.contains(symbol)) {
+            return Err(ErrorCode::FrozenOracle);
+        }
+
+        Ok(())
+    }
+
+    /// Records a stale oracle skip, returning whether it should be
+    /// reported. Repeated skips are reported once per cooldown.
+    pub fn record_stale_skip(&mut self, key: Pubkey) -> bool {
+        let cooldown = self.config.stale_oracle_cooldown;
+        match self.stale_skips.get(&key) {
+            Some(t) if t.elapsed() < cooldown => false,
+            _ => {
+                self.stale_skips.insert(key, std::time::Instant::now());
+                true
+            }
+        }
+    }
+
+    /// Accounts found liquidatable but that cannot be liquidated, and
+    /// need to be escalated to an operator.
+    pub fn stuck_accounts(&self) -> &HashMap<Pubkey, StuckReason> {
+        &self.stuck
+    }
+
+    /// Indices of the collaterals configured as non-seizable.
+    pub fn non_seizable_collaterals(&self) -> HashSet<usize> {
+        self.state
+            .collaterals
+            .iter()
+            .take(self.state.total_collaterals as usize)
+            .enumerate()
+            .filter(|(_, info)| {
+                let symbol: String = info.oracle_symbol.into();
+                self.config.non_seizable_collaterals.contains(&symbol)
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Least expected profit after fees for which a liquidation is
+    /// sent: `min_profit_usd`, raised above zero if profit after fees is
+    /// required.
+    pub fn min_profit(&self) -> Option<i64> {
+        match self.config.require_profit_after_fees {
+            true => Some(self.config.min_profit_usd.unwrap_or(0).max(1)),
+            false => self.config.min_profit_usd,
+        }
+    }
+
+    /// Indices of the perp markets configured as reduce-only. The state
+    /// has no flag for it to be read from.
+    pub fn reduce_only_markets(&self) -> HashSet<usize> {
+        self.state
+            .perp_markets
+            .iter()
+            .take(self.state.total_markets as usize)
+            .enumerate()
+            .filter(|(_, info)| {
+                let symbol: String = info.symbol.into();
+                self.config.reduce_only_markets.contains(&symbol)
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// The configured preferred liquidation pairs as collateral indices,
+    /// leaving out pairs with an unlisted symbol.
+    pub fn preferred_liq_pairs(&self) -> Vec<(usize, usize)> {
+        let index_of = |symbol: &str| {
+            self.state
+                .collaterals
+                .iter()
+                .take(self.state.total_collaterals as usize)
+                .position(|info| String::from(info.oracle_symbol) == symbol)
+        };
+
+        self.config
+            .preferred_liq_pairs
+            .iter()
+            .filter_map(|(asset, quote)| {
+                Some((index_of(asset)?, index_of(quote)?))
+            })
+            .collect()
+    }
+
+    /// The account's margin components and maintenance margin ratio, if
+    /// the ratio is within the configured band of maintenance.
+    fn boundary_components(
+        &self,
+        margin: &Margin,
+    ) -> Option<(MarginComponents, f64)> {
+        let band = self.config.log_margin_band?;
+        let (_, control) = self.get_control_from_margin(margin)?;
+        let c = margin_components(margin, control, &self.state, &self.cache)
+            .ok()?;
+        let mmf = match c.maint_requirement() {
+            Ok(mmf) if mmf > 0 => mmf,
+            _ => return None,
+        };
+
+        let ratio = c.total_acc_value as f64 * 1000.0 / mmf as f64;
+        match (ratio - 1.0).abs() <= band {
+            true => Some((c, ratio)),
+            false => None,
+        }
+    }
+
+    /// Logs the margin components of the account if its margin ratio is
+    /// within the configured band of maintenance, as a record of the
+    /// inputs should its liquidation be disputed later.
+    pub fn log_boundary_components(&self, margin: &Margin) {
+        if let Some((c, ratio)) = self.boundary_components(margin) {
+            let collateral = get_actual_collateral_breakdown(
+                margin,
+                &RefCell::new(self.state).borrow(),
+                &RefCell::new(self.cache).borrow(),
+            );
+            debug!(
+                "{} is at margin ratio {:.4}: {:?}, collateral: {:?}",
+                margin.authority, ratio, c, collateral
+            );
+        }
+    }
+
+    /// Logs every step of the account's margin math if its authority is
+    /// one of the `trace_accounts`.
+    pub fn log_margin_trace(&self, margin: &Margin) {
+        if !self.config.trace_accounts.contains(&margin.authority) {
+            return;
+        }
+        let trace = match self.get_control_from_margin(margin) {
+            Some((_, control)) => {
+                trace_margin(margin, control, &self.state, &self.cache)
+            }
+            None => return,
+        };
+        let trace = match trace {
+            Ok(trace) => trace,
+            Err(e) => {
+                warn!("Failed to trace {}'s margin: {:?}", margin.authority, e);
+                return;
+            }
+        };
+
+        for c in &trace.collaterals {
+            info!(
+                "{} collateral {}: {} at {}, weight {}, weighted value {}, \
+                 borrow notional {:?}, imf {:?}, mmf {:?}",
+                margin.authority,
+                c.index,
+                c.amount,
+                c.price,
+                c.weight,
+                c.weighted_value,
+                c.borrow_notional,
+                c.imf,
+                c.mmf
+            );
+        }
+        for m in &trace.markets {
+            info!(
+                "{} market {}: {} at mark {}, native pc {}, realized pnl {}, \
+                 unrealized funding {}, account value {}, notional {}, \
+                 with orders {}, imf {}, mmf {}, cmf {}",
+                margin.authority,
+                m.index,
+                m.pos_size,
+                m.mark,
+                m.native_pc_total,
+                m.realized_pnl,
+                m.unrealized_funding,
+                m.acc_value,
+                m.pos_notional,
+                m.pos_open_notional,
+                m.imf,
+                m.mmf,
+                m.cmf
+            );
+        }
+        let (initial, maintenance, cancel) = trace.fractions;
+        info!(
+            "{}: collateral {}, account value {}, realized pnl {}, \
+             initial requirement {}, maintenance requirement {}, meets \
+             initial {}, maintenance {}, cancel {}",
+            margin.authority,
+            trace.col,
+            trace.total_acc_value,
+            trace.total_realized_pnl,
+            trace.init_requirement,
+            trace.maint_requirement,
+            initial,
+            maintenance,
+            cancel
+        );
+    }
+
+    /// Logs the account's exposure and which market contributes most to
+    /// its maintenance requirement, to show what drives a liquidation.
+    pub fn log_mmr_driver(&self, margin: &Margin) {
+        let control = match self.get_control_from_margin(margin) {
+            Some((_, control)) => control,
+            None => return,
+        };
+        let col = get_total_collateral(margin, &self.cache, &self.state);
+        let info = col.and_then(checked_to_i64).and_then(|col| {
+            compute_health_info(
+                col,
+                self.state.total_markets as usize,
+                self.state.total_collaterals as usize,
+                &control.open_orders_agg,
+                &self.state.perp_markets,
+                &self.state.collaterals,
+                &{ margin.collateral },
+                &RefCell::new(self.cache).borrow(),
+            )
+        });
+
+        let info = match info {
+            Ok(info) => info,
+            Err(_) => return,
+        };
+        info!(
+            "{} has {} in perp positions, {} with orders, and {} in \
+             borrows, against a maintenance requirement of {}",
+            margin.authority,
+            info.perp_notionals.iter().sum::<i64>(),
+            info.perp_open_notionals.iter().sum::<i64>(),
+            info.spot_notionals.iter().sum::<i64>(),
+            info.maint_margin_requirement / 1000
+        );
+        if let Some((index, share)) = info.largest_mmr_share() {
+            let symbol: String = self.state.perp_markets[index].symbol.into();
+            info!(
+                "{} contributes {:.0}% of {}'s maintenance requirement",
+                symbol,
+                share * 100.0,
+                margin.authority
+            );
         }
     }
 
     pub fn update_control(&mut self, key: Pubkey, account: Control) {
-        if is_right_remainder(&key, self.worker_count, self.worker_index) {
+        if is_right_remainder(
+            &key,
+            self.config.worker_count,
+            self.config.worker_index,
+        ) {
             self.control_table.insert(key, account);
         }
     }
 
+    /// The margin accounts affected by updates to `keys`, which may be
+    /// margin or control accounts. Keys not in the table are left out.
+    pub fn margins_for_update(
+        &self,
+        keys: &HashSet<Pubkey>,
+    ) -> Vec<(Pubkey, Margin)> {
+        let margin_keys: HashSet<Pubkey> = keys
+            .iter()
+            .filter_map(|key| match self.control_table.get(key) {
+                Some(control) => Some(
+                    Pubkey::find_program_address(
+                        &[
+                            control.authority.as_ref(),
+                            self.state_key.as_ref(),
+                            b"marginv1",
+                        ],
+                        &zo_abi::ID,
+                    )
+                    .0,
+                ),
+                None => Some(*key),
+            })
+            .collect();
+
+        margin_keys
+            .into_iter()
+            .filter_map(|key| Some((key, *self.margin_table.get(&key)?)))
+            .collect()
+    }
+
     pub fn update_cache(&mut self, cache: Cache) {
+        // Oracle lookups would silently return wrong prices, so keep the
+        // last good cache instead.
+        if let Err(e) = check_oracles_sorted(&cache) {
+            error!("Rejecting cache update: {:?}", e);
+            return;
+        }
+        update_freeze_counts(&mut self.freeze_counts, &cache);
         self.cache = cache;
     }
 
@@ -205,6 +738,20 @@ impl AccountTable {
         self.state = state;
     }
 
+    pub fn update_slot(&mut self, slot: u64) {
+        self.last_slot = self.last_slot.max(slot);
+    }
+
+    pub fn set_scan_interval(&mut self, interval: std::time::Duration) {
+        self.scan_interval = interval;
+    }
+
+    /// Estimated slot of the next scan, from the slot of the last scan
+    /// and the current scan interval.
+    pub fn next_scan_slot_estimate(&self) -> u64 {
+        slot_after(self.last_scan_slot, self.scan_interval)
+    }
+
     /// The number of control accounts.
     pub fn size(&self) -> usize {
         self.control_table.len()
@@ -230,11 +777,311 @@ impl AccountTable {
         &self.payer_control
     }
 
+    /// The keeper's free collateral above its initial requirement, in
+    /// smol USD. See `margin_utils::available_buying_power`.
+    pub fn buying_power(&self) -> Result<i64, ErrorCode> {
+        let headroom = available_buying_power(
+            &self.payer_margin,
+            &self.payer_control,
+            &self.state,
+            &self.cache,
+        )?;
+        Ok(headroom.saturating_to_num())
+    }
+
+    /// Logs figures over every tracked account, e.g. after a refresh.
+    pub fn log_book_summary(&self) {
+        match protocol_funding_flow(
+            self.control_table.values(),
+            &self.cache,
+            &self.state,
+        ) {
+            Ok(flow) => {
+                let flow: BTreeMap<_, _> = flow.into_iter().collect();
+                info!("Unrealized funding owed by market: {:?}", flow);
+            }
+            Err(e) => warn!("Failed to sum funding by market: {:?}", e),
+        }
+
+        let accounts: Vec<(&Margin, &Control)> = self
+            .margin_table
+            .values()
+            .filter_map(|margin| {
+                let (_, control) = self.get_control_from_margin(margin)?;
+                Some((margin, control))
+            })
+            .collect();
+        match book_weighted_health(
+            accounts.iter().copied(),
+            &self.state,
+            &self.cache,
+        ) {
+            Ok(health) => info!("Notional-weighted margin ratio: {}", health),
+            Err(e) => warn!("Failed to compute the book's health: {:?}", e),
+        }
+        match total_insurance_exposure(
+            accounts.iter().copied(),
+            &self.state,
+            &self.cache,
+        ) {
+            Ok(exposure) => {
+                info!(
+                    "Insurance fund exposure to bankrupt accounts: {}",
+                    exposure
+                )
+            }
+            Err(e) => warn!("Failed to sum insurance exposure: {:?}", e),
+        }
+        match book_collateral_composition(
+            accounts.iter().map(|(margin, _)| *margin),
+            &self.state,
+            &self.cache,
+        ) {
+            Ok(composition) => {
+                let composition: Vec<(String, _)> = composition
+                    .into_iter()
+                    .map(|(symbol, value)| (symbol.into(), value))
+                    .collect();
+                info!("Collateral backing the book: {:?}", composition);
+            }
+            Err(e) => warn!("Failed to sum the book's collateral: {:?}", e),
+        }
+        if !self.config.param_overrides.is_empty() {
+            let overridden = self.config.param_overrides.apply(&self.state);
+            let outlook = |state: &State| {
+                pnl::liquidation_outlook(
+                    accounts.iter().copied(),
+                    state,
+                    &self.cache,
+                )
+            };
+            match (outlook(&self.state), outlook(&overridden)) {
+                (Ok((count, bonus)), Ok((new_count, new_bonus))) => info!(
+                    "Liquidatable accounts and expected bonus: {} and {} \
+                     on-chain, {} and {} with overrides",
+                    count, bonus, new_count, new_bonus
+                ),
+                (Err(e), _) | (_, Err(e)) => {
+                    warn!("Failed to compare overridden params: {:?}", e)
+                }
+            }
+        }
+        if !self.config.stress_shocks.is_empty() {
+            match self.stress_loss(&accounts) {
+                Ok(loss) => info!(
+                    "Book value change under {:?}: {}",
+                    self.config.stress_shocks, loss
+                ),
+                Err(e) => warn!("Failed to run the stress scenario: {:?}", e),
+            }
+        }
+
+        let exposed: BTreeMap<String, usize> = (0..self.state.total_markets
+            as usize)
+            .map(|i| {
+                let symbol: String = self.state.perp_markets[i].symbol.into();
+                (symbol, self.accounts_referencing(Exposure::Market(i)).len())
+            })
+            .collect();
+        info!("Accounts exposed by market: {:?}", exposed);
+
+        let stuck = self.stuck_accounts();
+        if !stuck.is_empty() {
+            warn!(
+                "{} accounts are underwater but cannot be liquidated: {:?}",
+                stuck.len(),
+                stuck
+            );
+        }
+    }
+
+    /// Change in the accounts' total value, in smol USD, under the
+    /// configured stress scenario.
+    fn stress_loss(
+        &self,
+        accounts: &[(&Margin, &Control)],
+    ) -> Result<i64, ErrorCode> {
+        let shocks: Vec<(Symbol, f64)> = self
+            .config
+            .stress_shocks
+            .iter()
+            .map(|(symbol, change)| (Symbol::from(symbol.as_str()), *change))
+            .collect();
+
+        accounts.iter().try_fold(0i64, |total, (margin, control)| {
+            let loss = scenario_loss(
+                margin,
+                control,
+                &self.state,
+                &self.cache,
+                &shocks,
+            )?;
+            Ok(total.saturating_add(loss))
+        })
+    }
+
+    /// Logs the accounts that closing out the positions of the planned
+    /// liquidations may push below maintenance, if a price impact is
+    /// configured.
+    pub fn log_cascade(&self, plan: &[Pubkey]) {
+        let impact = match self.config.cascade_impact {
+            Some(impact) if !plan.is_empty() => impact,
+            _ => return,
+        };
+
+        let accounts: Vec<(Pubkey, Margin, Control)> = self
+            .margin_table
+            .iter()
+            .filter_map(|(key, margin)| {
+                let (_, control) = self.get_control_from_margin(margin)?;
+                Some((*key, *margin, *control))
+            })
+            .collect();
+        match simulate_cascade(
+            plan,
+            &accounts,
+            &self.state,
+            &self.cache,
+            linear_price_impact(&self.cache, impact),
+        ) {
+            Ok(cascade) if cascade.is_empty() => {}
+            Ok(cascade) => {
+                let authorities: Vec<Pubkey> = cascade
+                    .iter()
+                    .map(|key| self.margin_table[key].authority)
+                    .collect();
+                warn!(
+                    "{} more accounts may fall below maintenance after \
+                     these liquidations: {:?}",
+                    authorities.len(),
+                    authorities
+                );
+            }
+            Err(e) => warn!("Failed to simulate a cascade: {:?}", e),
+        }
+    }
+
+    /// The candidates to liquidate, in the order the configured strategy
+    /// gives. Each liquidation moves exposure onto the keeper, so those
+    /// needing more margin than is left of `buying_power` are left out.
+    pub fn plan_liquidations(
+        &self,
+        candidates: &[(Pubkey, Margin)],
+        mut buying_power: i64,
+    ) -> Vec<Pubkey> {
+        let healths: Vec<(Pubkey, MarginHealth)> = candidates
+            .iter()
+            .filter_map(|(key, margin)| {
+                let (_, control) = self.get_control_from_margin(margin)?;
+                match MarginHealth::new(
+                    margin,
+                    control,
+                    &self.state,
+                    &self.cache,
+                ) {
+                    Ok(health) => Some((*key, health)),
+                    Err(e) => {
+                        error!(
+                            "Failed to assess {}: {:?}",
+                            margin.authority, e
+                        );
+                        None
+                    }
+                }
+            })
+            .collect();
+        let estimates: HashMap<Pubkey, MarginHealth> =
+            healths.iter().copied().collect();
+        let margins: HashMap<Pubkey, &Margin> =
+            candidates.iter().map(|(k, m)| (*k, m)).collect();
+
+        let mut plan = Vec::new();
+        for key in self.config.strategy.plan(&healths) {
+            let (margin, health) =
+                match (margins.get(&key), estimates.get(&key)) {
+                    (Some(margin), Some(health)) => (margin, health),
+                    _ => continue,
+                };
+            if health.expected_margin > buying_power {
+                metrics::guard_suppressed("buying_power");
+                info!(
+                    "Skipping {}, it needs {} of margin and the keeper has {} \
+                     left",
+                    margin.authority, health.expected_margin, buying_power
+                );
+                continue;
+            }
+            buying_power -= health.expected_margin;
+            debug!(
+                "Planned {} with expected reward {}",
+                margin.authority, health.expected_reward
+            );
+            plan.push(key);
+        }
+
+        plan
+    }
+
+    /// The keeper's own positions by market, most skewed first. See
+    /// `pnl::inventory_skew`.
+    pub fn inventory_skew(&self) -> Vec<(usize, i64)> {
+        pnl::inventory_skew(&self.payer_control, &self.cache)
+    }
+
+    /// Returns the control of `margin`, unless the two have come out of
+    /// sync and no longer belong to the same authority.
     pub fn get_control_from_margin(
         &self,
         margin: &Margin,
     ) -> Option<(&Pubkey, &Control)> {
-        self.control_table.get_key_value(&margin.control)
+        let (key, control) =
+            self.control_table.get_key_value(&margin.control)?;
+        match validate_linkage(margin, control) {
+            Ok(()) => Some((key, control)),
+            Err(e) => {
+                debug!(
+                    "Control {} doesn't belong to {}: {:?}",
+                    key, margin.authority, e
+                );
+                None
+            }
+        }
+    }
+
+    /// Margin accounts with a position, open orders, or a non-zero
+    /// balance in the given market or collateral. Empty if the index is
+    /// out of range.
+    pub fn accounts_referencing(&self, exposure: Exposure) -> Vec<Pubkey> {
+        let in_range = match exposure {
+            Exposure::Collateral(i) => {
+                i < self.state.total_collaterals as usize
+            }
+            Exposure::Market(i) => i < self.state.total_markets as usize,
+        };
+        if !in_range {
+            return Vec::new();
+        }
+
+        self.margin_table
+            .iter()
+            .filter(|(_, margin)| match exposure {
+                Exposure::Collateral(i) => {
+                    { margin.collateral }[i] != WrappedI80F48::zero()
+                }
+                Exposure::Market(i) => {
+                    match self.get_control_from_margin(margin) {
+                        Some((_, control)) => {
+                            let oo = &control.open_orders_agg[i];
+                            oo.pos_size != 0
+                                || oo.coin_on_bids != 0
+                                || oo.coin_on_asks != 0
+                        }
+                        None => false,
+                    }
+                }
+            })
+            .map(|(k, _)| *k)
+            .collect()
     }
 }
 
@@ -243,20 +1090,39 @@ pub type Db = Arc<Mutex<AccountTable>>;
 #[derive(Clone)]
 pub struct DbWrapper {
     db: Db,
+    updates: Arc<tokio::sync::Notify>,
+    // Margin or control accounts pushed since the last `updated`
+    updated_keys: Arc<Mutex<HashSet<Pubkey>>>,
 }
 
 impl DbWrapper {
     pub fn new(
         st: &crate::AppState,
-        worker_index: u8,
-        worker_count: u8,
-    ) -> Self {
-        DbWrapper {
-            db: Arc::new(Mutex::new(AccountTable::new(
-                st,
-                worker_index,
-                worker_count,
-            ))),
+        config: LiquidatorConfig,
+    ) -> Result<Self, ErrorCode> {
+        Ok(DbWrapper {
+            db: Arc::new(Mutex::new(AccountTable::new(st, config)?)),
+            updates: Arc::new(tokio::sync::Notify::new()),
+            updated_keys: Arc::new(Mutex::new(HashSet::new())),
+        })
+    }
+
+    /// Signals that the listener pushed the margin or control account
+    /// `key`. Signals made while no one waits are coalesced into one.
+    pub fn notify_update(&self, key: Pubkey) {
+        if let Ok(mut keys) = self.updated_keys.lock() {
+            keys.insert(key);
+        }
+        self.updates.notify_one();
+    }
+
+    /// Waits for the next `notify_update`, or returns at once if one was
+    /// made since the last wait, with the keys updated since then.
+    pub async fn updated(&self) -> HashSet<Pubkey> {
+        self.updates.notified().await;
+        match self.updated_keys.lock() {
+            Ok(mut keys) => std::mem::take(&mut *keys),
+            Err(_) => HashSet::new(),
         }
     }
 
@@ -266,107 +1132,163 @@ impl DbWrapper {
         dex_program: &Pubkey,
         serum_dex_program: &Pubkey,
     ) -> Result<usize, ErrorCode> {
-        let (size, handles) =
-            self.check_all_accounts_aux(st, dex_program, serum_dex_program)?;
+        let (size, handles) = self.check_all_accounts_aux(
+            st,
+            dex_program,
+            serum_dex_program,
+            None,
+        )?;
         match futures::future::try_join_all(handles).await {
             Ok(_) => Ok(size),
             Err(_) => Err(ErrorCode::LiquidationFailure),
         }
     }
 
+    /// Like `check_all_accounts`, but only checks the margin accounts
+    /// affected by updates to `keys`, as returned by `updated`.
+    pub async fn check_updated_accounts(
+        &self,
+        st: &'static crate::AppState,
+        dex_program: &Pubkey,
+        serum_dex_program: &Pubkey,
+        keys: &HashSet<Pubkey>,
+    ) -> Result<usize, ErrorCode> {
+        let (size, handles) = self.check_all_accounts_aux(
+            st,
+            dex_program,
+            serum_dex_program,
+            Some(keys),
+        )?;
+        match futures::future::try_join_all(handles).await {
+            Ok(_) => Ok(size),
+            Err(_) => Err(ErrorCode::LiquidationFailure),
+        }
+    }
+
+    /// Checks the margin accounts affected by updates to `only`, or all
+    /// of them in a sweep if not given, and spawns their liquidations.
     pub fn check_all_accounts_aux(
         &self,
         st: &'static crate::AppState,
         dex_program: &Pubkey,
         serum_dex_program: &Pubkey,
+        only: Option<&HashSet<Pubkey>>,
     ) -> Result<(usize, Vec<tokio::task::JoinHandle<()>>), ErrorCode> {
         let db_clone = self.get_clone();
         let db: &mut MutexGuard<AccountTable> =
             &mut db_clone.lock().map_err(|_| ErrorCode::LockFailure)?;
 
+        let accounts: Vec<(Pubkey, Margin)> = match only {
+            Some(keys) => db.margins_for_update(keys),
+            None => {
+                db.last_scan_slot = db.last_slot;
+                db.margin_table.clone().into_iter().collect()
+            }
+        };
+        let checked = match only {
+            Some(_) => accounts.len(),
+            None => db.size(),
+        };
+
         let mut handles: Vec<tokio::task::JoinHandle<_>> = Vec::new();
         let span = error_span!("check_all_accounts");
-        for (key, margin) in db.margin_table.clone().into_iter() {
+        let mut candidates: Vec<(Pubkey, Margin)> = Vec::new();
+        let stale = db.stale_oracle_symbols();
+        let frozen = db.frozen_oracle_symbols();
+        let mut empty_skipped = 0usize;
+        for (key, margin) in accounts {
+            if db.is_empty_account(&margin) {
+                empty_skipped += 1;
+                continue;
+            }
 
-            let (cancel_orders, liquidate) =
-                DbWrapper::is_liquidatable(&margin, &db, &db.state, &db.cache)?;
-            if liquidate {
+            if db.is_in_flight(&key) {
+                metrics::guard_suppressed("in_flight");
+                span.in_scope(|| {
+                    debug!("Skipping {}, a send is in flight", margin.authority)
+                });
+                continue;
+            }
+
+            if db.is_in_grace_period(&key) {
+                metrics::guard_suppressed("grace_period");
                 span.in_scope(|| {
-                    info!(
-                        "Found liquidatable account: {}",
-                        margin.authority.to_string()
+                    debug!(
+                        "Skipping {}'s new margin account",
+                        margin.authority
                     )
                 });
-                // Get the updated payer accounts
+                continue;
+            }
 
-                /*******************************/
-                let dex_program = *dex_program;
-                let serum_dex_program = *serum_dex_program;
-                let payer_pubkey = db.payer_key();
-                let payer_margin_key = db.payer_margin_key();
-                let payer_margin = *db.payer_margin();
-                let payer_control_key = db.payer_control_key();
-                let payer_control = *db.payer_control();
-                let payer_oo: [Pubkey; MAX_MARKETS as usize] =
-                    get_oo_keys(&payer_control.open_orders_agg);
-                let control_pair = db.get_control_from_margin(&margin).unwrap();
-                let control = *control_pair.1;
-                let cache = db.cache;
-                let cache_key = db.cache_key;
-                let state = db.state;
-                let state_key = db.state_key;
-                let state_signer = db.state_signer;
-                let market_state = db.market_state.clone();
-                let serum_markets = db.serum_markets.clone();
-                let serum_vault_signers = db.serum_vault_signers.clone();
+            // Only the freshness check is repeated while an account is
+            // cooling down, so it resumes as soon as prices update.
+            if let Err(e) = db.check_oracle_ages(&margin, &stale, &frozen) {
+                metrics::guard_suppressed(match e {
+                    ErrorCode::FrozenOracle => "frozen_oracle",
+                    _ => "stale_oracle",
+                });
+                if db.record_stale_skip(key) {
+                    span.in_scope(|| {
+                        warn!("Not checking {}: {:?}", margin.authority, e)
+                    });
+                }
+                continue;
+            }
+            db.stale_skips.remove(&key);
 
-                // TODO: Refactor to have a struct for this, right now it's a mess
-                let span_clone = span.clone();
-                let handle = tokio::task::spawn_blocking(move || {
-                    let result = liquidation::liquidate(
-                        &st.program(),
-                        &dex_program,
-                        &payer_pubkey,
-                        &payer_margin,
-                        &payer_margin_key,
-                        &payer_control,
-                        &payer_control_key,
-                        &payer_oo,
-                        &key,
-                        &margin,
-                        &control,
-                        &cache,
-                        &cache_key,
-                        &state,
-                        &state_key,
-                        &state_signer,
-                        market_state.clone(),
-                        serum_markets,
-                        &serum_dex_program,
-                        serum_vault_signers,
-                    );
+            let (cancel_orders, liquidate) =
+                DbWrapper::is_liquidatable(&margin, &db, &db.state, &db.cache)?;
+            span.in_scope(|| db.log_boundary_components(&margin));
+            span.in_scope(|| db.log_margin_trace(&margin));
 
-                    match result {
-                        Ok(()) => {
-                            span_clone.in_scope(|| {
-                                info!(
-                                    "liquidated account for: {}",
-                                    margin.authority
-                                );
-                            });
-                        }
-                        Err(e) => {
-                            span_clone.in_scope(|| {
-                                error!(
-                                    "Error liquidating account {} : {:?}",
-                                    margin.authority, e
-                                )
-                            });
-                        }
+            let stuck = match (liquidate, db.get_control_from_margin(&margin)) {
+                (true, Some((_, control))) => {
+                    stuck_reason(&margin, control, &db.state, &db.cache)
+                }
+                _ => None,
+            };
+            if let Some(reason) = stuck {
+                metrics::guard_suppressed("stuck");
+                if db.stuck.insert(key, reason).is_none() {
+                    span.in_scope(|| {
+                        error!(
+                            "Account {} is underwater but cannot be liquidated: {:?}",
+                            margin.authority, reason
+                        )
+                    });
+                }
+                continue;
+            }
+            db.stuck.remove(&key);
+
+            if liquidate {
+                let bankrupt = match db.get_control_from_margin(&margin) {
+                    Some((_, control)) => has_negative_equity(
+                        &margin, control, &db.state, &db.cache,
+                    )
+                    .unwrap_or(false),
+                    None => false,
+                };
+                span.in_scope(|| {
+                    if bankrupt {
+                        warn!(
+                            "Found bankrupt account: {}",
+                            margin.authority.to_string()
+                        )
+                    } else {
+                        info!(
+                            "Found liquidatable account: {}",
+                            margin.authority.to_string()
+                        )
                     }
                 });
-
-                handles.push(handle);
+                span.in_scope(|| db.log_mmr_driver(&margin));
+                candidates.push((key, margin));
+            } else if cancel_orders && db.config.dry_run {
+                span.in_scope(|| {
+                    info!("Dry run: would cancel {}'s orders", margin.authority)
+                });
             } else if cancel_orders {
                 let dex_program = *dex_program;
                 let payer_pubkey = db.payer_key();
@@ -378,9 +1300,12 @@ impl DbWrapper {
                 let state_key = db.state_key;
                 let state_signer = db.state_signer;
                 let market_state = db.market_state.clone();
+                let retry_policy = db.retry_policy.clone();
+                let in_flight = db.mark_in_flight(key);
 
                 let span_clone = span.clone();
                 let handle = tokio::task::spawn_blocking(move || {
+                    let _in_flight = in_flight;
                     let result = liquidation::cancel(
                         &st.program(),
                         &dex_program,
@@ -394,6 +1319,7 @@ impl DbWrapper {
                         &state_key,
                         &state_signer,
                         market_state.clone(),
+                        &retry_policy,
                     );
 
                     match result {
@@ -412,7 +1338,115 @@ impl DbWrapper {
             }
         }
 
-        Ok((db.size(), handles))
+        if empty_skipped > 0 {
+            span.in_scope(|| {
+                debug!("Skipped {} empty accounts", empty_skipped)
+            });
+        }
+
+        let buying_power = db.buying_power()?;
+        let plan =
+            span.in_scope(|| db.plan_liquidations(&candidates, buying_power));
+        span.in_scope(|| db.log_cascade(&plan));
+        let margins: HashMap<Pubkey, Margin> = candidates.into_iter().collect();
+        metrics::record_liquidatable(margins.len());
+
+        for key in plan {
+            let margin = match margins.get(&key) {
+                Some(margin) => *margin,
+                None => continue,
+            };
+
+            // Get the updated payer accounts
+
+            /*******************************/
+            let payer_control = *db.payer_control();
+            let control = *db.get_control_from_margin(&margin).unwrap().1;
+            let params = liquidation::LiquidationParams {
+                dex_program: *dex_program,
+                payer_pubkey: db.payer_key(),
+                payer_margin: *db.payer_margin(),
+                payer_margin_key: db.payer_margin_key(),
+                payer_control,
+                payer_control_key: db.payer_control_key(),
+                payer_oo: get_oo_keys(&payer_control.open_orders_agg),
+                cache: db.cache,
+                cache_key: db.cache_key,
+                state: db.state,
+                state_key: db.state_key,
+                state_signer: db.state_signer,
+                market_infos: db.market_state.clone(),
+                serum_markets: db.serum_markets.clone(),
+                serum_dex_program: *serum_dex_program,
+                serum_vault_signers: db.serum_vault_signers.clone(),
+                deadline: db
+                    .config
+                    .attempt_deadline
+                    .map(|d| std::time::Instant::now() + d),
+                max_oracle_age: db.config.max_oracle_age,
+                min_improvement: db.config.min_liq_improvement,
+                style: db.config.liquidation_style,
+                scope: db.config.liquidation_scope,
+                full_threshold: db.config.adaptive_full_threshold,
+                two_phase_threshold: db.config.two_phase_threshold,
+                min_profit: db.min_profit(),
+                non_seizable: db.non_seizable_collaterals(),
+                preferred_pairs: db.preferred_liq_pairs(),
+                reduce_only: db.reduce_only_markets(),
+                retry_policy: db.retry_policy.clone(),
+                dry_run: db.config.dry_run,
+            };
+            let jito_config = db.config.jito.clone();
+            let in_flight = db.mark_in_flight(key);
+
+            let span_clone = span.clone();
+            let handle = tokio::task::spawn_blocking(move || {
+                let _in_flight = in_flight;
+                let jito = jito_config
+                    .map(|cfg| JitoSender::new(st.payer_keypair(), cfg));
+                let result = liquidation::liquidate(
+                    &st.program(),
+                    &params,
+                    jito.as_ref(),
+                    &key,
+                    &margin,
+                    &control,
+                );
+
+                match result {
+                    Ok(()) => {
+                        span_clone.in_scope(|| {
+                            info!(
+                                "liquidated account for: {}",
+                                margin.authority
+                            );
+                        });
+                    }
+                    Err(ErrorCode::BelowMinProfit(profit)) => {
+                        metrics::guard_suppressed("profit_gate");
+                        span_clone.in_scope(|| {
+                            info!(
+                                "Skipping {}, expected profit {} is below \
+                                 the minimum",
+                                margin.authority, profit
+                            )
+                        });
+                    }
+                    Err(e) => {
+                        span_clone.in_scope(|| {
+                            error!(
+                                "Error liquidating account {} : {:?}",
+                                margin.authority, e
+                            )
+                        });
+                    }
+                }
+            });
+
+            handles.push(handle);
+        }
+
+        Ok((checked, handles))
     }
 
     fn is_liquidatable(
@@ -424,6 +1458,15 @@ impl DbWrapper {
         // Do the math on the margin account.
         let span = error_span!("is_liquidatable");
         let col = get_total_collateral(margin, cache, state);
+        if let Err(e) = col.and_then(checked_to_i64) {
+            span.in_scope(|| {
+                error!(
+                    "Collateral of {} can't be computed: {:?}",
+                    margin.authority, e
+                )
+            });
+            return Ok((false, false));
+        }
         
         let control = match table.get_control_from_margin(margin) {
             Some((_key, control)) => control,
@@ -436,31 +1479,55 @@ impl DbWrapper {
             }
         };
 
+        if let Err(e) = check_mark_bands(control, state, cache, |i| {
+            let symbol: String = state.perp_markets[i].symbol.into();
+            table.config.mark_oracle_band(&symbol)
+        }) {
+            metrics::guard_suppressed("mark_divergence");
+            span.in_scope(|| {
+                warn!("Not checking {}: {:?}", margin.authority, e)
+            });
+            return Ok((false, false));
+        }
+
         // Have to rewrite this func to use current util instead of stored cache variables.
         // Also for multipliers.
-        let cancel_result = check_fraction_requirement(
-            FractionType::Cancel,
-            col.to_num::<i64>(),
-            table.state.total_markets as usize,
-            table.state.total_collaterals as usize,
-            &control.open_orders_agg,
-            &table.state.perp_markets,
-            &table.state.collaterals,
-            &{ margin.collateral },
-            &RefCell::new(table.cache).borrow(),
-        );
-
-        let result = check_fraction_requirement(
-            FractionType::Maintenance,
-            col.to_num::<i64>(),
-            table.state.total_markets as usize,
-            table.state.total_collaterals as usize,
-            &control.open_orders_agg,
-            &table.state.perp_markets,
-            &table.state.collaterals,
-            &{ margin.collateral },
-            &RefCell::new(table.cache).borrow(),
-        );
+        // Both fractions come from one pass over the account. If that
+        // pass fails the account is skipped, so one bad account can't end
+        // the scan. If only one comparison fails, the other is acted on.
+        let (cancel_result, result) = match margin_components(
+            margin,
+            control,
+            &table.state,
+            &table.cache,
+        ) {
+            Ok(c) => (
+                c.meets(FractionType::Cancel, 0),
+                c.meets(
+                    FractionType::Maintenance,
+                    table.config.maint_tolerance,
+                ),
+            ),
+            Err(ErrorCode::InvalidBorrowPrice) => {
+                metrics::guard_suppressed("invalid_borrow_price");
+                span.in_scope(|| {
+                    warn!(
+                        "Not checking {}, a borrow has no valid price",
+                        margin.authority
+                    )
+                });
+                return Ok((false, false));
+            }
+            Err(e) => {
+                span.in_scope(|| {
+                    error!(
+                        "Not checking {}, its margin fractions failed: {:?}",
+                        margin.authority, e
+                    )
+                });
+                return Ok((false, false));
+            }
+        };
 
         let has_oo = has_open_orders(cache, control)?;
         match (cancel_result, result) {
@@ -486,7 +1553,7 @@ impl DbWrapper {
                 span.in_scope(|| {
                     error!("Error checking maintenance fraction: {:?}", e2)
                 });
-                Err(ErrorCode::LiquidationFailure)
+                Ok((false, false))
             }
         }
     }
@@ -499,12 +1566,387 @@ impl DbWrapper {
         &self.db
     }
 
+    pub fn next_scan_slot_estimate(&self) -> Result<u64, ErrorCode> {
+        let db = self.db.lock().map_err(|_| ErrorCode::LockFailure)?;
+        Ok(db.next_scan_slot_estimate())
+    }
+
     pub fn refresh_accounts(
         &self,
         st: &crate::AppState,
     ) -> Result<(), ErrorCode> {
-        let mut db = self.db.lock().unwrap();
-        db.refresh_accounts(st);
-        Ok(())
+        let mut db = self.db.lock().map_err(|_| ErrorCode::LockFailure)?;
+        db.refresh_accounts(st)
+    }
+}
+
+/// The slot `interval` after `slot`, at least the next one.
+fn slot_after(slot: u64, interval: std::time::Duration) -> u64 {
+    let slots =
+        interval.as_millis() as u64 / solana_sdk::clock::DEFAULT_MS_PER_SLOT;
+    slot + slots.max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::liquidator::fixtures::*;
+    use std::time::Duration;
+
+    /// A table over the book's state and cache holding `accounts`, with
+    /// an empty keeper account.
+    fn table(
+        book: &Book,
+        accounts: &[(Margin, Control)],
+        config: LiquidatorConfig,
+    ) -> AccountTable {
+        let (payer_margin, payer_control) = account();
+        let mut margin_table = HashMap::new();
+        let mut control_table = HashMap::new();
+        for (margin, control) in accounts {
+            margin_table.insert(Pubkey::new_unique(), *margin);
+            control_table.insert(margin.control, *control);
+        }
+
+        AccountTable {
+            margin_table,
+            control_table,
+            cache: book.cache,
+            cache_key: Pubkey::new_unique(),
+            state: book.state,
+            state_key: Pubkey::new_unique(),
+            state_signer: Pubkey::new_unique(),
+            market_state: Vec::new(),
+            serum_markets: HashMap::new(),
+            serum_vault_signers: HashMap::new(),
+            payer_key: payer_margin.authority,
+            payer_margin_key: Pubkey::new_unique(),
+            payer_margin,
+            payer_control_key: payer_margin.control,
+            payer_control,
+            retry_policy: RetryPolicy::new(config.priority_fees, None),
+            first_seen: HashMap::new(),
+            stale_skips: HashMap::new(),
+            freeze_counts: FreezeCounts::new(),
+            stuck: HashMap::new(),
+            last_slot: 0,
+            last_scan_slot: 0,
+            scan_interval: Duration::ZERO,
+            in_flight: Arc::new(Mutex::new(HashSet::new())),
+            config,
+        }
+    }
+
+    #[test]
+    fn new_accounts_wait_out_the_grace_period() {
+        let book = Book::new();
+        let (old, old_control) = account();
+        let mut db = table(
+            &book,
+            &[(old, old_control)],
+            LiquidatorConfig {
+                new_account_grace: Duration::from_secs(3600),
+                ..config()
+            },
+        );
+        let old_key = *db.margin_table.keys().next().unwrap();
+
+        let (new, _) = account();
+        let new_key = Pubkey::new_unique();
+        db.update_margin(new_key, new);
+        assert!(db.is_in_grace_period(&new_key));
+        assert!(!db.is_in_grace_period(&old_key));
+
+        // Later updates don't restart the grace period.
+        let seen = db.first_seen[&new_key];
+        db.update_margin(new_key, new);
+        assert_eq!(db.first_seen[&new_key], seen);
+
+        db.config.new_account_grace = Duration::ZERO;
+        assert!(!db.is_in_grace_period(&new_key));
+    }
+
+    #[test]
+    fn next_scan_slot_follows_the_interval() {
+        let slot_ms = solana_sdk::clock::DEFAULT_MS_PER_SLOT;
+        assert_eq!(slot_after(100, Duration::from_millis(slot_ms * 10)), 110);
+        assert_eq!(slot_after(200, Duration::from_millis(slot_ms * 10)), 210);
+        assert_eq!(slot_after(100, Duration::from_secs(30)), 100 + 75);
+
+        // Scans faster than a slot still wait for the next one.
+        assert_eq!(slot_after(100, Duration::from_millis(1)), 101);
+    }
+
+    #[test]
+    fn accounts_referencing_finds_exposed_accounts() {
+        let mut book = Book::new();
+        let usdc = book.collateral("USDC", 1.0, 1000);
+        let sol = book.market("SOL-PERP", 100.0, 100);
+
+        let (mut lender, lender_control) = account();
+        set_balance(&mut lender, usdc, 1_000_000);
+        let (long, mut long_control) = account();
+        set_position(&mut long_control, sol, 1_000_000, 100.0);
+        let (bidder, mut bidder_control) = account();
+        bidder_control.open_orders_agg[sol].coin_on_bids = 10;
+        let (idle, idle_control) = account();
+
+        let db = table(
+            &book,
+            &[
+                (lender, lender_control),
+                (long, long_control),
+                (bidder, bidder_control),
+                (idle, idle_control),
+            ],
+            config(),
+        );
+        let authorities = |exposure| {
+            let mut found: Vec<Pubkey> = db
+                .accounts_referencing(exposure)
+                .iter()
+                .map(|k| db.margin_table[k].authority)
+                .collect();
+            found.sort();
+            found
+        };
+
+        assert_eq!(authorities(Exposure::Collateral(usdc)), [lender.authority]);
+        let mut traders = vec![long.authority, bidder.authority];
+        traders.sort();
+        assert_eq!(authorities(Exposure::Market(sol)), traders);
+
+        // Indices past the listed ones reference nothing.
+        assert!(authorities(Exposure::Collateral(usdc + 1)).is_empty());
+        assert!(authorities(Exposure::Market(sol + 1)).is_empty());
+    }
+
+    #[test]
+    fn repeated_stale_skips_are_rate_limited() {
+        let book = Book::new();
+        let mut db = table(
+            &book,
+            &[],
+            LiquidatorConfig {
+                stale_oracle_cooldown: Duration::from_secs(3600),
+                ..config()
+            },
+        );
+        let (key, other) = (Pubkey::new_unique(), Pubkey::new_unique());
+
+        assert!(db.record_stale_skip(key));
+        assert!(!db.record_stale_skip(key));
+        assert!(!db.record_stale_skip(key));
+        assert!(db.record_stale_skip(other));
+
+        // Without a cooldown every skip is reported.
+        db.config.stale_oracle_cooldown = Duration::ZERO;
+        assert!(db.record_stale_skip(key));
+        assert!(db.record_stale_skip(key));
+    }
+
+    #[test]
+    fn the_configured_strategy_orders_the_plan() {
+        use crate::liquidator::strategy::{
+            LargestRiskFirst, LiquidationStrategy,
+        };
+
+        struct ClosestToHealthyFirst;
+
+        impl LiquidationStrategy for ClosestToHealthyFirst {
+            fn plan(
+                &self,
+                candidates: &[(Pubkey, MarginHealth)],
+            ) -> Vec<Pubkey> {
+                let mut candidates = candidates.to_vec();
+                candidates.sort_by_key(|(_, h)| h.shortfall());
+                candidates.into_iter().map(|(k, _)| k).collect()
+            }
+        }
+
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+
+        // Each needs 500 to meet maintenance, and is short by 400, 200
+        // and 300.
+        let accounts: Vec<(Margin, Control)> = [100, 300, 200]
+            .into_iter()
+            .map(|balance| {
+                let (mut margin, mut control) = account();
+                set_balance(&mut margin, usd, balance);
+                set_position(&mut control, btc, 10, 1000.0);
+                (margin, control)
+            })
+            .collect();
+        let authorities: Vec<Pubkey> =
+            accounts.iter().map(|(m, _)| m.authority).collect();
+
+        let mut db = table(&book, &accounts, config());
+        let candidates: Vec<(Pubkey, Margin)> =
+            db.margin_table.iter().map(|(k, m)| (*k, *m)).collect();
+        let planned = |db: &AccountTable, buying_power| -> Vec<Pubkey> {
+            db.plan_liquidations(&candidates, buying_power)
+                .iter()
+                .map(|k| db.margin_table[k].authority)
+                .collect()
+        };
+
+        assert_eq!(
+            planned(&db, i64::MAX),
+            [authorities[0], authorities[2], authorities[1]]
+        );
+        db.config.strategy = Arc::new(ClosestToHealthyFirst);
+        assert_eq!(
+            planned(&db, i64::MAX),
+            [authorities[1], authorities[2], authorities[0]]
+        );
+        db.config.strategy = Arc::new(LargestRiskFirst);
+
+        // Without buying power nothing is taken on.
+        assert!(planned(&db, -1).is_empty());
+    }
+
+    #[test]
+    fn only_accounts_near_maintenance_are_logged() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+
+        // Each needs 500 to meet maintenance.
+        let accounts: Vec<(Margin, Control)> = [200, 480, 530, 1_000]
+            .into_iter()
+            .map(|balance| {
+                let (mut margin, mut control) = account();
+                set_balance(&mut margin, usd, balance);
+                set_position(&mut control, btc, 10, 1000.0);
+                (margin, control)
+            })
+            .collect();
+        let mut db = table(
+            &book,
+            &accounts,
+            LiquidatorConfig {
+                log_margin_band: Some(0.1),
+                ..config()
+            },
+        );
+
+        let ratios: Vec<Option<f64>> = accounts
+            .iter()
+            .map(|(margin, _)| db.boundary_components(margin).map(|(_, r)| r))
+            .collect();
+        assert_eq!(ratios, [None, Some(0.96), Some(1.06), None]);
+
+        db.config.log_margin_band = None;
+        let (margin, _) = &accounts[1];
+        assert!(db.boundary_components(margin).is_none());
+    }
+
+    #[test]
+    fn in_flight_accounts_are_skipped_until_their_send_resolves() {
+        let book = Book::new();
+        let mut db = table(&book, &[account(), account()], config());
+        let keys: Vec<Pubkey> = db.margin_table.keys().copied().collect();
+
+        let sending = db.mark_in_flight(keys[0]);
+        assert!(db.is_in_flight(&keys[0]));
+        assert!(!db.is_in_flight(&keys[1]));
+
+        // The send resolves on another thread, as liquidations do.
+        std::thread::spawn(move || drop(sending)).join().unwrap();
+        assert!(!db.is_in_flight(&keys[0]));
+
+        let _sending = db.mark_in_flight(keys[1]);
+        assert!(db.is_in_flight(&keys[1]));
+        db.config.rescan_in_flight = true;
+        assert!(!db.is_in_flight(&keys[1]));
+    }
+
+    #[test]
+    fn controls_of_another_authority_are_not_paired() {
+        let book = Book::new();
+        let (margin, control) = account();
+        let (_, other_control) = account();
+        assert!(validate_linkage(&margin, &control).is_ok());
+        assert!(matches!(
+            validate_linkage(&margin, &other_control),
+            Err(ErrorCode::BrokenLinkage)
+        ));
+
+        // The margin's control key leads to another authority's control.
+        let mut db = table(&book, &[(margin, control)], config());
+        assert!(db.get_control_from_margin(&margin).is_some());
+        db.control_table.insert(margin.control, other_control);
+        assert!(db.get_control_from_margin(&margin).is_none());
+    }
+
+    #[test]
+    fn guards_count_the_accounts_they_hold_back() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let srm = book.collateral("SRM", 0.0, 800);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+        // The mark is 25% above the oracle.
+        book.oracle("BTC", 800.0);
+
+        let (mut diverged, mut diverged_control) = account();
+        set_balance(&mut diverged, usd, 100_000);
+        set_position(&mut diverged_control, btc, 10, 1000.0);
+        let (mut unpriced, unpriced_control) = account();
+        set_balance(&mut unpriced, usd, 100_000);
+        set_balance(&mut unpriced, srm, -5_000);
+
+        let mut config = config();
+        config.mark_oracle_band = Some(0.1);
+        let db = table(
+            &book,
+            &[(diverged, diverged_control), (unpriced, unpriced_control)],
+            config,
+        );
+        let check = |margin: &Margin| {
+            DbWrapper::is_liquidatable(margin, &db, &db.state, &db.cache)
+                .unwrap()
+        };
+        assert_eq!(check(&diverged), (false, false));
+        assert_eq!(check(&unpriced), (false, false));
+        assert_eq!(check(&unpriced), (false, false));
+
+        let count = |report: &[(&str, u64)], guard| {
+            report.iter().find(|(g, _)| *g == guard).map(|(_, n)| *n)
+        };
+        let report = metrics::guard_report();
+        assert_eq!(count(&report, "mark_divergence"), Some(1));
+        assert_eq!(count(&report, "invalid_borrow_price"), Some(2));
+
+        // Reports only count what fired since the last one.
+        let report = metrics::guard_report();
+        assert_eq!(count(&report, "mark_divergence"), None);
+        assert_eq!(count(&report, "invalid_borrow_price"), None);
+    }
+
+    #[test]
+    fn accounts_whose_fractions_fail_are_skipped() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+
+        // The position's notional doesn't fit in an i64.
+        let (mut overflowing, mut overflowing_control) = account();
+        set_balance(&mut overflowing, usd, 100_000);
+        set_position(&mut overflowing_control, btc, i64::MAX / 10, 1000.0);
+        assert!(margin_components(
+            &overflowing,
+            &overflowing_control,
+            &book.state,
+            &book.cache,
+        )
+        .is_err());
+
+        // The scan carries on with the next account rather than failing.
+        let db = table(&book, &[(overflowing, overflowing_control)], config());
+        let liquidatable =
+            DbWrapper::is_liquidatable(&overflowing, &db, &db.state, &db.cache);
+        assert!(matches!(liquidatable, Ok((false, false))));
     }
 }
@@ -1,18 +1,90 @@
-#[derive(Debug)]
+use solana_sdk::{pubkey::Pubkey, signature::Signature};
+
+#[derive(thiserror::Error, Debug)]
 pub enum ErrorCode {
+    #[error("Math failure")]
     MathFailure,
     #[allow(dead_code)]
+    #[error("Control account doesn't exist")]
     InexistentControl,
+    #[error("Failed to lock the account table")]
     LockFailure,
+    #[error("Failed to compute collateral")]
     CollateralFailure,
+    #[error("Account has no collateral")]
     NoCollateral,
+    #[error("Account has no positions")]
     NoPositions,
+    #[error("Liquidation failed")]
     LiquidationFailure,
+    #[error("Swap failed")]
     SwapError,
+    #[error("Send timed out")]
     TimeoutExceeded,
+    #[error("Failed to cancel orders")]
     CancelFailure,
+    #[error("Failed to settle bankruptcy")]
     SettlementFailure,
+    #[error("Order book has no asks")]
     NoAsks,
+    #[error("Transaction can't succeed")]
     UnrecoverableTransactionError,
+    #[error("Liquidation is larger than the liquidator can take")]
     LiquidationOverExposure,
+    #[error("Math overflow")]
+    MathOverflow,
+    #[error("Oracle cache isn't sorted")]
+    UnsortedOracleCache,
+    #[error("Failed to send bundle")]
+    BundleFailure,
+    #[error("Bundle neither landed nor expired")]
+    BundlePending,
+    #[error("Deadline exceeded")]
+    DeadlineExceeded,
+    #[error("Mark and oracle prices diverge")]
+    MarkOracleDivergence,
+    #[error("Oracle is stale")]
+    StaleOracle,
+    #[error("Oracle is frozen")]
+    FrozenOracle,
+    #[error("Failed to deserialize account {0}")]
+    AccountDeserializeFailure(Pubkey),
+    #[error("Failed to fetch all accounts")]
+    IncompleteAccountFetch,
+    #[error("Send failed: {0}")]
+    SendFailed(String),
+    #[error("Identical request already landed in {0}")]
+    AlreadyLanded(Signature),
+    #[error("Sent transaction may still land")]
+    SendInFlight,
+    #[error("Margin and control belong to different authorities")]
+    BrokenLinkage,
+    #[error("Found {0} accounts, more than the limit of {1}")]
+    ResultSetTooLarge(usize, usize),
+    #[error("Market is reduce-only")]
+    ReduceOnlyMarket,
+    #[error("Factor and notional vectors differ in length")]
+    MismatchedVectors,
+    #[error("Borrow has a non-positive price")]
+    InvalidBorrowPrice,
+    #[error("Account {0} isn't owned by the program")]
+    WrongOwner(Pubkey),
+    #[error("State points to a different cache")]
+    CacheMismatch,
+    #[error("State lists {0} markets, more than the keeper supports")]
+    TooManyMarkets(usize),
+    #[error("State lists {0} collaterals, more than the keeper supports")]
+    TooManyCollaterals(usize),
+    #[error("Cache has no oracle for {0}")]
+    MissingOracle(String),
+    #[error("Failed to write the liquidation log")]
+    WalFailure,
+    #[error("Expected profit of {0} is below the minimum")]
+    BelowMinProfit(i64),
+
+    // Library errors
+    #[error(transparent)]
+    AnchorClient(#[from] anchor_client::ClientError),
+    #[error(transparent)]
+    SolanaClient(#[from] solana_client::client_error::ClientError),
 }
@@ -0,0 +1,192 @@
+/*
+ * This file builds accounts for unit tests out of zeroed zero-copy
+ * structs. Prices are in smol USD per smol unit, and every listed
+ * collateral starts with interest multipliers of 1.
+ */
+
+use fixed::types::I80F48;
+
+use solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey};
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use zo_abi::{Cache, Control, Margin, State, Symbol};
+
+use crate::liquidator::{
+    LargestRiskFirst, LiquidationScope, LiquidationStyle, LiquidatorConfig,
+    ParamOverrides, PriorityFees,
+};
+
+/// The protocol state and cache that a test's accounts are checked
+/// against.
+pub struct Book {
+    pub state: State,
+    pub cache: Cache,
+}
+
+impl Book {
+    pub fn new() -> Self {
+        Self {
+            state: bytemuck::Zeroable::zeroed(),
+            cache: bytemuck::Zeroable::zeroed(),
+        }
+    }
+
+    /// Lists a collateral with its weight in thousandths and returns
+    /// its index.
+    pub fn collateral(
+        &mut self,
+        symbol: &str,
+        price: f64,
+        weight: u16,
+    ) -> usize {
+        let i = self.state.total_collaterals as usize;
+        let info = &mut self.state.collaterals[i];
+        info.mint = Pubkey::new_unique();
+        info.oracle_symbol = Symbol::from(symbol);
+        info.weight = weight;
+        info.liq_fee = 20;
+        self.state.total_collaterals += 1;
+
+        let borrows = &mut self.cache.borrow_cache[i];
+        borrows.supply_multiplier = I80F48::ONE.into();
+        borrows.borrow_multiplier = I80F48::ONE.into();
+        self.oracle(symbol, price);
+        i
+    }
+
+    /// Takes up a collateral slot without listing anything in it, as
+    /// left by a delisted collateral.
+    pub fn empty_collateral(&mut self) -> usize {
+        self.state.total_collaterals += 1;
+        self.state.total_collaterals as usize - 1
+    }
+
+    /// Lists a perp market marked at `mark`, with its oracle at the same
+    /// price, and returns its index.
+    pub fn market(&mut self, symbol: &str, mark: f64, base_imf: u16) -> usize {
+        let i = self.state.total_markets as usize;
+        let oracle = symbol.trim_end_matches("-PERP");
+        let info = &mut self.state.perp_markets[i];
+        info.symbol = Symbol::from(symbol);
+        info.oracle_symbol = Symbol::from(oracle);
+        info.dex_market = Pubkey::new_unique();
+        info.base_imf = base_imf;
+        info.liq_fee = 10;
+        info.asset_decimals = 6;
+        self.state.total_markets += 1;
+
+        self.cache.marks[i].price = I80F48::from_num(mark).into();
+        self.oracle(oracle, mark);
+        i
+    }
+
+    /// Sets the price of an oracle, adding it if missing, as updated
+    /// now. Oracles are kept sorted by symbol for `get_oracle`.
+    pub fn oracle(&mut self, symbol: &str, price: f64) {
+        let symbol = Symbol::from(symbol);
+        let mut oracles = self.cache.oracles;
+        let i = oracles
+            .iter()
+            .position(|o| o.symbol == symbol)
+            .or_else(|| oracles.iter().position(|o| o.symbol.is_nil()))
+            .expect("no room for another oracle");
+
+        oracles[i].symbol = symbol;
+        oracles[i].price = I80F48::from_num(price).into();
+        oracles[i].last_updated = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        oracles.sort_by_key(|o| o.symbol);
+        self.cache.oracles = oracles;
+    }
+}
+
+/// An empty margin account and its control.
+pub fn account() -> (Margin, Control) {
+    let mut margin: Margin = bytemuck::Zeroable::zeroed();
+    let mut control: Control = bytemuck::Zeroable::zeroed();
+    let authority = Pubkey::new_unique();
+    margin.authority = authority;
+    margin.control = Pubkey::new_unique();
+    control.authority = authority;
+    (margin, control)
+}
+
+/// Sets the account's balance of a collateral in smol units, negative
+/// for a borrow.
+pub fn set_balance(margin: &mut Margin, index: usize, amount: i64) {
+    let mut collateral = { margin.collateral };
+    collateral[index] = I80F48::from_num(amount).into();
+    margin.collateral = collateral;
+}
+
+/// Opens a perp position of `size` smol units, negative for a short,
+/// entered at `entry`.
+pub fn set_position(
+    control: &mut Control,
+    index: usize,
+    size: i64,
+    entry: f64,
+) {
+    let oo = &mut control.open_orders_agg[index];
+    oo.key = Pubkey::new_unique();
+    oo.pos_size = size;
+    oo.native_pc_total = -(size as f64 * entry) as i64;
+}
+
+/// A single worker's config with every guard and option off.
+pub fn config() -> LiquidatorConfig {
+    LiquidatorConfig {
+        worker_count: 1,
+        worker_index: 0,
+        maint_tolerance: 0,
+        new_account_grace: Duration::ZERO,
+        jito: None,
+        attempt_deadline: None,
+        mark_oracle_band: None,
+        market_mark_oracle_bands: HashMap::new(),
+        max_oracle_age: None,
+        stale_oracle_cooldown: Duration::ZERO,
+        frozen_oracle_updates: None,
+        min_liq_improvement: None,
+        liquidation_style: LiquidationStyle::PartialToTarget,
+        liquidation_scope: LiquidationScope::Both,
+        adaptive_full_threshold: 0,
+        two_phase_threshold: None,
+        min_profit_usd: None,
+        empty_account_threshold: None,
+        max_scan_failures: None,
+        strategy: Arc::new(LargestRiskFirst),
+        log_margin_band: None,
+        cascade_impact: None,
+        stress_shocks: Vec::new(),
+        param_overrides: ParamOverrides::default(),
+        trace_accounts: HashSet::new(),
+        non_seizable_collaterals: HashSet::new(),
+        preferred_liq_pairs: Vec::new(),
+        reduce_only_markets: HashSet::new(),
+        single_scan: false,
+        wal_path: None,
+        fork_check_url: None,
+        rescan_in_flight: false,
+        priority_fees: PriorityFees {
+            micro_lamports: None,
+            compute_unit_limit: None,
+            escalation: 1.0,
+        },
+        min_fee_runway: None,
+        confirm_timeout: None,
+        confirm_commitment: CommitmentConfig::confirmed(),
+        dry_run: false,
+        require_profit_after_fees: false,
+        scan_on_update: false,
+        stretch_scan_interval: false,
+        metrics_port: None,
+    }
+}
@@ -0,0 +1,245 @@
+/*
+ * This file handles submitting transactions as Jito bundles.
+ * A bundle carries a tip to the block engine and lands atomically,
+ * which avoids being sandwiched or outbid by naive submissions.
+*/
+use anchor_client::RequestBuilder;
+
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{
+    commitment_config::CommitmentConfig, hash::Hash, instruction::Instruction,
+    pubkey::Pubkey, signature::Signature, signer::keypair::Keypair,
+    signer::Signer, system_instruction, transaction::Transaction,
+};
+
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+use crate::liquidator::{
+    error::ErrorCode,
+    utils::{retry_send, RetryPolicy},
+};
+
+/// Longest wait for a bundle to land or expire. Blockhashes expire
+/// after 150 blocks, around a minute.
+const BUNDLE_WAIT: Duration = Duration::from_secs(120);
+
+/// Time between status checks of a submitted bundle.
+const BUNDLE_POLL: Duration = Duration::from_millis(500);
+
+#[derive(Clone)]
+pub struct JitoConfig {
+    /// Block engine endpoint, e.g. `https://mainnet.block-engine.jito.wtf`.
+    pub url: String,
+    pub tip_account: Pubkey,
+    pub tip_lamports: u64,
+}
+
+pub struct JitoSender {
+    payer: Keypair,
+    cfg: JitoConfig,
+}
+
+impl JitoSender {
+    pub fn new(payer: Keypair, cfg: JitoConfig) -> Self {
+        Self { payer, cfg }
+    }
+
+    pub fn tip_ix(&self) -> Instruction {
+        system_instruction::transfer(
+            &self.payer.pubkey(),
+            &self.cfg.tip_account,
+            self.cfg.tip_lamports,
+        )
+    }
+
+    pub fn bundles_url(&self) -> String {
+        format!("{}/api/v1/bundles", self.cfg.url.trim_end_matches('/'))
+    }
+
+    /// Signs the instructions with a trailing tip into one transaction.
+    pub fn bundle_transaction(
+        &self,
+        ixs: &[Instruction],
+        blockhash: Hash,
+    ) -> Transaction {
+        let mut ixs = ixs.to_vec();
+        ixs.push(self.tip_ix());
+        Transaction::new_signed_with_payer(
+            &ixs,
+            Some(&self.payer.pubkey()),
+            &[&self.payer],
+            blockhash,
+        )
+    }
+
+    /// Signs the instructions with a trailing tip into one transaction,
+    /// submits it as a bundle and waits for it to be confirmed. Fails
+    /// with `BundleFailure` only once the bundle can no longer land,
+    /// i.e. it was rejected, failed or its blockhash expired.
+    pub fn send_bundle(
+        &self,
+        rpc: &RpcClient,
+        ixs: &[Instruction],
+    ) -> Result<Signature, ErrorCode> {
+        let blockhash = rpc.get_latest_blockhash()?;
+        let tx = self.bundle_transaction(ixs, blockhash);
+        let signature = tx.signatures[0];
+        let body = bundle_request(&tx)?;
+
+        // The block engine may have received the bundle even if the
+        // response was lost, so only an error response rules it out.
+        let response = reqwest::blocking::Client::new()
+            .post(self.bundles_url())
+            .json(&body)
+            .send()
+            .and_then(|r| r.json::<serde_json::Value>());
+        match response {
+            Ok(response) => {
+                if let Some(e) = response.get("error") {
+                    warn!("Bundle rejected: {}", e);
+                    return Err(ErrorCode::BundleFailure);
+                }
+            }
+            Err(e) => warn!("Failed to submit bundle: {}", e),
+        }
+
+        self.wait_for_bundle(rpc, &signature, &blockhash)
+    }
+
+    /// Polls the bundle transaction until it is confirmed, fails or its
+    /// blockhash expires, for at most `BUNDLE_WAIT`.
+    fn wait_for_bundle(
+        &self,
+        rpc: &RpcClient,
+        signature: &Signature,
+        blockhash: &Hash,
+    ) -> Result<Signature, ErrorCode> {
+        let start = Instant::now();
+        while start.elapsed() < BUNDLE_WAIT {
+            match rpc.get_signature_statuses(&[*signature]) {
+                Ok(response) => {
+                    match response.value.into_iter().next().flatten() {
+                        Some(status) if status.err.is_some() => {
+                            warn!("Bundle failed: {:?}", status.err);
+                            return Err(ErrorCode::BundleFailure);
+                        }
+                        Some(status)
+                            if status.satisfies_commitment(
+                                CommitmentConfig::confirmed(),
+                            ) =>
+                        {
+                            return Ok(*signature)
+                        }
+                        Some(_) => {}
+                        None => {
+                            let valid = rpc.is_blockhash_valid(
+                                blockhash,
+                                CommitmentConfig::processed(),
+                            );
+                            if let Ok(false) = valid {
+                                warn!("Bundle expired without landing");
+                                return Err(ErrorCode::BundleFailure);
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!("Failed to get the status of {}: {}", signature, e)
+                }
+            }
+            std::thread::sleep(BUNDLE_POLL);
+        }
+
+        Err(ErrorCode::BundlePending)
+    }
+}
+
+/// The `sendBundle` request submitting the transaction alone.
+pub fn bundle_request(
+    tx: &Transaction,
+) -> Result<serde_json::Value, ErrorCode> {
+    let encoded = base64::encode(
+        bincode::serialize(tx).map_err(|_| ErrorCode::BundleFailure)?,
+    );
+    Ok(serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "sendBundle",
+        "params": [[encoded], { "encoding": "base64" }],
+    }))
+}
+
+/// Sends through a Jito bundle when a sender is given, falling back to
+/// `retry_send` if the bundle can no longer land.
+pub fn send<'a>(
+    jito: Option<&JitoSender>,
+    rpc: &RpcClient,
+    make_builder: impl Fn() -> RequestBuilder<'a>,
+    policy: &RetryPolicy,
+) -> Result<Signature, ErrorCode> {
+    if let Some(jito) = jito {
+        let ixs = make_builder().instructions()?;
+        match jito.send_bundle(rpc, &ixs) {
+            Err(ErrorCode::BundleFailure) => {
+                warn!("Bundle failed, falling back to rpc")
+            }
+            result => return result,
+        }
+    }
+
+    retry_send(make_builder, policy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundles_end_with_the_tip() {
+        let payer = Keypair::new();
+        let payer_key = payer.pubkey();
+        let tip_account = Pubkey::new_unique();
+        let jito = JitoSender::new(
+            payer,
+            JitoConfig {
+                url: "https://block-engine.example/".to_string(),
+                tip_account,
+                tip_lamports: 10_000,
+            },
+        );
+        assert_eq!(
+            jito.bundles_url(),
+            "https://block-engine.example/api/v1/bundles"
+        );
+
+        let liquidate = Instruction::new_with_bytes(
+            Pubkey::new_unique(),
+            &[1, 2, 3],
+            vec![],
+        );
+        let tx = jito.bundle_transaction(&[liquidate], Hash::default());
+        let message = &tx.message;
+        assert_eq!(message.instructions.len(), 2);
+        assert_eq!(message.account_keys[0], payer_key);
+
+        let tip = &message.instructions[1];
+        let tip_ix =
+            system_instruction::transfer(&payer_key, &tip_account, 10_000);
+        assert_eq!(
+            message.account_keys[tip.program_id_index as usize],
+            tip_ix.program_id
+        );
+        assert_eq!(tip.data, tip_ix.data);
+        let to = message.account_keys[tip.accounts[1] as usize];
+        assert_eq!(to, tip_account);
+
+        let request = bundle_request(&tx).unwrap();
+        assert_eq!(request["method"], "sendBundle");
+        let encoded = request["params"][0][0].as_str().unwrap();
+        let sent: Transaction =
+            bincode::deserialize(&base64::decode(encoded).unwrap()).unwrap();
+        assert_eq!(sent, tx);
+    }
+}
@@ -9,37 +9,155 @@ use fixed::types::I80F48;
 
 use serum_dex::state::MarketState as SerumMarketState;
 
+use solana_client::{rpc_client::RpcClient, rpc_config::RpcTransactionConfig};
 use solana_sdk::{
     commitment_config::CommitmentConfig, pubkey::Pubkey, signature::Signature,
 };
 
-use std::collections::HashMap;
+use solana_transaction_status::UiTransactionEncoding;
+
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    time::Instant,
+};
 
 use zo_abi::{
     accounts as ix_accounts, dex::ZoDexMarket as MarketState, instruction,
-    Cache, Control, Margin, State, WrappedI80F48, DUST_THRESHOLD,
-    MAX_COLLATERALS, MAX_MARKETS,
+    Cache, Control, Margin, State, WrappedI80F48, MAX_COLLATERALS, MAX_MARKETS,
 };
 
-use std::cell::RefCell;
-
 use tracing::{debug, error, error_span, info, warn};
 
 use crate::liquidator::{
-    accounts::*, error::ErrorCode, margin_utils::*, math::*, swap, utils::*,
+    accounts::*,
+    error::ErrorCode,
+    jito::{self, JitoSender},
+    margin_utils::*,
+    math::*,
+    metrics, pnl,
+    strategy::MarginHealth,
+    swap,
+    utils::*,
+    wal, LiquidationScope, LiquidationStyle,
 };
 
+/// Period over which the keeper's PnL is logged.
+const PNL_WINDOW: std::time::Duration =
+    std::time::Duration::from_secs(24 * 60 * 60);
+
+/// Period over which the fee payer's spending is measured to project
+/// how long its balance lasts.
+const FEE_RUNWAY_WINDOW: std::time::Duration =
+    std::time::Duration::from_secs(60 * 60);
+
+/// Compute units used settling one collateral of a bankrupt account,
+/// including the swap that covers it.
+const SETTLE_BANKRUPTCY_CU: u64 = 300_000;
+
+/// Time between refreshes of the account table.
+const REFRESH_INTERVAL: std::time::Duration =
+    std::time::Duration::from_secs(300);
+
+/// Wait before retrying a refresh of the account table that failed.
+const REFRESH_RETRY: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Longest wait between scans after consecutive failures.
+const MAX_SCAN_BACKOFF: std::time::Duration =
+    std::time::Duration::from_secs(30);
+
+/// Scans that failed in a row, with at most `max` allowed.
+struct ScanFailures {
+    count: u32,
+    max: Option<u32>,
+}
+
+impl ScanFailures {
+    fn new(max: Option<u32>) -> Self {
+        Self { count: 0, max }
+    }
+
+    /// Records a failed scan and returns how long to wait before the
+    /// next one, doubling from `period` with each failure. `None` once
+    /// `max` scans in a row have failed.
+    fn failed(
+        &mut self,
+        period: std::time::Duration,
+    ) -> Option<std::time::Duration> {
+        self.count += 1;
+        if matches!(self.max, Some(max) if self.count >= max) {
+            return None;
+        }
+
+        Some(
+            period
+                .saturating_mul(2u32.saturating_pow(self.count))
+                .min(MAX_SCAN_BACKOFF),
+        )
+    }
+
+    /// Records a successful scan and returns how many failed before it.
+    fn succeeded(&mut self) -> u32 {
+        std::mem::take(&mut self.count)
+    }
+}
+
 #[tracing::instrument(skip_all, level = "error")]
-pub async fn liquidate_loop(st: &'static crate::AppState, database: DbWrapper) {
+pub async fn liquidate_loop(
+    st: &'static crate::AppState,
+    database: DbWrapper,
+    max_scan_failures: Option<u32>,
+    single_scan: bool,
+    fork_reference: Option<RpcClient>,
+    min_fee_runway: Option<std::time::Duration>,
+    scan_on_update: bool,
+    stretch_scan_interval: bool,
+) {
     info!("starting...");
 
-    let mut last_refresh = std::time::Instant::now();
-    let mut interval =
-        tokio::time::interval(std::time::Duration::from_millis(250));
-    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let mut next_refresh = std::time::Instant::now() + REFRESH_INTERVAL;
+    let period = std::time::Duration::from_millis(250);
+    // Scans run one at a time, so ticks missed during a slow scan are
+    // skipped rather than run back to back.
+    let mut current_period = period;
+    let mut interval = scan_interval(current_period);
+    let mut failures = ScanFailures::new(max_scan_failures);
 
     loop {
-        interval.tick().await;
+        // Updates only have the accounts they touch checked, while the
+        // interval still sweeps all of them.
+        let updated = if scan_on_update {
+            tokio::select! {
+                _ = interval.tick() => None,
+                keys = database.updated() => Some(keys),
+            }
+        } else {
+            interval.tick().await;
+            None
+        };
+
+        if let Some(reference) = &fork_reference {
+            if is_on_different_fork(&st.rpc, reference) {
+                metrics::guard_suppressed("fork");
+                warn!("RPC node may be on a minority fork, skipping scan");
+                continue;
+            }
+        }
+
+        if let Some(keys) = updated {
+            if let Err(e) = database
+                .check_updated_accounts(
+                    st,
+                    &zo_abi::ZO_DEX_PID,
+                    &zo_abi::SERUM_DEX_PID,
+                    &keys,
+                )
+                .await
+            {
+                warn!("Failed to check updated accounts: {:?}", e);
+            }
+            continue;
+        }
 
         let loop_start = std::time::Instant::now();
         match database
@@ -51,25 +169,266 @@ pub async fn liquidate_loop(st: &'static crate::AppState, database: DbWrapper) {
             .await
         {
             Ok(n) => {
+                let elapsed = loop_start.elapsed();
+                metrics::record_sweep(n, elapsed);
+                if elapsed > current_period {
+                    warn!(
+                        "Scan took {:?}, longer than the {:?} interval, \
+                         skipping missed ticks",
+                        elapsed, current_period
+                    );
+                }
+                if stretch_scan_interval {
+                    let next = match elapsed > current_period {
+                        true => elapsed.min(MAX_SCAN_BACKOFF),
+                        false if elapsed <= period => period,
+                        false => current_period,
+                    };
+                    if next != current_period {
+                        info!("Scan interval is now {:?}", next);
+                        current_period = next;
+                        interval = scan_interval(current_period);
+                    }
+                }
+                if let Ok(mut db) = database.get().lock() {
+                    db.set_scan_interval(current_period);
+                }
+                let recovered = failures.succeeded();
+                if recovered > 0 {
+                    info!("Scan recovered after {} failures", recovered);
+                }
                 debug!(
                     "Checked {} accounts in {} μs",
                     n,
                     loop_start.elapsed().as_micros()
                 );
+                if let Ok(slot) = database.next_scan_slot_estimate() {
+                    debug!("Next scan around slot {}", slot);
+                }
+                let suppressed = metrics::guard_report();
+                if !suppressed.is_empty() {
+                    debug!("Held back by guards: {:?}", suppressed);
+                }
+                if single_scan {
+                    info!("Checked {} accounts, exiting", n);
+                    return;
+                }
             }
+            Err(e) if single_scan => panic!("Scan failed: {:?}", e),
             Err(e) => {
-                error!("Had an oopsie-doopsie {:?}", e);
+                let backoff = match failures.failed(period) {
+                    Some(backoff) => backoff,
+                    None => panic!(
+                        "Giving up after {} failed scans: {:?}",
+                        failures.count, e
+                    ),
+                };
+                error!(
+                    "Scan failed ({} in a row), retrying in {:?}: {:?}",
+                    failures.count, backoff, e
+                );
+                if let Ok(mut db) = database.get().lock() {
+                    db.set_scan_interval(backoff);
+                }
+                tokio::time::sleep(backoff).await;
+                continue;
             }
         };
 
-        if last_refresh.elapsed().as_secs() > 300 {
-            database.refresh_accounts(st).unwrap(); // TODO: Refactor this is bad.
-            last_refresh = std::time::Instant::now();
+        if std::time::Instant::now() >= next_refresh {
+            // A failed refresh is retried sooner, but not on every scan.
+            if let Err(e) = database.refresh_accounts(st) {
+                warn!(
+                    "Failed to refresh the account table, retrying in {:?}: \
+                     {:?}",
+                    REFRESH_RETRY, e
+                );
+                next_refresh = std::time::Instant::now() + REFRESH_RETRY;
+                continue;
+            }
+            next_refresh = std::time::Instant::now() + REFRESH_INTERVAL;
             info!("Refreshed account table");
+
+            if let Ok(db) = database.get().lock() {
+                let skew = db.inventory_skew();
+                if !skew.is_empty() {
+                    info!("Inventory by market, most skewed first: {:?}", skew);
+                }
+                db.log_book_summary();
+            }
+            info!(
+                "Keeper PnL over the last {:?}: {}",
+                PNL_WINDOW,
+                pnl::keeper_pnl(PNL_WINDOW)
+            );
+
+            if let Some(min_runway) = min_fee_runway {
+                check_fee_runway(st, min_runway);
+            }
         }
     }
 }
 
+/// Ticks every `period`, starting one period from now. Ticks missed
+/// while a scan runs are skipped.
+fn scan_interval(period: std::time::Duration) -> tokio::time::Interval {
+    let mut interval =
+        tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    interval
+}
+
+/// Warns if the fee payer would run out of SOL within `min_runway` at
+/// its recent rate of sends.
+fn check_fee_runway(st: &crate::AppState, min_runway: std::time::Duration) {
+    let balance = match st.rpc.get_balance(&st.payer()) {
+        Ok(balance) => balance,
+        Err(e) => {
+            warn!("Failed to get the fee payer's balance: {}", e);
+            return;
+        }
+    };
+
+    let runway = pnl::estimate_fee_runway(
+        pnl::recent_sends(FEE_RUNWAY_WINDOW),
+        pnl::recent_fees_paid(FEE_RUNWAY_WINDOW),
+        balance,
+        FEE_RUNWAY_WINDOW,
+    );
+    if runway < min_runway {
+        error!(
+            "Fee payer balance of {} lamports lasts about {:?} at the recent rate",
+            balance, runway
+        );
+    }
+}
+
+/// Lamports paid in fees by the landed `signature`. Falls back to the
+/// base fee and the priority fee sends currently land with, for `cu`
+/// compute units, if the transaction can't be fetched.
+fn fee_paid(
+    rpc: &RpcClient,
+    signature: &Signature,
+    fees: &PriorityFees,
+    cu: u64,
+) -> u64 {
+    let tx = rpc.get_transaction_with_config(
+        signature,
+        RpcTransactionConfig {
+            encoding: Some(UiTransactionEncoding::Base64),
+            commitment: Some(CommitmentConfig::confirmed()),
+        },
+    );
+    match tx.map(|tx| tx.transaction.meta) {
+        Ok(Some(meta)) => meta.fee,
+        result => {
+            if let Err(e) = result {
+                warn!("Failed to get the fee paid by {}: {}", signature, e);
+            }
+            pnl::LAMPORTS_PER_SIGNATURE
+                .saturating_add(fees.lamports(required_attempt(), cu))
+        }
+    }
+}
+
+/// Finds a liquidation of `account`, of the kind recorded in the log,
+/// that `payer` landed at or after `slot`. Used to reconcile the attempts
+/// a previous run left unresolved in the log.
+pub fn find_landed_liquidation(
+    rpc: &RpcClient,
+    payer: &Pubkey,
+    account: &Pubkey,
+    kind: &str,
+    slot: u64,
+) -> Result<Option<Signature>, ErrorCode> {
+    let data = match kind {
+        "perp" => instruction::LiquidatePerpPosition {
+            asset_transfer_lots: 0,
+        }
+        .data(),
+        _ => instruction::LiquidateSpotPosition {
+            asset_transfer_amount: 0,
+        }
+        .data(),
+    };
+    let discriminator = &data[..8];
+
+    // Newest first, so the search stops at the first older signature.
+    for status in rpc.get_signatures_for_address(account)? {
+        if status.slot < slot {
+            break;
+        }
+        if status.err.is_some() {
+            continue;
+        }
+        let signature = match Signature::from_str(&status.signature) {
+            Ok(signature) => signature,
+            Err(_) => continue,
+        };
+
+        let tx = rpc
+            .get_transaction(&signature, UiTransactionEncoding::Base64)?
+            .transaction
+            .transaction
+            .decode();
+        let message = match tx {
+            Some(tx) => tx.message,
+            None => continue,
+        };
+        if message.account_keys.first() != Some(payer) {
+            continue;
+        }
+
+        let liquidates = message.instructions.iter().any(|ix| {
+            message.account_keys[ix.program_id_index as usize] == zo_abi::ID
+                && ix.data.starts_with(discriminator)
+                && ix
+                    .accounts
+                    .iter()
+                    .any(|&i| message.account_keys[i as usize] == *account)
+        });
+        if liquidates {
+            return Ok(Some(signature));
+        }
+    }
+
+    Ok(None)
+}
+
+/// The accounts and settings a liquidation is sent with, copied out of
+/// the account table when it is planned.
+pub struct LiquidationParams {
+    pub dex_program: Pubkey,
+    pub payer_pubkey: Pubkey,
+    pub payer_margin: Margin,
+    pub payer_margin_key: Pubkey,
+    pub payer_control: Control,
+    pub payer_control_key: Pubkey,
+    pub payer_oo: [Pubkey; MAX_MARKETS as usize],
+    pub cache: Cache,
+    pub cache_key: Pubkey,
+    pub state: State,
+    pub state_key: Pubkey,
+    pub state_signer: Pubkey,
+    pub market_infos: Vec<MarketState>,
+    pub serum_markets: HashMap<usize, SerumMarketState>,
+    pub serum_dex_program: Pubkey,
+    pub serum_vault_signers: HashMap<usize, Pubkey>,
+    pub deadline: Option<Instant>,
+    pub max_oracle_age: Option<std::time::Duration>,
+    pub min_improvement: Option<f64>,
+    pub style: LiquidationStyle,
+    pub scope: LiquidationScope,
+    pub full_threshold: i64,
+    pub two_phase_threshold: Option<i64>,
+    pub min_profit: Option<i64>,
+    pub non_seizable: HashSet<usize>,
+    pub preferred_pairs: Vec<(usize, usize)>,
+    pub reduce_only: HashSet<usize>,
+    pub retry_policy: RetryPolicy,
+    pub dry_run: bool,
+}
+
 #[tracing::instrument(
     skip_all,
     level = "error",
@@ -77,37 +436,64 @@ pub async fn liquidate_loop(st: &'static crate::AppState, database: DbWrapper) {
 )]
 pub fn liquidate(
     program: &Program,
-    dex_program: &Pubkey,
-    payer_pubkey: &Pubkey,
-    payer_margin: &Margin,
-    payer_margin_key: &Pubkey,
-    payer_control: &Control,
-    payer_control_key: &Pubkey,
-    payer_oo: &[Pubkey; MAX_MARKETS as usize],
+    params: &LiquidationParams,
+    jito: Option<&JitoSender>,
     margin_key: &Pubkey,
     margin: &Margin,
     control: &Control,
-    cache: &Cache,
-    cache_key: &Pubkey,
-    state: &State,
-    state_key: &Pubkey,
-    state_signer: &Pubkey,
-    market_infos: Vec<MarketState>,
-    serum_markets: HashMap<usize, SerumMarketState>,
-    serum_dex_program: &Pubkey,
-    serum_vault_signers: HashMap<usize, Pubkey>,
 ) -> Result<(), ErrorCode> {
+    let LiquidationParams {
+        dex_program,
+        payer_pubkey,
+        payer_margin,
+        payer_margin_key,
+        payer_control,
+        payer_control_key,
+        payer_oo,
+        cache,
+        cache_key,
+        state,
+        state_key,
+        state_signer,
+        market_infos,
+        serum_markets,
+        serum_dex_program,
+        serum_vault_signers,
+        non_seizable,
+        preferred_pairs,
+        reduce_only,
+        retry_policy,
+        ..
+    } = params;
+    let LiquidationParams {
+        deadline,
+        max_oracle_age,
+        min_improvement,
+        style,
+        scope,
+        full_threshold,
+        two_phase_threshold,
+        min_profit,
+        dry_run,
+        ..
+    } = *params;
+
     // Given an account to liquidate
     // Go through its positions and pick the largest one.
     // Liquidate that position.
 
-    // Start by sorting the collateral
-    let colls = get_actual_collateral_vec(
-        margin,
-        &RefCell::new(*state).borrow(),
-        &RefCell::new(*cache).borrow(),
-        true,
-    );
+    // The task may have waited for a blocking thread.
+    check_deadline(deadline)?;
+
+    // For the same reason, the prices are checked again before they
+    // are acted on.
+    if let Some(max_age) = max_oracle_age {
+        check_fresh_oracles(margin, control, state, cache, max_age)?;
+    }
+
+    // Start by sorting the collateral. Entries are by collateral index,
+    // as they pick the collaterals to liquidate.
+    let colls = get_indexed_collateral_vec(margin, state, cache, true);
     let colls = match colls {
         Ok(colls) => colls,
         Err(e) => {
@@ -125,20 +511,11 @@ pub fn liquidate(
             None => return Err(ErrorCode::NoCollateral),
         };
 
-    // Find the highest weighted asset that is positive.
-    let mut quote_info: Option<(usize, &I80F48)> = None;
-    let mut current_weight = 0;
-    for (i, coll) in collateral_tuple {
-        if coll.is_positive() && state.collaterals[i].weight > current_weight {
-            current_weight = state.collaterals[i].weight;
-            quote_info = Some((i, &coll));
-        }
-    }
+    let seizable_colls = seizable_collaterals(&colls, non_seizable);
+
+    let quote_info = select_seize_collateral(&seizable_colls, state)
+        .or(Some((0, &I80F48::ZERO)));
 
-    if quote_info.is_none() {
-        quote_info = Some((0, &I80F48::ZERO));
-    }
-    
     // Sort the positions
     let positions: Vec<I80F48> = control
         .open_orders_agg
@@ -184,11 +561,57 @@ pub fn liquidate(
     );
     let market_info = market_infos[position_index];
 
-    let is_spot_bankrupt = colls.iter().all(|col| col < &DUST_THRESHOLD);
+    // Non-seizable deposits still back the account, so bankruptcy is
+    // decided on every balance. The seizable view only says whether a
+    // spot liquidation has anything to take.
+    let spot_bankrupt = is_spot_bankrupt(&colls);
+    let nothing_seizable = is_spot_bankrupt(&seizable_colls);
+
+    let perp_first = has_positions
+        && (min_col.abs() <= max_position_notional.abs() || nothing_seizable);
+    let (take_perp, take_spot) =
+        liquidation_paths(scope, has_positions, perp_first, nothing_seizable);
+
+    if take_perp {
+        // Large positions with resting orders are taken in two phases,
+        // so that each transaction stays small. The liquidation is then
+        // sized from the accounts as they are after the cancel.
+        let two_phase = matches!(
+            two_phase_threshold,
+            Some(t) if max_position_notional.abs() >= I80F48::from_num(t)
+        ) && has_open_orders(cache, control)?;
+        let (margin, control) = if two_phase && dry_run {
+            info!("Dry run: would cancel margin {}'s orders first", margin_key);
+            (*margin, *control)
+        } else if two_phase {
+            info!("Cancelling {}'s orders before liquidating", margin_key);
+            cancel_then_reload(
+                || {
+                    cancel(
+                        program,
+                        dex_program,
+                        payer_pubkey,
+                        margin_key,
+                        margin,
+                        control,
+                        cache,
+                        cache_key,
+                        state,
+                        state_key,
+                        state_signer,
+                        market_infos.clone(),
+                        retry_policy,
+                    )
+                },
+                || {
+                    check_deadline(deadline)?;
+                    reload_liqee(&program.rpc(), margin_key, &margin.control)
+                },
+            )?
+        } else {
+            (*margin, *control)
+        };
 
-    if has_positions
-        && (min_col.abs() <= max_position_notional.abs() || is_spot_bankrupt)
-    {
         liquidate_perp_position(
             program,
             payer_pubkey,
@@ -196,8 +619,9 @@ pub fn liquidate(
             payer_margin_key,
             payer_control,
             &payer_oo[position_index],
-            margin,
+            &margin,
             margin_key,
+            &control,
             &open_orders,
             cache,
             cache_key,
@@ -209,11 +633,29 @@ pub fn liquidate(
             &dex_market,
             position_index,
             max_position_notional.is_positive(),
+            jito,
+            retry_policy,
+            deadline,
+            min_improvement,
+            style,
+            full_threshold,
+            min_profit,
+            reduce_only.contains(&position_index),
+            dry_run,
         )?;
-    } else if is_spot_bankrupt && !has_positions {
+    } else if spot_bankrupt && !has_positions {
         let oo_index_result = largest_open_order(cache, control)?;
         
-        if let Some(_order_index) = oo_index_result {
+        if dry_run {
+            info!(
+                "Dry run: would {} bankrupt margin {}",
+                match oo_index_result {
+                    Some(_) => "cancel the orders of",
+                    None => "settle",
+                },
+                margin_key
+            );
+        } else if let Some(_order_index) = oo_index_result {
             cancel(
                 program,
                 dex_program,
@@ -226,7 +668,8 @@ pub fn liquidate(
                 state,
                 state_key,
                 state_signer,
-                market_infos,
+                market_infos.clone(),
+                retry_policy,
             )?;
         } else {
             settle_bankruptcy(
@@ -244,15 +687,24 @@ pub fn liquidate(
                 serum_markets,
                 serum_dex_program,
                 serum_vault_signers,
+                retry_policy,
             )?;
         };
-    } else if *min_col < 0u64 {
+    } else if *min_col < 0u64 && take_spot && nothing_seizable {
+        info!(
+            "Skipping margin {}, it has no seizable collateral left",
+            margin_key
+        );
+    } else if *min_col < 0u64 && take_spot {
         // Close a spot position
         let quote_idx = if let Some((q_idx, _q_coll)) = quote_info {
             q_idx
         } else {
             0
         };
+        let (asset_idx, quote_idx) =
+            select_liquidation_pair(&colls, &seizable_colls, preferred_pairs)
+                .unwrap_or((col_index, quote_idx));
 
         liquidate_spot_position(
             program,
@@ -261,20 +713,39 @@ pub fn liquidate(
             payer_margin_key,
             margin,
             margin_key,
+            control,
             cache,
             cache_key,
             state,
             state_key,
             state_signer,
-            col_index,
+            asset_idx,
             quote_idx,
-            min_col.abs().to_num(),
+            colls[asset_idx].abs().to_num(),
             serum_markets,
             serum_dex_program,
             serum_vault_signers,
+            jito,
+            retry_policy,
+            deadline,
+            style,
+            full_threshold,
+            min_profit,
+            dry_run,
         )?;
+    } else if scope != LiquidationScope::Both
+        && (has_positions || *min_col < 0u64)
+    {
+        info!(
+            "Skipping margin {}, its liquidation is outside the {:?} scope",
+            margin_key, scope
+        );
     } else if let Some(_order_index) = largest_open_order(cache, control)? {
         // Must cancel perp open orders
+        if dry_run {
+            info!("Dry run: would cancel margin {}'s orders", margin_key);
+            return Ok(());
+        }
         info!("Closing {}'s {} perp order", margin.authority, col_index);
         cancel(
             program,
@@ -288,7 +759,8 @@ pub fn liquidate(
             state,
             state_key,
             state_signer,
-            market_infos,
+            market_infos.clone(),
+            retry_policy,
         )?;
     }
 
@@ -297,6 +769,165 @@ pub fn liquidate(
     Ok(())
 }
 
+/// Fails with `StaleOracle` if any oracle the account depends on was
+/// last updated more than `max_age` ago.
+fn check_fresh_oracles(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+    max_age: std::time::Duration,
+) -> Result<(), ErrorCode> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    for symbol in account_symbols(margin, control, state) {
+        if let Err(ErrorCode::StaleOracle) =
+            get_fresh_oracle(cache, &symbol, max_age.as_secs(), now)
+        {
+            warn!(
+                "Not liquidating {}, {} is stale",
+                margin.authority,
+                String::from(symbol)
+            );
+            return Err(ErrorCode::StaleOracle);
+        }
+    }
+
+    Ok(())
+}
+
+/// The expected profit of liquidating `notional` at `liq_fee`, after
+/// the base fee and the priority fee of a send landing on `attempt`,
+/// e.g. `required_attempt()`, if it is below `min_profit`. This is the
+/// only profit gate, applied once the liquidation is sized.
+fn below_min_profit(
+    notional: I80F48,
+    liq_fee: u16,
+    cu: u64,
+    cache: &Cache,
+    fees: &PriorityFees,
+    attempt: usize,
+    min_profit: Option<i64>,
+) -> Option<i64> {
+    let min_profit = min_profit?;
+    let reward = pnl::LiquidationEstimate::new(
+        notional.abs().saturating_to_num(),
+        liq_fee,
+        cu,
+    )
+    .net(fees, attempt, cache);
+    (reward < min_profit).then(|| reward)
+}
+
+/// Whether to take the account's largest perp position and whether to
+/// seize its collateral, within `scope`. `perp_first` is the choice
+/// made when both are allowed.
+fn liquidation_paths(
+    scope: LiquidationScope,
+    has_positions: bool,
+    perp_first: bool,
+    nothing_seizable: bool,
+) -> (bool, bool) {
+    match scope {
+        LiquidationScope::Both => (perp_first, !perp_first),
+        LiquidationScope::PerpOnly => (has_positions, false),
+        // Without seizable collateral only the perp path can help.
+        LiquidationScope::SpotOnly => (false, !nothing_seizable),
+    }
+}
+
+/// The first phase of a two-phase liquidation: sends the cancel and,
+/// only once it went through, returns the liqee as `reload` finds it
+/// after the cancel, for the liquidation to be sized from.
+fn cancel_then_reload(
+    cancel: impl FnOnce() -> Result<(), ErrorCode>,
+    reload: impl FnOnce() -> Result<(Margin, Control), ErrorCode>,
+) -> Result<(Margin, Control), ErrorCode> {
+    cancel()?;
+    reload()
+}
+
+/// Fetches the liqee's margin and control afresh, e.g. once its orders
+/// are cancelled.
+fn reload_liqee(
+    rpc: &RpcClient,
+    margin_key: &Pubkey,
+    control_key: &Pubkey,
+) -> Result<(Margin, Control), ErrorCode> {
+    let margin = load_accounts::<Margin>(rpc, &[*margin_key])?
+        .pop()
+        .ok_or(ErrorCode::IncompleteAccountFetch)?;
+    let control = load_accounts::<Control>(rpc, &[*control_key])?
+        .pop()
+        .ok_or(ErrorCode::IncompleteAccountFetch)?;
+    Ok((margin.1, control.1))
+}
+
+/// Picks the collateral a spot liquidation takes from the liqee, which
+/// is the positive balance with the highest weight.
+fn select_seize_collateral<'a>(
+    colls: &'a [I80F48],
+    state: &State,
+) -> Option<(usize, &'a I80F48)> {
+    let mut seize: Option<(usize, &I80F48)> = None;
+    let mut current_weight = 0;
+    for (i, coll) in colls.iter().enumerate() {
+        if coll.is_positive() && state.collaterals[i].weight > current_weight {
+            current_weight = state.collaterals[i].weight;
+            seize = Some((i, coll));
+        }
+    }
+
+    seize
+}
+
+/// The balances a liquidation can take. Non-seizable balances can't be
+/// taken, so only debts are counted for them. Both `colls` and
+/// `non_seizable` are by collateral index.
+fn seizable_collaterals(
+    colls: &[I80F48],
+    non_seizable: &HashSet<usize>,
+) -> Vec<I80F48> {
+    colls
+        .iter()
+        .enumerate()
+        .map(|(i, &col)| match non_seizable.contains(&i) {
+            true => col.min(I80F48::ZERO),
+            false => col,
+        })
+        .collect()
+}
+
+/// The first preferred `(asset, quote)` pair in which the liqee has a
+/// debt in the asset and a seizable balance in the quote. Balances are
+/// by collateral index, as from `get_indexed_collateral_vec`.
+fn select_liquidation_pair(
+    colls: &[I80F48],
+    seizable_colls: &[I80F48],
+    preferred_pairs: &[(usize, usize)],
+) -> Option<(usize, usize)> {
+    preferred_pairs.iter().copied().find(|&(asset, quote)| {
+        asset != quote
+            && colls.get(asset).map_or(false, |c| c.is_negative())
+            && seizable_colls.get(quote).map_or(false, |c| c.is_positive())
+    })
+}
+
+/// Fails once the attempt's deadline has passed, so that a liquidation
+/// sized from an old snapshot is not sent.
+fn check_deadline(deadline: Option<Instant>) -> Result<(), ErrorCode> {
+    match deadline {
+        Some(d) if Instant::now() > d => {
+            warn!("Liquidation attempt passed its deadline, deferring");
+            Err(ErrorCode::DeadlineExceeded)
+        }
+        _ => Ok(()),
+    }
+}
+
 pub fn cancel(
     program: &Program,
     dex_program: &Pubkey,
@@ -310,6 +941,7 @@ pub fn cancel(
     state_key: &Pubkey,
     state_signer: &Pubkey,
     market_info: Vec<MarketState>,
+    retry_policy: &RetryPolicy,
 ) -> Result<(), ErrorCode> {
     let span = error_span!("cancel");
 
@@ -346,6 +978,7 @@ pub fn cancel(
         &market_info.bids,
         &market_info.asks,
         dex_program,
+        retry_policy,
     )?;
 
     Ok(())
@@ -366,6 +999,7 @@ fn cancel_orders(
     market_bids: &Pubkey,
     market_asks: &Pubkey,
     dex_program: &Pubkey,
+    retry_policy: &RetryPolicy,
 ) -> Result<(), ErrorCode> {
     // Can probably save some of these variables in the ds.
     // e.g. the state_signer and open_orders.
@@ -393,11 +1027,11 @@ fn cancel_orders(
                 .args(instruction::ForceCancelAllPerpOrders { limit: 32 })
                 .options(CommitmentConfig::confirmed())
         },
-        5,
+        retry_policy,
     );
 
     match signature {
-        Ok(tx) => {
+        Ok(tx) | Err(ErrorCode::AlreadyLanded(tx)) => {
             span.in_scope(|| {
                 info!("Cancelled {}'s open orders. tx: {:?}", margin_key, tx)
             });
@@ -420,6 +1054,7 @@ fn liquidate_perp_position(
     liqor_oo_key: &Pubkey,
     liqee_margin: &Margin,
     liqee_margin_key: &Pubkey,
+    liqee_control: &Control,
     liqee_open_orders: &Pubkey,
     cache: &Cache,
     cache_key: &Pubkey,
@@ -431,6 +1066,15 @@ fn liquidate_perp_position(
     dex_market: &Pubkey,
     index: usize,
     liqee_was_long: bool,
+    jito: Option<&JitoSender>,
+    retry_policy: &RetryPolicy,
+    deadline: Option<Instant>,
+    min_improvement: Option<f64>,
+    style: LiquidationStyle,
+    full_threshold: i64,
+    min_profit: Option<i64>,
+    reduce_only: bool,
+    dry_run: bool,
 ) -> Result<(), ErrorCode> {
     let span = error_span!(
         "liquidate_perp_position",
@@ -461,15 +1105,76 @@ fn liquidate_perp_position(
         program_id: program.id(),
     };
 
-    let mut asset_transfer_lots =
-        get_total_collateral(liqor_margin, cache, state)
-            .checked_div(cache.marks[index].price.into())
-            .unwrap()
-            .to_num::<i64>()
-            .safe_div(market_info.coin_lot_size)
-            .unwrap()
-            .safe_mul(5i64) // 5x leverage
-            .unwrap();
+    let partial_lots = get_total_collateral(liqor_margin, cache, state)?
+        .checked_div(cache.marks[index].price.into())
+        .unwrap()
+        .to_num::<i64>()
+        .safe_div(market_info.coin_lot_size)
+        .unwrap()
+        .safe_mul(5i64) // 5x leverage
+        .unwrap();
+
+    let pos_size = { liqee_control.open_orders_agg[index].pos_size };
+    let lot_size = market_info.coin_lot_size;
+    let mut asset_transfer_lots = select_liquidation_size(
+        style,
+        (pos_size.abs() + lot_size - 1) / lot_size,
+        partial_lots,
+        safe_mul_i80f48(
+            I80F48::from_num(pos_size),
+            cache.marks[index].price.into(),
+        ),
+        full_threshold,
+    );
+
+    if let Some(min_improvement) = min_improvement {
+        asset_transfer_lots = escalate_to_min_improvement(
+            liqee_margin,
+            liqee_control,
+            state,
+            cache,
+            index,
+            market_info.coin_lot_size,
+            asset_transfer_lots,
+            partial_lots,
+            min_improvement,
+        );
+    }
+
+    if reduce_only {
+        let max_lots =
+            reduce_only_lots(liqor_control, index, liqee_was_long, lot_size);
+        if max_lots == 0 {
+            span.in_scope(|| {
+                warn!("Market {} is reduce-only, nothing to offset", index)
+            });
+            return Err(ErrorCode::ReduceOnlyMarket);
+        }
+        asset_transfer_lots = asset_transfer_lots.min(max_lots);
+    }
+
+    let notional = safe_mul_i80f48(
+        I80F48::from_num(asset_transfer_lots.safe_mul(lot_size)?),
+        cache.marks[index].price.into(),
+    );
+    if let Some(reward) = below_min_profit(
+        notional,
+        state.perp_markets[index].liq_fee,
+        pnl::PERP_LIQUIDATION_CU,
+        cache,
+        &retry_policy.fees,
+        required_attempt(),
+        min_profit,
+    ) {
+        span.in_scope(|| {
+            debug!(
+                "Skipping margin {}'s perp {}, expected profit {} is below \
+                 the minimum",
+                liqee_margin_key, index, reward
+            )
+        });
+        return Err(ErrorCode::BelowMinProfit(reward));
+    }
 
     let mut liq_ix = Instruction {
         accounts: ix_accounts::LiquidatePerpPosition {
@@ -519,11 +1224,51 @@ fn liquidate_perp_position(
         }
     };
 
+    if dry_run {
+        let estimate = estimate_perp_liquidation_size(
+            liqee_margin,
+            liqee_control,
+            state,
+            cache,
+            index,
+            market_info.coin_lot_size,
+            None,
+        );
+        span.in_scope(|| {
+            info!(
+                "Dry run: would liquidate {} lots of margin {}'s perp {} \
+                 (estimated {:?}). ixs: {:?}",
+                asset_transfer_lots,
+                liqee_margin_key,
+                index,
+                estimate,
+                [Some(&cancel_ix), Some(&liq_ix), rebalance_ix.as_ref()],
+            )
+        });
+        return Ok(());
+    }
+
     let reduction_max = 5;
+    let rpc = program.rpc();
+
+    let health = MarginHealth::new(liqee_margin, liqee_control, state, cache)
+        .ok()
+        .map(|h| (h.acc_value, h.maint_requirement));
 
     let mut signature;
     for _reduction in 0..reduction_max {
-        signature = retry_send(
+        check_deadline(deadline)?;
+        let wal_id = wal::intend(
+            liqee_margin_key,
+            "perp",
+            asset_transfer_lots,
+            health,
+            || rpc.get_slot().unwrap_or(0),
+        )?;
+        metrics::liquidation_attempted();
+        signature = jito::send(
+            jito,
+            &rpc,
             || {
                 let request = program
                     .request()
@@ -536,20 +1281,103 @@ fn liquidate_perp_position(
                     request
                 }
             },
-            5,
+            retry_policy,
         );
+        // A send that may still land is left for the next run to
+        // reconcile.
+        if !matches!(signature, Err(ErrorCode::SendInFlight)) {
+            wal::resolve(wal_id, signature.as_ref().ok());
+        }
 
         match signature {
             Ok(tx) => {
+                metrics::liquidation_landed();
                 span.in_scope(|| {
                     info!(
                         "Liquidated {}'s perp. tx: {:?}",
                         liqee_margin.authority, tx
                     )
                 });
+
+                // Whole positions are sized up to a full lot, so no more
+                // than the position itself can be taken.
+                let intended = asset_transfer_lots
+                    .saturating_mul(market_info.coin_lot_size)
+                    .min(pos_size.abs());
+                match reload_liqee(
+                    &rpc,
+                    liqee_margin_key,
+                    &liqee_margin.control,
+                ) {
+                    Ok((_, post)) => {
+                        let outcome = verify_liquidation_outcome(
+                            liqee_control,
+                            &post,
+                            index,
+                            intended,
+                        );
+                        if outcome != LiquidationOutcome::AsIntended {
+                            span.in_scope(|| {
+                                warn!(
+                                    "Liquidation of {}'s perp went otherwise \
+                                     than planned: {:?}",
+                                    liqee_margin.authority, outcome
+                                )
+                            });
+                        }
+                    }
+                    Err(e) => span.in_scope(|| {
+                        warn!("Failed to verify the liquidation: {:?}", e)
+                    }),
+                }
+                let notional = safe_mul_i80f48(
+                    I80F48::from_num(
+                        asset_transfer_lots
+                            .safe_mul(market_info.coin_lot_size)
+                            .unwrap_or(0),
+                    ),
+                    cache.marks[index].price.into(),
+                );
+                let fee = fee_paid(
+                    &rpc,
+                    &tx,
+                    &retry_policy.fees,
+                    pnl::PERP_LIQUIDATION_CU,
+                );
+                pnl::record_liquidation(
+                    pnl::expected_liq_bonus(
+                        notional.saturating_to_num(),
+                        state.perp_markets[index].liq_fee,
+                    ),
+                    fee,
+                    cache,
+                );
                 return Ok(());
             }
             Err(e) => match e {
+                // Already recorded when it landed, so nothing is counted
+                // again.
+                ErrorCode::AlreadyLanded(tx) => {
+                    span.in_scope(|| {
+                        info!(
+                            "{}'s perp liquidation already landed. tx: {:?}",
+                            liqee_margin.authority, tx
+                        )
+                    });
+                    return Ok(());
+                }
+                // Sending a smaller one could take the account twice, so
+                // it is left to a later scan to see what landed.
+                ErrorCode::SendInFlight => {
+                    span.in_scope(|| {
+                        warn!(
+                            "{}'s perp liquidation may still land, \
+                             rescanning before another",
+                            liqee_margin.authority
+                        )
+                    });
+                    return Err(ErrorCode::SendInFlight);
+                }
                 ErrorCode::LiquidationOverExposure => {
                     asset_transfer_lots /= 2;
                     liq_ix.data = instruction::LiquidatePerpPosition {
@@ -570,6 +1398,124 @@ fn liquidate_perp_position(
     Err(ErrorCode::LiquidationFailure)
 }
 
+/// Most lots the liquidator can take in a reduce-only market, i.e. the
+/// lots of its own position on the other side of the liqee's.
+fn reduce_only_lots(
+    liqor_control: &Control,
+    index: usize,
+    liqee_was_long: bool,
+    lot_size: i64,
+) -> i64 {
+    let liqor_pos = { liqor_control.open_orders_agg[index].pos_size };
+    match liqee_was_long == (liqor_pos < 0) {
+        true => liqor_pos.abs() / lot_size,
+        false => 0,
+    }
+}
+
+/// Picks between the size closing the whole position and the partial
+/// size allowed by the liquidator's margin, both positive. The full size
+/// is capped by the partial one, as the liquidator can't take on more
+/// than 5x its collateral.
+fn select_liquidation_size(
+    style: LiquidationStyle,
+    full: i64,
+    partial: i64,
+    notional: I80F48,
+    full_threshold: i64,
+) -> i64 {
+    match style {
+        LiquidationStyle::AlwaysFull => full.min(partial),
+        LiquidationStyle::PartialToTarget => partial,
+        LiquidationStyle::Adaptive => {
+            if notional.abs() <= full_threshold {
+                full.min(partial)
+            } else {
+                partial
+            }
+        }
+    }
+}
+
+/// Doubles `lots` until taking them improves the liqee's approximate
+/// margin ratio by at least `min_improvement`, or until the whole
+/// position or `partial_lots` would be taken, so repeated partial
+/// liquidations each make meaningful progress. `partial_lots` is the
+/// most the liquidator's margin allows, as in `select_liquidation_size`.
+fn escalate_to_min_improvement(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+    index: usize,
+    coin_lot_size: i64,
+    lots: i64,
+    partial_lots: i64,
+    min_improvement: f64,
+) -> i64 {
+    let pos_size = { control.open_orders_agg[index].pos_size };
+    let max_lots = ((pos_size.abs() + coin_lot_size - 1) / coin_lot_size)
+        .min(partial_lots);
+    let before = approx_margin_ratio(margin, control, state, cache);
+
+    let mut lots = lots.max(1);
+    while lots < max_lots {
+        let after = approx_margin_ratio_after_perp_liq(
+            margin,
+            control,
+            state,
+            cache,
+            index,
+            lots.saturating_mul(coin_lot_size),
+        );
+        if after - before >= min_improvement {
+            break;
+        }
+        lots = lots.saturating_mul(2).min(max_lots);
+    }
+
+    lots
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LiquidationOutcome {
+    /// The position shrank by exactly the intended size.
+    AsIntended,
+    /// Less than intended was taken, e.g. on a partial fill.
+    Partial { taken: i64, intended: i64 },
+    /// More than intended was taken, e.g. another liquidator got in
+    /// between the snapshot and the transaction.
+    Interfered { taken: i64, intended: i64 },
+    /// The position was not reduced.
+    Unchanged,
+}
+
+/// Compares the liqee's position in `index` before and after a perp
+/// liquidation against the intended size, in native base units.
+pub fn verify_liquidation_outcome(
+    pre: &Control,
+    post: &Control,
+    index: usize,
+    intended: i64,
+) -> LiquidationOutcome {
+    let pre_size = { pre.open_orders_agg[index].pos_size };
+    let post_size = { post.open_orders_agg[index].pos_size };
+
+    // A flipped position means more than the whole position was taken.
+    let taken = if pre_size.signum() * post_size.signum() < 0 {
+        pre_size.abs() + post_size.abs()
+    } else {
+        pre_size.abs() - post_size.abs()
+    };
+
+    match taken {
+        t if t <= 0 => LiquidationOutcome::Unchanged,
+        t if t == intended => LiquidationOutcome::AsIntended,
+        t if t < intended => LiquidationOutcome::Partial { taken, intended },
+        _ => LiquidationOutcome::Interfered { taken, intended },
+    }
+}
+
 fn liquidate_spot_position(
     program: &Program,
     payer_pubkey: &Pubkey,
@@ -577,6 +1523,7 @@ fn liquidate_spot_position(
     liqor_margin_key: &Pubkey,
     liqee_margin: &Margin,
     liqee_margin_key: &Pubkey,
+    liqee_control: &Control,
     cache: &Cache,
     cache_key: &Pubkey,
     state: &State,
@@ -585,9 +1532,16 @@ fn liquidate_spot_position(
     asset_index: usize,
     quote_index: usize,
     debt_amount: u64,
-    serum_markets: HashMap<usize, SerumMarketState>,
+    serum_markets: &HashMap<usize, SerumMarketState>,
     serum_dex_program: &Pubkey,
-    serum_vault_signers: HashMap<usize, Pubkey>,
+    serum_vault_signers: &HashMap<usize, Pubkey>,
+    jito: Option<&JitoSender>,
+    retry_policy: &RetryPolicy,
+    deadline: Option<Instant>,
+    style: LiquidationStyle,
+    full_threshold: i64,
+    min_profit: Option<i64>,
+    dry_run: bool,
 ) -> Result<(), ErrorCode> {
     let span = error_span!("liquidate_spot_position");
 
@@ -601,13 +1555,46 @@ fn liquidate_spot_position(
             .into();
 
     let mut asset_transfer_amount =
-        -get_total_collateral(liqor_margin, cache, state)
+        -get_total_collateral(liqor_margin, cache, state)?
             .checked_div(spot_price)
             .unwrap()
             .to_num::<i64>()
             .safe_mul(5i64) // 5x leverage
             .unwrap();
 
+    let full_amount = I80F48::from_num(debt_amount)
+        .checked_div(spot_price)
+        .map(|a| -a.ceil().saturating_to_num::<i64>())
+        .unwrap_or(asset_transfer_amount);
+    asset_transfer_amount = -select_liquidation_size(
+        style,
+        -full_amount,
+        -asset_transfer_amount,
+        I80F48::from_num(debt_amount),
+        full_threshold,
+    );
+
+    let notional =
+        safe_mul_i80f48(I80F48::from_num(-asset_transfer_amount), spot_price);
+    if let Some(reward) = below_min_profit(
+        notional,
+        asset_collateral_info.liq_fee,
+        pnl::SPOT_LIQUIDATION_CU,
+        cache,
+        &retry_policy.fees,
+        required_attempt(),
+        min_profit,
+    ) {
+        span.in_scope(|| {
+            debug!(
+                "Skipping margin {}'s {} borrow, expected profit {} is below \
+                 the minimum",
+                liqee_margin_key, asset_index, reward
+            )
+        });
+        return Err(ErrorCode::BelowMinProfit(reward));
+    }
+
     let mut liq_ix = Instruction {
         accounts: ix_accounts::LiquidateSpotPosition {
             state: *state_key,
@@ -710,9 +1697,50 @@ fn liquidate_spot_position(
         swap_ixs.push(remove_excess);
     }
 
+    if dry_run {
+        let estimate = estimate_spot_liquidation_size(
+            liqee_margin,
+            liqee_control,
+            state,
+            cache,
+            asset_index,
+            quote_index,
+            None,
+        );
+        span.in_scope(|| {
+            info!(
+                "Dry run: would liquidate {} of margin {}'s collateral {} \
+                 for {} (estimated {:?}). ixs: {:?} {:?}",
+                asset_transfer_amount,
+                liqee_margin_key,
+                asset_index,
+                quote_index,
+                estimate,
+                liq_ix,
+                swap_ixs,
+            )
+        });
+        return Ok(());
+    }
+
     let reduction_max = 5;
+    let rpc = program.rpc();
+    let health = MarginHealth::new(liqee_margin, liqee_control, state, cache)
+        .ok()
+        .map(|h| (h.acc_value, h.maint_requirement));
     for _reduction in 0..reduction_max {
-        let signature = retry_send(
+        check_deadline(deadline)?;
+        let wal_id = wal::intend(
+            liqee_margin_key,
+            "spot",
+            asset_transfer_amount,
+            health,
+            || rpc.get_slot().unwrap_or(0),
+        )?;
+        metrics::liquidation_attempted();
+        let signature = jito::send(
+            jito,
+            &rpc,
             || {
                 let mut request_builder = program
                     .request()
@@ -724,20 +1752,67 @@ fn liquidate_spot_position(
                 }
                 request_builder
             },
-            5,
+            retry_policy,
         );
+        // A send that may still land is left for the next run to
+        // reconcile.
+        if !matches!(signature, Err(ErrorCode::SendInFlight)) {
+            wal::resolve(wal_id, signature.as_ref().ok());
+        }
 
         match signature {
             Ok(tx) => {
+                metrics::liquidation_landed();
                 span.in_scope(|| {
                     info!(
                         "Liquidated {}'s spot. tx: {:?}",
                         liqee_margin.authority, tx
                     )
                 });
+                let notional = safe_mul_i80f48(
+                    I80F48::from_num(asset_transfer_amount.abs()),
+                    spot_price,
+                );
+                let fee = fee_paid(
+                    &rpc,
+                    &tx,
+                    &retry_policy.fees,
+                    pnl::SPOT_LIQUIDATION_CU,
+                );
+                pnl::record_liquidation(
+                    pnl::expected_liq_bonus(
+                        notional.saturating_to_num(),
+                        asset_collateral_info.liq_fee,
+                    ),
+                    fee,
+                    cache,
+                );
                 return Ok(());
             }
             Err(e) => match e {
+                // Already recorded when it landed, so nothing is counted
+                // again.
+                ErrorCode::AlreadyLanded(tx) => {
+                    span.in_scope(|| {
+                        info!(
+                            "{}'s spot liquidation already landed. tx: {:?}",
+                            liqee_margin.authority, tx
+                        )
+                    });
+                    return Ok(());
+                }
+                // Sending a smaller one could take the account twice, so
+                // it is left to a later scan to see what landed.
+                ErrorCode::SendInFlight => {
+                    span.in_scope(|| {
+                        warn!(
+                            "{}'s spot liquidation may still land, \
+                             rescanning before another",
+                            liqee_margin.authority
+                        )
+                    });
+                    return Err(ErrorCode::SendInFlight);
+                }
                 ErrorCode::LiquidationOverExposure => {
                     asset_transfer_amount /= 2;
                     liq_ix.data = instruction::LiquidateSpotPosition {
@@ -769,17 +1844,19 @@ fn settle_bankruptcy(
     liqee_margin: &Margin,
     liqee_margin_key: &Pubkey,
     liqee_colls: Vec<I80F48>,
-    serum_markets: HashMap<usize, SerumMarketState>,
+    serum_markets: &HashMap<usize, SerumMarketState>,
     serum_dex_program: &Pubkey,
-    serum_vault_signers: HashMap<usize, Pubkey>,
+    serum_vault_signers: &HashMap<usize, Pubkey>,
+    retry_policy: &RetryPolicy,
 ) -> Result<(), ErrorCode> {
     let span = error_span!(
         "settle_bankruptcy",
         "{}",
         liqee_margin.authority.to_string()
     );
-    let mut signature_results: Vec<(usize, Result<Signature, ErrorCode>)> =
+    let mut collaterals: Vec<usize> =
         Vec::with_capacity(MAX_COLLATERALS as usize);
+    let mut groups: Vec<IxGroup> = Vec::with_capacity(MAX_COLLATERALS as usize);
 
     for (i, mint) in state.collaterals.iter().map(|c| &c.mint).enumerate() {
         if { liqee_margin.collateral[i] } >= WrappedI80F48::zero()
@@ -816,57 +1893,474 @@ fn settle_bankruptcy(
                 None
             };
 
-        signature_results.push((
-            i,
-            retry_send(
-                || {
-                    let request_builder = program
-                        .request()
-                        .accounts(ix_accounts::SettleBankruptcy {
-                            state: *state_key,
-                            state_signer: *state_signer,
-                            cache: *cache_key,
-                            liqor: *liqor_key,
-                            liqor_margin: *liqor_margin_key,
-                            liqor_control: *liqor_control_key,
-                            liqee_margin: *liqee_margin_key,
-                            liqee_control: liqee_margin.control,
-                            asset_mint: *mint,
-                        })
-                        .args(instruction::SettleBankruptcy {})
-                        .options(CommitmentConfig::confirmed());
-
-                    match swap.clone() {
-                        Some(ix) => request_builder.instruction(ix),
-                        None => request_builder,
-                    }
-                },
-                5,
-            ),
-        ));
+        let settle_ix = Instruction {
+            accounts: ix_accounts::SettleBankruptcy {
+                state: *state_key,
+                state_signer: *state_signer,
+                cache: *cache_key,
+                liqor: *liqor_key,
+                liqor_margin: *liqor_margin_key,
+                liqor_control: *liqor_control_key,
+                liqee_margin: *liqee_margin_key,
+                liqee_control: liqee_margin.control,
+                asset_mint: *mint,
+            }
+            .to_account_metas(None),
+            data: instruction::SettleBankruptcy {}.data(),
+            program_id: program.id(),
+        };
+
+        collaterals.push(i);
+        groups.push(IxGroup {
+            ixs: swap.into_iter().chain(Some(settle_ix)).collect(),
+            compute: SETTLE_BANKRUPTCY_CU,
+        });
     }
 
-    for (i, signature) in signature_results.iter() {
+    // A collateral that fails to settle doesn't hold back the others,
+    // which `send_batched` sends on their own.
+    let mut result = Ok(());
+    for (batch, signature) in send_batched(program, &groups, retry_policy) {
+        let settled: Vec<usize> =
+            batch.iter().map(|&g| collaterals[g]).collect();
         match signature {
-            Ok(tx) => {
+            Ok(tx) | Err(ErrorCode::AlreadyLanded(tx)) => {
                 span.in_scope(|| {
                     info!(
-                        "Settled margin {}'s {} collateral. tx: {:?}",
-                        liqee_margin_key, i, tx
+                        "Settled margin {}'s {:?} collateral. tx: {:?}",
+                        liqee_margin_key, settled, tx
                     )
                 });
             }
             Err(e) => {
                 span.in_scope(|| {
                     error!(
-                        "Failed to settle bankruptcy for asset {}: {:?}",
-                        i, e
+                        "Failed to settle bankruptcy for assets {:?}: {:?}",
+                        settled, e
                     )
                 });
-                return Err(ErrorCode::SettlementFailure);
+                result = Err(ErrorCode::SettlementFailure);
             }
         }
     }
 
-    Ok(())
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::liquidator::fixtures::*;
+
+    #[test]
+    fn seizable_collaterals_skip_empty_slots() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        book.empty_collateral();
+        let msol = book.collateral("mSOL", 100.0, 900);
+
+        let (mut margin, _) = account();
+        set_balance(&mut margin, usd, -500);
+        set_balance(&mut margin, msol, 10);
+
+        let colls =
+            get_indexed_collateral_vec(&margin, &book.state, &book.cache, true)
+                .unwrap();
+        assert_eq!(colls.len(), 3);
+        assert_eq!(colls[1], I80F48::ZERO);
+
+        let non_seizable = HashSet::from([msol]);
+        let seizable = seizable_collaterals(&colls, &non_seizable);
+        assert_eq!(seizable[usd], I80F48::from_num(-500));
+        assert_eq!(seizable[msol], I80F48::ZERO);
+        assert_eq!(
+            select_liquidation_pair(&colls, &seizable, &[(usd, msol)]),
+            None
+        );
+    }
+
+    #[test]
+    fn liquidation_outcome_compares_taken_with_intended() {
+        let (_, mut pre) = account();
+        set_position(&mut pre, 0, 1_000, 10.0);
+        let outcome = |post_size: i64| {
+            let mut post = pre;
+            post.open_orders_agg[0].pos_size = post_size;
+            verify_liquidation_outcome(&pre, &post, 0, 400)
+        };
+
+        assert_eq!(outcome(600), LiquidationOutcome::AsIntended);
+        assert_eq!(
+            outcome(800),
+            LiquidationOutcome::Partial {
+                taken: 200,
+                intended: 400
+            }
+        );
+        assert_eq!(
+            outcome(100),
+            LiquidationOutcome::Interfered {
+                taken: 900,
+                intended: 400
+            }
+        );
+        assert_eq!(
+            outcome(-100),
+            LiquidationOutcome::Interfered {
+                taken: 1_100,
+                intended: 400
+            }
+        );
+        assert_eq!(outcome(1_000), LiquidationOutcome::Unchanged);
+    }
+
+    #[test]
+    fn slow_attempts_are_abandoned_at_the_deadline() {
+        let deadline = Instant::now() + std::time::Duration::from_millis(20);
+        assert!(check_deadline(Some(deadline)).is_ok());
+
+        // Building and simulating took longer than the deadline allows.
+        std::thread::sleep(std::time::Duration::from_millis(40));
+        assert!(matches!(
+            check_deadline(Some(deadline)),
+            Err(ErrorCode::DeadlineExceeded)
+        ));
+
+        assert!(check_deadline(None).is_ok());
+    }
+
+    #[test]
+    fn undersized_partials_are_escalated() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+
+        // A margin ratio of 1.2, which a lot of 100 barely moves.
+        let (mut margin, mut control) = account();
+        set_balance(&mut margin, usd, 1_500_000);
+        set_position(&mut control, btc, 10_000, 1090.0);
+
+        let escalate_within = |lots, partial_lots, min_improvement| {
+            escalate_to_min_improvement(
+                &margin,
+                &control,
+                &book.state,
+                &book.cache,
+                btc,
+                100,
+                lots,
+                partial_lots,
+                min_improvement,
+            )
+        };
+        let escalate = |lots, min_improvement| {
+            escalate_within(lots, 1_000, min_improvement)
+        };
+
+        // 8 lots bring it to 1.287 and 16 lots to 1.39.
+        assert_eq!(escalate(1, 0.15), 16);
+        assert_eq!(escalate(16, 0.15), 16);
+        assert_eq!(escalate(1, 0.0), 1);
+        // Escalation stops at the whole position.
+        assert_eq!(escalate(1, 100.0), 100);
+        // Nor does it go past what the liquidator's margin allows.
+        assert_eq!(escalate_within(1, 10, 0.15), 10);
+        assert_eq!(escalate_within(1, 10, 100.0), 10);
+    }
+
+    #[test]
+    fn liquidation_styles_size_small_and_large_accounts() {
+        use LiquidationStyle::*;
+        let threshold = 10_000;
+        let size = |style, full, partial, notional: i64| {
+            select_liquidation_size(
+                style,
+                full,
+                partial,
+                I80F48::from_num(notional),
+                threshold,
+            )
+        };
+
+        // A small position the liquidator could take whole, and a large
+        // one it can only take a part of.
+        let small = (5, 20, 1_000);
+        let large = (500, 20, -100_000);
+        for (style, small_size, large_size) in [
+            (AlwaysFull, 5, 20),
+            (PartialToTarget, 20, 20),
+            (Adaptive, 5, 20),
+        ] {
+            assert_eq!(size(style, small.0, small.1, small.2), small_size);
+            assert_eq!(size(style, large.0, large.1, large.2), large_size);
+        }
+
+        // Positions at the threshold count as small.
+        assert_eq!(size(Adaptive, 50, 80, threshold), 50);
+        assert_eq!(size(Adaptive, 50, 80, -threshold), 50);
+    }
+
+    #[test]
+    fn scans_back_off_and_recover_from_transient_failures() {
+        let period = std::time::Duration::from_millis(250);
+        let mut failures = ScanFailures::new(Some(4));
+
+        // An outage makes three scans in a row fail.
+        assert_eq!(failures.failed(period), Some(period * 2));
+        assert_eq!(failures.failed(period), Some(period * 4));
+        assert_eq!(failures.failed(period), Some(period * 8));
+
+        // The next scan succeeds, so the outage doesn't count against
+        // later ones.
+        assert_eq!(failures.succeeded(), 3);
+        assert_eq!(failures.succeeded(), 0);
+        assert_eq!(failures.failed(period), Some(period * 2));
+
+        // A long outage backs off no further than the cap, and gives up
+        // at the limit.
+        let mut failures = ScanFailures::new(Some(10));
+        for _ in 0..8 {
+            let backoff = failures.failed(period).unwrap();
+            assert!(backoff <= MAX_SCAN_BACKOFF);
+        }
+        assert_eq!(failures.failed(period), Some(MAX_SCAN_BACKOFF));
+        assert_eq!(failures.failed(period), None);
+
+        let mut unlimited = ScanFailures::new(None);
+        for _ in 0..100 {
+            assert!(unlimited.failed(period).is_some());
+        }
+    }
+
+    #[test]
+    fn non_seizable_collaterals_are_not_seized() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let stsol = book.collateral("stSOL", 100.0, 950);
+        let msol = book.collateral("mSOL", 100.0, 900);
+
+        let (mut margin, _) = account();
+        set_balance(&mut margin, usd, -500);
+        set_balance(&mut margin, stsol, 10);
+        set_balance(&mut margin, msol, 10);
+        let colls =
+            get_indexed_collateral_vec(&margin, &book.state, &book.cache, true)
+                .unwrap();
+
+        let seized = |non_seizable: HashSet<usize>| {
+            let seizable = seizable_collaterals(&colls, &non_seizable);
+            select_seize_collateral(&seizable, &book.state).map(|(i, _)| i)
+        };
+        assert_eq!(seized(HashSet::new()), Some(stsol));
+        assert_eq!(seized(HashSet::from([stsol])), Some(msol));
+        assert_eq!(seized(HashSet::from([stsol, msol])), None);
+    }
+
+    #[test]
+    fn preferred_pairs_are_used_when_they_apply() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let sol = book.collateral("SOL", 100.0, 950);
+        let msol = book.collateral("mSOL", 100.0, 900);
+
+        let (mut margin, _) = account();
+        set_balance(&mut margin, usd, -500);
+        set_balance(&mut margin, sol, 10);
+        set_balance(&mut margin, msol, 10);
+        let colls =
+            get_indexed_collateral_vec(&margin, &book.state, &book.cache, true)
+                .unwrap();
+        let pair = |preferred: &[(usize, usize)]| {
+            select_liquidation_pair(&colls, &colls, preferred)
+        };
+
+        // Auto-selection would seize SOL, the highest weight.
+        assert_eq!(pair(&[(usd, msol)]), Some((usd, msol)));
+        assert_eq!(pair(&[(sol, usd), (usd, msol)]), Some((usd, msol)));
+
+        // Pairs without a debt and a balance to seize fall back to it.
+        assert_eq!(pair(&[(sol, usd)]), None);
+        assert_eq!(pair(&[(usd, usd), (usd, 7)]), None);
+        assert_eq!(pair(&[]), None);
+    }
+
+    #[test]
+    fn reduce_only_markets_cap_to_the_offsetting_position() {
+        let (_, mut liqor) = account();
+        let lots = |liqor: &Control, liqee_was_long| {
+            reduce_only_lots(liqor, 0, liqee_was_long, 100)
+        };
+
+        // Without a position, nothing can be taken on either side.
+        assert_eq!(lots(&liqor, true), 0);
+        assert_eq!(lots(&liqor, false), 0);
+
+        // A short of 25 lots offsets a long liqee's, but a short liqee's
+        // would grow it.
+        set_position(&mut liqor, 0, -2_550, 10.0);
+        assert_eq!(lots(&liqor, true), 25);
+        assert_eq!(lots(&liqor, false), 0);
+
+        set_position(&mut liqor, 0, 1_000, 10.0);
+        assert_eq!(lots(&liqor, true), 0);
+        assert_eq!(lots(&liqor, false), 10);
+    }
+
+    #[test]
+    fn two_phase_liquidations_size_from_the_cancelled_account() {
+        let (margin, mut control) = account();
+        set_position(&mut control, 0, 1_000, 10.0);
+        control.open_orders_agg[0].coin_on_bids = 500;
+        let mut cancelled = control;
+        cancelled.open_orders_agg[0].coin_on_bids = 0;
+
+        let phases = std::cell::RefCell::new(Vec::new());
+        let (_, reloaded) = cancel_then_reload(
+            || {
+                phases.borrow_mut().push("cancel");
+                Ok(())
+            },
+            || {
+                phases.borrow_mut().push("reload");
+                Ok((margin, cancelled))
+            },
+        )
+        .unwrap();
+        assert_eq!(*phases.borrow(), ["cancel", "reload"]);
+        assert_eq!({ reloaded.open_orders_agg[0].coin_on_bids }, 0);
+        assert_eq!({ reloaded.open_orders_agg[0].pos_size }, 1_000);
+
+        // A failed cancel leaves the liquidation unsent.
+        phases.borrow_mut().clear();
+        let result = cancel_then_reload(
+            || {
+                phases.borrow_mut().push("cancel");
+                Err(ErrorCode::UnrecoverableTransactionError)
+            },
+            || {
+                phases.borrow_mut().push("reload");
+                Ok((margin, control))
+            },
+        );
+        assert!(matches!(
+            result,
+            Err(ErrorCode::UnrecoverableTransactionError)
+        ));
+        assert_eq!(*phases.borrow(), ["cancel"]);
+    }
+
+    #[tokio::test]
+    async fn slow_scans_skip_the_ticks_they_overran() {
+        let period = std::time::Duration::from_millis(50);
+        let quick = std::time::Duration::from_millis(5);
+        let mut interval = scan_interval(period);
+        interval.tick().await;
+
+        // The scan overruns three ticks, ending between two of them.
+        std::thread::sleep(period * 3 + period / 2);
+
+        // The next scan starts right away, but the overrun ticks don't
+        // queue up further scans behind it.
+        let next = tokio::time::timeout(quick, interval.tick()).await;
+        assert!(next.is_ok());
+        let queued = tokio::time::timeout(quick, interval.tick()).await;
+        assert!(queued.is_err());
+    }
+
+    #[test]
+    fn liquidations_outside_the_scope_are_skipped() {
+        use LiquidationScope::*;
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let sol = book.collateral("SOL", 100.0, 900);
+
+        // Only a spot liquidation can act on a borrow with no positions.
+        let (mut margin, _) = account();
+        set_balance(&mut margin, usd, -50_000);
+        set_balance(&mut margin, sol, 500);
+        let colls =
+            get_indexed_collateral_vec(&margin, &book.state, &book.cache, true)
+                .unwrap();
+        let nothing_seizable = is_spot_bankrupt(&colls);
+        assert!(!nothing_seizable);
+
+        let paths =
+            |scope| liquidation_paths(scope, false, false, nothing_seizable);
+        assert_eq!(paths(Both), (false, true));
+        assert_eq!(paths(SpotOnly), (false, true));
+        assert_eq!(paths(PerpOnly), (false, false));
+
+        // With a position, each scope keeps to its own path.
+        assert_eq!(
+            liquidation_paths(PerpOnly, true, false, false),
+            (true, false)
+        );
+        assert_eq!(
+            liquidation_paths(SpotOnly, true, true, false),
+            (false, true)
+        );
+        assert_eq!(liquidation_paths(Both, true, true, false), (true, false));
+    }
+
+    #[test]
+    fn the_profit_gate_blocks_at_high_required_fees() {
+        // Half a smol USD per lamport keeps the conversions exact.
+        let mut book = Book::new();
+        book.oracle("SOL", 0.5);
+        let fees = PriorityFees {
+            micro_lamports: Some(1_000_000),
+            compute_unit_limit: None,
+            escalation: 2.0,
+        };
+        let gate = |attempt, min_profit| {
+            below_min_profit(
+                I80F48::from_num(-30_000_000),
+                10,
+                200_000,
+                &book.cache,
+                &fees,
+                attempt,
+                min_profit,
+            )
+        };
+
+        assert_eq!(gate(0, Some(1)), None);
+        assert_eq!(gate(1, Some(1)), None);
+        assert_eq!(gate(2, Some(1)), Some(300_000 - 402_500));
+        assert_eq!(gate(2, None), None);
+    }
+
+    #[test]
+    fn non_seizable_collateral_is_not_bankruptcy() {
+        use LiquidationScope::*;
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let msol = book.collateral("mSOL", 100.0, 900);
+
+        // The account is solvent, but only on collateral that can't be
+        // seized.
+        let (mut margin, _) = account();
+        set_balance(&mut margin, usd, -50_000);
+        set_balance(&mut margin, msol, 1_000);
+        let colls =
+            get_indexed_collateral_vec(&margin, &book.state, &book.cache, true)
+                .unwrap();
+        let seizable_colls =
+            seizable_collaterals(&colls, &HashSet::from([msol]));
+
+        assert!(!is_spot_bankrupt(&colls));
+        let nothing_seizable = is_spot_bankrupt(&seizable_colls);
+        assert!(nothing_seizable);
+        assert_eq!(select_seize_collateral(&seizable_colls, &book.state), None);
+
+        // Neither scope sends a spot liquidation with nothing to take.
+        assert_eq!(
+            liquidation_paths(SpotOnly, false, false, nothing_seizable),
+            (false, false)
+        );
+        assert_eq!(
+            liquidation_paths(Both, true, true, nothing_seizable),
+            (true, false)
+        );
+    }
 }
@@ -75,15 +75,18 @@ pub async fn start_listener(
                 _ => panic!(),
             };
             let pk = &resp.value.pubkey;
+            db.get().lock().unwrap().update_slot(resp.context.slot);
 
             if let Some(a) = load_buf::<Control>(buf) {
                 debug!("got control data: {}", pk);
                 let pk = Pubkey::from_str(pk).unwrap();
                 db.get().lock().unwrap().update_control(pk, *a);
+                db.notify_update(pk);
             } else if let Some(a) = load_buf::<Margin>(buf) {
                 debug!("got margin data: {}", pk);
                 let pk = Pubkey::from_str(pk).unwrap();
                 db.get().lock().unwrap().update_margin(pk, *a);
+                db.notify_update(pk);
             } else if let Some(a) = load_buf::<Cache>(buf) {
                 debug!("got cache data: {}", pk);
                 db.get().lock().unwrap().update_cache(*a);
@@ -2,12 +2,17 @@ use anchor_lang::prelude::Pubkey;
 
 use fixed::types::I80F48;
 
-use std::{cell::Ref, cmp};
+use std::{
+    cell::{Ref, RefCell},
+    cmp,
+    collections::{BTreeMap, BTreeSet, HashMap},
+};
 
 use zo_abi::{
     Cache, CollateralInfo, Control, FractionType, Margin, MarkCache,
-    OpenOrdersInfo, PerpMarketInfo, State, WrappedI80F48, MAX_COLLATERALS,
-    MAX_MARKETS, SPOT_INITIAL_MARGIN_REQ, SPOT_MAINT_MARGIN_REQ,
+    OpenOrdersInfo, PerpMarketInfo, State, Symbol, WrappedI80F48,
+    DUST_THRESHOLD, MAX_COLLATERALS, MAX_MARKETS, SPOT_INITIAL_MARGIN_REQ,
+    SPOT_MAINT_MARGIN_REQ,
 };
 
 use crate::liquidator::{error::ErrorCode, math::*, utils::*};
@@ -21,16 +26,114 @@ struct PerpAccParams {
     pcmf_vec: Vec<u16>,
     pos_open_notional_vec: Vec<i64>,
     pos_notional_vec: Vec<i64>,
+    /// `(market_index, pos_notional, mmf_contribution)` of each market,
+    /// if asked for. The contribution is scaled by 1000.
+    market_breakdown: Vec<(usize, i64, i64)>,
 }
 
 #[derive(Clone, Copy)]
 enum MfReturnOption {
-    Imf,
-    Mmf,
-    Cancel,
     Both,
+    All,
+}
+
+/// How an account stands against its margin requirements. Values are
+/// in smol USD; `omf` and the requirements are scaled by 1000, as the
+/// fraction checks compare them.
+#[derive(Clone, Debug)]
+pub struct HealthInfo {
+    pub total_account_value: i64,
+    /// Account value capped by collateral and realized pnl, which the
+    /// initial and cancel fractions are checked against.
+    pub omf: i64,
+    pub init_margin_requirement: i64,
+    pub maint_margin_requirement: i64,
+    pub cancel_margin_requirement: i64,
+    pub has_open_pos_notional: bool,
+    /// Position notionals, by market with an open orders account.
+    pub perp_notionals: Vec<i64>,
+    /// Position notionals including resting orders.
+    pub perp_open_notionals: Vec<i64>,
+    /// Borrow notionals, by borrowed collateral.
+    pub spot_notionals: Vec<i64>,
+    /// Each market's position notional and share of the maintenance
+    /// requirement, scaled by 1000, as `(market_index, notional, mmf)`.
+    pub perp_breakdown: Vec<(usize, i64, i64)>,
+}
+
+impl HealthInfo {
+    /// Whether the fraction is met, as `check_all_fractions` finds it
+    /// with no maintenance tolerance.
+    pub fn meets(
+        &self,
+        fraction_type: FractionType,
+    ) -> Result<bool, ErrorCode> {
+        if !self.has_open_pos_notional {
+            return Ok(true);
+        }
+        Ok(match fraction_type {
+            FractionType::Initial => self.omf > self.init_margin_requirement,
+            FractionType::Maintenance => {
+                self.total_account_value.safe_mul(1000i64)?
+                    > self.maint_margin_requirement
+            }
+            FractionType::Cancel => self.omf > self.cancel_margin_requirement,
+        })
+    }
+
+    /// The market contributing most to the maintenance requirement, with
+    /// the fraction of the requirement it makes up.
+    pub fn largest_mmr_share(&self) -> Option<(usize, f64)> {
+        if self.maint_margin_requirement <= 0 {
+            return None;
+        }
+        self.perp_breakdown
+            .iter()
+            .max_by_key(|(_, _, mmf)| *mmf)
+            .map(|&(index, _, mmf)| {
+                (index, mmf as f64 / self.maint_margin_requirement as f64)
+            })
+    }
+}
+
+/// Computes the values the fraction checks compare, see `HealthInfo`.
+pub fn compute_health_info(
+    col: i64, // weighted collateral adjusted for bnl fees
+    max_markets: usize,
+    max_cols: usize,
+    oo_agg: &[OpenOrdersInfo; MAX_MARKETS as usize],
+    pm: &[PerpMarketInfo; MAX_MARKETS as usize],
+    col_info_arr: &[CollateralInfo; MAX_COLLATERALS as usize],
+    margin_col: &[WrappedI80F48; MAX_COLLATERALS as usize],
+    cache: &Ref<Cache>,
+) -> Result<HealthInfo, ErrorCode> {
+    let c = collect_margin_components(
+        col,
+        max_markets,
+        max_cols,
+        oo_agg,
+        pm,
+        col_info_arr,
+        margin_col,
+        cache,
+        true,
+    )?;
+
+    Ok(HealthInfo {
+        total_account_value: c.total_acc_value,
+        omf: c.omf()?,
+        init_margin_requirement: c.init_requirement()?,
+        maint_margin_requirement: c.maint_requirement()?,
+        cancel_margin_requirement: c.cancel_requirement()?,
+        has_open_pos_notional: c.has_open_pos_notional,
+        perp_notionals: c.pos_notional_vec,
+        perp_open_notionals: c.pos_open_notional_vec,
+        spot_notionals: c.spot_pos_notional_vec,
+        perp_breakdown: c.market_breakdown,
+    })
 }
 
+#[allow(dead_code)]
 pub fn check_fraction_requirement(
     fraction_type: FractionType,
     col: i64, // weighted collateral adjusted for bnl fees
@@ -42,23 +145,170 @@ pub fn check_fraction_requirement(
     margin_col: &[WrappedI80F48; MAX_COLLATERALS as usize],
     cache: &Ref<Cache>,
 ) -> Result<bool, ErrorCode> {
-    let return_option = match fraction_type {
-        FractionType::Initial => MfReturnOption::Imf,
-        FractionType::Maintenance => MfReturnOption::Mmf,
-        FractionType::Cancel => MfReturnOption::Cancel,
-    };
+    compute_health_info(
+        col,
+        max_markets,
+        max_cols,
+        oo_agg,
+        pm,
+        col_info_arr,
+        margin_col,
+        cache,
+    )?
+    .meets(fraction_type)
+}
+
+/// The raw ingredients of the margin fractions, for use by external
+/// risk models. The factor vectors are in thousandths and parallel to
+/// the notional vectors, perp markets first and then spot borrows.
+/// Notionals and values are in smol USD.
+#[derive(Clone, Debug)]
+pub struct MarginComponents {
+    /// Weighted collateral the account value was computed from.
+    pub col: i64,
+    pub total_acc_value: i64,
+    pub total_realized_pnl: i64,
+    pub has_open_pos_notional: bool,
+    pub pimf_vec: Vec<u16>,
+    pub pmmf_vec: Vec<u16>,
+    pub pcmf_vec: Vec<u16>,
+    pub pos_notional_vec: Vec<i64>,
+    pub pos_open_notional_vec: Vec<i64>,
+    pub spot_imf_vec: Vec<u16>,
+    pub spot_mmf_vec: Vec<u16>,
+    pub spot_pos_notional_vec: Vec<i64>,
+    /// `(market_index, pos_notional, mmf_contribution)` of each market,
+    /// only filled in by `compute_health_info`.
+    pub market_breakdown: Vec<(usize, i64, i64)>,
+}
+
+impl MarginComponents {
+    /// The factors and notionals weighed against each other for the
+    /// fraction, perp markets first and then spot borrows. Spot borrows
+    /// use their initial factors for the cancel fraction.
+    fn requirement_vectors(
+        &self,
+        fraction_type: FractionType,
+    ) -> (Vec<u16>, Vec<i64>) {
+        let (perp_factors, spot_factors, perp_notionals) = match fraction_type {
+            FractionType::Initial => (
+                &self.pimf_vec,
+                &self.spot_imf_vec,
+                &self.pos_open_notional_vec,
+            ),
+            FractionType::Maintenance => {
+                (&self.pmmf_vec, &self.spot_mmf_vec, &self.pos_notional_vec)
+            }
+            FractionType::Cancel => (
+                &self.pcmf_vec,
+                &self.spot_imf_vec,
+                &self.pos_open_notional_vec,
+            ),
+        };
+
+        let factors = perp_factors.iter().chain(spot_factors).copied();
+        let notionals = perp_notionals
+            .iter()
+            .chain(self.spot_pos_notional_vec.iter())
+            .copied();
+        (factors.collect(), notionals.collect())
+    }
+
+    /// Requirement of the fraction in smol USD, scaled by 1000.
+    fn requirement(
+        &self,
+        fraction_type: FractionType,
+    ) -> Result<i64, ErrorCode> {
+        let (factors, notionals) = self.requirement_vectors(fraction_type);
+        calc_weighted_sum(factors, notionals)
+    }
+
+    /// Maintenance requirement in smol USD, scaled by 1000.
+    pub fn maint_requirement(&self) -> Result<i64, ErrorCode> {
+        self.requirement(FractionType::Maintenance)
+    }
+
+    /// Initial requirement in smol USD, scaled by 1000.
+    pub fn init_requirement(&self) -> Result<i64, ErrorCode> {
+        self.requirement(FractionType::Initial)
+    }
+
+    /// Cancel requirement in smol USD, scaled by 1000. Spot borrows
+    /// use their initial factors.
+    pub fn cancel_requirement(&self) -> Result<i64, ErrorCode> {
+        self.requirement(FractionType::Cancel)
+    }
+
+    /// Account value capped by collateral and realized pnl, scaled by
+    /// 1000, as the initial and cancel requirements are checked against.
+    pub fn omf(&self) -> Result<i64, ErrorCode> {
+        self.total_acc_value
+            .min(self.col + self.total_realized_pnl)
+            .safe_mul(1000i64)
+    }
+
+    /// Whether the fraction is met, with `maint_tolerance` smol USD of
+    /// slack on the maintenance check. Only fails if this fraction's
+    /// own requirement can't be computed.
+    pub fn meets(
+        &self,
+        fraction_type: FractionType,
+        maint_tolerance: i64,
+    ) -> Result<bool, ErrorCode> {
+        if !self.has_open_pos_notional {
+            return Ok(true);
+        }
+        Ok(match fraction_type {
+            FractionType::Initial => self.omf()? > self.init_requirement()?,
+            FractionType::Maintenance => {
+                self.total_acc_value
+                    .safe_add(maint_tolerance.max(0))?
+                    .safe_mul(1000i64)?
+                    > self.maint_requirement()?
+            }
+            FractionType::Cancel => self.omf()? > self.cancel_requirement()?,
+        })
+    }
+
+    /// Returns `(initial, maintenance, cancel)`, each true if the
+    /// fraction is met. See `check_all_fractions`.
+    pub fn fractions(
+        &self,
+        maint_tolerance: i64,
+    ) -> Result<(bool, bool, bool), ErrorCode> {
+        Ok((
+            self.meets(FractionType::Initial, maint_tolerance)?,
+            self.meets(FractionType::Maintenance, maint_tolerance)?,
+            self.meets(FractionType::Cancel, maint_tolerance)?,
+        ))
+    }
+}
+
+fn collect_margin_components(
+    col: i64,
+    max_markets: usize,
+    max_cols: usize,
+    oo_agg: &[OpenOrdersInfo; MAX_MARKETS as usize],
+    pm: &[PerpMarketInfo; MAX_MARKETS as usize],
+    col_info_arr: &[CollateralInfo; MAX_COLLATERALS as usize],
+    margin_col: &[WrappedI80F48; MAX_COLLATERALS as usize],
+    cache: &Ref<Cache>,
+    breakdown: bool,
+) -> Result<MarginComponents, ErrorCode> {
     let PerpAccParams {
         total_acc_value,
-        mut has_open_pos_notional,
+        has_open_pos_notional,
         total_realized_pnl,
-        mut pimf_vec,
-        mut pmmf_vec,
-        mut pcmf_vec,
-        mut pos_open_notional_vec,
-        mut pos_notional_vec,
+        pimf_vec,
+        pmmf_vec,
+        pcmf_vec,
+        pos_open_notional_vec,
+        pos_notional_vec,
+        market_breakdown,
     } = get_perp_acc_params(
         col,
-        return_option,
+        MfReturnOption::All,
+        breakdown,
         max_markets,
         oo_agg,
         &cache.marks,
@@ -68,11 +318,11 @@ pub fn check_fraction_requirement(
 
     let (
         has_spot_pos_notional,
-        mut spot_imf_vec,
-        mut spot_mmf_vec,
-        mut spot_pos_notional_vec,
+        spot_imf_vec,
+        spot_mmf_vec,
+        spot_pos_notional_vec,
     ) = get_spot_borrows(
-        return_option,
+        MfReturnOption::All,
         max_cols,
         margin_col,
         col_info_arr,
@@ -80,175 +330,733 @@ pub fn check_fraction_requirement(
         total_realized_pnl,
     )?;
 
-    if has_spot_pos_notional {
-        has_open_pos_notional = true;
+    Ok(MarginComponents {
+        col,
+        total_acc_value,
+        total_realized_pnl,
+        has_open_pos_notional: has_open_pos_notional || has_spot_pos_notional,
+        pimf_vec,
+        pmmf_vec,
+        pcmf_vec,
+        pos_notional_vec,
+        pos_open_notional_vec,
+        spot_imf_vec,
+        spot_mmf_vec,
+        spot_pos_notional_vec,
+        market_breakdown,
+    })
+}
+
+/// Checks that `margin` and `control` belong to the same authority, so
+/// margin math doesn't mix the positions of one account with the
+/// collateral of another. The margin side of the link is its `control`
+/// key, which callers look the control up by.
+pub fn validate_linkage(
+    margin: &Margin,
+    control: &Control,
+) -> Result<(), ErrorCode> {
+    if margin.authority == control.authority {
+        Ok(())
+    } else {
+        Err(ErrorCode::BrokenLinkage)
     }
+}
 
-    pos_open_notional_vec.extend(spot_pos_notional_vec.iter().clone());
-    pos_notional_vec.append(&mut spot_pos_notional_vec);
+/// Computes the `MarginComponents` of an account from its weighted
+/// collateral.
+pub fn margin_components(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+) -> Result<MarginComponents, ErrorCode> {
+    collect_margin_components(
+        checked_to_i64(get_total_collateral(margin, cache, state)?)?,
+        state.total_markets as usize,
+        state.total_collaterals as usize,
+        &control.open_orders_agg,
+        &state.perp_markets,
+        &state.collaterals,
+        &{ margin.collateral },
+        &RefCell::new(*cache).borrow(),
+        false,
+    )
+}
 
-    match fraction_type {
-        FractionType::Initial => {
-            if has_open_pos_notional {
-                pimf_vec.append(&mut spot_imf_vec);
-                let omf = total_acc_value
-                    .min(col + total_realized_pnl)
-                    .safe_mul(1000i64)?;
-                let imf =
-                    calc_weighted_sum(pimf_vec, pos_open_notional_vec).unwrap();
-                Ok(omf > imf)
-            } else {
-                Ok(true)
-            }
-        }
-        FractionType::Maintenance => {
-            if has_open_pos_notional {
-                pmmf_vec.append(&mut spot_mmf_vec);
-                let mf = total_acc_value.safe_mul(1000i64)?;
-                let mmf =
-                    calc_weighted_sum(pmmf_vec, pos_notional_vec).unwrap();
-                Ok(mf > mmf)
-            } else {
-                Ok(true)
-            }
-        }
-        FractionType::Cancel => {
-            if has_open_pos_notional {
-                pcmf_vec.append(&mut spot_imf_vec);
-                let omf = total_acc_value
-                    .min(col + total_realized_pnl)
-                    .safe_mul(1000)?;
+/// The account's equity in smol USD: its unweighted collateral plus the
+/// value of its perp positions.
+fn equity(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+) -> Result<i64, ErrorCode> {
+    let cache_ref = RefCell::new(*cache);
+    let col: I80F48 = get_actual_collateral_vec(
+        margin,
+        &RefCell::new(*state).borrow(),
+        &cache_ref.borrow(),
+        false,
+    )?
+    .into_iter()
+    .sum();
+
+    let c = collect_margin_components(
+        checked_to_i64(col)?,
+        state.total_markets as usize,
+        state.total_collaterals as usize,
+        &control.open_orders_agg,
+        &state.perp_markets,
+        &state.collaterals,
+        &{ margin.collateral },
+        &cache_ref.borrow(),
+        false,
+    )?;
+    Ok(c.total_acc_value)
+}
+
+/// Whether the account's equity, its unweighted collateral plus the
+/// value of its perp positions, is negative. Such an account can't be
+/// made whole by liquidation and needs its losses settled instead.
+pub fn has_negative_equity(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+) -> Result<bool, ErrorCode> {
+    Ok(equity(margin, control, state, cache)? < 0)
+}
 
-                let cmf =
-                    calc_weighted_sum(pcmf_vec, pos_open_notional_vec).unwrap();
+/// One perp market's part in the margin fractions. Amounts are in smol
+/// units, factors in thousandths.
+#[derive(Clone, Debug)]
+pub struct MarketTrace {
+    pub index: usize,
+    pub mark: I80F48,
+    pub pos_size: i64,
+    pub native_pc_total: i64,
+    pub realized_pnl: i64,
+    pub unrealized_funding: i64,
+    /// Account value after this market is added to it.
+    pub acc_value: i64,
+    pub pos_notional: i64,
+    pub pos_open_notional: i64,
+    pub imf: u16,
+    pub mmf: u16,
+    pub cmf: u16,
+}
 
-                Ok(omf > cmf)
-            } else {
-                Ok(true)
-            }
+/// One collateral's part in the margin fractions. Borrows also count
+/// towards the requirements, with the notional and factors given.
+#[derive(Clone, Debug)]
+pub struct CollateralTrace {
+    pub index: usize,
+    pub amount: I80F48,
+    pub price: I80F48,
+    pub weight: u16,
+    /// Value after weighting and interest, as summed into `col`.
+    pub weighted_value: I80F48,
+    pub borrow_notional: Option<i64>,
+    pub imf: Option<u16>,
+    pub mmf: Option<u16>,
+}
+
+/// Every intermediate value of an account's margin fractions, in the
+/// order they are computed. Sums are as in `MarginComponents`.
+#[derive(Clone, Debug)]
+pub struct MarginTrace {
+    pub collaterals: Vec<CollateralTrace>,
+    pub markets: Vec<MarketTrace>,
+    pub col: i64,
+    pub total_acc_value: i64,
+    pub total_realized_pnl: i64,
+    pub init_requirement: i64,
+    pub maint_requirement: i64,
+    /// Whether the initial, maintenance and cancel fractions are met.
+    pub fractions: (bool, bool, bool),
+}
+
+/// Records the margin math of an account step by step, to show how a
+/// liquidation decision was reached. Much slower than the regular
+/// checks, so only meant for accounts under investigation.
+pub fn trace_margin(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+) -> Result<MarginTrace, ErrorCode> {
+    let mut collaterals = Vec::new();
+    let mut col = I80F48::ZERO;
+    for (i, &coll) in { margin.collateral }.iter().enumerate() {
+        if coll == WrappedI80F48::zero() {
+            continue;
         }
+        let info = &state.collaterals[i];
+        let price: I80F48 = get_oracle(cache, &info.oracle_symbol)
+            .ok_or(ErrorCode::CollateralFailure)?
+            .price
+            .into();
+        let borrow = &cache.borrow_cache[i];
+
+        // Same steps as `get_total_collateral`.
+        let usdc_col = safe_mul_i80f48(coll.into(), price);
+        let weighted_col = match usdc_col > I80F48::ZERO {
+            true => safe_mul_i80f48(usdc_col, I80F48::from_num(info.weight))
+                .checked_div(I80F48::from_num(1000u16))
+                .ok_or(ErrorCode::MathFailure)?,
+            false => usdc_col,
+        };
+        let multiplier = match coll > WrappedI80F48::zero() {
+            true => borrow.supply_multiplier,
+            false => borrow.borrow_multiplier,
+        };
+        let weighted_value = safe_mul_i80f48(weighted_col, multiplier.into());
+        col = safe_add_i80f48(col, weighted_value);
+
+        collaterals.push(CollateralTrace {
+            index: i,
+            amount: calc_actual_collateral(
+                coll.into(),
+                borrow.supply_multiplier.into(),
+                borrow.borrow_multiplier.into(),
+            )?,
+            price,
+            weight: info.weight,
+            weighted_value,
+            borrow_notional: None,
+            imf: None,
+            mmf: None,
+        });
     }
-}
+    let col = checked_to_i64(col)?;
 
-fn get_perp_acc_params(
-    col: i64,
-    return_option: MfReturnOption,
-    max_markets: usize,
-    open_orders_agg: &[OpenOrdersInfo; 50],
-    marks: &[MarkCache; 50],
-    perp_markets: &[PerpMarketInfo; 50],
-    funding_cache: &[i128; 50],
-) -> Result<PerpAccParams, ErrorCode> {
-    // for omf
+    let funding_cache = { cache.funding_cache };
+    let mut markets = Vec::new();
     let mut total_acc_value = col;
-    let mut has_open_pos_notional = false;
     let mut total_realized_pnl = 0i64;
-
-    // for imf or mmf
-    let mut imf_vec = Vec::new();
-    let mut mmf_vec = Vec::new();
-    let mut cmf_vec = Vec::new();
-    let mut pos_notional_vec = Vec::new();
-    let mut pos_open_notional_vec = Vec::new();
-
-    for (index, oo_info) in open_orders_agg.iter().enumerate() {
-        if !(index < max_markets) {
+    for (i, oo_info) in control.open_orders_agg.iter().enumerate() {
+        if i >= state.total_markets as usize {
             break;
         }
         if oo_info.key == Pubkey::default() {
             continue;
         }
+        let market = &state.perp_markets[i];
+        let mark: I80F48 = cache.marks[i].price.into();
+        let funding_index = funding_cache[i];
 
-        let mark = marks[index].price.into();
-
-        let new_acc_val = calc_acc_val(
+        total_acc_value = calc_acc_val(
             total_acc_value,
             mark,
             oo_info.pos_size,
             oo_info.native_pc_total,
             oo_info.realized_pnl,
             oo_info.funding_index,
-            funding_cache[index],
-            perp_markets[index].asset_decimals as u32,
+            funding_index,
+            market.asset_decimals as u32,
         )?;
-        total_acc_value = new_acc_val;
+        total_realized_pnl =
+            total_realized_pnl.safe_add(oo_info.realized_pnl)?;
 
-        let pos_notional =
-            safe_mul_i80f48(I80F48::from_num(oo_info.pos_size.abs()), mark)
-                .ceil()
-                .to_num::<i64>();
-        let pos_open_notional = safe_mul_i80f48(
-            I80F48::from_num(cmp::max(
-                (oo_info.pos_size + oo_info.coin_on_bids as i64).abs(),
-                (oo_info.pos_size - oo_info.coin_on_asks as i64).abs(),
-            )),
+        let pos_open_size = cmp::max(
+            (oo_info.pos_size as i128 + oo_info.coin_on_bids as i128).abs(),
+            (oo_info.pos_size as i128 - oo_info.coin_on_asks as i128).abs(),
+        );
+        markets.push(MarketTrace {
+            index: i,
             mark,
-        )
-        .ceil()
-        .to_num::<i64>();
+            pos_size: oo_info.pos_size,
+            native_pc_total: oo_info.native_pc_total,
+            realized_pnl: oo_info.realized_pnl,
+            unrealized_funding: match oo_info.pos_size {
+                0 => 0,
+                _ => calc_unrealized_funding(
+                    oo_info.pos_size,
+                    oo_info.funding_index,
+                    funding_index,
+                    market.asset_decimals as u32,
+                )?,
+            },
+            acc_value: total_acc_value,
+            pos_notional: checked_to_i64(
+                safe_mul_i80f48(I80F48::from_num(oo_info.pos_size.abs()), mark)
+                    .ceil(),
+            )?,
+            pos_open_notional: checked_to_i64(
+                I80F48::from_num(pos_open_size)
+                    .checked_mul(mark)
+                    .ok_or(ErrorCode::MathOverflow)?
+                    .ceil(),
+            )?,
+            imf: market.base_imf,
+            mmf: market.base_imf.safe_div(2u16)?,
+            cmf: market.base_imf.safe_mul(5u16)?.safe_div(8u16)?,
+        });
+    }
 
-        if pos_open_notional.is_positive() {
-            has_open_pos_notional = true;
+    // Borrows, as in `get_spot_borrows`.
+    for c in collaterals.iter_mut() {
+        if c.amount >= I80F48::ZERO
+            || c.index >= state.total_collaterals as usize
+        {
+            continue;
         }
-
-        let base_imf = perp_markets[index].base_imf;
-        match return_option {
-            MfReturnOption::Mmf => {
-                mmf_vec.push(base_imf.safe_div(2u16)?);
-            }
-            MfReturnOption::Imf => {
-                imf_vec.push(base_imf);
-            }
-            MfReturnOption::Cancel => {
-                cmf_vec.push(base_imf.safe_mul(5u16)?.safe_div(8u16)?);
-            }
-            MfReturnOption::Both => {
-                imf_vec.push(base_imf);
-                mmf_vec.push(base_imf.safe_div(2u16)?);
-            }
-        };
-        pos_open_notional_vec.push(pos_open_notional);
-        pos_notional_vec.push(pos_notional);
-
-        total_realized_pnl =
-            total_realized_pnl.safe_add(oo_info.realized_pnl)?;
+        let mut dep = c.amount;
+        if c.index == 0 {
+            dep += I80F48::from_num(total_realized_pnl);
+        }
+        let weight = c.weight as u32;
+        c.borrow_notional =
+            Some(checked_to_i64(safe_mul_i80f48(c.price, -dep).ceil())?);
+        c.imf = Some((SPOT_INITIAL_MARGIN_REQ as u32 / weight) as u16 - 1000);
+        c.mmf = Some((SPOT_MAINT_MARGIN_REQ as u32 / weight) as u16 - 1000);
     }
 
-    Ok(PerpAccParams {
+    let borrows: Vec<_> = collaterals
+        .iter()
+        .filter(|c| c.borrow_notional.is_some())
+        .collect();
+    let components = MarginComponents {
+        col,
+        total_acc_value,
+        total_realized_pnl,
+        has_open_pos_notional: markets.iter().any(|m| m.pos_open_notional > 0)
+            || borrows
+                .iter()
+                .any(|c| matches!(c.borrow_notional, Some(n) if n > 0)),
+        pimf_vec: markets.iter().map(|m| m.imf).collect(),
+        pmmf_vec: markets.iter().map(|m| m.mmf).collect(),
+        pcmf_vec: markets.iter().map(|m| m.cmf).collect(),
+        pos_notional_vec: markets.iter().map(|m| m.pos_notional).collect(),
+        pos_open_notional_vec: markets
+            .iter()
+            .map(|m| m.pos_open_notional)
+            .collect(),
+        spot_imf_vec: borrows.iter().filter_map(|c| c.imf).collect(),
+        spot_mmf_vec: borrows.iter().filter_map(|c| c.mmf).collect(),
+        spot_pos_notional_vec: borrows
+            .iter()
+            .filter_map(|c| c.borrow_notional)
+            .collect(),
+        market_breakdown: Vec::new(),
+    };
+
+    Ok(MarginTrace {
+        init_requirement: components.init_requirement()?,
+        maint_requirement: components.maint_requirement()?,
+        fractions: components.fractions(0)?,
+        collaterals,
+        markets,
+        col,
         total_acc_value,
-        has_open_pos_notional,
         total_realized_pnl,
-        pimf_vec: imf_vec,
-        pmmf_vec: mmf_vec,
-        pcmf_vec: cmf_vec,
-        pos_open_notional_vec,
-        pos_notional_vec,
     })
 }
 
-fn get_spot_borrows(
-    return_option: MfReturnOption,
-    max_cols: usize,
-    col_arr: &[WrappedI80F48; 25],
-    col_info_arr: &[CollateralInfo; 25],
+/// Free collateral of the keeper's own account above its initial
+/// requirement, in smol USD. This bounds how much more exposure
+/// liquidations can move onto the keeper. Never negative.
+pub fn available_buying_power(
+    keeper_margin: &Margin,
+    keeper_control: &Control,
+    state: &State,
     cache: &Cache,
-    total_realized_pnl: i64,
-) -> Result<(bool, Vec<u16>, Vec<u16>, Vec<i64>), ErrorCode> {
-    // for omf
-    let mut has_open_pos_notional = false;
+) -> Result<I80F48, ErrorCode> {
+    let c = margin_components(keeper_margin, keeper_control, state, cache)?;
+    let omf = c
+        .total_acc_value
+        .min(c.col + c.total_realized_pnl)
+        .safe_mul(1000i64)?;
+    let imf = match c.has_open_pos_notional {
+        true => c.init_requirement()?,
+        false => 0,
+    };
 
-    // for imf or mmf
-    let mut imf_vec = Vec::new();
-    let mut mmf_vec = Vec::new();
-    let mut pos_open_notional_vec = Vec::new();
+    let headroom = I80F48::from_num(omf.safe_sub(imf)?)
+        .checked_div(I80F48::from_num(1000))
+        .ok_or(ErrorCode::MathFailure)?;
+    Ok(headroom.max(I80F48::ZERO))
+}
 
-    // loop through negative margin collateral
-    for (dep_index, col_info) in col_info_arr.iter().enumerate() {
-        if !(dep_index < max_cols) {
-            break;
+/// Average maintenance margin ratio of the given accounts, where 1.0 is
+/// maintenance, weighted by each account's perp position notional.
+/// Accounts without a maintenance requirement are left out. Returns
+/// infinity if no account has one.
+pub fn book_weighted_health<'a>(
+    accounts: impl IntoIterator<Item = (&'a Margin, &'a Control)>,
+    state: &State,
+    cache: &Cache,
+) -> Result<f64, ErrorCode> {
+    let mut weighted_sum = 0f64;
+    let mut total_weight = 0f64;
+
+    for (margin, control) in accounts {
+        let c = margin_components(margin, control, state, cache)?;
+        let mmf = c.maint_requirement()?;
+        if mmf <= 0 {
+            continue;
         }
 
-        if col_arr[dep_index] >= WrappedI80F48::zero() {
+        let ratio = c.total_acc_value as f64 * 1000.0 / mmf as f64;
+        let weight = c.pos_notional_vec.iter().sum::<i64>() as f64;
+        weighted_sum += ratio * weight;
+        total_weight += weight;
+    }
+
+    if total_weight == 0.0 {
+        return Ok(f64::INFINITY);
+    }
+
+    Ok(weighted_sum / total_weight)
+}
+
+/// Accounts that would fall below maintenance once the perp positions
+/// of `initial_liquidations` are closed out on the market, excluding
+/// those already below it. `price_impact` gives the relative mark price
+/// move of a market for a signed base size traded into it, positive
+/// for buys. Only perp marks are moved, collateral prices are kept.
+pub fn simulate_cascade(
+    initial_liquidations: &[Pubkey],
+    accounts: &[(Pubkey, Margin, Control)],
+    state: &State,
+    cache: &Cache,
+    price_impact: impl Fn(usize, i64) -> f64,
+) -> Result<Vec<Pubkey>, ErrorCode> {
+    // Closing a long sells into the market, closing a short buys.
+    let mut flow = vec![0i64; state.total_markets as usize];
+    for (_, _, control) in accounts
+        .iter()
+        .filter(|(key, _, _)| initial_liquidations.contains(key))
+    {
+        for (i, size) in flow.iter_mut().enumerate() {
+            *size = size.saturating_sub(control.open_orders_agg[i].pos_size);
+        }
+    }
+
+    let shocked = scale_marks(cache, &flow, |i, &size| match size {
+        0 => 0.0,
+        _ => price_impact(i, size),
+    });
+
+    let mut cascade = Vec::new();
+    for (key, margin, control) in accounts {
+        if initial_liquidations.contains(key)
+            || is_below_maint(margin, control, state, cache)?
+        {
+            continue;
+        }
+        if is_below_maint(margin, control, state, &shocked)? {
+            cascade.push(*key);
+        }
+    }
+
+    Ok(cascade)
+}
+
+/// A price impact for `simulate_cascade` that moves a market's mark by
+/// `impact` per million USD of notional traded into it.
+pub fn linear_price_impact(
+    cache: &Cache,
+    impact: f64,
+) -> impl Fn(usize, i64) -> f64 + '_ {
+    move |i, size| {
+        let mark: I80F48 = cache.marks[i].price.into();
+        size as f64 * mark.to_num::<f64>() / 1e12 * impact
+    }
+}
+
+/// Smallest uniform relative move of the perp marks, up or down, after
+/// which a flagged account is back above maintenance. Zero for healthy
+/// accounts, infinite if no move of up to 100% in either direction
+/// suffices. As in `simulate_cascade`, collateral prices are kept.
+pub fn recovery_distance(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+) -> Result<f64, ErrorCode> {
+    const STEPS: u32 = 100;
+    const BISECTIONS: u32 = 20;
+
+    let markets = vec![(); state.total_markets as usize];
+    let recovers = |change: f64| -> Result<bool, ErrorCode> {
+        let moved = scale_marks(cache, &markets, |_, _| change);
+        Ok(!is_below_maint(margin, control, state, &moved)?)
+    };
+
+    if recovers(0.0)? {
+        return Ok(0.0);
+    }
+
+    let mut distance = f64::INFINITY;
+    for direction in [1.0, -1.0] {
+        // Scan outwards for the first step that recovers, then narrow
+        // down the boundary between it and the step before.
+        let mut healthy = None;
+        for step in 1..=STEPS {
+            let change = direction * step as f64 / STEPS as f64;
+            if change.abs() >= distance {
+                break;
+            }
+            if recovers(change)? {
+                healthy = Some(change);
+                break;
+            }
+        }
+        let mut hi = match healthy {
+            Some(hi) => hi,
+            None => continue,
+        };
+        let mut lo = hi - direction / STEPS as f64;
+        for _ in 0..BISECTIONS {
+            let mid = (lo + hi) / 2.0;
+            match recovers(mid)? {
+                true => hi = mid,
+                false => lo = mid,
+            }
+        }
+        distance = distance.min(hi.abs());
+    }
+
+    Ok(distance)
+}
+
+/// Change in the account's value, in smol USD and negative for a loss,
+/// if the prices of several assets moved at once, e.g. everything down
+/// 15%. Each shock is a relative move of an oracle price, applied to
+/// the marks of the markets using that oracle too.
+pub fn scenario_loss(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+    shocks: &[(Symbol, f64)],
+) -> Result<i64, ErrorCode> {
+    let mut shocked = *cache;
+    for oracle in shocked.oracles.iter_mut() {
+        if let Some((_, change)) = shocks
+            .iter()
+            .find(|(s, _)| !s.is_nil() && *s == oracle.symbol)
+        {
+            let price: I80F48 = oracle.price.into();
+            let price = (price.to_num::<f64>() * (1.0 + change)).max(0.0);
+            oracle.price = I80F48::from_num(price).into();
+        }
+    }
+
+    let markets: Vec<_> = state
+        .perp_markets
+        .iter()
+        .take(state.total_markets as usize)
+        .collect();
+    let shocked = scale_marks(&shocked, &markets, |_, market| {
+        shocks
+            .iter()
+            .find(|(s, _)| *s == market.oracle_symbol)
+            .map_or(0.0, |(_, change)| *change)
+    });
+
+    let before = margin_components(margin, control, state, cache)?;
+    let after = margin_components(margin, control, state, &shocked)?;
+    Ok(after.total_acc_value.saturating_sub(before.total_acc_value))
+}
+
+/// Copies the cache with each market's mark moved by the relative
+/// change `change` returns for it, given that market's entry in
+/// `markets`.
+fn scale_marks<T>(
+    cache: &Cache,
+    markets: &[T],
+    change: impl Fn(usize, &T) -> f64,
+) -> Cache {
+    let mut moved = *cache;
+    for (i, market) in markets.iter().enumerate() {
+        let change = change(i, market);
+        if change == 0.0 {
+            continue;
+        }
+        let mark: I80F48 = moved.marks[i].price.into();
+        let price = (mark.to_num::<f64>() * (1.0 + change)).max(0.0);
+        moved.marks[i].price = I80F48::from_num(price).into();
+    }
+    moved
+}
+
+fn is_below_maint(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+) -> Result<bool, ErrorCode> {
+    let (_, maintenance, _) =
+        margin_components(margin, control, state, cache)?.fractions(0)?;
+    Ok(!maintenance)
+}
+
+/// Evaluates the initial, maintenance and cancel fractions in a single
+/// pass over the account, returning `(initial, maintenance, cancel)`.
+/// Each value matches `HealthInfo::meets` for the corresponding
+/// `FractionType`, except that the maintenance check only fails once
+/// the requirement exceeds the account value by `maint_tolerance` smol
+/// USD, so rounding noise at the boundary does not trigger a
+/// liquidation.
+pub fn check_all_fractions(
+    col: i64, // weighted collateral adjusted for bnl fees
+    max_markets: usize,
+    max_cols: usize,
+    oo_agg: &[OpenOrdersInfo; MAX_MARKETS as usize],
+    pm: &[PerpMarketInfo; MAX_MARKETS as usize],
+    col_info_arr: &[CollateralInfo; MAX_COLLATERALS as usize],
+    margin_col: &[WrappedI80F48; MAX_COLLATERALS as usize],
+    cache: &Ref<Cache>,
+    maint_tolerance: i64,
+) -> Result<(bool, bool, bool), ErrorCode> {
+    collect_margin_components(
+        col,
+        max_markets,
+        max_cols,
+        oo_agg,
+        pm,
+        col_info_arr,
+        margin_col,
+        cache,
+        false,
+    )?
+    .fractions(maint_tolerance)
+}
+
+fn get_perp_acc_params(
+    col: i64,
+    return_option: MfReturnOption,
+    breakdown: bool,
+    max_markets: usize,
+    open_orders_agg: &[OpenOrdersInfo; 50],
+    marks: &[MarkCache; 50],
+    perp_markets: &[PerpMarketInfo; 50],
+    funding_cache: &[i128; 50],
+) -> Result<PerpAccParams, ErrorCode> {
+    // for omf
+    let mut total_acc_value = col;
+    let mut has_open_pos_notional = false;
+    let mut total_realized_pnl = 0i64;
+
+    // for imf or mmf
+    let mut imf_vec = Vec::new();
+    let mut mmf_vec = Vec::new();
+    let mut cmf_vec = Vec::new();
+    let mut pos_notional_vec = Vec::new();
+    let mut pos_open_notional_vec = Vec::new();
+    let mut market_breakdown = Vec::new();
+
+    for (index, oo_info) in open_orders_agg.iter().enumerate() {
+        if !(index < max_markets) {
+            break;
+        }
+        if oo_info.key == Pubkey::default() {
+            continue;
+        }
+
+        let mark = marks[index].price.into();
+
+        let new_acc_val = calc_acc_val(
+            total_acc_value,
+            mark,
+            oo_info.pos_size,
+            oo_info.native_pc_total,
+            oo_info.realized_pnl,
+            oo_info.funding_index,
+            funding_cache[index],
+            perp_markets[index].asset_decimals as u32,
+        )?;
+        total_acc_value = new_acc_val;
+
+        let pos_notional = checked_to_i64(
+            safe_mul_i80f48(I80F48::from_num(oo_info.pos_size.abs()), mark)
+                .ceil(),
+        )?;
+        // Resting sizes are u64 and would wrap if cast to i64.
+        let pos_open_size = cmp::max(
+            (oo_info.pos_size as i128 + oo_info.coin_on_bids as i128).abs(),
+            (oo_info.pos_size as i128 - oo_info.coin_on_asks as i128).abs(),
+        );
+        let pos_open_notional = checked_to_i64(
+            I80F48::from_num(pos_open_size)
+                .checked_mul(mark)
+                .ok_or(ErrorCode::MathOverflow)?
+                .ceil(),
+        )?;
+
+        if pos_open_notional.is_positive() {
+            has_open_pos_notional = true;
+        }
+
+        // A zero base_imf gives the position no margin requirement. The
+        // program computes the same, so the factor is kept as-is to stay
+        // in agreement with on-chain liquidation checks; such markets are
+        // flagged through `zero_imf_markets` instead.
+        let base_imf = perp_markets[index].base_imf;
+        match return_option {
+            MfReturnOption::Both => {
+                imf_vec.push(base_imf);
+                mmf_vec.push(base_imf.safe_div(2u16)?);
+            }
+            MfReturnOption::All => {
+                imf_vec.push(base_imf);
+                mmf_vec.push(base_imf.safe_div(2u16)?);
+                cmf_vec.push(base_imf.safe_mul(5u16)?.safe_div(8u16)?);
+            }
+        };
+        pos_open_notional_vec.push(pos_open_notional);
+        pos_notional_vec.push(pos_notional);
+        if breakdown {
+            let mmf = base_imf.safe_div(2u16)? as i64;
+            market_breakdown.push((
+                index,
+                pos_notional,
+                mmf.safe_mul(pos_notional)?,
+            ));
+        }
+
+        total_realized_pnl =
+            total_realized_pnl.safe_add(oo_info.realized_pnl)?;
+    }
+
+    Ok(PerpAccParams {
+        total_acc_value,
+        has_open_pos_notional,
+        total_realized_pnl,
+        pimf_vec: imf_vec,
+        pmmf_vec: mmf_vec,
+        pcmf_vec: cmf_vec,
+        pos_open_notional_vec,
+        pos_notional_vec,
+        market_breakdown,
+    })
+}
+
+fn get_spot_borrows(
+    return_option: MfReturnOption,
+    max_cols: usize,
+    col_arr: &[WrappedI80F48; 25],
+    col_info_arr: &[CollateralInfo; 25],
+    cache: &Cache,
+    total_realized_pnl: i64,
+) -> Result<(bool, Vec<u16>, Vec<u16>, Vec<i64>), ErrorCode> {
+    // for omf
+    let mut has_open_pos_notional = false;
+
+    // for imf or mmf
+    let mut imf_vec = Vec::new();
+    let mut mmf_vec = Vec::new();
+    let mut pos_open_notional_vec = Vec::new();
+
+    // loop through negative margin collateral
+    for (dep_index, col_info) in col_info_arr.iter().enumerate() {
+        if !(dep_index < max_cols) {
+            break;
+        }
+
+        if col_arr[dep_index] >= WrappedI80F48::zero() {
             continue;
         }
 
@@ -264,12 +1072,20 @@ fn get_spot_borrows(
         }
 
         // get oracle price
-        let oracle_cache = get_oracle(&cache, &col_info.oracle_symbol).unwrap();
+        let oracle_cache = get_oracle(cache, &col_info.oracle_symbol)
+            .ok_or_else(|| {
+                ErrorCode::MissingOracle(col_info.oracle_symbol.into())
+            })?;
         let oracle_price: I80F48 = oracle_cache.price.into();
 
+        // A bad feed would value the liability at nothing.
+        if dep.is_negative() && oracle_price <= 0 {
+            return Err(ErrorCode::InvalidBorrowPrice);
+        }
+
         // get position notional
         let pos_notional =
-            safe_mul_i80f48(oracle_price, -dep).ceil().to_num::<i64>();
+            checked_to_i64(safe_mul_i80f48(oracle_price, -dep).ceil())?;
 
         // add it to total open pos notional
         if pos_notional.is_positive() {
@@ -277,30 +1093,18 @@ fn get_spot_borrows(
         }
 
         let (imf, mmf) = match return_option {
-            MfReturnOption::Imf => (
+            MfReturnOption::All => (
                 Some(
                     (SPOT_INITIAL_MARGIN_REQ as u32 / col_info.weight as u32)
                         as u16
                         - 1000u16,
                 ),
-                None,
-            ),
-            MfReturnOption::Mmf => (
-                None,
                 Some(
                     (SPOT_MAINT_MARGIN_REQ as u32 / col_info.weight as u32)
                         as u16
                         - 1000u16,
                 ),
             ),
-            MfReturnOption::Cancel => (
-                Some(
-                    (SPOT_INITIAL_MARGIN_REQ as u32 / col_info.weight as u32)
-                        as u16
-                        - 1000u16,
-                ),
-                None,
-            ),
             _ => (None, None),
         };
 
@@ -321,14 +1125,272 @@ fn get_spot_borrows(
     ))
 }
 
+/// Fails with `MarkOracleDivergence` if the mark price of any market the
+/// account has positions or orders in deviates from that market's oracle
+/// by more than its band, a fraction of the oracle price. Markets without
+/// a band are not checked.
+pub fn check_mark_bands(
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+    band: impl Fn(usize) -> Option<f64>,
+) -> Result<(), ErrorCode> {
+    for (i, oo_info) in control.open_orders_agg.iter().enumerate() {
+        if i >= state.total_markets as usize {
+            break;
+        }
+        if oo_info.key == Pubkey::default()
+            || (oo_info.pos_size == 0
+                && oo_info.coin_on_bids == 0
+                && oo_info.coin_on_asks == 0)
+        {
+            continue;
+        }
+
+        let band = match band(i) {
+            Some(b) => b,
+            None => continue,
+        };
+
+        let oracle =
+            match get_oracle(cache, &state.perp_markets[i].oracle_symbol) {
+                Some(o) => o,
+                None => continue,
+            };
+        let oracle_price: I80F48 = oracle.price.into();
+        let mark_price: I80F48 = cache.marks[i].price.into();
+
+        if oracle_price <= 0 {
+            return Err(ErrorCode::MarkOracleDivergence);
+        }
+
+        let deviation = ((mark_price - oracle_price) / oracle_price).abs();
+        if deviation.to_num::<f64>() > band {
+            return Err(ErrorCode::MarkOracleDivergence);
+        }
+    }
+
+    Ok(())
+}
+
+/// Oracle symbols backing the account's balances and perp positions.
+pub fn account_symbols(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+) -> Vec<Symbol> {
+    let collaterals = { margin.collateral }
+        .iter()
+        .zip(state.collaterals.iter())
+        .take(state.total_collaterals as usize)
+        .filter(|(&c, _)| c != WrappedI80F48::zero())
+        .map(|(_, info)| info.oracle_symbol);
+    let markets = control
+        .open_orders_agg
+        .iter()
+        .zip(state.perp_markets.iter())
+        .take(state.total_markets as usize)
+        .filter(|(oo, _)| oo.key != Pubkey::default())
+        .map(|(_, info)| info.oracle_symbol);
+
+    collaterals.chain(markets).collect()
+}
+
+/// Every oracle symbol the accounts depend on, so that oracles can be
+/// checked once per scan rather than once per account.
+pub fn required_symbols<'a>(
+    accounts: impl IntoIterator<Item = (&'a Margin, &'a Control)>,
+    state: &State,
+) -> BTreeSet<Symbol> {
+    accounts
+        .into_iter()
+        .flat_map(|(margin, control)| account_symbols(margin, control, state))
+        .collect()
+}
+
+/// The symbols whose oracle was last updated more than `max_age`
+/// seconds before `now`.
+pub fn stale_symbols(
+    symbols: &BTreeSet<Symbol>,
+    cache: &Cache,
+    now: u64,
+    max_age: u64,
+) -> BTreeSet<Symbol> {
+    symbols
+        .iter()
+        .filter(|symbol| {
+            matches!(
+                get_fresh_oracle(cache, symbol, max_age, now),
+                Err(ErrorCode::StaleOracle)
+            )
+        })
+        .copied()
+        .collect()
+}
+
+/// Why an account below maintenance cannot be liquidated by the keeper.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StuckReason {
+    /// The only positions are in markets without a price.
+    HaltedMarkets,
+    /// The only assets are collaterals that cannot be swapped.
+    IlliquidCollateral,
+}
+
+/// Detects accounts that no liquidation path can act on, so they can be
+/// escalated instead of retried. A market is considered halted when its
+/// mark or oracle price is missing.
+pub fn stuck_reason(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+) -> Option<StuckReason> {
+    let mut has_halted_positions = false;
+    for (i, oo_info) in control.open_orders_agg.iter().enumerate() {
+        if i >= state.total_markets as usize {
+            break;
+        }
+        if oo_info.key == Pubkey::default() || oo_info.pos_size == 0 {
+            continue;
+        }
+
+        let mark: I80F48 = cache.marks[i].price.into();
+        let has_oracle =
+            get_oracle(cache, &state.perp_markets[i].oracle_symbol).is_some();
+        if mark.is_positive() && has_oracle {
+            return None;
+        }
+        has_halted_positions = true;
+    }
+
+    let mut has_assets = false;
+    for (i, &coll) in { margin.collateral }.iter().enumerate() {
+        if i >= state.total_collaterals as usize {
+            break;
+        }
+        if coll <= WrappedI80F48::zero() {
+            continue;
+        }
+        if state.collaterals[i].is_swappable {
+            return None;
+        }
+        has_assets = true;
+    }
+
+    // Accounts with neither are bankrupt, which is handled separately.
+    match (has_halted_positions, has_assets) {
+        (true, _) => Some(StuckReason::HaltedMarkets),
+        (false, true) => Some(StuckReason::IlliquidCollateral),
+        (false, false) => None,
+    }
+}
+
+/// USD collateral, index 0, after interest and with the realized PnL of
+/// all perp positions applied, as the margin math sees it. This differs
+/// from the balance stored on the margin account. In smol USD.
+pub fn effective_usd_collateral(
+    margin: &Margin,
+    control: &Control,
+    cache: &Cache,
+    state: &State,
+) -> Result<I80F48, ErrorCode> {
+    let borrow = &cache.borrow_cache[0];
+    let usd = get_actual_collateral(
+        0,
+        margin,
+        borrow.supply_multiplier.into(),
+        borrow.borrow_multiplier.into(),
+    )?;
+
+    let mut total_realized_pnl = 0i64;
+    for oo_info in control
+        .open_orders_agg
+        .iter()
+        .take(state.total_markets as usize)
+        .filter(|oo| oo.key != Pubkey::default())
+    {
+        total_realized_pnl =
+            total_realized_pnl.safe_add(oo_info.realized_pnl)?;
+    }
+
+    usd.checked_add(I80F48::from_num(total_realized_pnl))
+        .ok_or(ErrorCode::MathOverflow)
+}
+
+/// Whether none of `colls`, an account's weighted balances as from
+/// `get_actual_collateral_vec`, is above dust, leaving nothing for a
+/// spot liquidation to seize. Without perp positions either, the
+/// account goes through bankruptcy settlement in `liquidate`.
+pub fn is_spot_bankrupt(colls: &[I80F48]) -> bool {
+    colls.iter().all(|col| col < &DUST_THRESHOLD)
+}
+
+/// Loss in smol USD that the account leaves to the insurance fund, i.e.
+/// its negative equity. Zero for accounts with non-negative equity.
+pub fn insurance_fund_impact(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+) -> Result<i64, ErrorCode> {
+    Ok(0i64
+        .safe_sub(equity(margin, control, state, cache)?)?
+        .max(0))
+}
+
+/// Sums `insurance_fund_impact` over the accounts, for comparison
+/// against the insurance fund balance. Bankrupt here means negative
+/// equity rather than `is_spot_bankrupt`: the shortfall reaches the
+/// fund whether it is settled now or once the remaining positions are
+/// closed, while a spot-bankrupt account may owe nothing.
+pub fn total_insurance_exposure<'a>(
+    accounts: impl IntoIterator<Item = (&'a Margin, &'a Control)>,
+    state: &State,
+    cache: &Cache,
+) -> Result<i64, ErrorCode> {
+    let mut total = 0i64;
+    for (margin, control) in accounts {
+        total = total
+            .safe_add(insurance_fund_impact(margin, control, state, cache)?)?;
+    }
+
+    Ok(total)
+}
+
+/// Returns the indices of listed markets whose `base_imf` is zero.
+/// Positions in these markets carry no margin requirement, so any
+/// exposure to them is invisible to the fraction checks.
+pub fn zero_imf_markets(state: &State) -> Vec<usize> {
+    state
+        .perp_markets
+        .iter()
+        .enumerate()
+        .take(state.total_markets as usize)
+        .filter(|(_, m)| m.dex_market != Pubkey::default() && m.base_imf == 0)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Largest leverage a position can be opened at in a market with the
+/// given `base_imf`, in thousandths. Unbounded for a zero `base_imf`.
+pub fn max_leverage(base_imf: u16) -> f64 {
+    1000.0 / base_imf as f64
+}
+
 fn calc_weighted_sum(
     factor: Vec<u16>,
     weights: Vec<i64>,
 ) -> Result<i64, ErrorCode> {
-    let mut numerator = 0i64;
+    // The vectors are assembled separately for perps and spot, so a
+    // mismatch is a bug upstream.
+    if factor.len() != weights.len() {
+        return Err(ErrorCode::MismatchedVectors);
+    }
 
-    for (i, &factor) in factor.iter().enumerate() {
-        numerator += (factor as i64).safe_mul(weights[i]).unwrap();
+    let mut numerator = 0i64;
+    for (&factor, &weight) in factor.iter().zip(weights.iter()) {
+        numerator = numerator.safe_add((factor as i64).safe_mul(weight)?)?;
     }
 
     Ok(numerator)
@@ -348,74 +1410,561 @@ fn calc_acc_val(
         return Ok(collateral + realized_pnl);
     }
 
+    let unrealized_funding = calc_unrealized_funding(
+        pos_size,
+        current_funding_index,
+        market_funding_index,
+        coin_decimals,
+    )?;
+
+    let unrealized_pnl = if pos_size > 0 {
+        let pos = checked_to_i64(
+            safe_mul_i80f48(I80F48::from_num(pos_size), smol_mark_price)
+                .floor(),
+        )?;
+        let bor = -native_pc_total;
+        pos.safe_sub(bor)?
+    } else {
+        let pos = native_pc_total;
+        let bor = checked_to_i64(
+            safe_mul_i80f48(I80F48::from_num(-pos_size), smol_mark_price)
+                .floor(),
+        )?;
+        pos.safe_sub(bor)?
+    };
+
+    Ok(collateral + realized_pnl + unrealized_pnl + unrealized_funding)
+}
+
+pub fn calc_unrealized_funding(
+    pos_size: i64,
+    current_funding_index: i128,
+    market_funding_index: i128,
+    coin_decimals: u32,
+) -> Result<i64, ErrorCode> {
     let funding_diff = market_funding_index.safe_sub(current_funding_index)?;
-    let unrealized_funding: i64 = (pos_size as i128)
+    (pos_size as i128)
         .safe_mul(-funding_diff)?
-        .safe_div(10i64.pow(coin_decimals))?
+        .safe_div(safe_pow(10, coin_decimals)?)?
         .try_into()
-        .unwrap();
+        .map_err(|_| ErrorCode::MathOverflow)
+}
+
+/// Average entry price of the position in smol USD per whole asset,
+/// as in the funding index. `None` if the position is flat.
+pub fn avg_entry_price(
+    oo_info: &OpenOrdersInfo,
+    coin_decimals: u32,
+) -> Option<I80F48> {
+    let pos_size = oo_info.pos_size;
+    if pos_size == 0 {
+        return None;
+    }
+
+    // `native_pc_total` is what was paid for a long, so it has the
+    // opposite sign to the position.
+    let cost = I80F48::from_num(-oo_info.native_pc_total);
+    let scale = I80F48::from_num(10i64.checked_pow(coin_decimals)?);
+    cost.checked_mul(scale)?
+        .checked_div(I80F48::from_num(pos_size))
+}
+
+/// Sums the unrealized funding of every account per market, in smol
+/// USD. A positive value means the accounts are owed funding in
+/// aggregate, a negative one that they owe it.
+pub fn protocol_funding_flow<'a>(
+    accounts: impl IntoIterator<Item = &'a Control>,
+    cache: &Cache,
+    state: &State,
+) -> Result<HashMap<usize, i64>, ErrorCode> {
+    let mut flow = HashMap::new();
+    let funding_cache = { cache.funding_cache };
+
+    for control in accounts {
+        for (index, oo_info) in control.open_orders_agg.iter().enumerate() {
+            if index >= state.total_markets as usize {
+                break;
+            }
+            if oo_info.key == Pubkey::default() || oo_info.pos_size == 0 {
+                continue;
+            }
+
+            let funding = calc_unrealized_funding(
+                oo_info.pos_size,
+                oo_info.funding_index,
+                funding_cache[index],
+                state.perp_markets[index].asset_decimals as u32,
+            )?;
+            let total: &mut i64 = flow.entry(index).or_default();
+            *total = total.safe_add(funding)?;
+        }
+    }
+
+    Ok(flow)
+}
+
+/// `f64` approximation of an account's value and maintenance
+/// requirement, both in smol USD with the requirement scaled by 1000 as
+/// in the exact math. Also returns, per oracle symbol, how the value and
+/// requirement change for a unit relative move in that oracle's price.
+struct ApproxMargin {
+    acc_value: f64,
+    mmf: f64,
+    sensitivities: BTreeMap<Symbol, (f64, f64)>,
+}
+
+fn approx_margin(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+) -> ApproxMargin {
+    let mut acc_value = 0f64;
+    let mut realized_pnl = 0f64;
+    let mut mmf = 0f64;
+    let mut sensitivities: BTreeMap<Symbol, (f64, f64)> = BTreeMap::new();
+    let funding_cache = { cache.funding_cache };
+
+    for (i, oo_info) in control.open_orders_agg.iter().enumerate() {
+        if i >= state.total_markets as usize {
+            break;
+        }
+        if oo_info.key == Pubkey::default() {
+            continue;
+        }
+
+        let market = &state.perp_markets[i];
+        let mark: I80F48 = cache.marks[i].price.into();
+        let mark = mark.to_num::<f64>();
+        let pos_size = oo_info.pos_size as f64;
+        let decimals = market.asset_decimals as u32;
+        let funding_diff =
+            funding_cache[i].saturating_sub(oo_info.funding_index) as f64;
+        let factor = (market.base_imf / 2) as f64;
+
+        realized_pnl += oo_info.realized_pnl as f64;
+        if oo_info.pos_size != 0 {
+            acc_value += pos_size * mark + oo_info.native_pc_total as f64;
+            acc_value -= to_display(oo_info.pos_size, decimals) * funding_diff;
+
+            let entry = sensitivities.entry(market.oracle_symbol).or_default();
+            entry.0 += pos_size * mark;
+            entry.1 += factor * pos_size.abs() * mark;
+        }
+        mmf += factor * pos_size.abs() * mark;
+    }
+    acc_value += realized_pnl;
+
+    for (i, &coll) in { margin.collateral }.iter().enumerate() {
+        if i >= state.total_collaterals as usize {
+            break;
+        }
+        let info = &state.collaterals[i];
+        if coll == WrappedI80F48::zero() || info.is_empty() {
+            continue;
+        }
+
+        let price: I80F48 = match get_oracle(cache, &info.oracle_symbol) {
+            Some(o) => o.price.into(),
+            None => continue,
+        };
+        let price = price.to_num::<f64>();
+        let borrow = &cache.borrow_cache[i];
+        let coll: I80F48 = coll.into();
+        let coll = coll.to_num::<f64>();
+        let entry = sensitivities.entry(info.oracle_symbol).or_default();
+
+        if coll > 0.0 {
+            let supply: I80F48 = borrow.supply_multiplier.into();
+            let value = coll
+                * supply.to_num::<f64>()
+                * price
+                * (info.weight as f64 / 1000.0);
+            acc_value += value;
+            entry.0 += value;
+        } else {
+            let borrow: I80F48 = borrow.borrow_multiplier.into();
+            let mut dep = coll * borrow.to_num::<f64>();
+            acc_value += dep * price;
+            entry.0 += dep * price;
+
+            if i == 0 {
+                dep += realized_pnl;
+            }
+            let factor = (SPOT_MAINT_MARGIN_REQ as f64 / info.weight as f64)
+                .floor()
+                - 1000.0;
+            mmf += factor * (-dep * price).max(0.0);
+            entry.1 += factor * (-dep * price).max(0.0);
+        }
+    }
+
+    ApproxMargin {
+        acc_value,
+        mmf,
+        sensitivities,
+    }
+}
+
+/// Approximates the maintenance margin ratio, account value over the
+/// maintenance requirement, using `f64` arithmetic throughout. This is
+/// only meant for screening out clearly healthy accounts before running
+/// the exact math, and must never decide a liquidation on its own.
+/// Returns infinity for accounts without exposure.
+pub fn approx_margin_ratio(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+) -> f64 {
+    let ApproxMargin { acc_value, mmf, .. } =
+        approx_margin(margin, control, state, cache);
+
+    if mmf <= 0.0 {
+        return f64::INFINITY;
+    }
+
+    acc_value * 1000.0 / mmf
+}
+
+/// `approx_margin_ratio` after `size` native base units of the position
+/// in `index` are taken over at the mark price, net of the market's
+/// liquidation fee. The size is capped at the position.
+pub fn approx_margin_ratio_after_perp_liq(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+    index: usize,
+    size: i64,
+) -> f64 {
+    let mut control = *control;
+    let pos_size = control.open_orders_agg[index].pos_size;
+    let native_pc_total = control.open_orders_agg[index].native_pc_total;
+    let taken = size.clamp(0, pos_size.abs()) * pos_size.signum();
+
+    let mark: I80F48 = cache.marks[index].price.into();
+    let proceeds = (taken as f64) * mark.to_num::<f64>();
+    let fee =
+        proceeds.abs() * state.perp_markets[index].liq_fee as f64 / 1000.0;
+
+    control.open_orders_agg[index].pos_size = pos_size - taken;
+    control.open_orders_agg[index].native_pc_total =
+        native_pc_total + (proceeds - fee) as i64;
+
+    approx_margin_ratio(margin, &control, state, cache)
+}
+
+/// For every oracle the account is exposed to, the relative price move
+/// of that oracle alone which brings the account to maintenance, e.g.
+/// `-0.2` for a 20% drop. The value is zero for accounts already below
+/// maintenance, and infinite when no move in that price can liquidate
+/// the account. Uses the `f64` approximation of the margin math.
+pub fn adverse_move_tolerance(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+) -> Vec<(Symbol, f64)> {
+    let ApproxMargin {
+        acc_value,
+        mmf,
+        sensitivities,
+    } = approx_margin(margin, control, state, cache);
+
+    let shortfall = mmf - acc_value * 1000.0;
+
+    sensitivities
+        .into_iter()
+        .map(|(symbol, (d_value, d_mmf))| {
+            if shortfall >= 0.0 {
+                return (symbol, 0.0);
+            }
+
+            // Solve 1000 * (value + d_value * x) == mmf + d_mmf * x.
+            let denom = d_value * 1000.0 - d_mmf;
+            let x = shortfall / denom;
+            match denom == 0.0 || x < -1.0 {
+                true => (symbol, f64::INFINITY),
+                false => (symbol, x),
+            }
+        })
+        .collect()
+}
+
+/// Mark price of the perp in `market_index`, in smol USD per smol
+/// asset, at which the account reaches maintenance, other prices being
+/// kept. A long is liquidated below it and a short above it. The price
+/// is not positive if no mark liquidates a long, or if every mark
+/// liquidates a short.
+pub fn liquidation_price(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+    market_index: usize,
+) -> Result<I80F48, ErrorCode> {
+    let pos_size = control.open_orders_agg[market_index].pos_size;
+    if pos_size == 0 {
+        return Err(ErrorCode::NoPositions);
+    }
+
+    let c = margin_components(margin, control, state, cache)?;
+    let mark: I80F48 = cache.marks[market_index].price.into();
+    let mmf = I80F48::from_num(
+        state.perp_markets[market_index].base_imf.safe_div(2u16)?,
+    );
+    let size = I80F48::from_num(pos_size.abs());
+    let notional = safe_mul_i80f48(size, mark);
+    let thousand = I80F48::from_num(1000u16);
+
+    // The rest of the requirement, without this position's share.
+    let rest_mmf = I80F48::from_num(c.maint_requirement()?)
+        - safe_mul_i80f48(mmf, notional.ceil());
+
+    let (numerator, denom) = if pos_size > 0 {
+        // The position adds its value, as in `calc_acc_val`. Solve
+        // 1000 * (rest + size * p) == rest_mmf + mmf * size * p.
+        let rest = I80F48::from_num(c.total_acc_value) - notional.floor();
+        (
+            rest_mmf - safe_mul_i80f48(thousand, rest),
+            safe_mul_i80f48(thousand - mmf, size),
+        )
+    } else {
+        // The position owes its value. Solve
+        // 1000 * (rest - size * p) == rest_mmf + mmf * size * p.
+        let rest = I80F48::from_num(c.total_acc_value) + notional.floor();
+        (
+            safe_mul_i80f48(thousand, rest) - rest_mmf,
+            safe_mul_i80f48(thousand + mmf, size),
+        )
+    };
+
+    numerator.checked_div(denom).ok_or(ErrorCode::MathOverflow)
+}
+
+/// Balances of each collateral, scaled by the cached borrow and supply
+/// multipliers. Deposits and withdrawals settle within the instruction
+/// that makes them, so `margin.collateral` has no pending amounts to
+/// leave out.
+pub fn get_actual_collateral_vec(
+    margin: &Margin,
+    state: &Ref<State>,
+    cache: &Ref<Cache>,
+    is_weighted: bool,
+) -> Result<Vec<I80F48>, ErrorCode> {
+    let mut vec = Vec::with_capacity({ margin.collateral }.len());
+
+    let max_col = state.total_collaterals;
+    for (i, _v) in { margin.collateral }.iter().enumerate() {
+        if i >= max_col as usize {
+            break;
+        }
+
+        let info = &state.collaterals[i];
+        let borrow = &cache.borrow_cache[i];
+
+        if info.is_empty() {
+            continue;
+        }
+
+        let v: I80F48 = get_actual_collateral(
+            i,
+            margin,
+            borrow.supply_multiplier.into(),
+            borrow.borrow_multiplier.into(),
+        )?;
+
+        let oracle_cache =
+            get_oracle(cache, &info.oracle_symbol).ok_or_else(|| {
+                ErrorCode::MissingOracle(info.oracle_symbol.into())
+            })?;
+        let price: I80F48 = oracle_cache.price.into();
+
+        // Price is only weighted when collateral is non-negative.
+        let weighted_price = match is_weighted && v >= 0u64 {
+            true => safe_mul_i80f48(
+                price,
+                I80F48::from_num(info.weight as f64 / 1000.0),
+            ),
+            false => price,
+        };
+        vec.push(safe_mul_i80f48(weighted_price, v));
+    }
+
+    Ok(vec)
+}
+
+/// State indices of the collaterals `get_actual_collateral_vec` has
+/// entries for, in the same order. Empty slots are skipped there, so its
+/// positions are not collateral indices in general.
+pub fn listed_collateral_indices(state: &State) -> Vec<usize> {
+    state
+        .collaterals
+        .iter()
+        .take(state.total_collaterals as usize)
+        .enumerate()
+        .filter(|(_, info)| !info.is_empty())
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Like `get_actual_collateral_vec`, but indexed by collateral index,
+/// with zero for empty slots.
+pub fn get_indexed_collateral_vec(
+    margin: &Margin,
+    state: &State,
+    cache: &Cache,
+    is_weighted: bool,
+) -> Result<Vec<I80F48>, ErrorCode> {
+    let values = get_actual_collateral_vec(
+        margin,
+        &RefCell::new(*state).borrow(),
+        &RefCell::new(*cache).borrow(),
+        is_weighted,
+    )?;
 
-    let unrealized_pnl = if pos_size > 0 {
-        let pos = safe_mul_i80f48(I80F48::from_num(pos_size), smol_mark_price)
-            .floor()
-            .to_num::<i64>();
-        let bor = -native_pc_total;
-        pos.safe_sub(bor)?
-    } else {
-        let pos = native_pc_total;
-        let bor = safe_mul_i80f48(I80F48::from_num(-pos_size), smol_mark_price)
-            .floor()
-            .to_num::<i64>();
-        pos.safe_sub(bor)?
-    };
+    let mut vec = vec![I80F48::ZERO; state.total_collaterals as usize];
+    for (i, value) in listed_collateral_indices(state).into_iter().zip(values) {
+        vec[i] = value;
+    }
+    Ok(vec)
+}
 
-    Ok(collateral + realized_pnl + unrealized_pnl + unrealized_funding)
+/// Like `get_actual_collateral_vec` weighted, but with each entry's
+/// oracle symbol and unweighted amount in native units alongside its
+/// weighted value in smol USD.
+pub fn get_actual_collateral_breakdown(
+    margin: &Margin,
+    state: &Ref<State>,
+    cache: &Ref<Cache>,
+) -> Result<Vec<(Symbol, I80F48, I80F48)>, ErrorCode> {
+    let weighted = get_actual_collateral_vec(margin, state, cache, true)?;
+
+    listed_collateral_indices(state)
+        .into_iter()
+        .zip(weighted)
+        .map(|(i, value)| {
+            let info = &state.collaterals[i];
+            let borrow = &cache.borrow_cache[i];
+            let amount = get_actual_collateral(
+                i,
+                margin,
+                borrow.supply_multiplier.into(),
+                borrow.borrow_multiplier.into(),
+            )?;
+            Ok((info.oracle_symbol, amount, value))
+        })
+        .collect()
 }
 
-pub fn get_actual_collateral_vec(
+/// Returns the value in smol USD that collateral weighting removes from
+/// each listed collateral, by collateral index, i.e. its unweighted minus
+/// its weighted value. Borrows are not weighted, so their haircut is zero.
+pub fn collateral_haircuts(
     margin: &Margin,
     state: &Ref<State>,
     cache: &Ref<Cache>,
-    is_weighted: bool,
-) -> Result<Vec<I80F48>, ErrorCode> {
-    let mut vec = Vec::with_capacity({ margin.collateral }.len());
+) -> Result<Vec<(usize, I80F48)>, ErrorCode> {
+    let raw = get_actual_collateral_vec(margin, state, cache, false)?;
+    let weighted = get_actual_collateral_vec(margin, state, cache, true)?;
+
+    // Entries are for listed collaterals only, so they are paired with
+    // their collateral index rather than their position.
+    Ok(listed_collateral_indices(state)
+        .into_iter()
+        .zip(raw.iter().zip(weighted.iter()))
+        .map(|(i, (&raw, &weighted))| (i, raw - weighted))
+        .collect())
+}
 
-    let max_col = state.total_collaterals;
-    for (i, _v) in { margin.collateral }.iter().enumerate() {
-        if i >= max_col as usize {
+/// Share of the account's collateral value that each oracle prices,
+/// largest first. Shares are of the summed absolute values, so borrows
+/// count as exposure and the shares add up to 1. Accounts with one
+/// share near 1 hinge on a single feed.
+pub fn oracle_concentration(
+    margin: &Margin,
+    state: &State,
+    cache: &Cache,
+) -> Result<Vec<(Symbol, f64)>, ErrorCode> {
+    let mut exposures: BTreeMap<Symbol, f64> = BTreeMap::new();
+    for (i, &coll) in { margin.collateral }.iter().enumerate() {
+        if i >= state.total_collaterals as usize {
             break;
         }
-
         let info = &state.collaterals[i];
-        let borrow = &cache.borrow_cache[i];
-
-        if info.is_empty() {
+        if coll == WrappedI80F48::zero() || info.is_empty() {
             continue;
         }
 
-        let v: I80F48 = get_actual_collateral(
+        let borrow = &cache.borrow_cache[i];
+        let amount = get_actual_collateral(
             i,
             margin,
             borrow.supply_multiplier.into(),
             borrow.borrow_multiplier.into(),
-        )
-        .unwrap();
+        )?;
+        let price: I80F48 = get_oracle(cache, &info.oracle_symbol)
+            .ok_or(ErrorCode::CollateralFailure)?
+            .price
+            .into();
+        let value = safe_mul_i80f48(amount, price).abs().to_num::<f64>();
+        *exposures.entry(info.oracle_symbol).or_default() += value;
+    }
 
-        let oracle_cache = get_oracle(cache, &info.oracle_symbol).unwrap();
-        let price: I80F48 = oracle_cache.price.into();
+    let total: f64 = exposures.values().sum();
+    if total == 0.0 {
+        return Ok(Vec::new());
+    }
 
-        // Price is only weighted when collateral is non-negative.
-        let weighted_price = match is_weighted && v >= 0u64 {
-            true => safe_mul_i80f48(
-                price,
-                I80F48::from_num(info.weight as f64 / 1000.0),
-            ),
-            false => price,
-        };
-        vec.push(safe_mul_i80f48(weighted_price, v));
+    let mut shares: Vec<(Symbol, f64)> = exposures
+        .into_iter()
+        .map(|(symbol, value)| (symbol, value / total))
+        .collect();
+    shares.sort_by(|a, b| b.1.total_cmp(&a.1));
+    Ok(shares)
+}
+
+/// Deposits across `accounts` by oracle symbol, in smol USD at oracle
+/// prices and unweighted, largest first. Borrows are left out, so this
+/// is what backs the book.
+pub fn book_collateral_composition<'a>(
+    accounts: impl IntoIterator<Item = &'a Margin>,
+    state: &State,
+    cache: &Cache,
+) -> Result<Vec<(Symbol, I80F48)>, ErrorCode> {
+    let mut totals: BTreeMap<Symbol, I80F48> = BTreeMap::new();
+    for margin in accounts {
+        for (i, &coll) in { margin.collateral }.iter().enumerate() {
+            if i >= state.total_collaterals as usize {
+                break;
+            }
+            let info = &state.collaterals[i];
+            if coll <= WrappedI80F48::zero() || info.is_empty() {
+                continue;
+            }
+
+            let borrow = &cache.borrow_cache[i];
+            let amount = get_actual_collateral(
+                i,
+                margin,
+                borrow.supply_multiplier.into(),
+                borrow.borrow_multiplier.into(),
+            )?;
+            let price: I80F48 = get_oracle(cache, &info.oracle_symbol)
+                .ok_or(ErrorCode::CollateralFailure)?
+                .price
+                .into();
+            let total = totals.entry(info.oracle_symbol).or_default();
+            *total = total
+                .checked_add(safe_mul_i80f48(amount, price))
+                .ok_or(ErrorCode::MathOverflow)?;
+        }
     }
 
-    Ok(vec)
+    let mut composition: Vec<(Symbol, I80F48)> = totals.into_iter().collect();
+    composition.sort_by(|a, b| b.1.cmp(&a.1));
+    Ok(composition)
 }
 
 pub fn get_actual_collateral(
@@ -449,10 +1998,11 @@ pub fn largest_open_order(
         .iter()
         .zip(cache.marks)
         .map(|(order, mark)| {
-            safe_mul_i80f48(
-                I80F48::from_num(order.coin_on_asks.max(order.coin_on_bids)),
-                mark.price.into(),
-            )
+            // Any u64 size fits in an I80F48. An order whose notional
+            // overflows is still the largest one.
+            I80F48::from_num(order.coin_on_asks.max(order.coin_on_bids))
+                .checked_mul(mark.price.into())
+                .unwrap_or(I80F48::MAX)
         })
         .collect();
 
@@ -485,11 +2035,35 @@ pub fn has_open_orders(
     Ok(result.is_some())
 }
 
+/// Whether the account has no positions, open orders or borrows, and
+/// less than `threshold` smol USD of weighted collateral. Such accounts
+/// have no requirement and can't become liquidatable, so sweeps can
+/// skip the full margin checks on them.
+pub fn is_empty_account(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+    threshold: i64,
+) -> Result<bool, ErrorCode> {
+    let has_position =
+        control.open_orders_agg.iter().any(|oo| oo.pos_size != 0);
+    let has_borrow = { margin.collateral }
+        .iter()
+        .any(|&coll| coll < WrappedI80F48::zero());
+    if has_position || has_borrow || has_open_orders(cache, control)? {
+        return Ok(false);
+    }
+
+    let col = get_total_collateral(margin, cache, state)?;
+    Ok(col < I80F48::from_num(threshold))
+}
+
 pub fn get_total_collateral(
     margin: &Margin,
     cache: &Cache,
     state: &State,
-) -> I80F48 {
+) -> Result<I80F48, ErrorCode> {
     let mut total: I80F48 = I80F48::ZERO;
     // Estimate using mark prices.
 
@@ -498,8 +2072,9 @@ pub fn get_total_collateral(
             continue;
         }
 
-        let oracle =
-            get_oracle(cache, &state.collaterals[i].oracle_symbol).unwrap();
+        let symbol = &state.collaterals[i].oracle_symbol;
+        let oracle = get_oracle(cache, symbol)
+            .ok_or_else(|| ErrorCode::MissingOracle((*symbol).into()))?;
         let borrow_cache = cache.borrow_cache[i];
         let usdc_col = safe_mul_i80f48(coll.into(), oracle.price.into());
 
@@ -507,7 +2082,7 @@ pub fn get_total_collateral(
             match state.collaterals[i].weight.try_into() {
                 Ok(weight) => safe_mul_i80f48(usdc_col, weight)
                     .checked_div(I80F48::from_num(1000u16))
-                    .unwrap(),
+                    .ok_or(ErrorCode::MathOverflow)?,
                 Err(_) => usdc_col,
             }
         } else {
@@ -523,7 +2098,7 @@ pub fn get_total_collateral(
         total = safe_add_i80f48(total, accrued);
     }
 
-    total
+    Ok(total)
 }
 
 #[allow(dead_code)]
@@ -541,12 +2116,16 @@ fn calc_max_reducible(
     let diff = I80F48::from_num(base_imf) - liq_fee;
 
     let denom = safe_mul_i80f48(price, diff);
-    Ok(I80F48::from_num(numerator)
-        .checked_div(denom)
-        .unwrap()
-        .ceil()
-        .checked_to_num()
-        .unwrap())
+    if denom <= 0 {
+        return Err(ErrorCode::MathFailure);
+    }
+    let reducible = checked_to_i64(
+        I80F48::from_num(numerator)
+            .checked_div(denom)
+            .ok_or(ErrorCode::MathFailure)?
+            .ceil(),
+    )?;
+    Ok(reducible.max(0))
 }
 
 #[allow(dead_code)]
@@ -572,9 +2151,11 @@ fn get_max_reducible_assets(
         pcmf_vec: _,
         mut pos_open_notional_vec,
         mut pos_notional_vec,
+        market_breakdown: _,
     } = get_perp_acc_params(
         weighted_col,
         MfReturnOption::Both,
+        false,
         max_markets,
         oo_agg,
         &cache.marks,
@@ -605,7 +2186,8 @@ fn get_max_reducible_assets(
 
     let mut weighted_sum_pimfs = 0i64;
     for (i, &pimf) in pimf_vec.iter().enumerate() {
-        weighted_sum_pimfs += pos_open_notional_vec[i].safe_mul(pimf as i64)?;
+        weighted_sum_pimfs = weighted_sum_pimfs
+            .safe_add(pos_open_notional_vec[i].safe_mul(pimf as i64)?)?;
     }
 
     let max_reducible = calc_max_reducible(
@@ -620,7 +2202,51 @@ fn get_max_reducible_assets(
     Ok(max_reducible)
 }
 
-#[allow(dead_code)]
+/// Base lots of the perp position in `market_index` to take over for
+/// the account to clear its initial requirement, and so maintenance.
+/// Like the spot estimate, `fudge` scales the size up to leave room for
+/// prices moving before the liquidation lands. Capped at the position.
+pub fn estimate_perp_liquidation_size(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+    market_index: usize,
+    coin_lot_size: i64,
+    fudge: Option<f64>,
+) -> Result<i64, ErrorCode> {
+    let info = &state.perp_markets[market_index];
+    let mark: I80F48 = cache.marks[market_index].price.into();
+    let weighted_col = get_total_collateral(margin, cache, state)?;
+
+    let asset_amount = get_max_reducible_assets(
+        info.base_imf,
+        I80F48::from_num(info.liq_fee),
+        mark,
+        checked_to_i64(weighted_col)?,
+        state.total_markets as usize,
+        state.total_collaterals as usize,
+        cache,
+        &control.open_orders_agg,
+        &state.perp_markets,
+        &{ margin.collateral },
+        &state.collaterals,
+    )?; // In smol asset
+    let asset_amount = match fudge {
+        Some(f) => (f * asset_amount as f64) as i64,
+        None => asset_amount,
+    };
+
+    let pos_size = control.open_orders_agg[market_index].pos_size.abs();
+    let lots = asset_amount
+        .safe_add(coin_lot_size - 1)?
+        .safe_div(coin_lot_size)?;
+    let pos_lots = pos_size
+        .safe_add(coin_lot_size - 1)?
+        .safe_div(coin_lot_size)?;
+    Ok(lots.min(pos_lots))
+}
+
 pub fn estimate_spot_liquidation_size(
     // In assets
     margin: &Margin,
@@ -639,15 +2265,27 @@ pub fn estimate_spot_liquidation_size(
         - 1.0;
     let num_lf = -1000.0
         + state.collaterals[quote_index].weight as f64 * (1.0 + liq_fee);
-    let asset_oracle =
-        get_oracle(cache, &state.collaterals[asset_index].oracle_symbol)
-            .unwrap();
-    let asset_price: I80F48 = asset_oracle.price.into();
+    let asset_symbol = &state.collaterals[asset_index].oracle_symbol;
+    let asset_price: I80F48 = get_oracle(cache, asset_symbol)
+        .ok_or_else(|| ErrorCode::MissingOracle((*asset_symbol).into()))?
+        .price
+        .into();
+
+    // Without deposits there is nothing for a spot liquidation to take,
+    // and such accounts are left to the perp and bankruptcy paths. Ones
+    // whose borrows outweigh their deposits are still liquidated, so
+    // only the deposits are checked.
+    let deposits = get_indexed_collateral_vec(margin, state, cache, true)?;
+    if is_spot_bankrupt(&deposits) {
+        return Ok(0);
+    }
+    let weighted_col = get_total_collateral(margin, cache, state)?;
+
     let asset_amount = get_max_reducible_assets(
         base_imf,
         I80F48::from_num(num_lf),
         asset_price,
-        get_total_collateral(margin, cache, state).to_num(),
+        checked_to_i64(weighted_col)?,
         state.total_markets as usize,
         state.total_collaterals as usize,
         cache,
@@ -657,7 +2295,11 @@ pub fn estimate_spot_liquidation_size(
         &state.collaterals,
     )?; // In smol asset
     
-    let usdc_amount = asset_amount.safe_mul(asset_price.to_num::<i64>())?;
+    // Prices are per smol unit and can be below 1, so they are only
+    // rounded once multiplied out.
+    let usdc_amount = checked_to_i64(
+        safe_mul_i80f48(I80F48::from_num(asset_amount), asset_price).floor(),
+    )?;
     match fudge {
         Some(f) => Ok((f * usdc_amount as f64) as i64),
         None => Ok(usdc_amount),
@@ -745,3 +2387,1117 @@ pub fn estimate_spot_liquidation_size(
     )
     */
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::liquidator::fixtures::*;
+
+    #[test]
+    fn buying_power_drops_as_positions_grow() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+
+        let (mut keeper, mut keeper_control) = account();
+        set_balance(&mut keeper, usd, 10_000);
+        let idle = available_buying_power(
+            &keeper,
+            &keeper_control,
+            &book.state,
+            &book.cache,
+        )
+        .unwrap();
+        assert_eq!(idle, I80F48::from_num(10_000));
+
+        let mut last = idle;
+        for size in [10, 30, 60, 90] {
+            set_position(&mut keeper_control, btc, size, 1000.0);
+            let power = available_buying_power(
+                &keeper,
+                &keeper_control,
+                &book.state,
+                &book.cache,
+            )
+            .unwrap();
+            assert!(power < last, "{} at size {}", power, size);
+            last = power;
+        }
+
+        // Past its collateral the keeper has nothing left, not less.
+        set_position(&mut keeper_control, btc, 200, 1000.0);
+        let power = available_buying_power(
+            &keeper,
+            &keeper_control,
+            &book.state,
+            &book.cache,
+        )
+        .unwrap();
+        assert_eq!(power, I80F48::ZERO);
+    }
+
+    #[test]
+    fn all_fractions_match_separate_checks() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+
+        // From no position, through healthy, to below maintenance.
+        let accounts = [(1_000, 0), (1_000, 5), (1_000, 15), (1_000, 30)];
+        for (balance, size) in accounts {
+            let (mut margin, mut control) = account();
+            set_balance(&mut margin, usd, balance);
+            if size != 0 {
+                set_position(&mut control, btc, size, 1000.0);
+            }
+
+            let col = checked_to_i64(
+                get_total_collateral(&margin, &book.cache, &book.state)
+                    .unwrap(),
+            )
+            .unwrap();
+            let cache = RefCell::new(book.cache);
+            let combined = check_all_fractions(
+                col,
+                book.state.total_markets as usize,
+                book.state.total_collaterals as usize,
+                &control.open_orders_agg,
+                &book.state.perp_markets,
+                &book.state.collaterals,
+                &{ margin.collateral },
+                &cache.borrow(),
+                0,
+            )
+            .unwrap();
+
+            let check = |fraction_type| {
+                check_fraction_requirement(
+                    fraction_type,
+                    col,
+                    book.state.total_markets as usize,
+                    book.state.total_collaterals as usize,
+                    &control.open_orders_agg,
+                    &book.state.perp_markets,
+                    &book.state.collaterals,
+                    &{ margin.collateral },
+                    &cache.borrow(),
+                )
+                .unwrap()
+            };
+            let separate = (
+                check(FractionType::Initial),
+                check(FractionType::Maintenance),
+                check(FractionType::Cancel),
+            );
+            assert_eq!(combined, separate, "size {}", size);
+        }
+    }
+
+    #[test]
+    fn insurance_exposure_sums_negative_equity() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let sol = book.collateral("SOL", 100.0, 900);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+
+        // Borrowed 500 more than its SOL is worth.
+        let (mut borrower, borrower_control) = account();
+        set_balance(&mut borrower, usd, -1_500);
+        set_balance(&mut borrower, sol, 10);
+
+        // A long that lost 300 more than its collateral.
+        let (mut trader, mut trader_control) = account();
+        set_balance(&mut trader, usd, 200);
+        set_position(&mut trader_control, btc, 1, 1500.0);
+
+        // A short that lost everything and borrowed against it.
+        let (mut short, mut short_control) = account();
+        set_balance(&mut short, usd, -100);
+        set_position(&mut short_control, btc, -2, 900.0);
+
+        // Spot bankrupt with no debt, so nothing reaches the fund.
+        let (empty, empty_control) = account();
+        let (mut solvent, solvent_control) = account();
+        set_balance(&mut solvent, usd, 1_000);
+
+        let colls = get_actual_collateral_vec(
+            &empty,
+            &RefCell::new(book.state).borrow(),
+            &RefCell::new(book.cache).borrow(),
+            true,
+        )
+        .unwrap();
+        assert!(is_spot_bankrupt(&colls));
+
+        let accounts = [
+            (&borrower, &borrower_control),
+            (&trader, &trader_control),
+            (&short, &short_control),
+            (&empty, &empty_control),
+            (&solvent, &solvent_control),
+        ];
+        for (margin, control) in accounts {
+            let impact = insurance_fund_impact(
+                margin,
+                control,
+                &book.state,
+                &book.cache,
+            )
+            .unwrap();
+            assert_eq!(
+                impact > 0,
+                has_negative_equity(margin, control, &book.state, &book.cache)
+                    .unwrap()
+            );
+        }
+
+        let total =
+            total_insurance_exposure(accounts, &book.state, &book.cache);
+        assert_eq!(total.unwrap(), 500 + 300 + 300);
+    }
+
+    #[test]
+    fn collateral_haircuts_are_by_collateral_index() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        book.empty_collateral();
+        let sol = book.collateral("SOL", 100.0, 500);
+
+        let (mut margin, _) = account();
+        set_balance(&mut margin, usd, 1_000);
+        set_balance(&mut margin, sol, 10);
+
+        let haircuts = collateral_haircuts(
+            &margin,
+            &RefCell::new(book.state).borrow(),
+            &RefCell::new(book.cache).borrow(),
+        )
+        .unwrap();
+        assert_eq!(
+            haircuts,
+            vec![(usd, I80F48::ZERO), (sol, I80F48::from_num(500))]
+        );
+    }
+
+    #[test]
+    fn margin_trace_sums_match_the_regular_checks() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let sol = book.collateral("SOL", 100.0, 800);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+
+        let (mut margin, mut control) = account();
+        set_balance(&mut margin, usd, -400);
+        set_balance(&mut margin, sol, 20);
+        set_position(&mut control, btc, 2, 950.0);
+
+        let trace =
+            trace_margin(&margin, &control, &book.state, &book.cache).unwrap();
+        let c = margin_components(&margin, &control, &book.state, &book.cache)
+            .unwrap();
+        let col =
+            get_total_collateral(&margin, &book.cache, &book.state).unwrap();
+
+        assert_eq!(trace.col, checked_to_i64(col).unwrap());
+        assert_eq!(trace.col, c.col);
+        assert_eq!(trace.total_acc_value, c.total_acc_value);
+        assert_eq!(trace.total_realized_pnl, c.total_realized_pnl);
+        assert_eq!(trace.init_requirement, c.init_requirement().unwrap());
+        assert_eq!(trace.maint_requirement, c.maint_requirement().unwrap());
+        assert_eq!(trace.fractions, c.fractions(0).unwrap());
+    }
+
+    #[test]
+    fn zero_imf_markets_carry_no_requirement() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+        let sol = book.market("SOL-PERP", 100.0, 0);
+        assert_eq!(zero_imf_markets(&book.state), vec![sol]);
+
+        // Far more exposure than the collateral covers, yet the account
+        // meets every fraction, as it does on chain.
+        let (mut margin, mut control) = account();
+        set_balance(&mut margin, usd, 100);
+        set_position(&mut control, sol, 1_000, 100.0);
+        let c = margin_components(&margin, &control, &book.state, &book.cache)
+            .unwrap();
+        assert_eq!(c.maint_requirement().unwrap(), 0);
+        assert_eq!(c.fractions(0).unwrap(), (true, true, true));
+
+        // The same exposure in a margined market is liquidatable.
+        set_position(&mut control, btc, 100, 1000.0);
+        let c = margin_components(&margin, &control, &book.state, &book.cache)
+            .unwrap();
+        assert!(!c.fractions(0).unwrap().1);
+    }
+
+    #[test]
+    fn funding_flow_nets_positions_by_market() {
+        let mut book = Book::new();
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+        let eth = book.market("ETH-PERP", 100.0, 100);
+        let mut funding = { book.cache.funding_cache };
+        funding[btc] = 2_000_000;
+        funding[eth] = 500_000;
+        book.cache.funding_cache = funding;
+
+        // Positions in whole units of 6 decimals, all funded from zero
+        // except where noted.
+        let positions = [
+            (btc, 3_000_000, 0),         // owes 6
+            (btc, -1_000_000, 0),        // is owed 2
+            (btc, 5_000_000, 2_000_000), // settled, owes nothing
+            (eth, 1_000_000, 1_000_000), // is owed 0.5
+            (eth, 0, 0),                 // flat
+        ];
+        let controls: Vec<Control> = positions
+            .iter()
+            .map(|&(index, size, funding_index)| {
+                let (_, mut control) = account();
+                set_position(&mut control, index, size, 1.0);
+                control.open_orders_agg[index].funding_index = funding_index;
+                control
+            })
+            .collect();
+
+        let flow =
+            protocol_funding_flow(&controls, &book.cache, &book.state).unwrap();
+        assert_eq!(flow.get(&btc), Some(&-4_000_000));
+        assert_eq!(flow.get(&eth), Some(&500_000));
+        assert_eq!(flow.len(), 2);
+    }
+
+    #[test]
+    fn maint_tolerance_spares_accounts_at_the_boundary() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+
+        // A requirement of 5_000, a smol USD above the account value.
+        let (mut margin, mut control) = account();
+        set_balance(&mut margin, usd, 4_999);
+        set_position(&mut control, btc, 100, 1000.0);
+        let c = margin_components(&margin, &control, &book.state, &book.cache)
+            .unwrap();
+        assert_eq!(c.maint_requirement().unwrap(), 5_000_000);
+
+        assert!(!c.fractions(0).unwrap().1);
+        assert!(!c.fractions(1).unwrap().1);
+        assert!(c.fractions(5).unwrap().1);
+
+        // A negative tolerance is no tolerance.
+        assert!(!c.fractions(-5).unwrap().1);
+    }
+
+    #[test]
+    fn approx_margin_ratio_tracks_the_exact_ratio() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let sol = book.collateral("SOL", 100.0, 900);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+        let eth = book.market("ETH-PERP", 100.0, 50);
+
+        for balance in [1_000, 5_000, 20_000] {
+            for (size, entry) in [(10, 900.0), (-25, 1100.0), (60, 1010.0)] {
+                let (mut margin, mut control) = account();
+                set_balance(&mut margin, usd, balance);
+                set_balance(&mut margin, sol, 10);
+                set_position(&mut control, btc, size, entry);
+                set_position(&mut control, eth, -size, 100.0);
+
+                let c = margin_components(
+                    &margin,
+                    &control,
+                    &book.state,
+                    &book.cache,
+                )
+                .unwrap();
+                let exact = c.total_acc_value as f64 * 1000.0
+                    / c.maint_requirement().unwrap() as f64;
+                let approx = approx_margin_ratio(
+                    &margin,
+                    &control,
+                    &book.state,
+                    &book.cache,
+                );
+                assert!(
+                    (approx - exact).abs() <= exact.abs() * 0.01,
+                    "approx {} exact {}",
+                    approx,
+                    exact
+                );
+            }
+        }
+
+        // Without exposure there is no requirement to divide by.
+        let (margin, control) = account();
+        let ratio =
+            approx_margin_ratio(&margin, &control, &book.state, &book.cache);
+        assert!(ratio.is_infinite());
+    }
+
+    #[test]
+    fn adverse_move_tolerance_reaches_maintenance() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let sol = book.collateral("SOL", 100.0, 900);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+
+        let (mut margin, mut control) = account();
+        set_balance(&mut margin, usd, 1_000_000);
+        set_balance(&mut margin, sol, 10_000);
+        set_position(&mut control, btc, 10_000, 1000.0);
+
+        let tolerances: BTreeMap<Symbol, f64> =
+            adverse_move_tolerance(&margin, &control, &book.state, &book.cache)
+                .into_iter()
+                .collect();
+
+        // 1000 * (1.9M + 10M x) == 50 * 10M (1 + x)
+        let x = tolerances[&Symbol::from("BTC")];
+        let solved = (0.5e9 - 1.9e9) / (1e10 - 0.5e9);
+        assert!((x - solved).abs() < 1e-6, "{} != {}", x, solved);
+
+        // Collateral alone can't lose enough value to liquidate.
+        assert!(tolerances[&Symbol::from("USDC")].is_infinite());
+        assert!(tolerances[&Symbol::from("SOL")].is_infinite());
+
+        let meets_at = |book: &mut Book, move_by: f64| {
+            let price = 1000.0 * (1.0 + move_by);
+            book.cache.marks[btc].price = I80F48::from_num(price).into();
+            book.oracle("BTC", price);
+            margin_components(&margin, &control, &book.state, &book.cache)
+                .unwrap()
+                .meets(FractionType::Maintenance, 0)
+                .unwrap()
+        };
+        assert!(meets_at(&mut book, x * 0.98));
+        assert!(!meets_at(&mut book, x * 1.02));
+    }
+
+    #[test]
+    fn marks_far_from_the_oracle_are_refused() {
+        let mut book = Book::new();
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+        let eth = book.market("ETH-PERP", 100.0, 50);
+
+        let (_, mut control) = account();
+        set_position(&mut control, btc, 10, 1000.0);
+        let band = |_| Some(0.05);
+        assert!(
+            check_mark_bands(&control, &book.state, &book.cache, band).is_ok()
+        );
+
+        // A mark 20% above the oracle, as if pushed up by a thin book.
+        book.cache.marks[btc].price = I80F48::from_num(1200.0).into();
+        assert!(matches!(
+            check_mark_bands(&control, &book.state, &book.cache, band),
+            Err(ErrorCode::MarkOracleDivergence)
+        ));
+        let unbanded = |i| if i == btc { None } else { Some(0.05) };
+        assert!(
+            check_mark_bands(&control, &book.state, &book.cache, unbanded)
+                .is_ok()
+        );
+
+        // Markets the account isn't in don't matter.
+        book.cache.marks[btc].price = I80F48::from_num(1000.0).into();
+        book.cache.marks[eth].price = I80F48::from_num(10.0).into();
+        assert!(
+            check_mark_bands(&control, &book.state, &book.cache, band).is_ok()
+        );
+    }
+
+    #[test]
+    fn avg_entry_price_of_longs_and_shorts() {
+        let (_, control) = account();
+        let mut oo = control.open_orders_agg[0];
+        assert_eq!(avg_entry_price(&oo, 6), None);
+
+        // 2 BTC bought for 60k USD.
+        oo.pos_size = 2_000_000;
+        oo.native_pc_total = -60_000_000_000;
+        assert_eq!(avg_entry_price(&oo, 6), Some(I80F48::from_num(30_000e6)));
+
+        // 0.5 BTC sold for 15.5k USD.
+        oo.pos_size = -500_000;
+        oo.native_pc_total = 15_500_000_000;
+        assert_eq!(avg_entry_price(&oo, 6), Some(I80F48::from_num(31_000e6)));
+    }
+
+    #[test]
+    fn stuck_accounts_are_told_apart() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let luna = book.collateral("LUNA", 0.1, 500);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+        book.state.collaterals[usd].is_swappable = true;
+
+        let (mut illiquid, illiquid_control) = account();
+        set_balance(&mut illiquid, luna, 1_000_000);
+        set_balance(&mut illiquid, usd, -100_000);
+        let (mut swappable, swappable_control) = account();
+        set_balance(&mut swappable, usd, 100_000);
+        let (trader, mut trader_control) = account();
+        set_position(&mut trader_control, btc, 10, 1000.0);
+
+        let reason = |margin: &Margin, control: &Control, book: &Book| {
+            stuck_reason(margin, control, &book.state, &book.cache)
+        };
+        assert_eq!(
+            reason(&illiquid, &illiquid_control, &book),
+            Some(StuckReason::IlliquidCollateral)
+        );
+        assert_eq!(reason(&swappable, &swappable_control, &book), None);
+        assert_eq!(reason(&trader, &trader_control, &book), None);
+
+        // Without a mark the position can't be taken over.
+        book.cache.marks[btc].price = I80F48::ZERO.into();
+        assert_eq!(
+            reason(&trader, &trader_control, &book),
+            Some(StuckReason::HaltedMarkets)
+        );
+    }
+
+    #[test]
+    fn margin_components_reconstruct_the_fraction_checks() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let sol = book.collateral("SOL", 100.0, 800);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+        let eth = book.market("ETH-PERP", 100.0, 50);
+
+        for balance in [300, 1_000, 3_000, 10_000] {
+            let (mut margin, mut control) = account();
+            set_balance(&mut margin, usd, balance);
+            set_balance(&mut margin, sol, -5);
+            set_position(&mut control, btc, 10, 1000.0);
+            set_position(&mut control, eth, -20, 100.0);
+
+            let c =
+                margin_components(&margin, &control, &book.state, &book.cache)
+                    .unwrap();
+            assert_eq!(c.pimf_vec[btc], 100);
+            assert_eq!(c.pmmf_vec[btc], 50);
+            assert_eq!(c.pcmf_vec[btc], 62);
+            assert_eq!(c.pos_notional_vec[btc], 10_000);
+            assert_eq!(c.pos_notional_vec[eth], 2_000);
+
+            let weighted = |factors: &[u16], notionals: &[i64]| -> i64 {
+                factors
+                    .iter()
+                    .zip(notionals)
+                    .map(|(&f, &n)| f as i64 * n)
+                    .sum()
+            };
+            let maint = weighted(&c.pmmf_vec, &c.pos_notional_vec)
+                + weighted(&c.spot_mmf_vec, &c.spot_pos_notional_vec);
+            let init = weighted(&c.pimf_vec, &c.pos_open_notional_vec)
+                + weighted(&c.spot_imf_vec, &c.spot_pos_notional_vec);
+
+            let cache = RefCell::new(book.cache);
+            let (initial, maintenance, _) = check_all_fractions(
+                c.col,
+                book.state.total_markets as usize,
+                book.state.total_collaterals as usize,
+                &control.open_orders_agg,
+                &book.state.perp_markets,
+                &book.state.collaterals,
+                &{ margin.collateral },
+                &cache.borrow(),
+                0,
+            )
+            .unwrap();
+            assert_eq!(maintenance, c.total_acc_value * 1000 > maint);
+            assert_eq!(initial, c.omf().unwrap() > init, "{}", balance);
+        }
+    }
+
+    #[test]
+    fn book_health_is_weighted_by_notional() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+        let eth = book.market("ETH-PERP", 100.0, 50);
+
+        // A ratio of 4 on 10k of notional.
+        let (mut long, mut long_control) = account();
+        set_balance(&mut long, usd, 2_000);
+        set_position(&mut long_control, btc, 10, 1000.0);
+        // A ratio of 12 on 2k of notional.
+        let (mut short, mut short_control) = account();
+        set_balance(&mut short, usd, 600);
+        set_position(&mut short_control, eth, -20, 100.0);
+        // No requirement, so left out.
+        let (mut idle, idle_control) = account();
+        set_balance(&mut idle, usd, 1_000_000);
+
+        let health = book_weighted_health(
+            [
+                (&long, &long_control),
+                (&short, &short_control),
+                (&idle, &idle_control),
+            ],
+            &book.state,
+            &book.cache,
+        )
+        .unwrap();
+        let expected = (4.0 * 10_000.0 + 12.0 * 2_000.0) / 12_000.0;
+        assert!((health - expected).abs() < 1e-9, "{}", health);
+
+        let health = book_weighted_health(
+            [(&idle, &idle_control)],
+            &book.state,
+            &book.cache,
+        )
+        .unwrap();
+        assert!(health.is_infinite());
+    }
+
+    #[test]
+    fn spot_size_is_zero_without_collateral() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let sol = book.collateral("SOL", 100.0, 900);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+
+        // Nothing but open positions.
+        let (margin, mut control) = account();
+        set_position(&mut control, btc, 10, 1000.0);
+        let size = estimate_spot_liquidation_size(
+            &margin,
+            &control,
+            &book.state,
+            &book.cache,
+            sol,
+            usd,
+            None,
+        );
+        assert_eq!(size.unwrap(), 0);
+
+        // Borrowed against positions, so the collateral is negative.
+        let (mut margin, mut control) = account();
+        set_balance(&mut margin, sol, -5);
+        set_position(&mut control, btc, 10, 1000.0);
+        let size = estimate_spot_liquidation_size(
+            &margin,
+            &control,
+            &book.state,
+            &book.cache,
+            sol,
+            usd,
+            Some(1.1),
+        );
+        assert_eq!(size.unwrap(), 0);
+    }
+
+    #[test]
+    fn largest_open_order_handles_whale_sizes() {
+        let mut book = Book::new();
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+        let eth = book.market("ETH-PERP", 100.0, 50);
+        let sol = book.market("SOL-PERP", 1_000_000.0, 100);
+
+        // A size past i64::MAX would come out negative if cast to i64.
+        let (_, mut control) = account();
+        control.open_orders_agg[btc].coin_on_asks = 1_000;
+        control.open_orders_agg[eth].coin_on_bids = 1 << 63;
+        assert_eq!(
+            largest_open_order(&book.cache, &control).unwrap(),
+            Some(eth)
+        );
+
+        control.open_orders_agg[btc].coin_on_bids = u64::MAX;
+        assert_eq!(
+            largest_open_order(&book.cache, &control).unwrap(),
+            Some(btc)
+        );
+
+        // Too large a notional for an I80F48 is still the largest.
+        control.open_orders_agg[sol].coin_on_asks = u64::MAX;
+        assert_eq!(
+            largest_open_order(&book.cache, &control).unwrap(),
+            Some(sol)
+        );
+    }
+
+    #[test]
+    fn effective_usd_collateral_includes_realized_pnl() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+        let eth = book.market("ETH-PERP", 100.0, 50);
+
+        let (mut margin, mut control) = account();
+        set_balance(&mut margin, usd, 1_000);
+        let effective = |book: &Book, control: &Control| {
+            effective_usd_collateral(&margin, control, &book.cache, &book.state)
+                .unwrap()
+        };
+        assert_eq!(effective(&book, &control), I80F48::from_num(1_000));
+
+        set_position(&mut control, btc, 1, 1000.0);
+        set_position(&mut control, eth, -2, 100.0);
+        control.open_orders_agg[btc].realized_pnl = 250;
+        control.open_orders_agg[eth].realized_pnl = -100;
+        assert_eq!(effective(&book, &control), I80F48::from_num(1_150));
+
+        // Interest applies to the stored balance only.
+        book.cache.borrow_cache[usd].supply_multiplier =
+            I80F48::from_num(1.5).into();
+        assert_eq!(effective(&book, &control), I80F48::from_num(1_650));
+    }
+
+    #[test]
+    fn required_symbols_are_the_union_over_accounts() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let sol = book.collateral("SOL", 100.0, 900);
+        book.collateral("SRM", 2.0, 800);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+        book.market("ETH-PERP", 100.0, 50);
+
+        let (mut a, a_control) = account();
+        set_balance(&mut a, usd, 1_000);
+        let (mut b, mut b_control) = account();
+        set_balance(&mut b, sol, -10);
+        set_position(&mut b_control, btc, 1, 1000.0);
+        let (c, c_control) = account();
+        let accounts = [(&a, &a_control), (&b, &b_control), (&c, &c_control)];
+
+        let union: BTreeSet<Symbol> = accounts
+            .iter()
+            .flat_map(|&(margin, control)| {
+                account_symbols(margin, control, &book.state)
+            })
+            .collect();
+        let symbols = required_symbols(accounts, &book.state);
+        let expected: BTreeSet<Symbol> = ["USDC", "SOL", "BTC"]
+            .into_iter()
+            .map(Symbol::from)
+            .collect();
+        assert_eq!(symbols, union);
+        assert_eq!(symbols, expected);
+        assert!(required_symbols([], &book.state).is_empty());
+    }
+
+    #[test]
+    fn large_liquidations_cascade_to_thin_accounts() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+
+        let mut accounts = Vec::new();
+        for (collateral, size) in [
+            (100_000_000, 1_000_000), // the whale liquidated first
+            (100_000, 1_000),         // pushed under by a 10% drop
+            (500_000, 1_000),         // enough margin to ride it out
+            (100_000, -1_000),        // short, so gains from it
+            (10_000, 1_000),          // already under
+        ] {
+            let (mut margin, mut control) = account();
+            set_balance(&mut margin, usd, collateral);
+            set_position(&mut control, btc, size, 1000.0);
+            accounts.push((Pubkey::new_unique(), margin, control));
+        }
+        let whale = [accounts[0].0];
+
+        // Closing $1000 of BTC moves its mark by 0.1% per unit of impact.
+        let cascade = |impact| {
+            simulate_cascade(
+                &whale,
+                &accounts,
+                &book.state,
+                &book.cache,
+                linear_price_impact(&book.cache, impact),
+            )
+            .unwrap()
+        };
+        assert_eq!(cascade(100.0), [accounts[1].0]);
+        assert!(cascade(1.0).is_empty());
+        assert!(cascade(0.0).is_empty());
+    }
+
+    #[test]
+    fn deeper_accounts_need_a_larger_recovery() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+
+        // A long of 1_000_000 notional needs 50_000 at maintenance, and
+        // a rise x recovers once collateral + 1e6 x >= 50_000 (1 + x).
+        let distance = |collateral| {
+            let (mut margin, mut control) = account();
+            set_balance(&mut margin, usd, collateral);
+            set_position(&mut control, btc, 1_000, 1000.0);
+            recovery_distance(&margin, &control, &book.state, &book.cache)
+                .unwrap()
+        };
+        let expected = |collateral| (50_000.0 - collateral as f64) / 950_000.0;
+
+        assert_eq!(distance(60_000), 0.0);
+        let shallow = distance(49_000);
+        let deep = distance(10_000);
+        assert!((shallow - expected(49_000)).abs() < 1e-4, "{}", shallow);
+        assert!((deep - expected(10_000)).abs() < 1e-4, "{}", deep);
+        assert!(shallow < 0.01 && deep > shallow * 10.0);
+    }
+
+    #[test]
+    fn scenario_loss_combines_shocks() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let sol = book.collateral("SOL", 100.0, 1000);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+
+        let (mut margin, mut control) = account();
+        set_balance(&mut margin, usd, 10_000);
+        set_balance(&mut margin, sol, 100);
+        set_position(&mut control, btc, 10, 1000.0);
+        let loss = |shocks: &[(&str, f64)]| {
+            let shocks: Vec<(Symbol, f64)> =
+                shocks.iter().map(|&(s, x)| (Symbol::from(s), x)).collect();
+            scenario_loss(&margin, &control, &book.state, &book.cache, &shocks)
+                .unwrap()
+        };
+
+        // SOL and BTC hold 10_000 each, and USDC isn't shocked.
+        assert_eq!(loss(&[("SOL", -0.25), ("BTC", -0.25)]), -5_000);
+        assert_eq!(loss(&[("SOL", -0.25)]), -2_500);
+        assert_eq!(loss(&[("BTC", 0.5)]), 5_000);
+        assert_eq!(loss(&[("ETH", -0.5)]), 0);
+    }
+
+    #[test]
+    fn single_asset_accounts_are_fully_concentrated() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let sol = book.collateral("SOL", 100.0, 900);
+        let srm = book.collateral("SRM", 2.0, 800);
+
+        let (mut single, _) = account();
+        set_balance(&mut single, sol, 1_000);
+        let shares =
+            oracle_concentration(&single, &book.state, &book.cache).unwrap();
+        assert_eq!(shares, [(Symbol::from("SOL"), 1.0)]);
+
+        // Borrows count as exposure, at their absolute value.
+        let (mut mixed, _) = account();
+        set_balance(&mut mixed, usd, 60_000);
+        set_balance(&mut mixed, sol, 300);
+        set_balance(&mut mixed, srm, -5_000);
+        let shares =
+            oracle_concentration(&mixed, &book.state, &book.cache).unwrap();
+        let symbols: Vec<Symbol> = shares.iter().map(|(s, _)| *s).collect();
+        assert_eq!(symbols, ["USDC", "SOL", "SRM"].map(Symbol::from));
+        assert!((shares[0].1 - 0.6).abs() < 1e-9);
+        assert!((shares[2].1 - 0.1).abs() < 1e-9);
+        let total: f64 = shares.iter().map(|(_, share)| share).sum();
+        assert!((total - 1.0).abs() < 1e-9);
+        assert!(shares.iter().all(|(_, share)| *share < 0.8));
+
+        let (empty, _) = account();
+        assert!(oracle_concentration(&empty, &book.state, &book.cache)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn book_composition_sums_deposits_by_asset() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let sol = book.collateral("SOL", 100.0, 900);
+        let srm = book.collateral("SRM", 2.0, 800);
+
+        let (mut first, _) = account();
+        set_balance(&mut first, usd, 20_000);
+        set_balance(&mut first, sol, 400);
+        let (mut second, _) = account();
+        set_balance(&mut second, usd, 10_000);
+        set_balance(&mut second, sol, 200);
+        // Borrows don't back the book.
+        set_balance(&mut second, srm, -5_000);
+
+        let composition = book_collateral_composition(
+            [&first, &second],
+            &book.state,
+            &book.cache,
+        )
+        .unwrap();
+        assert_eq!(
+            composition,
+            [
+                (Symbol::from("SOL"), I80F48::from_num(60_000)),
+                (Symbol::from("USDC"), I80F48::from_num(30_000)),
+            ]
+        );
+    }
+
+    #[test]
+    fn requirement_vectors_are_parallel_for_each_fraction() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let sol = book.collateral("SOL", 100.0, 800);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+        let eth = book.market("ETH-PERP", 100.0, 50);
+
+        let (mut margin, mut control) = account();
+        set_balance(&mut margin, usd, 10_000);
+        set_balance(&mut margin, sol, -5);
+        set_position(&mut control, btc, 10, 1000.0);
+        set_position(&mut control, eth, -20, 100.0);
+        let c = margin_components(&margin, &control, &book.state, &book.cache)
+            .unwrap();
+
+        for fraction_type in [
+            FractionType::Initial,
+            FractionType::Maintenance,
+            FractionType::Cancel,
+        ] {
+            let (factors, notionals) = c.requirement_vectors(fraction_type);
+            assert_eq!(factors.len(), notionals.len());
+        }
+        assert!(c.init_requirement().is_ok());
+        assert!(c.maint_requirement().is_ok());
+        assert!(c.cancel_requirement().is_ok());
+
+        assert!(matches!(
+            calc_weighted_sum(vec![100, 50], vec![10_000]),
+            Err(ErrorCode::MismatchedVectors)
+        ));
+    }
+
+    #[test]
+    fn zero_priced_borrows_are_refused() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let srm = book.collateral("SRM", 0.0, 800);
+
+        let (mut margin, control) = account();
+        set_balance(&mut margin, usd, 10_000);
+        set_balance(&mut margin, srm, 100);
+        // Deposits at a bad price are only undervalued.
+        assert!(
+            margin_components(&margin, &control, &book.state, &book.cache)
+                .is_ok()
+        );
+
+        set_balance(&mut margin, srm, -5_000);
+        assert!(matches!(
+            margin_components(&margin, &control, &book.state, &book.cache),
+            Err(ErrorCode::InvalidBorrowPrice)
+        ));
+    }
+
+    #[test]
+    fn leverage_caps_follow_base_imf() {
+        for (base_imf, leverage) in
+            [(1000, 1.0), (500, 2.0), (100, 10.0), (50, 20.0), (40, 25.0)]
+        {
+            assert_eq!(max_leverage(base_imf), leverage);
+        }
+        assert_eq!(max_leverage(0), f64::INFINITY);
+    }
+
+    #[test]
+    fn liquidation_prices_are_at_the_maintenance_boundary() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+
+        let meets_at = |margin: &Margin, control: &Control, mark: f64| {
+            let mut cache = book.cache;
+            cache.marks[btc].price = I80F48::from_num(mark).into();
+            margin_components(margin, control, &book.state, &cache)
+                .unwrap()
+                .meets(FractionType::Maintenance, 0)
+                .unwrap()
+        };
+        let price = |margin: &Margin, control: &Control| {
+            liquidation_price(margin, control, &book.state, &book.cache, btc)
+                .unwrap()
+                .to_num::<f64>()
+        };
+
+        // 1000 * (10_000p - 5M) == 50 * 10_000p at p = 526.3.
+        let (mut long, mut long_control) = account();
+        set_balance(&mut long, usd, 5_000_000);
+        set_position(&mut long_control, btc, 10_000, 1000.0);
+        let p = price(&long, &long_control);
+        assert!((p - 5_000_000.0 / 9_500.0).abs() < 0.01, "{}", p);
+        assert!(meets_at(&long, &long_control, p * 1.01));
+        assert!(!meets_at(&long, &long_control, p * 0.99));
+
+        // 1000 * (25M - 10_000p) == 50 * 10_000p at p = 2381.
+        let (mut short, mut short_control) = account();
+        set_balance(&mut short, usd, 15_000_000);
+        set_position(&mut short_control, btc, -10_000, 1000.0);
+        let p = price(&short, &short_control);
+        assert!((p - 25_000_000.0 / 10_500.0).abs() < 0.01, "{}", p);
+        assert!(meets_at(&short, &short_control, p * 0.99));
+        assert!(!meets_at(&short, &short_control, p * 1.01));
+
+        let (flat, flat_control) = account();
+        assert!(matches!(
+            liquidation_price(
+                &flat,
+                &flat_control,
+                &book.state,
+                &book.cache,
+                btc
+            ),
+            Err(ErrorCode::NoPositions)
+        ));
+    }
+
+    #[test]
+    fn underwater_borrowers_have_a_spot_size() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let sol = book.collateral("SOL", 100.0, 900);
+
+        // 100_000 of deposits weigh 90_000 against a 100_000 borrow.
+        let (mut margin, control) = account();
+        set_balance(&mut margin, usd, -100_000);
+        set_balance(&mut margin, sol, 1_000);
+        let col =
+            get_total_collateral(&margin, &book.cache, &book.state).unwrap();
+        assert!(col < 0);
+
+        let size = estimate_spot_liquidation_size(
+            &margin,
+            &control,
+            &book.state,
+            &book.cache,
+            usd,
+            sol,
+            None,
+        );
+        assert!(size.unwrap() > 0);
+    }
+
+    #[test]
+    fn spot_sizes_of_cheap_smol_units_are_not_truncated() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        // SOL at $40 with 9 decimals, 0.04 smol USD per lamport.
+        let sol = book.collateral("SOL", 0.04, 900);
+
+        let (mut margin, control) = account();
+        set_balance(&mut margin, usd, 40_000_000);
+        set_balance(&mut margin, sol, -1_000_000_000);
+        let size = |state: &State| {
+            estimate_spot_liquidation_size(
+                &margin,
+                &control,
+                state,
+                &book.cache,
+                sol,
+                usd,
+                None,
+            )
+        };
+        assert!(size(&book.state).unwrap() > 0);
+
+        // A delisted feed fails the estimate rather than the scan.
+        let mut state = book.state;
+        state.collaterals[sol].oracle_symbol = Symbol::from("XSOL");
+        assert!(matches!(
+            size(&state),
+            Err(ErrorCode::MissingOracle(symbol)) if symbol == "XSOL"
+        ));
+    }
+
+    #[test]
+    fn fraction_requirements_match_health_info() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+
+        // From no position, through healthy, to below maintenance.
+        for size in [0, 5, 15, 30] {
+            let (mut margin, mut control) = account();
+            set_balance(&mut margin, usd, 1_000);
+            if size != 0 {
+                set_position(&mut control, btc, size, 1000.0);
+            }
+
+            let col = checked_to_i64(
+                get_total_collateral(&margin, &book.cache, &book.state)
+                    .unwrap(),
+            )
+            .unwrap();
+            let cache = RefCell::new(book.cache);
+            let info = compute_health_info(
+                col,
+                book.state.total_markets as usize,
+                book.state.total_collaterals as usize,
+                &control.open_orders_agg,
+                &book.state.perp_markets,
+                &book.state.collaterals,
+                &{ margin.collateral },
+                &cache.borrow(),
+            )
+            .unwrap();
+
+            let fractions = || {
+                [
+                    FractionType::Initial,
+                    FractionType::Maintenance,
+                    FractionType::Cancel,
+                ]
+            };
+            let pairs = fractions().into_iter().zip(fractions()).enumerate();
+            for (i, (fraction, same_fraction)) in pairs {
+                let expected = info.meets(fraction).unwrap();
+                let met = check_fraction_requirement(
+                    same_fraction,
+                    col,
+                    book.state.total_markets as usize,
+                    book.state.total_collaterals as usize,
+                    &control.open_orders_agg,
+                    &book.state.perp_markets,
+                    &book.state.collaterals,
+                    &{ margin.collateral },
+                    &cache.borrow(),
+                )
+                .unwrap();
+                assert_eq!(met, expected, "fraction {} at size {}", i, size);
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_range_funding_is_an_overflow() {
+        // A long pays as the funding index rises.
+        assert_eq!(
+            calc_unrealized_funding(1_000_000, 0, 2_000, 6).unwrap(),
+            -2_000
+        );
+
+        // The i128 product fits, but not the funding it comes to.
+        let funding = calc_unrealized_funding(i64::MAX, i64::MAX as i128, 0, 0);
+        assert!(matches!(funding, Err(ErrorCode::MathOverflow)));
+    }
+
+    #[test]
+    fn collateral_without_an_oracle_is_an_error() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let sol = book.collateral("SOL", 100.0, 900);
+
+        let (mut margin, _) = account();
+        set_balance(&mut margin, usd, 1_000);
+        set_balance(&mut margin, sol, 10);
+
+        let mut state = book.state;
+        let mut collaterals = state.collaterals;
+        collaterals[sol].oracle_symbol = Symbol::from("XSOL");
+        state.collaterals = collaterals;
+        assert!(matches!(
+            get_total_collateral(&margin, &book.cache, &state),
+            Err(ErrorCode::MissingOracle(symbol)) if symbol == "XSOL"
+        ));
+    }
+}
@@ -34,7 +34,59 @@ where
     safe_impl!(safe_div, checked_div);
 }
 
+/// Saturating counterparts of `SafeOp`, for totals that are only
+/// reported or ranked, e.g. the keeper's PnL or book-wide notionals,
+/// where clamping at the bounds is better than failing. Amounts that
+/// decide a liquidation, such as account values and margin
+/// requirements, must use `SafeOp` so an overflow surfaces as an error.
+pub trait SatOp {
+    fn sat_add(&self, x: Self) -> Self;
+    fn sat_mul(&self, x: Self) -> Self;
+}
+
+macro_rules! sat_impl {
+    ( $($t:ty),* ) => {
+        $(
+            impl SatOp for $t {
+                fn sat_add(&self, x: Self) -> Self {
+                    self.saturating_add(x)
+                }
+
+                fn sat_mul(&self, x: Self) -> Self {
+                    self.saturating_mul(x)
+                }
+            }
+        )*
+    };
+}
+
+sat_impl!(i64, i128, u64);
+
+/// `base` raised to `exp`, failing on overflow, e.g. for powers of ten
+/// from on-chain decimals.
+pub fn safe_pow(base: i64, exp: u32) -> Result<i64, ErrorCode> {
+    base.checked_pow(exp).ok_or(MathOverflow)
+}
+
+/// Converts an amount in smol units to whole units, e.g. `1_500_000`
+/// with 6 decimals to `1.5`. Decimals come from `PerpMarketInfo` or
+/// `CollateralInfo`.
+pub fn to_display(smol: i64, decimals: u32) -> f64 {
+    smol as f64 / 10f64.powi(decimals as i32)
+}
+
+/// Inverse of `to_display`, rounded to the nearest smol unit.
+pub fn from_display(display: f64, decimals: u32) -> i64 {
+    (display * 10f64.powi(decimals as i32)).round() as i64
+}
+
 // I80F48
+/// Converts to `i64`, failing rather than saturating when the value is
+/// out of range. Fractional parts are truncated as with `to_num`.
+pub fn checked_to_i64(v: I80F48) -> Result<i64, ErrorCode> {
+    v.checked_to_num::<i64>().ok_or(MathOverflow)
+}
+
 pub fn safe_add_i80f48(a: I80F48, b: I80F48) -> I80F48 {
     let c = a.checked_add(b).ok_or(MathFailure);
     c.unwrap()
@@ -44,3 +96,37 @@ pub fn safe_mul_i80f48(a: I80F48, b: I80F48) -> I80F48 {
     let c = a.checked_mul(b).ok_or(MathFailure);
     c.unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_conversions_round_trip() {
+        for decimals in [6, 8, 9] {
+            for smol in [0, 1, -1, 1_234_567, -987_654_321, 10i64.pow(15)] {
+                let display = to_display(smol, decimals);
+                assert_eq!(from_display(display, decimals), smol);
+            }
+        }
+
+        assert_eq!(to_display(1_500_000, 6), 1.5);
+        assert_eq!(to_display(150_000_000, 8), 1.5);
+        assert_eq!(to_display(1_500_000_000, 9), 1.5);
+        assert_eq!(from_display(1.5, 9), 1_500_000_000);
+    }
+
+    #[test]
+    fn out_of_range_conversions_fail() {
+        let huge = I80F48::from_num(i64::MAX) * I80F48::from_num(4);
+        assert!(matches!(checked_to_i64(huge), Err(MathOverflow)));
+        assert!(matches!(checked_to_i64(-huge), Err(MathOverflow)));
+
+        assert_eq!(
+            checked_to_i64(I80F48::from_num(i64::MIN)).unwrap(),
+            i64::MIN
+        );
+        assert_eq!(checked_to_i64(I80F48::from_num(-2.5)).unwrap(), -3);
+        assert_eq!(checked_to_i64(I80F48::from_num(2.5)).unwrap(), 2);
+    }
+}
@@ -0,0 +1,231 @@
+/*
+ * This file keeps metrics of the liquidator and serves them in the
+ * Prometheus text format, so each keeper instance can be scraped.
+ * Counters are process-wide, like the ledger in pnl.rs.
+*/
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{Read, Write},
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use tracing::{info, warn};
+
+/// Upper bounds of the sweep duration buckets, in seconds.
+const SWEEP_BUCKETS: [f64; 10] =
+    [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+struct Histogram {
+    buckets: [u64; SWEEP_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+static ACCOUNTS_SCANNED: AtomicU64 = AtomicU64::new(0);
+static LIQUIDATABLE_FOUND: AtomicU64 = AtomicU64::new(0);
+static LIQUIDATIONS_ATTEMPTED: AtomicU64 = AtomicU64::new(0);
+static LIQUIDATIONS_LANDED: AtomicU64 = AtomicU64::new(0);
+static SEND_FAILURES: Mutex<BTreeMap<&'static str, u64>> =
+    Mutex::new(BTreeMap::new());
+/// Times each safety guard held back an account, or a whole sweep for
+/// the fork guard: in total, and since the last `guard_report`.
+static GUARD_SUPPRESSIONS: Mutex<BTreeMap<&'static str, (u64, u64)>> =
+    Mutex::new(BTreeMap::new());
+static SWEEP_DURATION: Mutex<Histogram> = Mutex::new(Histogram {
+    buckets: [0; SWEEP_BUCKETS.len()],
+    count: 0,
+    sum: 0.0,
+});
+
+/// Records a finished sweep over `scanned` accounts.
+pub fn record_sweep(scanned: usize, duration: Duration) {
+    ACCOUNTS_SCANNED.store(scanned as u64, Ordering::Relaxed);
+
+    if let Ok(mut h) = SWEEP_DURATION.lock() {
+        let secs = duration.as_secs_f64();
+        for (bucket, bound) in h.buckets.iter_mut().zip(SWEEP_BUCKETS) {
+            if secs <= bound {
+                *bucket += 1;
+            }
+        }
+        h.count += 1;
+        h.sum += secs;
+    }
+}
+
+/// Records how many liquidatable accounts the last sweep found.
+pub fn record_liquidatable(found: usize) {
+    LIQUIDATABLE_FOUND.store(found as u64, Ordering::Relaxed);
+}
+
+pub fn liquidation_attempted() {
+    LIQUIDATIONS_ATTEMPTED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn liquidation_landed() {
+    LIQUIDATIONS_LANDED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a failed `retry_send` attempt, by the kind of error.
+pub fn send_failed(kind: &'static str) {
+    if let Ok(mut failures) = SEND_FAILURES.lock() {
+        *failures.entry(kind).or_default() += 1;
+    }
+}
+
+/// Counts an account, or a sweep, held back by the named guard.
+pub fn guard_suppressed(guard: &'static str) {
+    if let Ok(mut suppressions) = GUARD_SUPPRESSIONS.lock() {
+        let (total, recent) = suppressions.entry(guard).or_default();
+        *total += 1;
+        *recent += 1;
+    }
+}
+
+/// How many times each guard fired since the last report, leaving out
+/// guards that didn't. Resets the counts, so calling it once per sweep
+/// gives per-sweep figures.
+pub fn guard_report() -> Vec<(&'static str, u64)> {
+    let mut suppressions = match GUARD_SUPPRESSIONS.lock() {
+        Ok(suppressions) => suppressions,
+        Err(_) => return Vec::new(),
+    };
+
+    suppressions
+        .iter_mut()
+        .filter(|(_, (_, recent))| *recent > 0)
+        .map(|(guard, (_, recent))| (*guard, std::mem::take(recent)))
+        .collect()
+}
+
+/// Renders all metrics in the Prometheus text format.
+pub fn render() -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(
+        out,
+        "# HELP zo_keeper_accounts_scanned Accounts checked by the last sweep\n\
+         # TYPE zo_keeper_accounts_scanned gauge\n\
+         zo_keeper_accounts_scanned {}",
+        ACCOUNTS_SCANNED.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# HELP zo_keeper_liquidatable_accounts Liquidatable accounts found by the last sweep\n\
+         # TYPE zo_keeper_liquidatable_accounts gauge\n\
+         zo_keeper_liquidatable_accounts {}",
+        LIQUIDATABLE_FOUND.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# HELP zo_keeper_liquidations_attempted_total Liquidation transactions sent\n\
+         # TYPE zo_keeper_liquidations_attempted_total counter\n\
+         zo_keeper_liquidations_attempted_total {}",
+        LIQUIDATIONS_ATTEMPTED.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# HELP zo_keeper_liquidations_landed_total Liquidation transactions confirmed\n\
+         # TYPE zo_keeper_liquidations_landed_total counter\n\
+         zo_keeper_liquidations_landed_total {}",
+        LIQUIDATIONS_LANDED.load(Ordering::Relaxed)
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP zo_keeper_send_failures_total Failed send attempts by error kind\n\
+         # TYPE zo_keeper_send_failures_total counter"
+    );
+    if let Ok(failures) = SEND_FAILURES.lock() {
+        for (kind, count) in failures.iter() {
+            let _ = writeln!(
+                out,
+                "zo_keeper_send_failures_total{{kind=\"{}\"}} {}",
+                kind, count
+            );
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP zo_keeper_guard_suppressions_total Accounts held back by each safety guard\n\
+         # TYPE zo_keeper_guard_suppressions_total counter"
+    );
+    if let Ok(suppressions) = GUARD_SUPPRESSIONS.lock() {
+        for (guard, (total, _)) in suppressions.iter() {
+            let _ = writeln!(
+                out,
+                "zo_keeper_guard_suppressions_total{{guard=\"{}\"}} {}",
+                guard, total
+            );
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP zo_keeper_sweep_duration_seconds Time taken by each sweep\n\
+         # TYPE zo_keeper_sweep_duration_seconds histogram"
+    );
+    if let Ok(h) = SWEEP_DURATION.lock() {
+        for (count, bound) in h.buckets.iter().zip(SWEEP_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "zo_keeper_sweep_duration_seconds_bucket{{le=\"{}\"}} {}",
+                bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "zo_keeper_sweep_duration_seconds_bucket{{le=\"+Inf\"}} {}\n\
+             zo_keeper_sweep_duration_seconds_sum {}\n\
+             zo_keeper_sweep_duration_seconds_count {}",
+            h.count, h.sum, h.count
+        );
+    }
+
+    out
+}
+
+/// Serves the metrics on `port` from a background thread. Every request
+/// gets the metrics, whatever its path.
+pub fn serve(port: u16) -> std::io::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    info!("Serving metrics on port {}", port);
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Failed to accept metrics connection: {}", e);
+                    continue;
+                }
+            };
+
+            // The request itself doesn't matter, only that it was read.
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf);
+
+            let body = render();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            if let Err(e) = stream.write_all(response.as_bytes()) {
+                warn!("Failed to write metrics: {}", e);
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -1,20 +1,235 @@
 mod accounts;
 mod error;
+#[cfg(test)]
+mod fixtures;
+mod jito;
 mod liquidation;
 mod listener;
 mod margin_utils;
 mod math;
+mod metrics;
+mod pnl;
+mod report;
+mod strategy;
 mod swap;
 mod utils;
+mod wal;
 
 use crate::{AppState, Error};
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+pub use error::ErrorCode;
+pub use jito::JitoConfig;
+pub use pnl::ParamOverrides;
+pub use utils::PriorityFees;
+pub use strategy::{
+    LargestRiskFirst, LiquidationStrategy, MarginHealth, MostProfitableFirst,
+    MostProfitablePerCuFirst,
+};
+
+/// Whether to close a liquidated position fully or only reduce it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ArgEnum)]
+pub enum LiquidationStyle {
+    /// Take the whole position, up to what the liquidator's margin
+    /// allows.
+    AlwaysFull,
+    /// Take as much as the liquidator's margin allows.
+    PartialToTarget,
+    /// Full below `adaptive_full_threshold`, partial above it.
+    Adaptive,
+}
+
+/// Which kinds of liquidation the keeper sends. Accounts are still
+/// flagged using their full cross-margin health.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ArgEnum)]
+pub enum LiquidationScope {
+    PerpOnly,
+    SpotOnly,
+    Both,
+}
+
+#[derive(Clone)]
+pub struct LiquidatorConfig {
+    pub worker_count: u8,
+    pub worker_index: u8,
+    /// Amount in smol USD by which an account's maintenance requirement
+    /// must exceed its account value before it is liquidated.
+    pub maint_tolerance: i64,
+    /// How long after a margin account is first seen before it can be
+    /// liquidated. Accounts present at startup are never in grace.
+    pub new_account_grace: Duration,
+    /// Send liquidations as Jito bundles, falling back to the RPC.
+    pub jito: Option<JitoConfig>,
+    /// Longest time after an account is flagged that a liquidation may
+    /// still be sent. Later attempts are dropped and retried next scan.
+    pub attempt_deadline: Option<Duration>,
+    /// Largest relative deviation of a market's mark price from its
+    /// oracle before accounts exposed to it are no longer checked.
+    pub mark_oracle_band: Option<f64>,
+    /// Per-market overrides of `mark_oracle_band`, keyed by symbol.
+    pub market_mark_oracle_bands: HashMap<String, f64>,
+    /// Oldest an oracle price may be before accounts depending on it
+    /// are no longer checked.
+    pub max_oracle_age: Option<Duration>,
+    /// How long an account skipped for a stale oracle is skipped quietly
+    /// before being reported again.
+    pub stale_oracle_cooldown: Duration,
+    /// Number of updates in a row an oracle's price may stay unchanged
+    /// before it is considered frozen, and accounts depending on it are
+    /// no longer checked.
+    pub frozen_oracle_updates: Option<u32>,
+    /// Smallest improvement in the liqee's margin ratio, where 1.0 is
+    /// maintenance, a partial perp liquidation must make. Smaller ones
+    /// are enlarged up to the whole position.
+    pub min_liq_improvement: Option<f64>,
+    pub liquidation_style: LiquidationStyle,
+    /// Accounts that can only be liquidated through an excluded kind
+    /// of liquidation are skipped.
+    pub liquidation_scope: LiquidationScope,
+    /// Notional in smol USD up to which `Adaptive` closes positions
+    /// fully, which is cheaper than several partial liquidations.
+    pub adaptive_full_threshold: i64,
+    /// Position notional in smol USD from which the liqee's orders are
+    /// cancelled in a transaction of their own, and the liquidation is
+    /// sized once that is confirmed. Orders are cancelled alongside the
+    /// liquidation if not set.
+    pub two_phase_threshold: Option<i64>,
+    /// Smallest expected profit in smol USD, after the base and current
+    /// priority fees, for which a liquidation is sent.
+    pub min_profit_usd: Option<i64>,
+    /// Weighted collateral in smol USD under which accounts with no
+    /// positions, orders or borrows are skipped by sweeps. Every account
+    /// is checked if not set.
+    pub empty_account_threshold: Option<i64>,
+    /// Consecutive failed scans after which the liquidator exits. Scans
+    /// are otherwise retried with an exponential backoff.
+    pub max_scan_failures: Option<u32>,
+    /// Decides which liquidatable accounts are liquidated, and in what
+    /// order.
+    pub strategy: Arc<dyn LiquidationStrategy>,
+    /// Log the margin components of accounts whose margin ratio is
+    /// within this distance of 1.0, i.e. of maintenance.
+    pub log_margin_band: Option<f64>,
+    /// Relative move of a perp market's mark per million USD of notional
+    /// closed out in it. If set, the accounts that each scan's planned
+    /// liquidations may push below maintenance are logged.
+    pub cascade_impact: Option<f64>,
+    /// Relative oracle price moves, by symbol, of a stress scenario. The
+    /// change in the book's value with all of them applied at once is
+    /// logged in the book summary.
+    pub stress_shocks: Vec<(String, f64)>,
+    /// Liquidation fees and weights to compare with the on-chain ones.
+    /// The book summary logs the liquidatable accounts and expected
+    /// bonuses under both, but liquidations always use the on-chain
+    /// state.
+    pub param_overrides: ParamOverrides,
+    /// Authorities of accounts whose margin math is logged step by step
+    /// each time they are checked. Slow, so only for accounts under
+    /// investigation.
+    pub trace_accounts: HashSet<Pubkey>,
+    /// Collaterals, by oracle symbol, that cannot be seized, e.g. locked
+    /// or staked assets.
+    pub non_seizable_collaterals: HashSet<String>,
+    /// `(asset, quote)` collateral pairs, by oracle symbol, to use for
+    /// spot liquidations in order of preference. Pairs that don't apply
+    /// to an account fall back to automatic selection.
+    pub preferred_liq_pairs: Vec<(String, String)>,
+    /// Perp markets, by symbol, in reduce-only mode. The liquidator can
+    /// only take positions there that reduce its own. `PerpMarketInfo`
+    /// has no reduce-only flag, so the mode can't be read from the state
+    /// and has to be configured here.
+    pub reduce_only_markets: HashSet<String>,
+    /// Exit after one scan and its liquidations have finished, e.g. for
+    /// end-to-end runs against a local validator.
+    pub single_scan: bool,
+    /// File to log liquidations to before they are sent.
+    pub wal_path: Option<PathBuf>,
+    /// RPC endpoint whose blocks are compared to the main one's before
+    /// each scan. Scans are skipped while the two disagree, as the main
+    /// node may be on a minority fork.
+    pub fork_check_url: Option<String>,
+    /// Keep checking accounts while a liquidation or cancel for them is
+    /// still being sent, instead of waiting for it to resolve.
+    pub rescan_in_flight: bool,
+    /// Compute budget and priority fee requested by liquidations, with
+    /// the fee raised on each retry.
+    pub priority_fees: PriorityFees,
+    /// Least time the fee payer's balance should last at the recent rate
+    /// of liquidations before an alert is logged.
+    pub min_fee_runway: Option<Duration>,
+    /// How long to wait for each sent transaction to reach
+    /// `confirm_commitment`. Transactions seen without error, or whose
+    /// blockhash hasn't expired, are waited on rather than resent. Sends
+    /// only wait for the RPC node's confirmation if not set.
+    pub confirm_timeout: Option<Duration>,
+    pub confirm_commitment: CommitmentConfig,
+    /// Scan and size liquidations as usual, but log the instructions
+    /// instead of sending them.
+    pub dry_run: bool,
+    /// Skip liquidations whose expected profit doesn't cover the base
+    /// fee and the priority fee recent sends needed to land, as if
+    /// `min_profit_usd` were at least 1.
+    pub require_profit_after_fees: bool,
+    /// Check the margin account behind each margin or control update as
+    /// soon as the listener pushes it, rather than only in the sweeps on
+    /// the scan interval.
+    pub scan_on_update: bool,
+    /// Lengthen the scan interval to the duration of scans that take
+    /// longer than it, until they catch up again.
+    pub stretch_scan_interval: bool,
+    /// Port to serve Prometheus metrics on, if any.
+    pub metrics_port: Option<u16>,
+}
+
+impl LiquidatorConfig {
+    pub fn mark_oracle_band(&self, market_symbol: &str) -> Option<f64> {
+        self.market_mark_oracle_bands
+            .get(market_symbol)
+            .copied()
+            .or(self.mark_oracle_band)
+    }
+}
 
 pub async fn run(
     st: &'static AppState,
-    worker_count: u8,
-    worker_index: u8,
+    cfg: LiquidatorConfig,
 ) -> Result<(), Error> {
-    let database = accounts::DbWrapper::new(st, worker_index, worker_count);
+    if let Err(errors) = utils::validate_config(&st.rpc, st) {
+        return Err(Error::InvalidConfig(
+            errors.iter().map(|e| e.to_string()).collect(),
+        ));
+    }
+    if let Some(path) = &cfg.wal_path {
+        wal::open(path, |account, kind, slot| {
+            liquidation::find_landed_liquidation(
+                &st.rpc,
+                &st.payer(),
+                account,
+                kind,
+                slot,
+            )
+        })?;
+    }
+    if let Some(port) = cfg.metrics_port {
+        metrics::serve(port)?;
+    }
+
+    let max_scan_failures = cfg.max_scan_failures;
+    let single_scan = cfg.single_scan;
+    let min_fee_runway = cfg.min_fee_runway;
+    let scan_on_update = cfg.scan_on_update;
+    let stretch_scan_interval = cfg.stretch_scan_interval;
+    let fork_reference = cfg.fork_check_url.clone().map(|url| {
+        RpcClient::new_with_commitment(url, CommitmentConfig::confirmed())
+    });
+    let database = accounts::DbWrapper::new(st, cfg)?;
 
     let f = tokio::spawn(self::listener::start_listener(
         &zo_abi::ID,
@@ -22,7 +237,16 @@ pub async fn run(
         database.clone(),
     ));
 
-    let g = tokio::spawn(self::liquidation::liquidate_loop(&st, database));
+    let g = tokio::spawn(self::liquidation::liquidate_loop(
+        &st,
+        database,
+        max_scan_failures,
+        single_scan,
+        fork_reference,
+        min_fee_runway,
+        scan_on_update,
+        stretch_scan_interval,
+    ));
 
     // Propagate panic.
     tokio::select! {
@@ -32,3 +256,30 @@ pub async fn run(
 
     Ok(())
 }
+
+/// Prints the margin analysis of `authority`'s margin account as JSON,
+/// in the shape described on `report::account_report_json`.
+pub fn inspect(st: &AppState, authority: &Pubkey) -> Result<(), Error> {
+    let margin_key = Pubkey::find_program_address(
+        &[authority.as_ref(), st.zo_state_pubkey.as_ref(), b"marginv1"],
+        &zo_abi::ID,
+    )
+    .0;
+    let (_, margin) =
+        utils::load_accounts::<zo_abi::Margin>(&st.rpc, &[margin_key])?
+            .pop()
+            .ok_or(ErrorCode::IncompleteAccountFetch)?;
+    let (_, control) =
+        utils::load_accounts::<zo_abi::Control>(&st.rpc, &[margin.control])?
+            .pop()
+            .ok_or(ErrorCode::IncompleteAccountFetch)?;
+
+    let report = report::account_report_json(
+        &margin,
+        &control,
+        &st.zo_state,
+        &st.zo_cache,
+    )?;
+    println!("{:#}", report);
+    Ok(())
+}
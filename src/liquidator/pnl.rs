@@ -0,0 +1,564 @@
+/*
+ * This file keeps a running record of what the keeper earns from
+ * liquidations and spends on fees, so operators can tell whether
+ * it is profitable. All amounts are in smol USD.
+*/
+use fixed::types::I80F48;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use zo_abi::{Cache, Control, Margin, State};
+
+use crate::liquidator::{
+    error::ErrorCode,
+    margin_utils::{
+        estimate_perp_liquidation_size, estimate_spot_liquidation_size,
+        get_indexed_collateral_vec, margin_components,
+    },
+    math::*,
+    utils::PriorityFees,
+};
+
+/// Base fee charged per transaction signature.
+pub const LAMPORTS_PER_SIGNATURE: u64 = 5000;
+
+/// Rough compute budgets of a perp liquidation with its rebalancing
+/// order, and of a spot liquidation with its swap.
+pub const PERP_LIQUIDATION_CU: u64 = 400_000;
+pub const SPOT_LIQUIDATION_CU: u64 = 300_000;
+
+/// How long liquidations are kept for.
+const HISTORY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+struct Entry {
+    at: Instant,
+    bonus: i64,
+    fee: i64,
+    lamports: u64,
+}
+
+static LEDGER: Mutex<VecDeque<Entry>> = Mutex::new(VecDeque::new());
+
+/// Bonus captured by taking over `notional` at a liquidation fee given
+/// in thousandths, as in `PerpMarketInfo` and `CollateralInfo`.
+pub fn expected_liq_bonus(notional: i64, liq_fee: u16) -> i64 {
+    (notional as i128 * liq_fee as i128 / 1000) as i64
+}
+
+/// Converts lamports to smol USD using the cached SOL price, which is
+/// already in smol USD per lamport.
+pub fn lamports_to_usd(lamports: u64, cache: &Cache) -> i64 {
+    let price = cache
+        .oracles
+        .iter()
+        .find(|o| !o.symbol.is_nil() && String::from(o.symbol) == "SOL")
+        .map(|o| I80F48::from(o.price))
+        .unwrap_or(I80F48::ZERO);
+
+    safe_mul_i80f48(I80F48::from_num(lamports), price)
+        .ceil()
+        .saturating_to_num()
+}
+
+/// Records a landed liquidation capturing `bonus` for `lamports` in
+/// fees, valued at the cached SOL price.
+pub fn record_liquidation(bonus: i64, lamports: u64, cache: &Cache) {
+    if let Ok(mut ledger) = LEDGER.lock() {
+        while matches!(ledger.front(), Some(e) if e.at.elapsed() > HISTORY) {
+            ledger.pop_front();
+        }
+        ledger.push_back(Entry {
+            at: Instant::now(),
+            bonus,
+            fee: lamports_to_usd(lamports, cache),
+            lamports,
+        });
+    }
+}
+
+/// Number of liquidations that landed within the last `window`.
+pub fn recent_sends(window: Duration) -> u64 {
+    match LEDGER.lock() {
+        Ok(ledger) => ledger
+            .iter()
+            .rev()
+            .take_while(|e| e.at.elapsed() <= window)
+            .count() as u64,
+        Err(_) => 0,
+    }
+}
+
+/// Lamports paid in fees by the liquidations that landed within the
+/// last `window`.
+pub fn recent_fees_paid(window: Duration) -> u64 {
+    match LEDGER.lock() {
+        Ok(ledger) => ledger
+            .iter()
+            .rev()
+            .take_while(|e| e.at.elapsed() <= window)
+            .fold(0u64, |paid, e| paid.saturating_add(e.lamports)),
+        Err(_) => 0,
+    }
+}
+
+/// How long `current_sol_balance` lamports last if spent at the rate of
+/// the last `window`, in which `recent_send_count` transactions paid
+/// `recent_fees_paid` lamports. Each send costs at least the signature
+/// fee. Unbounded if nothing was sent.
+pub fn estimate_fee_runway(
+    recent_send_count: u64,
+    recent_fees_paid: u64,
+    current_sol_balance: u64,
+    window: Duration,
+) -> Duration {
+    let spent = recent_fees_paid
+        .max(recent_send_count.saturating_mul(LAMPORTS_PER_SIGNATURE));
+    if spent == 0 {
+        return Duration::MAX;
+    }
+
+    window.mul_f64(current_sol_balance as f64 / spent as f64)
+}
+
+/// Captured bonuses minus fees paid for liquidations that landed within
+/// the last `window`.
+pub fn keeper_pnl(window: Duration) -> i64 {
+    match LEDGER.lock() {
+        Ok(ledger) => ledger
+            .iter()
+            .rev()
+            .take_while(|e| e.at.elapsed() <= window)
+            .fold(0i64, |pnl, e| pnl.sat_add(e.bonus.saturating_sub(e.fee))),
+        Err(_) => 0,
+    }
+}
+
+/// How long a perp position can be held before the funding it pays
+/// adds up to `captured_bonus`. `position_notional` is signed, positive
+/// for longs, and `current_funding_rate` is the fraction of notional
+/// longs pay shorts per hour. Unbounded if the position earns funding.
+#[allow(dead_code)]
+pub fn funding_breakeven_hold(
+    position_notional: i64,
+    captured_bonus: i64,
+    current_funding_rate: f64,
+) -> Duration {
+    let paid_per_hour = position_notional as f64 * current_funding_rate;
+    if paid_per_hour <= 0.0 {
+        return Duration::MAX;
+    }
+    if captured_bonus <= 0 {
+        return Duration::ZERO;
+    }
+
+    let secs = 3600.0 * captured_bonus as f64 / paid_per_hour;
+    if secs >= Duration::MAX.as_secs_f64() {
+        return Duration::MAX;
+    }
+    Duration::from_secs_f64(secs)
+}
+
+/// Largest perp position of the account by notional, as picked by
+/// `liquidate`, with its market index.
+fn largest_position(
+    control: &Control,
+    cache: &Cache,
+) -> Option<(usize, I80F48)> {
+    control
+        .open_orders_agg
+        .iter()
+        .zip(cache.marks)
+        .map(|(oo, mark)| {
+            safe_mul_i80f48(I80F48::from_num(oo.pos_size), mark.price.into())
+                .abs()
+        })
+        .enumerate()
+        .filter(|(_, notional)| !notional.is_zero())
+        .max_by_key(|(_, notional)| *notional)
+}
+
+/// The keeper's directional inventory in each market it holds a
+/// position in, as signed notional in smol USD, positive for longs.
+/// Sorted by size, so the markets most in need of rebalancing come
+/// first.
+pub fn inventory_skew(
+    keeper_control: &Control,
+    cache: &Cache,
+) -> Vec<(usize, i64)> {
+    let mut skew: Vec<(usize, i64)> = keeper_control
+        .open_orders_agg
+        .iter()
+        .zip(cache.marks)
+        .enumerate()
+        .filter(|(_, (oo, _))| oo.pos_size != 0)
+        .map(|(i, (oo, mark))| {
+            let notional = safe_mul_i80f48(
+                I80F48::from_num(oo.pos_size),
+                mark.price.into(),
+            );
+            (i, notional.saturating_to_num())
+        })
+        .collect();
+
+    skew.sort_by_key(|(_, notional)| {
+        std::cmp::Reverse(notional.unsigned_abs())
+    });
+    skew
+}
+
+/// What a liquidation is expected to earn, in smol USD, and the
+/// compute units it takes.
+#[derive(Clone, Copy, Debug)]
+pub struct LiquidationEstimate {
+    /// Bonus captured at the liquidation fee, before any fees.
+    pub bonus: i64,
+    pub cu: u64,
+    /// Initial margin the keeper needs to take the liquidation over.
+    pub margin: i64,
+}
+
+impl LiquidationEstimate {
+    /// Taking over `notional` at a liquidation fee in thousandths, in a
+    /// send of `cu` compute units. The keeper is assumed to need the
+    /// whole notional as margin.
+    pub fn new(notional: i64, liq_fee: u16, cu: u64) -> Self {
+        Self {
+            bonus: expected_liq_bonus(notional, liq_fee),
+            cu,
+            margin: notional,
+        }
+    }
+
+    /// The account's next liquidation, sized as the liquidation itself
+    /// would be: the part of the largest position, or of the largest
+    /// borrow, that brings the account back above its initial
+    /// requirement.
+    pub fn for_account(
+        margin: &Margin,
+        control: &Control,
+        state: &State,
+        cache: &Cache,
+    ) -> Result<Self, ErrorCode> {
+        if let Some((index, _)) = largest_position(control, cache) {
+            // With a lot size of 1 the estimate is in smol asset.
+            let size = estimate_perp_liquidation_size(
+                margin, control, state, cache, index, 1, None,
+            )?;
+            let notional = safe_mul_i80f48(
+                I80F48::from_num(size),
+                cache.marks[index].price.into(),
+            );
+            let notional: i64 = notional.saturating_to_num();
+            let market = &state.perp_markets[index];
+            return Ok(Self {
+                margin: notional
+                    .saturating_mul(market.base_imf as i64)
+                    .saturating_div(1000),
+                ..Self::new(notional, market.liq_fee, PERP_LIQUIDATION_CU)
+            });
+        }
+
+        let colls = get_indexed_collateral_vec(margin, state, cache, false)?;
+        let asset = colls
+            .iter()
+            .enumerate()
+            .filter(|(_, col)| col.is_negative())
+            .min_by_key(|(_, col)| **col)
+            .map(|(i, _)| i);
+        let quote = colls
+            .iter()
+            .enumerate()
+            .filter(|(_, col)| col.is_positive())
+            .max_by_key(|(i, _)| state.collaterals[*i].weight)
+            .map(|(i, _)| i);
+
+        let notional = match (asset, quote) {
+            (Some(asset), Some(quote)) if asset != quote => {
+                estimate_spot_liquidation_size(
+                    margin, control, state, cache, asset, quote, None,
+                )? // In smol USD
+            }
+            _ => 0,
+        };
+        // The repaid borrow only comes back as weighted collateral.
+        let haircut = quote.map_or(1000, |i| {
+            1000u16.saturating_sub(state.collaterals[i].weight)
+        });
+        Ok(Self {
+            margin: notional
+                .saturating_mul(haircut as i64)
+                .saturating_div(1000),
+            ..Self::new(
+                notional,
+                asset.map_or(0, |i| state.collaterals[i].liq_fee),
+                SPOT_LIQUIDATION_CU,
+            )
+        })
+    }
+
+    /// The bonus less the base fee and the priority fee of a send that
+    /// lands on `attempt`, e.g. `required_attempt()` under the current
+    /// competition.
+    pub fn net(
+        &self,
+        fees: &PriorityFees,
+        attempt: usize,
+        cache: &Cache,
+    ) -> i64 {
+        let lamports = LAMPORTS_PER_SIGNATURE
+            .saturating_add(fees.lamports(attempt, self.cu));
+        self.bonus.saturating_sub(lamports_to_usd(lamports, cache))
+    }
+}
+
+/// Liquidation fees and weights to use in place of the on-chain ones,
+/// to model how a governance change would affect liquidations. Perp
+/// fees are keyed by market symbol, collateral parameters by oracle
+/// symbol. Fees and weights are in thousandths.
+#[derive(Clone, Debug, Default)]
+pub struct ParamOverrides {
+    pub perp_liq_fees: HashMap<String, u16>,
+    pub collateral_liq_fees: HashMap<String, u16>,
+    pub collateral_weights: HashMap<String, u16>,
+}
+
+impl ParamOverrides {
+    pub fn is_empty(&self) -> bool {
+        self.perp_liq_fees.is_empty()
+            && self.collateral_liq_fees.is_empty()
+            && self.collateral_weights.is_empty()
+    }
+
+    /// Returns a copy of the state with the overrides applied, for use
+    /// with the margin and profit estimates. The state passed in, and so
+    /// the one liquidations are sent with, is left as is.
+    pub fn apply(&self, state: &State) -> State {
+        let mut state = *state;
+
+        let total_markets = state.total_markets as usize;
+        for market in state.perp_markets.iter_mut().take(total_markets) {
+            let symbol: String = market.symbol.into();
+            if let Some(&fee) = self.perp_liq_fees.get(&symbol) {
+                market.liq_fee = fee;
+            }
+        }
+
+        let total_collaterals = state.total_collaterals as usize;
+        for info in state.collaterals.iter_mut().take(total_collaterals) {
+            let symbol: String = info.oracle_symbol.into();
+            if let Some(&fee) = self.collateral_liq_fees.get(&symbol) {
+                info.liq_fee = fee;
+            }
+            if let Some(&weight) = self.collateral_weights.get(&symbol) {
+                info.weight = weight;
+            }
+        }
+
+        state
+    }
+}
+
+/// The number of accounts below maintenance under `state`, and the
+/// bonus expected from their next liquidations, e.g. to compare the
+/// on-chain parameters with overridden ones.
+pub fn liquidation_outlook<'a>(
+    accounts: impl IntoIterator<Item = (&'a Margin, &'a Control)>,
+    state: &State,
+    cache: &Cache,
+) -> Result<(usize, i64), ErrorCode> {
+    let mut count = 0;
+    let mut bonus = 0i64;
+    for (margin, control) in accounts {
+        let (_, maintenance, _) =
+            margin_components(margin, control, state, cache)?.fractions(0)?;
+        if maintenance {
+            continue;
+        }
+
+        count += 1;
+        if let Ok(estimate) =
+            LiquidationEstimate::for_account(margin, control, state, cache)
+        {
+            bonus = bonus.saturating_add(estimate.bonus);
+        }
+    }
+
+    Ok((count, bonus))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::liquidator::fixtures::*;
+
+    #[test]
+    fn net_estimate_takes_off_base_and_priority_fees() {
+        // Half a smol USD per lamport keeps the conversions exact.
+        let mut book = Book::new();
+        book.oracle("SOL", 0.5);
+
+        let estimate = LiquidationEstimate::new(100_000_000, 10, 200_000);
+        assert_eq!(estimate.bonus, 1_000_000);
+
+        let fees = PriorityFees {
+            micro_lamports: Some(1_000_000),
+            compute_unit_limit: None,
+            escalation: 2.0,
+        };
+        assert_eq!(estimate.net(&fees, 0, &book.cache), 1_000_000 - 102_500);
+        assert_eq!(estimate.net(&fees, 1, &book.cache), 1_000_000 - 202_500);
+    }
+
+    #[test]
+    fn keeper_pnl_sums_bonuses_less_fees_in_the_window() {
+        let mut book = Book::new();
+        book.oracle("SOL", 0.5);
+        let window = Duration::from_millis(50);
+
+        record_liquidation(1_000_000, 10_000, &book.cache);
+        std::thread::sleep(window * 2);
+        record_liquidation(2_000_000, 20_000, &book.cache);
+        // Fees can outweigh a small bonus.
+        record_liquidation(1_000, 100_000, &book.cache);
+
+        assert_eq!(keeper_pnl(window), 1_990_000 - 49_000);
+        assert_eq!(recent_sends(window), 2);
+        assert_eq!(recent_fees_paid(window), 120_000);
+        assert_eq!(keeper_pnl(window * 10), 995_000 + 1_990_000 - 49_000);
+    }
+
+    #[test]
+    fn overridden_params_change_liquidation_economics() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let sol = book.collateral("SOL", 100.0, 1000);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+
+        // Both hold a long needing 50_000 at maintenance, the first
+        // with too little collateral, the second with 60_000 of SOL.
+        let (mut under, mut under_control) = account();
+        set_balance(&mut under, usd, 40_000);
+        set_position(&mut under_control, btc, 1_000, 1000.0);
+        let (mut sol_backed, mut sol_control) = account();
+        set_balance(&mut sol_backed, sol, 600);
+        set_position(&mut sol_control, btc, 1_000, 1000.0);
+        let accounts = [(&under, &under_control), (&sol_backed, &sol_control)];
+
+        let overrides = ParamOverrides {
+            perp_liq_fees: HashMap::from([("BTC-PERP".to_string(), 20)]),
+            collateral_weights: HashMap::from([("SOL".to_string(), 800)]),
+            ..ParamOverrides::default()
+        };
+        let overridden = overrides.apply(&book.state);
+        assert_eq!(book.state.perp_markets[btc].liq_fee, 10);
+        assert_eq!(book.state.collaterals[sol].weight, 1000);
+
+        let bonus = |state: &State| {
+            LiquidationEstimate::for_account(
+                &under,
+                &under_control,
+                state,
+                &book.cache,
+            )
+            .unwrap()
+            .bonus
+        };
+        let (original, raised) = (bonus(&book.state), bonus(&overridden));
+        assert!(original > 0 && raised > original, "{} {}", original, raised);
+
+        // A lower SOL weight also puts the second account under.
+        let (count, total) =
+            liquidation_outlook(accounts, &book.state, &book.cache).unwrap();
+        assert_eq!((count, total), (1, original));
+        let (count, total) =
+            liquidation_outlook(accounts, &overridden, &book.cache).unwrap();
+        assert_eq!(count, 2);
+        assert!(total > raised);
+    }
+
+    #[test]
+    fn fee_runway_projects_the_recent_burn_rate() {
+        let hour = Duration::from_secs(3600);
+
+        // 0.01 SOL an hour out of 1 SOL lasts 100 hours.
+        let runway = estimate_fee_runway(100, 10_000_000, 1_000_000_000, hour);
+        assert_eq!(runway, hour * 100);
+
+        // Sends cost at least the signature fee, whatever was recorded.
+        let runway = estimate_fee_runway(10, 0, 500_000, hour);
+        assert_eq!(runway, hour * 10);
+
+        assert_eq!(estimate_fee_runway(0, 0, 1_000, hour), Duration::MAX);
+    }
+
+    #[test]
+    fn liquidations_are_gated_at_the_required_fee() {
+        let mut book = Book::new();
+        book.oracle("SOL", 0.5);
+        let fees = PriorityFees {
+            micro_lamports: Some(1_000_000),
+            compute_unit_limit: None,
+            escalation: 2.0,
+        };
+        let estimate = LiquidationEstimate::new(30_000_000, 10, 200_000);
+        assert_eq!(estimate.bonus, 300_000);
+        let net = |attempt| estimate.net(&fees, attempt, &book.cache);
+
+        // A quiet network lands the first attempt, at 5_000 + 200_000
+        // lamports in fees.
+        assert_eq!(net(0), 300_000 - 102_500);
+        assert_eq!(net(1), 300_000 - 202_500);
+        // Competition pushes the required fee past the bonus.
+        assert_eq!(net(2), 300_000 - 402_500);
+    }
+
+    #[test]
+    fn inventory_skew_follows_the_keepers_positions() {
+        let mut book = Book::new();
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+        let eth = book.market("ETH-PERP", 100.0, 50);
+        let sol = book.market("SOL-PERP", 10.0, 100);
+
+        let (_, mut keeper) = account();
+        set_position(&mut keeper, btc, 10, 900.0);
+        set_position(&mut keeper, eth, -300, 110.0);
+        // Flat markets have nothing to rebalance.
+        set_position(&mut keeper, sol, 0, 10.0);
+
+        assert_eq!(
+            inventory_skew(&keeper, &book.cache),
+            [(eth, -30_000), (btc, 10_000)]
+        );
+    }
+
+    #[test]
+    fn funding_eats_the_bonus_at_the_breakeven_hold() {
+        let hour = Duration::from_secs(3600);
+
+        // A long paying 0.1% an hour on 1_000_000 pays 1_000 an hour.
+        assert_eq!(funding_breakeven_hold(1_000_000, 10_000, 0.001), hour * 10);
+        // Shorts pay when the rate is negative.
+        assert_eq!(
+            funding_breakeven_hold(-1_000_000, 10_000, -0.001),
+            hour * 10
+        );
+
+        // Positions earning funding can be held indefinitely.
+        assert_eq!(
+            funding_breakeven_hold(-1_000_000, 10_000, 0.001),
+            Duration::MAX
+        );
+        assert_eq!(
+            funding_breakeven_hold(1_000_000, 10_000, 0.0),
+            Duration::MAX
+        );
+        assert_eq!(funding_breakeven_hold(1, 10_000, 1e-300), Duration::MAX);
+
+        // Without a bonus any funding paid is a loss.
+        assert_eq!(funding_breakeven_hold(1_000_000, 0, 0.001), Duration::ZERO);
+    }
+}
@@ -0,0 +1,305 @@
+/*
+ * This file renders the margin analysis of a single account as JSON,
+ * for consumption by external tooling. Field names are part of the
+ * output format and must stay stable.
+*/
+use anchor_lang::prelude::Pubkey;
+
+use fixed::types::I80F48;
+
+use serde_json::{json, Value};
+
+use std::{cell::RefCell, collections::HashMap};
+
+use zo_abi::{Cache, Control, Margin, State, WrappedI80F48};
+
+use crate::liquidator::{
+    error::ErrorCode, margin_utils::*, math::*, utils::*,
+};
+
+/// Returns the account's analysis in the following shape. Amounts are
+/// in smol units, prices in smol USD per smol asset.
+///
+/// ```text
+/// {
+///   "authority": string,
+///   "control": string,
+///   "total_collateral": number,      // weighted, smol USD
+///   "effective_usd_collateral": number, // with realized PnL, smol USD
+///   "margin_ratio": number | null,   // approximate, null if no exposure
+///   "adverse_move_tolerance": {      // by oracle symbol, relative move
+///     string: number | null          // to maintenance, null if none
+///   },
+///   "recovery_distance": number | null, // relative mark move back above
+///                                    // maintenance, 0 if above, null if
+///                                    // none within 100%
+///   "oracle_concentration": {        // by oracle symbol, share of the
+///     string: number                 // collateral value it prices
+///   },
+///   "fractions": {
+///     "initial": bool,               // true if the fraction is met
+///     "maintenance": bool,
+///     "cancel": bool
+///   },
+///   "balances": [{
+///     "index": number,
+///     "symbol": string,
+///     "amount": number,              // after interest, smol asset
+///     "price": number,
+///     "value": number,               // smol USD
+///     "weighted_value": number,      // smol USD
+///     "haircut": number              // smol USD lost to weighting
+///   }],
+///   "positions": [{
+///     "index": number,
+///     "symbol": string,
+///     "pos_size": number,            // smol asset
+///     "native_pc_total": number,     // smol USD
+///     "avg_entry_price": number | null, // smol USD per asset, null if flat
+///     "realized_pnl": number,        // smol USD
+///     "unrealized_funding": number,  // smol USD
+///     "mark_price": number,
+///     "notional": number,            // smol USD
+///     "coin_on_bids": number,
+///     "coin_on_asks": number,
+///     "max_leverage": number | null, // of the market, null if unbounded
+///     "liquidation_price": number | null // mark at maintenance, others
+///                                    // kept, null if flat
+///   }]
+/// }
+/// ```
+pub fn account_report_json(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+) -> Result<Value, ErrorCode> {
+    let total_collateral =
+        checked_to_i64(get_total_collateral(margin, cache, state)?)?;
+
+    let (initial, maintenance, cancel) = check_all_fractions(
+        total_collateral,
+        state.total_markets as usize,
+        state.total_collaterals as usize,
+        &control.open_orders_agg,
+        &state.perp_markets,
+        &state.collaterals,
+        &{ margin.collateral },
+        &RefCell::new(*cache).borrow(),
+        0,
+    )?;
+
+    let effective_usd =
+        effective_usd_collateral(margin, control, cache, state)?;
+    let ratio = approx_margin_ratio(margin, control, state, cache);
+    let tolerances: serde_json::Map<String, Value> =
+        adverse_move_tolerance(margin, control, state, cache)
+            .into_iter()
+            .map(|(symbol, x)| {
+                let symbol: String = symbol.into();
+                let x = if x.is_finite() { Some(x) } else { None };
+                (symbol, json!(x))
+            })
+            .collect();
+    let recovery = recovery_distance(margin, control, state, cache)?;
+    let concentration: serde_json::Map<String, Value> =
+        oracle_concentration(margin, state, cache)?
+            .into_iter()
+            .map(|(symbol, share)| (symbol.into(), json!(share)))
+            .collect();
+    let haircuts: HashMap<usize, I80F48> = collateral_haircuts(
+        margin,
+        &RefCell::new(*state).borrow(),
+        &RefCell::new(*cache).borrow(),
+    )?
+    .into_iter()
+    .collect();
+
+    let mut balances = Vec::new();
+    for (i, &coll) in { margin.collateral }.iter().enumerate() {
+        if i >= state.total_collaterals as usize {
+            break;
+        }
+        let info = &state.collaterals[i];
+        if info.is_empty() || coll == WrappedI80F48::zero() {
+            continue;
+        }
+
+        let borrow = &cache.borrow_cache[i];
+        let amount = get_actual_collateral(
+            i,
+            margin,
+            borrow.supply_multiplier.into(),
+            borrow.borrow_multiplier.into(),
+        )?;
+        let price: I80F48 = match get_oracle(cache, &info.oracle_symbol) {
+            Some(o) => o.price.into(),
+            None => continue,
+        };
+        let value = safe_mul_i80f48(amount, price);
+        let weighted_value = match value.is_positive() {
+            true => safe_mul_i80f48(
+                value,
+                I80F48::from_num(info.weight as f64 / 1000.0),
+            ),
+            false => value,
+        };
+        let symbol: String = info.oracle_symbol.into();
+
+        balances.push(json!({
+            "index": i,
+            "symbol": symbol,
+            "amount": amount.to_num::<f64>(),
+            "price": price.to_num::<f64>(),
+            "value": value.to_num::<f64>(),
+            "weighted_value": weighted_value.to_num::<f64>(),
+            "haircut": haircuts
+                .get(&i)
+                .map_or(0.0, |h| h.to_num::<f64>()),
+        }));
+    }
+
+    let mut positions = Vec::new();
+    let funding_cache = { cache.funding_cache };
+    for (i, oo_info) in control.open_orders_agg.iter().enumerate() {
+        if i >= state.total_markets as usize {
+            break;
+        }
+        if oo_info.key == Pubkey::default() {
+            continue;
+        }
+
+        let market = &state.perp_markets[i];
+        let mark: I80F48 = cache.marks[i].price.into();
+        let pos_size = oo_info.pos_size;
+        let native_pc_total = oo_info.native_pc_total;
+        let realized_pnl = oo_info.realized_pnl;
+        let coin_on_bids = oo_info.coin_on_bids;
+        let coin_on_asks = oo_info.coin_on_asks;
+        let notional = safe_mul_i80f48(I80F48::from_num(pos_size), mark);
+        let unrealized_funding = calc_unrealized_funding(
+            pos_size,
+            oo_info.funding_index,
+            funding_cache[i],
+            market.asset_decimals as u32,
+        )?;
+        let avg_entry = avg_entry_price(oo_info, market.asset_decimals as u32)
+            .map(|p| p.to_num::<f64>());
+        let symbol: String = market.symbol.into();
+        let leverage = max_leverage(market.base_imf);
+        let liq_price = match pos_size {
+            0 => None,
+            _ => Some(
+                liquidation_price(margin, control, state, cache, i)?
+                    .to_num::<f64>(),
+            ),
+        };
+
+        positions.push(json!({
+            "index": i,
+            "symbol": symbol,
+            "pos_size": pos_size,
+            "native_pc_total": native_pc_total,
+            "avg_entry_price": avg_entry,
+            "realized_pnl": realized_pnl,
+            "unrealized_funding": unrealized_funding,
+            "mark_price": mark.to_num::<f64>(),
+            "notional": notional.to_num::<f64>(),
+            "coin_on_bids": coin_on_bids,
+            "coin_on_asks": coin_on_asks,
+            "max_leverage": if leverage.is_finite() {
+                Some(leverage)
+            } else {
+                None
+            },
+            "liquidation_price": liq_price,
+        }));
+    }
+
+    Ok(json!({
+        "authority": margin.authority.to_string(),
+        "control": margin.control.to_string(),
+        "total_collateral": total_collateral,
+        "effective_usd_collateral": effective_usd.to_num::<f64>(),
+        "margin_ratio": if ratio.is_finite() { Some(ratio) } else { None },
+        "adverse_move_tolerance": tolerances,
+        "recovery_distance": if recovery.is_finite() {
+            Some(recovery)
+        } else {
+            None
+        },
+        "oracle_concentration": concentration,
+        "fractions": {
+            "initial": initial,
+            "maintenance": maintenance,
+            "cancel": cancel,
+        },
+        "balances": balances,
+        "positions": positions,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::liquidator::fixtures::*;
+
+    #[test]
+    fn report_has_the_documented_shape() {
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let sol = book.collateral("SOL", 100.0, 900);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+
+        let (mut margin, mut control) = account();
+        set_balance(&mut margin, usd, 1_000_000);
+        set_balance(&mut margin, sol, 10_000);
+        set_position(&mut control, btc, 10_000, 1000.0);
+
+        let report =
+            account_report_json(&margin, &control, &book.state, &book.cache)
+                .unwrap();
+        assert_eq!(report["authority"], margin.authority.to_string());
+        assert_eq!(report["control"], margin.control.to_string());
+        assert!(report["total_collateral"].is_i64());
+        assert_eq!(report["effective_usd_collateral"], 1_000_000.0);
+        assert!(report["margin_ratio"].is_f64());
+        assert!(report["adverse_move_tolerance"]["BTC"].is_f64());
+        assert!(report["adverse_move_tolerance"]["USDC"].is_null());
+        assert_eq!(report["recovery_distance"], 0.0);
+        assert_eq!(report["oracle_concentration"]["USDC"], 0.5);
+        assert_eq!(report["oracle_concentration"]["SOL"], 0.5);
+        for fraction in ["initial", "maintenance", "cancel"] {
+            assert!(report["fractions"][fraction].is_boolean());
+        }
+
+        let balances = report["balances"].as_array().unwrap();
+        assert_eq!(balances.len(), 2);
+        assert_eq!(balances[1]["symbol"], "SOL");
+        assert_eq!(balances[1]["index"], sol);
+        for key in ["amount", "price", "value", "weighted_value", "haircut"] {
+            assert!(balances[1][key].is_f64(), "{}", key);
+        }
+        let haircut = balances[1]["haircut"].as_f64().unwrap();
+        assert!((haircut - 100_000.0).abs() < 1.0, "{}", haircut);
+
+        let positions = report["positions"].as_array().unwrap();
+        assert_eq!(positions.len(), 1);
+        let position = &positions[0];
+        assert_eq!(position["symbol"], "BTC-PERP");
+        assert_eq!(position["pos_size"], 10_000);
+        assert_eq!(position["native_pc_total"], -10_000_000);
+        assert_eq!(position["notional"], 10_000_000.0);
+        assert_eq!(position["avg_entry_price"], 1_000_000_000.0);
+        assert_eq!(position["max_leverage"], 10.0);
+        assert!(position["liquidation_price"].is_f64());
+        for key in [
+            "realized_pnl",
+            "unrealized_funding",
+            "coin_on_bids",
+            "coin_on_asks",
+        ] {
+            assert!(position[key].is_i64(), "{}", key);
+        }
+    }
+}
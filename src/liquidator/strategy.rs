@@ -0,0 +1,229 @@
+/*
+ * This file decides the order in which liquidatable accounts are acted
+ * on. Operators can supply their own strategy through the config, e.g.
+ * to go after the most profitable accounts first.
+*/
+use solana_sdk::pubkey::Pubkey;
+
+use tracing::warn;
+
+use zo_abi::{Cache, Control, Margin, State};
+
+use crate::liquidator::{
+    error::ErrorCode, margin_utils::margin_components, pnl,
+};
+
+/// Margin state of an account, in smol USD. The requirement is scaled
+/// by 1000 as in the fraction checks.
+#[derive(Clone, Copy, Debug)]
+pub struct MarginHealth {
+    pub acc_value: i64,
+    pub maint_requirement: i64,
+    /// Bonus expected from liquidating the account, before fees, zero
+    /// if it can't be estimated. See `pnl::LiquidationEstimate`.
+    pub expected_reward: i64,
+    /// Initial margin the keeper needs for the liquidation, zero if it
+    /// can't be estimated.
+    pub expected_margin: i64,
+    /// Compute units of the liquidation, zero if it can't be estimated.
+    pub expected_cu: u64,
+}
+
+impl MarginHealth {
+    pub fn new(
+        margin: &Margin,
+        control: &Control,
+        state: &State,
+        cache: &Cache,
+    ) -> Result<Self, ErrorCode> {
+        let c = margin_components(margin, control, state, cache)?;
+        let estimate = pnl::LiquidationEstimate::for_account(
+            margin, control, state, cache,
+        );
+        Ok(Self {
+            acc_value: c.total_acc_value,
+            maint_requirement: c.maint_requirement()?,
+            expected_reward: estimate.as_ref().map_or(0, |e| e.bonus),
+            expected_margin: estimate.as_ref().map_or(0, |e| e.margin),
+            expected_cu: estimate.as_ref().map_or(0, |e| e.cu),
+        })
+    }
+
+    /// How far the account is below maintenance, in smol USD.
+    pub fn shortfall(&self) -> i64 {
+        (self.maint_requirement / 1000).saturating_sub(self.acc_value)
+    }
+
+    /// Expected reward per compute unit, zero if either can't be
+    /// estimated.
+    pub fn reward_per_cu(&self) -> f64 {
+        match self.expected_cu {
+            0 => 0.0,
+            cu => self.expected_reward as f64 / cu as f64,
+        }
+    }
+}
+
+/// An account and the protocol state it was read with, at one slot.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub struct AccountSnapshot {
+    pub slot: u64,
+    pub margin: Margin,
+    pub control: Control,
+    pub state: State,
+    pub cache: Cache,
+}
+
+/// The account's health at each snapshot, in slot order, e.g. to see
+/// how it came to be liquidated. Snapshots whose health can't be
+/// computed are left out.
+#[allow(dead_code)]
+pub fn reconstruct_history(
+    snapshots: impl IntoIterator<Item = AccountSnapshot>,
+) -> Vec<(u64, MarginHealth)> {
+    let mut history: Vec<(u64, MarginHealth)> = snapshots
+        .into_iter()
+        .filter_map(|s| {
+            match MarginHealth::new(&s.margin, &s.control, &s.state, &s.cache) {
+                Ok(health) => Some((s.slot, health)),
+                Err(e) => {
+                    warn!("Skipping snapshot at slot {}: {:?}", s.slot, e);
+                    None
+                }
+            }
+        })
+        .collect();
+    history.sort_by_key(|(slot, _)| *slot);
+    history
+}
+
+pub trait LiquidationStrategy: Send + Sync {
+    /// Returns the accounts to liquidate, in the order they should be
+    /// sent. Accounts left out are not liquidated this scan.
+    fn plan(&self, candidates: &[(Pubkey, MarginHealth)]) -> Vec<Pubkey>;
+}
+
+/// Liquidates the accounts furthest below maintenance first.
+pub struct LargestRiskFirst;
+
+impl LiquidationStrategy for LargestRiskFirst {
+    fn plan(&self, candidates: &[(Pubkey, MarginHealth)]) -> Vec<Pubkey> {
+        let mut candidates = candidates.to_vec();
+        candidates.sort_by_key(|(_, h)| std::cmp::Reverse(h.shortfall()));
+        candidates.into_iter().map(|(k, _)| k).collect()
+    }
+}
+
+/// Liquidates the accounts with the largest expected reward first,
+/// breaking ties by how far they are below maintenance.
+pub struct MostProfitableFirst;
+
+impl LiquidationStrategy for MostProfitableFirst {
+    fn plan(&self, candidates: &[(Pubkey, MarginHealth)]) -> Vec<Pubkey> {
+        let mut candidates = candidates.to_vec();
+        candidates.sort_by_key(|(_, h)| {
+            (
+                std::cmp::Reverse(h.expected_reward),
+                std::cmp::Reverse(h.shortfall()),
+            )
+        });
+        candidates.into_iter().map(|(k, _)| k).collect()
+    }
+}
+
+/// Liquidates the accounts with the largest expected reward per compute
+/// unit first, for when the compute budget can't fit all of them. Ties
+/// are broken by how far accounts are below maintenance.
+pub struct MostProfitablePerCuFirst;
+
+impl LiquidationStrategy for MostProfitablePerCuFirst {
+    fn plan(&self, candidates: &[(Pubkey, MarginHealth)]) -> Vec<Pubkey> {
+        let mut candidates = candidates.to_vec();
+        candidates.sort_by(|(_, a), (_, b)| {
+            b.reward_per_cu()
+                .total_cmp(&a.reward_per_cu())
+                .then(b.shortfall().cmp(&a.shortfall()))
+        });
+        candidates.into_iter().map(|(k, _)| k).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn health(reward: i64, cu: u64, shortfall: i64) -> MarginHealth {
+        MarginHealth {
+            acc_value: 0,
+            maint_requirement: shortfall * 1000,
+            expected_reward: reward,
+            expected_margin: 0,
+            expected_cu: cu,
+        }
+    }
+
+    #[test]
+    fn cheap_profitable_liquidations_are_ranked_first() {
+        let keys: Vec<Pubkey> = (0..4).map(|_| Pubkey::new_unique()).collect();
+        let candidates = [
+            (keys[0], health(1_000, 200_000, 10)), // most reward, costly
+            (keys[1], health(600, 50_000, 10)),    // most reward per cu
+            (keys[2], health(100, 0, 50)),         // can't be estimated
+            (keys[3], health(600, 50_000, 20)),    // same, further under
+        ];
+
+        assert_eq!(
+            MostProfitablePerCuFirst.plan(&candidates),
+            [keys[3], keys[1], keys[0], keys[2]]
+        );
+        assert_eq!(
+            MostProfitableFirst.plan(&candidates),
+            [keys[0], keys[3], keys[1], keys[2]]
+        );
+    }
+
+    #[test]
+    fn history_is_rebuilt_in_slot_order() {
+        use crate::liquidator::fixtures::*;
+        use fixed::types::I80F48;
+
+        let mut book = Book::new();
+        let usd = book.collateral("USDC", 1.0, 1000);
+        let sol = book.collateral("SOL", 100.0, 900);
+        let btc = book.market("BTC-PERP", 1000.0, 100);
+
+        let (mut margin, mut control) = account();
+        set_balance(&mut margin, usd, 10_000);
+        set_position(&mut control, btc, 10, 1000.0);
+        let mut borrowed = margin;
+        set_balance(&mut borrowed, sol, -5);
+
+        // The mark falls by 100 later on.
+        let priced = book.cache;
+        let mut fallen = book.cache;
+        let mut marks = fallen.marks;
+        marks[btc].price = I80F48::from_num(900.0).into();
+        fallen.marks = marks;
+
+        // In between, a borrow with no price can't be assessed.
+        book.oracle("SOL", 0.0);
+        let unpriced = book.cache;
+
+        let snapshot = |slot, margin, cache| AccountSnapshot {
+            slot,
+            margin,
+            control,
+            state: book.state,
+            cache,
+        };
+        let history = reconstruct_history([
+            snapshot(30, margin, fallen),
+            snapshot(20, borrowed, unpriced),
+            snapshot(10, margin, priced),
+        ]);
+        let slots: Vec<u64> = history.iter().map(|(slot, _)| *slot).collect();
+        assert_eq!(slots, [10, 30]);
+        assert_eq!(history[0].1.acc_value - history[1].1.acc_value, 1_000);
+    }
+}
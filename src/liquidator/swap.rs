@@ -31,7 +31,11 @@ use zo_abi::{
     OrderType, State,
 };
 
-use crate::liquidator::{error::ErrorCode, math::SafeOp, utils::*};
+use crate::liquidator::{
+    error::ErrorCode,
+    math::{from_display, SafeOp},
+    utils::*,
+};
 
 #[deprecated]
 #[allow(dead_code)]
@@ -47,6 +51,7 @@ pub fn swap_asset(
     serum_dex_program: &Pubkey,
     serum_vault_signer: &Pubkey,
     asset_index: usize,
+    retry_policy: &RetryPolicy,
 ) -> Result<(), ErrorCode> {
     let span = error_span!("swap_asset", asset = asset_index);
 
@@ -57,7 +62,7 @@ pub fn swap_asset(
 
     let client = program.rpc();
 
-    let margin_account = client.get_account(payer_margin).unwrap();
+    let margin_account = client.get_account(payer_margin)?;
     let col_index = 41 + asset_index * 16;
     let collateral: [u8; 16] = margin_account.data[col_index..col_index + 16]
         .to_vec()
@@ -68,7 +73,7 @@ pub fn swap_asset(
     let buy = collateral_amount.is_negative();
     let swap_amount: u64 = if buy {
         let asks_key = array_to_pubkey(&{ serum_market.asks });
-        let mut asks_account = client.get_account(&asks_key).unwrap();
+        let mut asks_account = client.get_account(&asks_key)?;
         let asks_info = get_account_info(&asks_key, &mut asks_account);
         let asks: RefMut<Slab> = match serum_market.load_asks_mut(&asks_info) {
             Ok(asks) => asks,
@@ -117,8 +122,9 @@ pub fn swap_asset(
         999_999_999_999_999u64
     };
 
-    if swap_amount <= 50 * 1000000 {
-        // 50 USDC
+    // 50 USDC
+    let min_swap = from_display(50.0, state.collaterals[0].decimals as u32);
+    if swap_amount <= min_swap as u64 {
         span.in_scope(|| warn!("No coins to swap for asset {}", asset_index));
         return Ok(());
     }
@@ -169,11 +175,11 @@ pub fn swap_asset(
                 })
                 .options(CommitmentConfig::confirmed())
         },
-        5,
+        retry_policy,
     );
 
     match result {
-        Ok(_tx) => Ok(()),
+        Ok(_tx) | Err(ErrorCode::AlreadyLanded(_tx)) => Ok(()),
         Err(e) => {
             span.in_scope(|| error!("Failed to swap asset {:?}", e));
             Err(ErrorCode::SwapError)
@@ -260,6 +266,7 @@ pub fn close_position(
     dex_market: &MarketState,
     dex_program: &Pubkey,
     index: usize,
+    retry_policy: &RetryPolicy,
 ) -> Result<(), ErrorCode> {
     // Pick the right market and place a market order to close the position you received from liquidating someone.
     // Need to know the amount to close
@@ -315,7 +322,7 @@ pub fn close_position(
                     })
                     .options(CommitmentConfig::confirmed())
             },
-            5,
+            retry_policy,
         )
     } else {
         // Long order
@@ -352,12 +359,12 @@ pub fn close_position(
                     })
                     .options(CommitmentConfig::confirmed())
             },
-            5,
+            retry_policy,
         )
     };
 
     match result {
-        Ok(tx) => {
+        Ok(tx) | Err(ErrorCode::AlreadyLanded(tx)) => {
             span.in_scope(|| {
                 info!("Successfully placed order to close position {:?}", tx)
             });
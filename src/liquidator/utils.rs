@@ -3,30 +3,44 @@ use anchor_lang::{
     Owner, ZeroCopy,
 };
 
-use anchor_client::{ClientError::SolanaClientError, RequestBuilder};
+use anchor_client::{ClientError::SolanaClientError, Program, RequestBuilder};
 
-use solana_account_decoder::UiAccountEncoding;
+use rayon::prelude::*;
+
+use solana_account_decoder::{UiAccountEncoding, UiDataSliceConfig};
 use solana_client::{
     client_error::{ClientError, ClientErrorKind},
     rpc_client::RpcClient,
-    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
-    rpc_filter::{Memcmp, MemcmpEncodedBytes, RpcFilterType},
+    rpc_config::{
+        RpcAccountInfoConfig, RpcBlockConfig, RpcProgramAccountsConfig,
+    },
     rpc_request::{RpcError, RpcResponseErrorData},
 };
 use solana_sdk::{
     account::Account, commitment_config::CommitmentConfig, pubkey::Pubkey,
     signature::Signature,
-    transaction::TransactionError,
-    instruction::InstructionError
+    transaction::{Transaction, TransactionError},
+    instruction::{Instruction, InstructionError},
 };
 
-use std::ops::Deref;
+use solana_transaction_status::TransactionDetails;
 
-use tracing::{error, warn};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, VecDeque},
+    hash::{Hash, Hasher},
+    ops::Deref,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use zo_abi::{Cache, OpenOrdersInfo, OracleCache, Symbol, MAX_MARKETS};
+use tracing::{debug, error, warn};
 
-use crate::liquidator::error::ErrorCode;
+use zo_abi::{
+    Cache, Margin, OpenOrdersInfo, OracleCache, State, Symbol, WrappedI80F48,
+    MAX_COLLATERALS, MAX_MARKETS,
+};
+
+use crate::liquidator::{error::ErrorCode, metrics};
 
 pub fn get_account_info<'a>(
     key: &'a Pubkey,
@@ -41,55 +55,423 @@ pub fn get_account_info<'a>(
     level = "error",
     fields(key = %key, ty = %std::any::type_name::<T>())
 )]
-pub fn get_type_from_account<T>(key: &Pubkey, account: &mut Account) -> T
+pub fn get_type_from_account<T>(
+    key: &Pubkey,
+    account: &mut Account,
+) -> Result<T, ErrorCode>
 where
     T: ZeroCopy + Owner,
 {
     let account_info: AccountInfo<'_> = get_account_info(key, account);
-    let loader: AccountLoader<'_, T> =
-        AccountLoader::try_from(&account_info).unwrap();
-    let value = loader.load();
-    match value {
-        Ok(x) => *x.deref(),
-        Err(e) => {
-            error!("Failed to get type from {}: {:?}.", key, e);
-            panic!()
+    AccountLoader::<'_, T>::try_from(&account_info)
+        .and_then(|loader| loader.load().map(|x| *x.deref()))
+        .map_err(|e| {
+            debug!("Failed to get type from {}: {:?}.", key, e);
+            ErrorCode::AccountDeserializeFailure(*key)
+        })
+}
+
+/// Loads each fetched account, skipping those that fail to deserialize,
+/// e.g. accounts still in an old layout during a program upgrade.
+fn load_fetched_accounts<T>(
+    accounts: Vec<(Pubkey, Account)>,
+) -> Vec<(Pubkey, T)>
+where
+    T: ZeroCopy + Owner + Send,
+{
+    // Each account is loaded on its own, so large sets are spread
+    // over all cores.
+    accounts
+        .into_par_iter()
+        .filter_map(|(k, mut a)| match get_type_from_account::<T>(&k, &mut a) {
+            Ok(x) => Some((k, x)),
+            Err(_) => {
+                warn!("Skipping {} which failed to deserialize", k);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Fails if the program has more accounts of type `T` than `max_len`.
+/// They are counted before they are fetched, and only if there is a
+/// limit.
+fn check_account_set_len<T>(
+    client: &RpcClient,
+    program_address: &Pubkey,
+    commitment: CommitmentConfig,
+    max_len: Option<usize>,
+) -> Result<(), ErrorCode>
+where
+    T: ZeroCopy + Owner + 'static,
+{
+    let max = match max_len {
+        Some(max) => max,
+        None => return Ok(()),
+    };
+    match crate::utils::exceeded_account_set_len::<T>(
+        client,
+        program_address,
+        commitment,
+        max,
+    )? {
+        Some((len, max)) => {
+            error!("Found {} accounts, more than the limit of {}", len, max);
+            Err(ErrorCode::ResultSetTooLarge(len, max))
         }
+        None => Ok(()),
     }
 }
 
 pub fn load_program_accounts<T>(
     client: &RpcClient,
+    sets: &crate::AccountSetCache,
     program_address: &Pubkey,
+    commitment: CommitmentConfig,
+    max_len: Option<usize>,
 ) -> Result<Vec<(Pubkey, T)>, ErrorCode>
 where
-    T: ZeroCopy + Owner,
+    T: ZeroCopy + Owner + Send + Sync + 'static,
 {
     let config = RpcProgramAccountsConfig {
-        filters: Some(vec![
-            RpcFilterType::DataSize((8 + std::mem::size_of::<T>()) as u64),
-            RpcFilterType::Memcmp(Memcmp {
-                offset: 0,
-                bytes: MemcmpEncodedBytes::Bytes(T::discriminator().into()),
-                encoding: None,
-            }),
-        ]),
+        filters: Some(crate::utils::account_filters::<T>()),
         account_config: RpcAccountInfoConfig {
             encoding: Some(UiAccountEncoding::Base64),
             data_slice: None,
-            commitment: Some(CommitmentConfig::finalized()),
+            commitment: Some(commitment),
         },
         with_context: Some(false),
     };
 
-    Ok(client
-        .get_program_accounts_with_config(program_address, config)
-        .map(|v| {
-            v.into_iter()
-                .map(|(k, mut a)| (k, get_type_from_account::<T>(&k, &mut a)))
-                .collect()
+    sets.get_or_fetch(program_address, commitment, || {
+        check_account_set_len::<T>(
+            client,
+            program_address,
+            commitment,
+            max_len,
+        )?;
+        let accounts =
+            client.get_program_accounts_with_config(program_address, config)?;
+        Ok(load_fetched_accounts::<T>(accounts))
+    })
+}
+
+/// Leading fields of a margin account, enough to shard margins between
+/// workers without fetching and loading whole accounts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MarginHeader {
+    pub control: Pubkey,
+}
+
+impl MarginHeader {
+    fn offset() -> usize {
+        let margin: Margin = bytemuck::Zeroable::zeroed();
+        let base = std::ptr::addr_of!(margin) as usize;
+        // Skip the anchor discriminator.
+        8 + std::ptr::addr_of!(margin.control) as usize - base
+    }
+
+    /// Number of leading account bytes the header is read from.
+    pub fn len() -> usize {
+        Self::offset() + 32
+    }
+
+    /// Reads the header from the leading bytes of a margin account,
+    /// discriminator included.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        let control = Self::offset();
+        if data.len() < Self::len() {
+            return None;
+        }
+        Some(Self {
+            control: Pubkey::new(&data[control..control + 32]),
         })
-        .unwrap())
+    }
+}
+
+/// Fetches the headers of all margin accounts, requesting only the
+/// bytes they are read from.
+pub fn load_margin_headers(
+    client: &RpcClient,
+    program_address: &Pubkey,
+    commitment: CommitmentConfig,
+    max_len: Option<usize>,
+) -> Result<Vec<(Pubkey, MarginHeader)>, ErrorCode> {
+    let config = RpcProgramAccountsConfig {
+        filters: Some(crate::utils::account_filters::<Margin>()),
+        account_config: RpcAccountInfoConfig {
+            encoding: Some(UiAccountEncoding::Base64),
+            data_slice: Some(UiDataSliceConfig {
+                offset: 0,
+                length: MarginHeader::len(),
+            }),
+            commitment: Some(commitment),
+        },
+        with_context: Some(false),
+    };
+
+    check_account_set_len::<Margin>(
+        client,
+        program_address,
+        commitment,
+        max_len,
+    )?;
+    let accounts =
+        client.get_program_accounts_with_config(program_address, config)?;
+
+    Ok(accounts
+        .into_iter()
+        .filter_map(|(k, a)| MarginHeader::from_bytes(&a.data).map(|h| (k, h)))
+        .collect())
+}
+
+/// Fetches and loads the given accounts, in batches of the most
+/// `getMultipleAccounts` accepts. Accounts that no longer exist are
+/// left out.
+pub fn load_accounts<T>(
+    client: &RpcClient,
+    keys: &[Pubkey],
+) -> Result<Vec<(Pubkey, T)>, ErrorCode>
+where
+    T: ZeroCopy + Owner + Send,
+{
+    let mut accounts = Vec::with_capacity(keys.len());
+    for chunk in keys.chunks(100) {
+        let fetched = fetch_multiple_accounts(client, chunk)?;
+        accounts.extend(load_fetched_accounts::<T>(
+            chunk
+                .iter()
+                .zip(fetched)
+                .filter_map(|(k, a)| Some((*k, a?)))
+                .collect(),
+        ));
+    }
+    Ok(accounts)
+}
+
+/// Attempts at fetching a batch that return nothing new before giving up.
+const MAX_FETCH_ATTEMPTS: usize = 3;
+
+/// Fetches `keys` with `getMultipleAccounts`, one result per key in the
+/// same order. Some providers cut responses short or fail partway, so
+/// the keys past a short response are requested again.
+fn fetch_multiple_accounts(
+    client: &RpcClient,
+    keys: &[Pubkey],
+) -> Result<Vec<Option<Account>>, ErrorCode> {
+    refetch_missing(keys, |missing| client.get_multiple_accounts(missing))
+}
+
+/// Calls `fetch` on the keys it hasn't returned accounts for yet, until
+/// all are fetched or it stops making progress.
+fn refetch_missing<F>(
+    keys: &[Pubkey],
+    mut fetch: F,
+) -> Result<Vec<Option<Account>>, ErrorCode>
+where
+    F: FnMut(&[Pubkey]) -> Result<Vec<Option<Account>>, ClientError>,
+{
+    let mut fetched = Vec::with_capacity(keys.len());
+    let mut attempts = 0;
+
+    while fetched.len() < keys.len() {
+        let missing = &keys[fetched.len()..];
+        match fetch(missing) {
+            Ok(accounts) if !accounts.is_empty() => {
+                if accounts.len() < missing.len() {
+                    warn!(
+                        "Got {} of {} accounts, fetching the rest",
+                        accounts.len(),
+                        missing.len()
+                    );
+                }
+                attempts = 0;
+                fetched.extend(accounts.into_iter().take(missing.len()));
+            }
+            Ok(_) => {
+                attempts += 1;
+                warn!("Got no accounts for {} keys", missing.len());
+            }
+            Err(e) => {
+                attempts += 1;
+                warn!("Failed to fetch {} accounts: {}", missing.len(), e);
+            }
+        }
+
+        if attempts >= MAX_FETCH_ATTEMPTS {
+            return Err(ErrorCode::IncompleteAccountFetch);
+        }
+    }
+
+    Ok(fetched)
+}
+
+/// Whether `client` appears to serve a different fork than `reference`,
+/// judged by their blockhashes at the latest slot `client` has
+/// confirmed. Gives the benefit of the doubt when either can't tell,
+/// e.g. when `reference` hasn't caught up to the slot yet.
+pub fn is_on_different_fork(client: &RpcClient, reference: &RpcClient) -> bool {
+    let config = RpcBlockConfig {
+        transaction_details: Some(TransactionDetails::None),
+        rewards: Some(false),
+        commitment: Some(CommitmentConfig::confirmed()),
+        ..RpcBlockConfig::default()
+    };
+
+    let slot =
+        match client.get_slot_with_commitment(CommitmentConfig::confirmed()) {
+            Ok(slot) => slot,
+            Err(e) => {
+                warn!("Failed to get slot for fork check: {}", e);
+                return false;
+            }
+        };
+
+    match (
+        client.get_block_with_config(slot, config),
+        reference.get_block_with_config(slot, config),
+    ) {
+        (Ok(ours), Ok(theirs)) if ours.blockhash != theirs.blockhash => {
+            warn!(
+                "Blockhash at slot {} is {} but the reference has {}",
+                slot, ours.blockhash, theirs.blockhash
+            );
+            true
+        }
+        (Ok(_), Ok(_)) => false,
+        (Err(e), _) | (_, Err(e)) => {
+            debug!("Could not compare blocks at slot {}: {}", slot, e);
+            false
+        }
+    }
+}
+
+/// Checks that the cache's oracles are sorted by symbol, which the
+/// binary search in `get_oracle` relies on.
+pub fn check_oracles_sorted(cache: &Cache) -> Result<(), ErrorCode> {
+    let symbols: Vec<&Symbol> = cache
+        .oracles
+        .iter()
+        .map(|o| &o.symbol)
+        .filter(|s| !s.is_nil())
+        .collect();
+
+    match symbols.windows(2).all(|w| w[0] < w[1]) {
+        true => Ok(()),
+        false => Err(ErrorCode::UnsortedOracleCache),
+    }
+}
+
+/// Checks the on-chain state and cache against what the keeper was
+/// built and configured for, so that version skew is caught before the
+/// first scan rather than in the middle of one. Returns every problem
+/// found.
+pub fn validate_config(
+    client: &RpcClient,
+    st: &crate::AppState,
+) -> Result<(), Vec<ErrorCode>> {
+    let mut errors = Vec::new();
+
+    let mut state_account = client
+        .get_account(&st.zo_state_pubkey)
+        .map_err(|e| vec![e.into()])?;
+    if state_account.owner != zo_abi::ID {
+        errors.push(ErrorCode::WrongOwner(st.zo_state_pubkey));
+    }
+    let state =
+        get_type_from_account::<State>(&st.zo_state_pubkey, &mut state_account)
+            .map_err(|e| vec![e])?;
+
+    let cache = client
+        .get_account(&state.cache)
+        .map_err(ErrorCode::from)
+        .and_then(|mut a| get_type_from_account::<Cache>(&state.cache, &mut a))
+        .map_err(|e| vec![e])?;
+    errors.extend(check_state_and_cache(&state, &cache, &st.zo_cache_pubkey));
+
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors),
+    }
+}
+
+/// The checks of `validate_config` on the fetched state and cache,
+/// given the cache the keeper was configured with.
+fn check_state_and_cache(
+    state: &State,
+    cache: &Cache,
+    cache_key: &Pubkey,
+) -> Vec<ErrorCode> {
+    let mut errors = Vec::new();
+
+    if state.cache != *cache_key {
+        errors.push(ErrorCode::CacheMismatch);
+    }
+    if state.total_markets as usize > MAX_MARKETS as usize {
+        errors.push(ErrorCode::TooManyMarkets(state.total_markets as usize));
+    }
+    if state.total_collaterals as usize > MAX_COLLATERALS as usize {
+        errors.push(ErrorCode::TooManyCollaterals(
+            state.total_collaterals as usize,
+        ));
+    }
+    if let Err(e) = check_oracles_sorted(cache) {
+        errors.push(e);
+    }
+
+    let collateral_oracles = state
+        .collaterals
+        .iter()
+        .take(state.total_collaterals as usize)
+        .map(|c| c.oracle_symbol);
+    let market_oracles = state
+        .perp_markets
+        .iter()
+        .take(state.total_markets as usize)
+        .filter(|m| m.dex_market != Pubkey::default())
+        .map(|m| m.oracle_symbol);
+    for symbol in collateral_oracles.chain(market_oracles) {
+        if get_oracle(cache, &symbol).is_none() {
+            errors.push(ErrorCode::MissingOracle(symbol.into()));
+        }
+    }
+
+    errors
+}
+
+/// Per oracle symbol, the last price seen, when it was last updated,
+/// and how many updates in a row have left the price unchanged.
+pub type FreezeCounts = BTreeMap<Symbol, (WrappedI80F48, u64, u32)>;
+
+/// Counts updates that advance an oracle's timestamp without changing
+/// its price. A live feed moves, so a long run of these means the feed
+/// is stuck, even though it doesn't look stale.
+pub fn update_freeze_counts(counts: &mut FreezeCounts, cache: &Cache) {
+    for oracle in cache.oracles.iter().filter(|o| !o.symbol.is_nil()) {
+        let (price, last_updated, unchanged) = counts
+            .entry(oracle.symbol)
+            .or_insert((oracle.price, oracle.last_updated, 0));
+
+        if oracle.last_updated <= *last_updated {
+            continue;
+        }
+        *unchanged = match oracle.price == *price {
+            true => *unchanged + 1,
+            false => 0,
+        };
+        *price = oracle.price;
+        *last_updated = oracle.last_updated;
+    }
+}
+
+/// Symbols whose price has been unchanged for at least `updates`
+/// updates.
+pub fn frozen_symbols(counts: &FreezeCounts, updates: u32) -> BTreeSet<Symbol> {
+    counts
+        .iter()
+        .filter(|(_, (_, _, unchanged))| *unchanged >= updates)
+        .map(|(symbol, _)| *symbol)
+        .collect()
 }
 
 fn get_oracle_index(cache: &Cache, s: &Symbol) -> Option<usize> {
@@ -104,6 +486,24 @@ pub fn get_oracle<'a>(cache: &'a Cache, s: &Symbol) -> Option<&'a OracleCache> {
     Some(&cache.oracles[get_oracle_index(cache, s)?])
 }
 
+/// Like `get_oracle`, but fails if the price was last updated more than
+/// `max_age` seconds before `now`. The cache records `last_updated` as a
+/// unix timestamp in seconds rather than a slot, so `now` is one too and
+/// the age is measured in seconds, not slots.
+pub fn get_fresh_oracle<'a>(
+    cache: &'a Cache,
+    s: &Symbol,
+    max_age: u64,
+    now: u64,
+) -> Result<&'a OracleCache, ErrorCode> {
+    let oracle = get_oracle(cache, s)
+        .ok_or_else(|| ErrorCode::MissingOracle((*s).into()))?;
+    match now.saturating_sub(oracle.last_updated) > max_age {
+        true => Err(ErrorCode::StaleOracle),
+        false => Ok(oracle),
+    }
+}
+
 pub fn get_oo_keys(
     agg: &[OpenOrdersInfo; MAX_MARKETS as usize],
 ) -> [Pubkey; MAX_MARKETS as usize] {
@@ -123,17 +523,16 @@ pub fn is_right_remainder(key: &Pubkey, modulus: u8, remainder: u8) -> bool {
      * For margin accounts, check it on the control field.
      */
 
-    // Convert the key to a number
-    // The hash which actually does the conversion is bad.
-    // The hash which just does the sum is good
-    // Convert key to bytes and sum?
-    let bytes = key.to_bytes();
-    let mut sum = 0;
-    for byte in bytes {
-        sum += byte % modulus;
-    }
+    // Reduce the key, read as a little-endian integer, modulo the
+    // number of workers, which splits random keys evenly.
+    let modulus = modulus as u32;
+    let residue = key
+        .to_bytes()
+        .iter()
+        .rev()
+        .fold(0u32, |acc, &byte| (acc * 256 + byte as u32) % modulus);
 
-    sum % modulus == remainder
+    residue == remainder as u32
 }
 
 pub fn array_to_le_bytes(array: &[u64; 4]) -> [u8; 32] {
@@ -174,20 +573,364 @@ pub fn get_preflight_error_code(
     error_code
 }
 
+/// How long a landed instruction set is remembered. Identical requests
+/// sent within this window are assumed to be duplicates, e.g. an account
+/// flagged again before the listener caught its update.
+const LANDED_TTL: Duration = Duration::from_secs(20);
+
+/// Confirmed signatures keyed by a hash of the instructions they carried.
+/// They are only kept in memory, so a request that landed just before a
+/// restart isn't recognised after it and may be sent again.
+static LANDED: Mutex<BTreeMap<u64, (Signature, Instant)>> =
+    Mutex::new(BTreeMap::new());
+
+fn instructions_key(ixs: &[Instruction]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for ix in ixs {
+        ix.program_id.hash(&mut hasher);
+        ix.data.hash(&mut hasher);
+        for meta in &ix.accounts {
+            meta.pubkey.hash(&mut hasher);
+            meta.is_signer.hash(&mut hasher);
+            meta.is_writable.hash(&mut hasher);
+        }
+    }
+    hasher.finish()
+}
+
+fn get_landed(key: u64) -> Option<Signature> {
+    let mut landed = LANDED.lock().ok()?;
+    landed.retain(|_, (_, t)| t.elapsed() < LANDED_TTL);
+    landed.get(&key).map(|(sig, _)| *sig)
+}
+
+fn set_landed(key: u64, sig: Signature) {
+    if let Ok(mut landed) = LANDED.lock() {
+        landed.insert(key, (sig, Instant::now()));
+    }
+}
+
+/// Compute budget requested by transactions sent with `retry_send`.
+/// The price, in micro-lamports per compute unit, is multiplied by
+/// `escalation` on each retry so later attempts bid higher.
+#[derive(Clone, Copy, Debug)]
+pub struct PriorityFees {
+    pub micro_lamports: Option<u64>,
+    pub compute_unit_limit: Option<u32>,
+    pub escalation: f64,
+}
+
+/// How `retry_send` waits on sent transactions before returning or
+/// sending another.
+#[derive(Clone)]
+pub struct Confirmation {
+    /// RPC node polled for signature statuses, shared by every send.
+    pub rpc: Arc<RpcClient>,
+    /// Longest wait after each send for it to reach `commitment`.
+    pub timeout: Duration,
+    pub commitment: CommitmentConfig,
+}
+
+impl std::fmt::Debug for Confirmation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Confirmation")
+            .field("timeout", &self.timeout)
+            .field("commitment", &self.commitment)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Time between status checks while waiting for confirmation.
+const CONFIRMATION_POLL: Duration = Duration::from_millis(500);
+
+/// Where the transactions sent by one `retry_send` call stand.
+enum SendStatus {
+    /// One reached the commitment, with the attempt it was sent at.
+    Landed(Signature, usize),
+    /// One was seen without error, or may still be as its blockhash is
+    /// valid. Sending another could land the instructions twice.
+    InFlight,
+    /// None can land anymore: each failed or expired unseen.
+    Expired,
+}
+
+impl Confirmation {
+    /// Checks on `sent`, each with the attempt it was sent at.
+    /// `blockhash` is no older than any of theirs, so they have all
+    /// expired once it has. Unknown statuses count as in flight.
+    fn status(
+        &self,
+        sent: &[(Signature, usize)],
+        blockhash: Option<&solana_sdk::hash::Hash>,
+    ) -> SendStatus {
+        let rpc = &self.rpc;
+        let signatures: Vec<Signature> =
+            sent.iter().map(|(sig, _)| *sig).collect();
+        let statuses = match rpc.get_signature_statuses(&signatures) {
+            Ok(response) => response.value,
+            Err(e) => {
+                warn!("Failed to get signature statuses: {}", e);
+                return SendStatus::InFlight;
+            }
+        };
+
+        let mut unseen = false;
+        for (status, &(sig, attempt)) in statuses.iter().zip(sent) {
+            match status {
+                Some(status) if status.err.is_some() => {}
+                Some(status)
+                    if status.satisfies_commitment(self.commitment) =>
+                {
+                    return SendStatus::Landed(sig, attempt)
+                }
+                Some(_) => return SendStatus::InFlight,
+                None => unseen = true,
+            }
+        }
+
+        let expired = blockhash.map_or(false, |hash| {
+            !rpc.is_blockhash_valid(hash, CommitmentConfig::processed())
+                .unwrap_or(true)
+        });
+        match unseen && !expired {
+            true => SendStatus::InFlight,
+            false => SendStatus::Expired,
+        }
+    }
+
+    /// Like `status`, polling for up to `timeout` while in flight.
+    fn wait(
+        &self,
+        sent: &[(Signature, usize)],
+        blockhash: Option<&solana_sdk::hash::Hash>,
+    ) -> SendStatus {
+        let start = Instant::now();
+        loop {
+            let status = self.status(sent, blockhash);
+            if !matches!(status, SendStatus::InFlight)
+                || start.elapsed() >= self.timeout
+            {
+                return status;
+            }
+            std::thread::sleep(CONFIRMATION_POLL);
+        }
+    }
+}
+
+impl PriorityFees {
+    /// Price in micro-lamports per compute unit at the given attempt.
+    fn price(&self, attempt: usize) -> Option<u64> {
+        self.micro_lamports.map(|price| {
+            (price as f64 * self.escalation.max(1.0).powi(attempt as i32))
+                as u64
+        })
+    }
+
+    /// Lamports of priority fee paid by a send at the given attempt that
+    /// uses `units` compute units. The fee is charged on the requested
+    /// limit if there is one.
+    pub fn lamports(&self, attempt: usize, units: u64) -> u64 {
+        let units = match self.compute_unit_limit {
+            Some(limit) => limit as u64,
+            None => units,
+        };
+        self.price(attempt)
+            .map(|price| price.saturating_mul(units) / 1_000_000)
+            .unwrap_or(0)
+    }
+
+    /// Compute budget instructions for the given attempt. The pinned
+    /// SDK predates `set_compute_unit_limit` and `set_compute_unit_price`,
+    /// so their instruction data is built here. The runtime reads them
+    /// from anywhere in the transaction.
+    fn instructions(&self, attempt: usize) -> Vec<Instruction> {
+        let mut ixs = Vec::new();
+        if let Some(units) = self.compute_unit_limit {
+            let mut data = vec![2u8];
+            data.extend_from_slice(&units.to_le_bytes());
+            ixs.push(Instruction::new_with_bytes(
+                solana_sdk::compute_budget::id(),
+                &data,
+                vec![],
+            ));
+        }
+        if let Some(price) = self.price(attempt) {
+            let mut data = vec![3u8];
+            data.extend_from_slice(&price.to_le_bytes());
+            ixs.push(Instruction::new_with_bytes(
+                solana_sdk::compute_budget::id(),
+                &data,
+                vec![],
+            ));
+        }
+        ixs
+    }
+}
+
+/// How many recent sends' landing attempts are kept.
+const LANDING_HISTORY: usize = 50;
+
+/// Attempts, counted from 0, recent sends landed on. Sends needing more
+/// attempts mean more competition for block space.
+static LANDING_ATTEMPTS: Mutex<VecDeque<usize>> = Mutex::new(VecDeque::new());
+
+fn record_landing_attempt(attempt: usize) {
+    if let Ok(mut attempts) = LANDING_ATTEMPTS.lock() {
+        if attempts.len() == LANDING_HISTORY {
+            attempts.pop_front();
+        }
+        attempts.push_back(attempt);
+    }
+}
+
+/// Attempt by which nine in ten recent sends landed, and so the one
+/// whose priority fee a send should expect to pay. 0 if none landed.
+pub fn required_attempt() -> usize {
+    let mut attempts: Vec<usize> = match LANDING_ATTEMPTS.lock() {
+        Ok(attempts) => attempts.iter().copied().collect(),
+        Err(_) => return 0,
+    };
+    if attempts.is_empty() {
+        return 0;
+    }
+
+    attempts.sort_unstable();
+    attempts[(attempts.len() * 9 / 10).min(attempts.len() - 1)]
+}
+
+/// How often and how far apart `retry_send` retries. The delay before
+/// a retry doubles with each attempt up to `max_delay`, with up to 25%
+/// of jitter either way so keepers don't retry in lockstep.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub retries: usize,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub fees: PriorityFees,
+    /// Sends are only confirmed by the RPC node's `send` if unset.
+    /// Otherwise each is waited on, and nothing is sent again until
+    /// earlier transactions can no longer land.
+    pub confirmation: Option<Confirmation>,
+    /// The jitter is derived from this and the attempt, so it differs
+    /// between keepers with different seeds but is reproducible.
+    pub jitter_seed: u64,
+}
+
+impl RetryPolicy {
+    /// Five attempts at `fees`, from 100ms up to 2s apart.
+    pub fn new(fees: PriorityFees, confirmation: Option<Confirmation>) -> Self {
+        Self {
+            retries: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(2),
+            fees,
+            confirmation,
+            jitter_seed: 0,
+        }
+    }
+
+    fn delay(&self, attempt: usize) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt as u32))
+            .min(self.max_delay);
+
+        // Spread evenly over [0.75, 1.25] by hashing.
+        let mut hasher = DefaultHasher::new();
+        (self.jitter_seed, attempt).hash(&mut hasher);
+        let random = hasher.finish();
+        let jitter = 0.75 + 0.5 * (random as f64 / u64::MAX as f64);
+        delay.mul_f64(jitter)
+    }
+}
+
 // TODO: Refactor to take vector of ixs 
 #[tracing::instrument(skip_all, level = "error")]
 pub fn retry_send<'a>(
     make_builder: impl Fn() -> RequestBuilder<'a>,
-    retries: usize,
+    policy: &RetryPolicy,
 ) -> Result<Signature, ErrorCode> {
     let mut last_error: Option<_> = None;
+    let mut last_other_error: Option<String> = None;
+
+    // `send` confirms the transaction, so a signature in the cache has
+    // already landed and must not be broadcast again. It is an error so
+    // that callers don't account for the same transaction twice.
+    let key = make_builder()
+        .instructions()
+        .ok()
+        .map(|ixs| instructions_key(&ixs));
+    if let Some(sig) = key.and_then(get_landed) {
+        warn!("Identical request already landed in {}, not resending", sig);
+        return Err(ErrorCode::AlreadyLanded(sig));
+    }
+
+    let confirmer = policy.confirmation.as_ref();
+    // Every transaction sent, with the attempt it was sent at, and the
+    // latest blockhash after the last send. Nothing is sent again while
+    // any of them may still land.
+    let mut sent: Vec<(Signature, usize)> = Vec::new();
+    let mut blockhash = None;
+    let landed = |sig: Signature, attempt: usize| {
+        if let Some(key) = key {
+            set_landed(key, sig);
+        }
+        record_landing_attempt(attempt);
+        sig
+    };
+
+    for attempt in 0..policy.retries {
+        if attempt > 0 {
+            std::thread::sleep(policy.delay(attempt - 1));
+        }
+
+        if let Some(confirmation) = confirmer {
+            if !sent.is_empty() {
+                match confirmation.status(&sent, blockhash.as_ref()) {
+                    SendStatus::Landed(sig, sent_at) => {
+                        return Ok(landed(sig, sent_at))
+                    }
+                    SendStatus::InFlight => continue,
+                    SendStatus::Expired => {}
+                }
+            }
+        }
 
-    for _i in 0..retries {
-        let request_builder = make_builder();
+        let request_builder = policy
+            .fees
+            .instructions(attempt)
+            .into_iter()
+            .fold(make_builder(), |builder, ix| builder.instruction(ix));
 
         match request_builder.send() {
             Ok(response) => {
-                return Ok(response);
+                if let Some(confirmation) = confirmer {
+                    sent.push((response, attempt));
+                    blockhash = confirmation
+                        .rpc
+                        .get_latest_blockhash_with_commitment(
+                            CommitmentConfig::processed(),
+                        )
+                        .ok()
+                        .map(|(hash, _)| hash);
+
+                    match confirmation.wait(&sent, blockhash.as_ref()) {
+                        SendStatus::Landed(sig, sent_at) => {
+                            return Ok(landed(sig, sent_at))
+                        }
+                        _ => {
+                            metrics::send_failed("unconfirmed");
+                            warn!(
+                                "{} didn't reach {:?} in time",
+                                response, confirmation.commitment.commitment
+                            );
+                            last_other_error =
+                                Some(format!("{} wasn't confirmed", response));
+                            continue;
+                        }
+                    }
+                }
+                return Ok(landed(response, attempt));
             }
             Err(e) => {
                 if let SolanaClientError(ClientError {
@@ -200,26 +943,33 @@ pub fn retry_send<'a>(
                             match get_preflight_error_code(e) {
                                 Some(&code) => {
                                     if code == 6006 || code == 6016 || code == 6046 {
+                                        metrics::send_failed("over_exposure");
                                         warn!("Retrying with smaller liquidation");
                                         return Err(ErrorCode::LiquidationOverExposure);
                                     } else if code == 6007 || code == 6012 || code == 6011 {
+                                        metrics::send_failed("not_liquidatable");
                                         warn!("Account is not liquidatable");
                                         return Err(
                                             ErrorCode::UnrecoverableTransactionError,
                                         );
                                     } else if code == 6017 {
+                                        metrics::send_failed("already_liquidated");
                                         warn!("Account was already liquidated");
                                         return Err(
                                             ErrorCode::UnrecoverableTransactionError,
                                         );
                                     } else if code == 6052 {
+                                        metrics::send_failed("unliquidated_spot");
                                         warn!("Account has unliquidated spot, possibly already liquidated.");
                                         return Err(
                                             ErrorCode::UnrecoverableTransactionError,
                                         );
+                                    } else {
+                                        metrics::send_failed("program");
                                     }
                                 }
                                 None => {
+                                    metrics::send_failed("rpc");
                                     warn!("Got rpc error: {:?}", e);
                                     return Err(
                                         ErrorCode::UnrecoverableTransactionError,
@@ -228,23 +978,47 @@ pub fn retry_send<'a>(
                             }
                         }
                         ClientErrorKind::Reqwest(e) => {
+                            metrics::send_failed("reqwest");
                             warn!("Got reqwest error: {:?}", e);
                         }
                         ClientErrorKind::TransactionError(e) => {
+                            metrics::send_failed("transaction");
                             warn!("Got transaction error: {:?}", e);
                         }
                         _ => {
+                            metrics::send_failed("other");
                             return Err(
                                 ErrorCode::UnrecoverableTransactionError,
                             );
                         }
                     }
                     last_error = Some(kind);
+                } else {
+                    metrics::send_failed("client");
+                    warn!("Got client error: {}", e);
+                    last_other_error = Some(e.to_string());
                 }
             }
         };
     }
 
+    // Whatever else failed, a transaction that may still land isn't a
+    // failed send, or the caller could send the instructions again.
+    if let Some(confirmation) = confirmer {
+        if !sent.is_empty() {
+            match confirmation.status(&sent, blockhash.as_ref()) {
+                SendStatus::Landed(sig, sent_at) => {
+                    return Ok(landed(sig, sent_at))
+                }
+                SendStatus::InFlight => {
+                    error!("Sent transactions may still land: {:?}", sent);
+                    return Err(ErrorCode::SendInFlight);
+                }
+                SendStatus::Expired => {}
+            }
+        }
+    }
+
     if let Some(e) = last_error {
         if let ClientErrorKind::RpcError(RpcError::RpcResponseError {
             code: c,
@@ -253,13 +1027,537 @@ pub fn retry_send<'a>(
         }) = e
         {
             error!("Failed to send request. message: {:?}, data: {:?}. Code: {}", error_msg, d, c);
+            Err(ErrorCode::SendFailed(format!("{} (code {})", error_msg, c)))
         } else {
             error!("Failed to send request with error {:?}", e);
+            Err(ErrorCode::SendFailed(format!("{:?}", e)))
         }
+    } else if let Some(e) = last_other_error {
+        error!("Failed to send request with error {}", e);
+        Err(ErrorCode::SendFailed(e))
     } else {
         let ix = make_builder().instructions().unwrap();
         error!("Failed to send request {:?}", ix);
+        Err(ErrorCode::TimeoutExceeded)
+    }
+}
+
+/// Largest serialized transaction the network accepts. Batches are
+/// sized as legacy transactions: the solana 1.9 client can neither
+/// build v0 messages with address lookup tables nor send them.
+const MAX_TX_SIZE: u64 = 1232;
+
+/// Most compute units a transaction may use.
+const MAX_TX_COMPUTE: u64 = 1_400_000;
+
+/// Instructions that must land together, and the compute units they use.
+pub struct IxGroup {
+    pub ixs: Vec<Instruction>,
+    pub compute: u64,
+}
+
+fn tx_size(payer: &Pubkey, ixs: &[Instruction]) -> u64 {
+    let tx = Transaction::new_with_payer(ixs, Some(payer));
+    bincode::serialized_size(&tx).unwrap_or(u64::MAX)
+}
+
+/// Packs `groups` in order into as few transactions as fit within the
+/// size and compute limits, leaving room for the compute budget
+/// instructions of `fees`. Returns the indices of the groups in each
+/// transaction. A group too large on its own gets a transaction of its
+/// own.
+pub fn batch_ix_groups(
+    payer: &Pubkey,
+    groups: &[IxGroup],
+    fees: &PriorityFees,
+) -> Vec<Vec<usize>> {
+    let max_compute = match fees.compute_unit_limit {
+        Some(limit) => (limit as u64).min(MAX_TX_COMPUTE),
+        None => MAX_TX_COMPUTE,
+    };
+
+    let mut batches: Vec<Vec<usize>> = Vec::new();
+    let mut ixs = fees.instructions(0);
+    let mut compute = 0;
+
+    for (i, group) in groups.iter().enumerate() {
+        let mut candidate = ixs.clone();
+        candidate.extend_from_slice(&group.ixs);
+
+        let fits = compute + group.compute <= max_compute
+            && tx_size(payer, &candidate) <= MAX_TX_SIZE;
+        match batches.last_mut() {
+            Some(batch) if fits => {
+                batch.push(i);
+                ixs = candidate;
+                compute += group.compute;
+            }
+            _ => {
+                batches.push(vec![i]);
+                ixs = fees.instructions(0);
+                ixs.extend_from_slice(&group.ixs);
+                compute = group.compute;
+            }
+        }
     }
 
-    Err(ErrorCode::TimeoutExceeded)
+    batches
+}
+
+/// Sends `groups` batched by `batch_ix_groups`, each transaction
+/// through `retry_send`. Groups in one transaction land or fail
+/// together, so the groups of a failed transaction are sent again one
+/// per transaction, and only the ones that fail on their own are
+/// reported as failed. Returns the indices of the groups in each
+/// transaction along with its result.
+pub fn send_batched(
+    program: &Program,
+    groups: &[IxGroup],
+    policy: &RetryPolicy,
+) -> Vec<(Vec<usize>, Result<Signature, ErrorCode>)> {
+    let send = |batch: &[usize]| {
+        retry_send(
+            || {
+                batch
+                    .iter()
+                    .flat_map(|&i| groups[i].ixs.iter().cloned())
+                    .fold(program.request(), |builder, ix| {
+                        builder.instruction(ix)
+                    })
+                    .options(CommitmentConfig::confirmed())
+            },
+            policy,
+        )
+    };
+
+    let mut results = Vec::new();
+    for batch in batch_ix_groups(&program.payer(), groups, &policy.fees) {
+        match send(&batch) {
+            // Sent separately, the groups would no longer match the
+            // landed request and would be sent again.
+            Err(ErrorCode::AlreadyLanded(sig)) => {
+                results.push((batch, Err(ErrorCode::AlreadyLanded(sig))))
+            }
+            Err(e) if batch.len() > 1 => {
+                warn!("Batch {:?} failed, sending separately: {}", batch, e);
+                for i in batch {
+                    results.push((vec![i], send(&[i])));
+                }
+            }
+            result => results.push((batch, result)),
+        }
+    }
+
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::liquidator::fixtures::*;
+
+    #[test]
+    fn margin_header_matches_the_full_account() {
+        let (margin, _) = account();
+        let mut data = vec![0u8; 8];
+        data.extend_from_slice(bytemuck::bytes_of(&margin));
+        let full: &Margin = bytemuck::from_bytes(&data[8..]);
+
+        let header = MarginHeader::from_bytes(&data[..MarginHeader::len()]);
+        assert_eq!(header.map(|h| h.control), Some({ full.control }));
+        assert_eq!(
+            MarginHeader::from_bytes(&data[..MarginHeader::len() - 1]),
+            None
+        );
+    }
+
+    #[test]
+    fn landed_requests_are_not_sent_again() {
+        use anchor_client::{Client, Cluster};
+        use solana_sdk::signer::keypair::Keypair;
+
+        // Nothing listens on the local cluster, so a send would fail.
+        let client = Client::new_with_options(
+            Cluster::Localnet,
+            std::rc::Rc::new(Keypair::new()),
+            CommitmentConfig::confirmed(),
+        );
+        let program = client.program(Pubkey::new_unique());
+        let ix =
+            Instruction::new_with_bytes(Pubkey::new_unique(), &[1, 2], vec![]);
+        let sig = Signature::new_unique();
+        set_landed(instructions_key(&[ix.clone()]), sig);
+
+        let fees = PriorityFees {
+            micro_lamports: None,
+            compute_unit_limit: None,
+            escalation: 1.0,
+        };
+        let sent = retry_send(
+            || program.request().instruction(ix.clone()),
+            &RetryPolicy::new(fees, None),
+        );
+        assert!(matches!(sent, Err(ErrorCode::AlreadyLanded(s)) if s == sig));
+
+        // A different request isn't taken for it.
+        let other =
+            Instruction::new_with_bytes(Pubkey::new_unique(), &[1, 2], vec![]);
+        assert!(get_landed(instructions_key(&[other])).is_none());
+    }
+
+    #[test]
+    fn unsorted_oracles_are_detected() {
+        let mut book = Book::new();
+        book.oracle("BTC", 40_000.0);
+        book.oracle("ETH", 3_000.0);
+        book.oracle("SOL", 100.0);
+        assert!(check_oracles_sorted(&book.cache).is_ok());
+
+        // Unused slots are nil and sort first.
+        let first = book.cache.oracles.len() - 3;
+        let mut oracles = book.cache.oracles;
+        oracles.swap(first, first + 2);
+        book.cache.oracles = oracles;
+        assert!(matches!(
+            check_oracles_sorted(&book.cache),
+            Err(ErrorCode::UnsortedOracleCache)
+        ));
+
+        // A duplicate symbol would make the binary search ambiguous.
+        let mut oracles = book.cache.oracles;
+        oracles[first] = oracles[first + 1];
+        oracles.sort_by_key(|o| o.symbol);
+        book.cache.oracles = oracles;
+        assert!(matches!(
+            check_oracles_sorted(&book.cache),
+            Err(ErrorCode::UnsortedOracleCache)
+        ));
+    }
+
+    #[test]
+    fn frozen_prices_are_detected_across_updates() {
+        let mut book = Book::new();
+        book.oracle("BTC", 40_000.0);
+        book.oracle("SOL", 100.0);
+
+        // Every snapshot advances both timestamps, only SOL's price moves.
+        let snapshot = |book: &mut Book, t: u64| {
+            book.oracle("SOL", 100.0 + t as f64);
+            let mut oracles = book.cache.oracles;
+            for oracle in oracles.iter_mut().filter(|o| !o.symbol.is_nil()) {
+                oracle.last_updated = t;
+            }
+            book.cache.oracles = oracles;
+        };
+
+        let mut counts = FreezeCounts::new();
+        for t in 1..=3 {
+            snapshot(&mut book, t);
+            update_freeze_counts(&mut counts, &book.cache);
+        }
+        assert!(frozen_symbols(&counts, 3).is_empty());
+
+        snapshot(&mut book, 4);
+        update_freeze_counts(&mut counts, &book.cache);
+        let frozen = frozen_symbols(&counts, 3);
+        assert_eq!(
+            frozen.into_iter().collect::<Vec<_>>(),
+            [Symbol::from("BTC")]
+        );
+
+        // Snapshots that don't advance the timestamp aren't updates.
+        update_freeze_counts(&mut counts, &book.cache);
+        assert!(frozen_symbols(&counts, 4).is_empty());
+
+        // A price change unfreezes the feed.
+        book.oracle("BTC", 41_000.0);
+        snapshot(&mut book, 5);
+        update_freeze_counts(&mut counts, &book.cache);
+        assert!(frozen_symbols(&counts, 1).is_empty());
+    }
+
+    #[test]
+    fn divergent_blockhashes_are_taken_for_a_fork() {
+        use solana_client::rpc_request::RpcRequest;
+        use solana_sdk::hash::Hash as Blockhash;
+        use std::collections::HashMap;
+
+        // Mocked responses are used once, so each check needs new clients.
+        let node = |blockhash: &str| {
+            let block = serde_json::json!({
+                "previousBlockhash": Blockhash::default().to_string(),
+                "blockhash": blockhash,
+                "parentSlot": 99,
+                "blockTime": null,
+                "blockHeight": null,
+            });
+            RpcClient::new_mock_with_mocks(
+                "succeeds".to_string(),
+                HashMap::from([
+                    (RpcRequest::GetSlot, serde_json::json!(100)),
+                    (RpcRequest::GetBlock, block),
+                ]),
+            )
+        };
+        let (ours, theirs) = (Blockhash::new_unique(), Blockhash::new_unique());
+
+        assert!(is_on_different_fork(
+            &node(&ours.to_string()),
+            &node(&theirs.to_string()),
+        ));
+        assert!(!is_on_different_fork(
+            &node(&ours.to_string()),
+            &node(&ours.to_string()),
+        ));
+
+        // A reference that can't serve the block gets the benefit of the
+        // doubt.
+        let failing = RpcClient::new_mock("fails".to_string());
+        assert!(!is_on_different_fork(&node(&ours.to_string()), &failing));
+    }
+
+    #[test]
+    fn keys_are_sharded_evenly() {
+        // Hashes stand in for random keys, so the test is deterministic.
+        let keys: Vec<Pubkey> = (0u32..100_000)
+            .map(|i| {
+                let hash = solana_sdk::hash::hashv(&[&i.to_le_bytes()]);
+                Pubkey::new_from_array(hash.to_bytes())
+            })
+            .collect();
+
+        for modulus in [2u8, 3, 4, 8] {
+            let even = keys.len() as f64 / modulus as f64;
+            for remainder in 0..modulus {
+                let count = keys
+                    .iter()
+                    .filter(|key| is_right_remainder(key, modulus, remainder))
+                    .count();
+                assert!(
+                    (count as f64 - even).abs() <= even * 0.05,
+                    "{} keys in bucket {} of {}",
+                    count,
+                    remainder,
+                    modulus
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn accounts_missing_from_short_responses_are_refetched() {
+        let keys: Vec<Pubkey> = (0..5).map(|_| Pubkey::new_unique()).collect();
+        // Accounts are told apart by their lamports, their key's index.
+        let account = |key: &Pubkey| {
+            let i = keys.iter().position(|k| k == key).unwrap();
+            Some(Account::new(i as u64, 0, &Pubkey::default()))
+        };
+
+        let mut requests: Vec<usize> = Vec::new();
+        let fetched = refetch_missing(&keys, |missing| {
+            requests.push(missing.len());
+            // The first response is cut short after two accounts.
+            let served = match requests.len() {
+                1 => 2,
+                _ => missing.len(),
+            };
+            Ok(missing.iter().take(served).map(&account).collect())
+        })
+        .unwrap();
+        assert_eq!(requests, [5, 3]);
+        let lamports: Vec<u64> = fetched
+            .iter()
+            .map(|a| a.as_ref().unwrap().lamports)
+            .collect();
+        assert_eq!(lamports, [0, 1, 2, 3, 4]);
+
+        // Providers that keep returning nothing are given up on.
+        let mut calls = 0;
+        let result = refetch_missing(&keys, |_| {
+            calls += 1;
+            Ok(Vec::new())
+        });
+        assert!(matches!(result, Err(ErrorCode::IncompleteAccountFetch)));
+        assert_eq!(calls, MAX_FETCH_ATTEMPTS);
+    }
+
+    #[test]
+    fn account_sets_over_the_limit_are_refused() {
+        use solana_client::rpc_request::RpcRequest;
+        use std::collections::HashMap;
+
+        let program = Pubkey::new_unique();
+        // Mocked responses are used once, so each count needs a client.
+        let client = || {
+            let accounts: Vec<_> = (0..3)
+                .map(|_| {
+                    serde_json::json!({
+                        "pubkey": Pubkey::new_unique().to_string(),
+                        "account": {
+                            "lamports": 1,
+                            "data": ["", "base64"],
+                            "owner": program.to_string(),
+                            "executable": false,
+                            "rentEpoch": 0,
+                        },
+                    })
+                })
+                .collect();
+            RpcClient::new_mock_with_mocks(
+                "succeeds".to_string(),
+                HashMap::from([(
+                    RpcRequest::GetProgramAccounts,
+                    serde_json::json!(accounts),
+                )]),
+            )
+        };
+        let check = |client: &RpcClient, max| {
+            check_account_set_len::<Margin>(
+                client,
+                &program,
+                CommitmentConfig::confirmed(),
+                max,
+            )
+        };
+
+        assert!(matches!(
+            check(&client(), Some(2)),
+            Err(ErrorCode::ResultSetTooLarge(3, 2))
+        ));
+        assert!(check(&client(), Some(3)).is_ok());
+
+        // Without a limit nothing is requested, so a failing node is fine.
+        let failing = RpcClient::new_mock("fails".to_string());
+        assert!(check(&failing, None).is_ok());
+    }
+
+    // A benchmark rather than a check, so it is ignored by default:
+    //
+    //     $ cargo test --release -- --ignored fetched_accounts --nocapture
+    //
+    // It compares loading a large set of fetched control accounts one
+    // after the other with `load_fetched_accounts`, which spreads them
+    // over all cores.
+    #[test]
+    #[ignore]
+    fn fetched_accounts_loaded_sequentially_and_in_parallel() {
+        use anchor_lang::Discriminator;
+        use zo_abi::Control;
+        const ACCOUNTS: usize = 20_000;
+
+        let (_, mut control) = account();
+        set_position(&mut control, 0, 1_000, 10.0);
+        let mut data = Control::discriminator().to_vec();
+        data.extend_from_slice(bytemuck::bytes_of(&control));
+        let fetched: Vec<(Pubkey, Account)> = (0..ACCOUNTS)
+            .map(|_| {
+                let account = Account {
+                    lamports: 1,
+                    data: data.clone(),
+                    owner: zo_abi::ID,
+                    executable: false,
+                    rent_epoch: 0,
+                };
+                (Pubkey::new_unique(), account)
+            })
+            .collect();
+
+        let accounts = fetched.clone();
+        let start = Instant::now();
+        let sequential: Vec<(Pubkey, Control)> = accounts
+            .into_iter()
+            .filter_map(|(k, mut a)| {
+                let control = get_type_from_account::<Control>(&k, &mut a);
+                Some((k, control.ok()?))
+            })
+            .collect();
+        let sequential_time = start.elapsed();
+
+        let start = Instant::now();
+        let parallel = load_fetched_accounts::<Control>(fetched);
+        let parallel_time = start.elapsed();
+
+        assert_eq!(sequential.len(), ACCOUNTS);
+        assert_eq!(parallel.len(), ACCOUNTS);
+        println!(
+            "{} control accounts loaded in {:?} one by one, {:?} over {} \
+             threads",
+            ACCOUNTS,
+            sequential_time,
+            parallel_time,
+            rayon::current_num_threads()
+        );
+    }
+
+    #[test]
+    fn mismatched_configs_report_every_problem() {
+        let mut book = Book::new();
+        book.collateral("USDC", 1.0, 1000);
+        let srm = book.collateral("SRM", 2.0, 800);
+        book.market("BTC-PERP", 1000.0, 100);
+        let cache_key = Pubkey::new_unique();
+        book.state.cache = cache_key;
+        assert!(check_state_and_cache(&book.state, &book.cache, &cache_key)
+            .is_empty());
+
+        // The keeper was pointed at another cache, for a program version
+        // with more markets, and a collateral's oracle isn't cached.
+        book.state.total_markets = (MAX_MARKETS + 1) as _;
+        book.state.collaterals[srm].oracle_symbol = Symbol::from("MSRM");
+        let errors = check_state_and_cache(
+            &book.state,
+            &book.cache,
+            &Pubkey::new_unique(),
+        );
+        assert!(
+            matches!(
+                errors.as_slice(),
+                [
+                    ErrorCode::CacheMismatch,
+                    ErrorCode::TooManyMarkets(markets),
+                    ErrorCode::MissingOracle(symbol),
+                ] if *markets == MAX_MARKETS as usize + 1 && symbol == "MSRM"
+            ),
+            "{:?}",
+            errors
+        );
+
+        // Empty slots sort first, so the listed oracles are last.
+        let mut oracles = book.cache.oracles;
+        let last = oracles.len() - 1;
+        oracles.swap(last - 1, last);
+        book.cache.oracles = oracles;
+        let errors =
+            check_state_and_cache(&book.state, &book.cache, &cache_key);
+        assert!(errors
+            .iter()
+            .any(|e| matches!(e, ErrorCode::UnsortedOracleCache)));
+    }
+
+    #[test]
+    fn retry_jitter_is_reproducible_and_bounded() {
+        let fees = PriorityFees {
+            micro_lamports: None,
+            compute_unit_limit: None,
+            escalation: 1.0,
+        };
+        let policy = RetryPolicy::new(fees, None);
+        let mut other = policy.clone();
+        other.jitter_seed = 1;
+
+        for attempt in 0..policy.retries {
+            let base = policy
+                .base_delay
+                .saturating_mul(1 << attempt)
+                .min(policy.max_delay);
+            let delay = policy.delay(attempt);
+            assert_eq!(delay, policy.delay(attempt));
+            assert!(delay >= base.mul_f64(0.75), "attempt {}", attempt);
+            assert!(delay <= base.mul_f64(1.25), "attempt {}", attempt);
+        }
+
+        // Another seed retries out of step.
+        assert!((0..policy.retries)
+            .any(|attempt| policy.delay(attempt) != other.delay(attempt)));
+    }
 }
@@ -0,0 +1,258 @@
+/*
+ * This file keeps a write-ahead log of liquidations. Each attempt is
+ * appended before it is sent and resolved once its outcome is known,
+ * so a crash leaves a record of what may have landed. The log is a
+ * file of JSON lines.
+*/
+use serde::{Deserialize, Serialize};
+
+use solana_sdk::{pubkey::Pubkey, signature::Signature};
+
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    str::FromStr,
+    sync::Mutex,
+};
+
+use tracing::{error, info, warn};
+
+use crate::liquidator::error::ErrorCode;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record {
+    Intent {
+        id: u64,
+        account: String,
+        kind: String,
+        size: i64,
+        acc_value: Option<i64>,
+        maint_requirement: Option<i64>,
+        slot: u64,
+    },
+    Resolved {
+        id: u64,
+        signature: Option<String>,
+    },
+}
+
+struct Wal {
+    file: File,
+    next_id: u64,
+}
+
+static WAL: Mutex<Option<Wal>> = Mutex::new(None);
+
+/// Opens the log at `path`, creating it if needed, and reconciles the
+/// attempts a previous run left unresolved. `lookup` finds the signature
+/// of an attempt's liquidation of the account, of the kind given, that
+/// landed at or after its slot. Attempts are resolved with what it
+/// finds, and left unresolved for the next run if it fails.
+pub fn open(
+    path: &Path,
+    lookup: impl Fn(&Pubkey, &str, u64) -> Result<Option<Signature>, ErrorCode>,
+) -> std::io::Result<()> {
+    let mut pending = BTreeMap::new();
+    let mut next_id = 0;
+
+    if path.exists() {
+        for line in BufReader::new(File::open(path)?).lines() {
+            match serde_json::from_str::<Record>(&line?) {
+                Ok(Record::Intent {
+                    id,
+                    account,
+                    kind,
+                    slot,
+                    ..
+                }) => {
+                    next_id = next_id.max(id + 1);
+                    pending.insert(id, (account, kind, slot));
+                }
+                Ok(Record::Resolved { id, .. }) => {
+                    pending.remove(&id);
+                }
+                Err(e) => warn!("Skipping corrupt log line: {}", e),
+            }
+        }
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    for (id, (account, kind, slot)) in pending {
+        let key = match Pubkey::from_str(&account) {
+            Ok(key) => key,
+            Err(e) => {
+                warn!("Unresolved liquidation of invalid {}: {}", account, e);
+                continue;
+            }
+        };
+
+        let signature = match lookup(&key, &kind, slot) {
+            Ok(signature) => signature,
+            Err(e) => {
+                warn!(
+                    "Unresolved {} liquidation of {} in log, failed to look \
+                     it up: {:?}",
+                    kind, account, e
+                );
+                continue;
+            }
+        };
+        match &signature {
+            Some(sig) => {
+                info!(
+                    "Unresolved {} liquidation of {} landed in {}",
+                    kind, account, sig
+                )
+            }
+            None => warn!(
+                "Unresolved {} liquidation of {} didn't land",
+                kind, account
+            ),
+        }
+        append(
+            &mut file,
+            &Record::Resolved {
+                id,
+                signature: signature.map(|s| s.to_string()),
+            },
+        )?;
+    }
+
+    *WAL.lock().unwrap() = Some(Wal { file, next_id });
+    Ok(())
+}
+
+fn append(file: &mut File, record: &Record) -> std::io::Result<()> {
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+    file.write_all(line.as_bytes())?;
+    file.sync_data()
+}
+
+/// Records an intended liquidation before it is sent. `health` is the
+/// account value and maintenance requirement, as in `MarginHealth`.
+/// Returns the id to resolve it with, or `None` if no log is open. The
+/// liquidation must not be sent if the record can't be written.
+pub fn intend(
+    account: &Pubkey,
+    kind: &str,
+    size: i64,
+    health: Option<(i64, i64)>,
+    slot: impl FnOnce() -> u64,
+) -> Result<Option<u64>, ErrorCode> {
+    let mut guard = WAL.lock().map_err(|_| ErrorCode::LockFailure)?;
+    let wal = match guard.as_mut() {
+        Some(wal) => wal,
+        None => return Ok(None),
+    };
+
+    let id = wal.next_id;
+    wal.next_id += 1;
+    let record = Record::Intent {
+        id,
+        account: account.to_string(),
+        kind: kind.to_string(),
+        size,
+        acc_value: health.map(|h| h.0),
+        maint_requirement: health.map(|h| h.1),
+        slot: slot(),
+    };
+    if let Err(e) = append(&mut wal.file, &record) {
+        error!("Failed to write liquidation log: {}", e);
+        return Err(ErrorCode::WalFailure);
+    }
+
+    Ok(Some(id))
+}
+
+/// Resolves an intended liquidation, with its signature if it landed.
+pub fn resolve(id: Option<u64>, signature: Option<&Signature>) {
+    let id = match id {
+        Some(id) => id,
+        None => return,
+    };
+    let mut guard = match WAL.lock() {
+        Ok(guard) => guard,
+        Err(_) => return,
+    };
+
+    if let Some(wal) = guard.as_mut() {
+        let record = Record::Resolved {
+            id,
+            signature: signature.map(|s| s.to_string()),
+        };
+        if let Err(e) = append(&mut wal.file, &record) {
+            error!("Failed to write liquidation log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn records(path: &Path) -> Vec<Record> {
+        BufReader::new(File::open(path).unwrap())
+            .lines()
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect()
+    }
+
+    // The log is global, so the whole lifecycle is one test.
+    #[test]
+    fn intents_are_written_before_send_and_resolved_after() {
+        let path = std::env::temp_dir()
+            .join(format!("zo-keeper-wal-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let account = Pubkey::new_unique();
+        let signature = Signature::new(&[7; 64]);
+
+        open(&path, |_, _, _| panic!("nothing to reconcile")).unwrap();
+        let id = intend(&account, "perp", 5, Some((90, 100)), || 42).unwrap();
+        assert!(matches!(
+            records(&path).as_slice(),
+            [Record::Intent {
+                id: 0,
+                size: 5,
+                slot: 42,
+                ..
+            }]
+        ));
+
+        resolve(id, Some(&signature));
+        assert!(matches!(
+            &records(&path)[1],
+            Record::Resolved {
+                id: 0,
+                signature: Some(_)
+            }
+        ));
+
+        // A crash after sending leaves the intent to be reconciled.
+        let id = intend(&account, "spot", 3, None, || 50).unwrap();
+        assert_eq!(id, Some(1));
+        let lookups = Cell::new(0);
+        open(&path, |key, kind, slot| {
+            lookups.set(lookups.get() + 1);
+            assert_eq!((key, kind, slot), (&account, "spot", 50));
+            Ok(Some(signature))
+        })
+        .unwrap();
+        assert_eq!(lookups.get(), 1);
+        assert!(matches!(
+            &records(&path)[3],
+            Record::Resolved {
+                id: 1,
+                signature: Some(_)
+            }
+        ));
+
+        // Ids carry on past those already in the log.
+        assert_eq!(intend(&account, "perp", 1, None, || 60).unwrap(), Some(2));
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,4 +1,9 @@
-use anchor_client::{solana_sdk::signer::keypair, Cluster};
+use anchor_client::{
+    solana_sdk::{
+        commitment_config::CommitmentConfig, pubkey::Pubkey, signer::keypair,
+    },
+    Cluster,
+};
 use clap::{AppSettings, Parser, Subcommand};
 use std::{env, time::Duration};
 use zo_keeper as lib;
@@ -19,6 +24,15 @@ struct Cli {
     #[clap(short, long)]
     payer: Option<std::path::PathBuf>,
 
+    /// Time a fetched set of program accounts is reused by other
+    /// subsystems, in seconds. Not cached if 0
+    #[clap(long, default_value = "0", parse(try_from_str = parse_seconds))]
+    account_cache_ttl: Duration,
+
+    /// Most accounts a program account fetch may return before failing
+    #[clap(long)]
+    max_program_accounts: Option<usize>,
+
     #[clap(subcommand)]
     command: Command,
 }
@@ -64,10 +78,236 @@ enum Command {
         /// The slice of addresses this bot is responsible for
         #[clap(long, default_value = "0")]
         worker_index: u8,
+
+        /// Amount in smol USD an account must be below maintenance
+        /// before it is liquidated
+        #[clap(long, default_value = "0")]
+        maint_tolerance: i64,
+
+        /// Time after a margin account is created before it can be
+        /// liquidated, in seconds
+        #[clap(long, default_value = "0", parse(try_from_str = parse_seconds))]
+        new_account_grace: Duration,
+
+        /// Jito block engine URL. If set, liquidations are sent as
+        /// bundles
+        #[clap(long, requires = "jito_tip_account")]
+        jito_url: Option<String>,
+
+        /// Account receiving the Jito tip
+        #[clap(long)]
+        jito_tip_account: Option<Pubkey>,
+
+        /// Jito tip per bundle, in lamports
+        #[clap(long, default_value = "10000")]
+        jito_tip_lamports: u64,
+
+        /// Time after an account is flagged within which its
+        /// liquidation must be sent, in seconds
+        #[clap(long, parse(try_from_str = parse_seconds))]
+        attempt_deadline: Option<Duration>,
+
+        /// Largest relative deviation of a mark price from its oracle
+        /// before accounts exposed to that market are skipped
+        #[clap(long)]
+        mark_oracle_band: Option<f64>,
+
+        /// Per-market mark price band, e.g. BTC-PERP=0.05
+        #[clap(long, parse(try_from_str = parse_key_val))]
+        market_mark_oracle_band: Vec<(String, f64)>,
+
+        /// Oldest an oracle price may be before accounts depending on
+        /// it are skipped, in seconds
+        #[clap(long, parse(try_from_str = parse_seconds))]
+        max_oracle_age: Option<Duration>,
+
+        /// Time between reports of an account skipped for a stale
+        /// oracle, in seconds
+        #[clap(long, default_value = "30", parse(try_from_str = parse_seconds))]
+        stale_oracle_cooldown: Duration,
+
+        /// Updates in a row without a price change after which an
+        /// oracle is considered frozen
+        #[clap(long)]
+        frozen_oracle_updates: Option<u32>,
+
+        /// Smallest improvement in margin ratio a partial perp
+        /// liquidation must make, where 1.0 is maintenance
+        #[clap(long)]
+        min_liq_improvement: Option<f64>,
+
+        /// Whether to close positions fully or partially
+        #[clap(long, arg_enum, default_value = "partial-to-target")]
+        liquidation_style: lib::liquidator::LiquidationStyle,
+
+        /// Which kinds of liquidation to send
+        #[clap(long, arg_enum, default_value = "both")]
+        liquidation_scope: lib::liquidator::LiquidationScope,
+
+        /// Notional in smol USD up to which the adaptive style closes
+        /// positions fully
+        #[clap(long, default_value = "1000000000")]
+        adaptive_full_threshold: i64,
+
+        /// Position notional in smol USD from which orders are cancelled
+        /// and confirmed before the liquidation is sent
+        #[clap(long)]
+        two_phase_threshold: Option<i64>,
+
+        /// Smallest expected profit in smol USD, after fees, for which a
+        /// liquidation is sent
+        #[clap(long)]
+        min_profit_usd: Option<i64>,
+
+        /// Weighted collateral in smol USD under which accounts with no
+        /// positions, orders or borrows are not checked
+        #[clap(long)]
+        empty_account_threshold: Option<i64>,
+
+        /// Consecutive failed scans before exiting. Retries forever if
+        /// not set
+        #[clap(long)]
+        max_scan_failures: Option<u32>,
+
+        /// Liquidate accounts with the largest expected reward first,
+        /// instead of those furthest below maintenance
+        #[clap(long)]
+        profit_first: bool,
+
+        /// Liquidate accounts with the largest expected reward per
+        /// compute unit first, for when the compute budget is tight
+        #[clap(long)]
+        profit_per_cu_first: bool,
+
+        /// Log the margin math of accounts whose margin ratio is within
+        /// this distance of maintenance, at debug level
+        #[clap(long)]
+        log_margin_band: Option<f64>,
+
+        /// Relative mark move per million USD closed out in a market,
+        /// to log the accounts liquidations may cascade to
+        #[clap(long)]
+        cascade_impact: Option<f64>,
+
+        /// Oracle price move of a stress scenario, e.g. SOL=-0.15. May
+        /// be given several times, and the book's loss with all of them
+        /// applied at once is logged
+        #[clap(long, parse(try_from_str = parse_key_val))]
+        stress_shock: Vec<(String, f64)>,
+
+        /// Perp liquidation fee, in thousandths, to compare with the
+        /// on-chain one, e.g. BTC-PERP=15. May be given several times
+        #[clap(long, parse(try_from_str = parse_key_val))]
+        override_perp_liq_fee: Vec<(String, u16)>,
+
+        /// Collateral liquidation fee, in thousandths, to compare with
+        /// the on-chain one, e.g. SOL=25. May be given several times
+        #[clap(long, parse(try_from_str = parse_key_val))]
+        override_collateral_liq_fee: Vec<(String, u16)>,
+
+        /// Collateral weight, in thousandths, to compare with the
+        /// on-chain one, e.g. SOL=850. May be given several times
+        #[clap(long, parse(try_from_str = parse_key_val))]
+        override_collateral_weight: Vec<(String, u16)>,
+
+        /// Authority of an account whose margin math is logged step by
+        /// step each time it is checked. May be given several times
+        #[clap(long)]
+        trace_account: Vec<Pubkey>,
+
+        /// Oracle symbol of a collateral that cannot be seized. May be
+        /// given several times
+        #[clap(long)]
+        non_seizable_collateral: Vec<String>,
+
+        /// Preferred collateral pair for spot liquidations, as
+        /// ASSET=QUOTE oracle symbols, e.g. SOL=USDC. May be given
+        /// several times, in order of preference
+        #[clap(long, parse(try_from_str = parse_key_val))]
+        preferred_liq_pair: Vec<(String, String)>,
+
+        /// Symbol of a perp market in reduce-only mode, which the
+        /// on-chain state doesn't record. May be given several times
+        #[clap(long)]
+        reduce_only_market: Vec<String>,
+
+        /// Exit after a single scan and its liquidations
+        #[clap(long)]
+        single_scan: bool,
+
+        /// File to log liquidations to before they are sent
+        #[clap(long)]
+        wal_path: Option<std::path::PathBuf>,
+
+        /// Second RPC endpoint to compare blocks with. Scans are skipped
+        /// while the two disagree
+        #[clap(long)]
+        fork_check_url: Option<String>,
+
+        /// Keep checking accounts whose liquidation is still being sent
+        #[clap(long)]
+        rescan_in_flight: bool,
+
+        /// Priority fee of liquidations, in micro-lamports per compute
+        /// unit
+        #[clap(long)]
+        priority_fee: Option<u64>,
+
+        /// Compute unit limit requested by liquidations
+        #[clap(long)]
+        compute_unit_limit: Option<u32>,
+
+        /// Factor the priority fee is multiplied by on each retry
+        #[clap(long, default_value = "1.5")]
+        priority_fee_escalation: f64,
+
+        /// Least time the fee payer's balance should last at the recent
+        /// rate of liquidations before an alert is logged, in seconds
+        #[clap(long, parse(try_from_str = parse_seconds))]
+        min_fee_runway: Option<Duration>,
+
+        /// Longest wait for a sent transaction to reach the confirmation
+        /// commitment, in seconds. It is only resent once it can no
+        /// longer land
+        #[clap(long, parse(try_from_str = parse_seconds))]
+        confirm_timeout: Option<Duration>,
+
+        /// Commitment sent transactions are waited for at, one of
+        /// processed, confirmed or finalized
+        #[clap(long, default_value = "finalized")]
+        confirm_commitment: CommitmentConfig,
+
+        /// Log liquidations instead of sending them
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Skip liquidations not expected to cover the base fee and the
+        /// priority fee needed to land under recent competition
+        #[clap(long)]
+        require_profit_after_fees: bool,
+
+        /// Check margin accounts as soon as an update to them or their
+        /// control is pushed, not only on the scan interval
+        #[clap(long)]
+        scan_on_update: bool,
+
+        /// Lengthen the scan interval while scans take longer than it
+        #[clap(long)]
+        stretch_scan_interval: bool,
+
+        /// Port to serve Prometheus metrics on
+        #[clap(long)]
+        metrics_port: Option<u16>,
     },
 
     /// Listen and store events into a database
     Recorder,
+
+    /// Print the margin analysis of an account as JSON
+    Inspect {
+        /// Authority of the margin account
+        authority: Pubkey,
+    },
 }
 
 fn main() -> Result<(), lib::Error> {
@@ -88,6 +328,8 @@ fn main() -> Result<(), lib::Error> {
         rpc_url,
         ws_url,
         payer,
+        account_cache_ttl,
+        max_program_accounts,
         command,
     } = Cli::parse();
 
@@ -104,8 +346,12 @@ fn main() -> Result<(), lib::Error> {
 
     let cluster = Cluster::Custom(rpc_url, ws_url);
 
-    let app_state: &'static _ =
-        Box::leak(Box::new(lib::AppState::new(cluster, payer)));
+    let app_state: &'static _ = Box::leak(Box::new(lib::AppState::new(
+        cluster,
+        payer,
+        max_program_accounts,
+        account_cache_ttl,
+    )));
 
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -116,13 +362,128 @@ fn main() -> Result<(), lib::Error> {
         Command::Liquidator {
             worker_count,
             worker_index,
-        } => {
-            rt.block_on(lib::liquidator::run(
-                app_state,
+            maint_tolerance,
+            new_account_grace,
+            jito_url,
+            jito_tip_account,
+            jito_tip_lamports,
+            attempt_deadline,
+            mark_oracle_band,
+            market_mark_oracle_band,
+            max_oracle_age,
+            stale_oracle_cooldown,
+            frozen_oracle_updates,
+            min_liq_improvement,
+            liquidation_style,
+            liquidation_scope,
+            adaptive_full_threshold,
+            two_phase_threshold,
+            min_profit_usd,
+            empty_account_threshold,
+            max_scan_failures,
+            profit_first,
+            profit_per_cu_first,
+            log_margin_band,
+            cascade_impact,
+            stress_shock,
+            override_perp_liq_fee,
+            override_collateral_liq_fee,
+            override_collateral_weight,
+            trace_account,
+            non_seizable_collateral,
+            preferred_liq_pair,
+            reduce_only_market,
+            single_scan,
+            wal_path,
+            fork_check_url,
+            rescan_in_flight,
+            priority_fee,
+            compute_unit_limit,
+            priority_fee_escalation,
+            min_fee_runway,
+            confirm_timeout,
+            confirm_commitment,
+            dry_run,
+            require_profit_after_fees,
+            scan_on_update,
+            stretch_scan_interval,
+            metrics_port,
+        } => rt.block_on(lib::liquidator::run(
+            app_state,
+            lib::liquidator::LiquidatorConfig {
                 worker_count,
                 worker_index,
-            ))?;
-        }
+                maint_tolerance,
+                new_account_grace,
+                jito: jito_url.zip(jito_tip_account).map(|(url, tip)| {
+                    lib::liquidator::JitoConfig {
+                        url,
+                        tip_account: tip,
+                        tip_lamports: jito_tip_lamports,
+                    }
+                }),
+                attempt_deadline,
+                mark_oracle_band,
+                market_mark_oracle_bands: market_mark_oracle_band
+                    .into_iter()
+                    .collect(),
+                max_oracle_age,
+                stale_oracle_cooldown,
+                frozen_oracle_updates,
+                min_liq_improvement,
+                liquidation_style,
+                liquidation_scope,
+                adaptive_full_threshold,
+                two_phase_threshold,
+                min_profit_usd,
+                empty_account_threshold,
+                max_scan_failures,
+                strategy: if profit_per_cu_first {
+                    std::sync::Arc::new(
+                        lib::liquidator::MostProfitablePerCuFirst,
+                    )
+                } else if profit_first {
+                    std::sync::Arc::new(lib::liquidator::MostProfitableFirst)
+                } else {
+                    std::sync::Arc::new(lib::liquidator::LargestRiskFirst)
+                },
+                log_margin_band,
+                cascade_impact,
+                stress_shocks: stress_shock,
+                param_overrides: lib::liquidator::ParamOverrides {
+                    perp_liq_fees: override_perp_liq_fee.into_iter().collect(),
+                    collateral_liq_fees: override_collateral_liq_fee
+                        .into_iter()
+                        .collect(),
+                    collateral_weights: override_collateral_weight
+                        .into_iter()
+                        .collect(),
+                },
+                trace_accounts: trace_account.into_iter().collect(),
+                non_seizable_collaterals: non_seizable_collateral
+                    .into_iter()
+                    .collect(),
+                preferred_liq_pairs: preferred_liq_pair,
+                reduce_only_markets: reduce_only_market.into_iter().collect(),
+                single_scan,
+                wal_path,
+                fork_check_url,
+                rescan_in_flight,
+                priority_fees: lib::liquidator::PriorityFees {
+                    micro_lamports: priority_fee,
+                    compute_unit_limit,
+                    escalation: priority_fee_escalation,
+                },
+                min_fee_runway,
+                confirm_timeout,
+                confirm_commitment,
+                dry_run,
+                require_profit_after_fees,
+                scan_on_update,
+                stretch_scan_interval,
+                metrics_port,
+            },
+        ))?,
         Command::Crank {
             cache_oracle_interval,
             cache_interest_interval,
@@ -148,11 +509,26 @@ fn main() -> Result<(), lib::Error> {
             },
         ))?,
         Command::Recorder => rt.block_on(lib::recorder::run(app_state))?,
+        Command::Inspect { authority } => {
+            lib::liquidator::inspect(app_state, &authority)?
+        }
     };
 
     Ok(())
 }
 
+fn parse_key_val<T>(s: &str) -> Result<(String, T), String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let (k, v) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got {}", s))?;
+    let v = v.parse::<T>().map_err(|e| e.to_string())?;
+    Ok((k.to_string(), v))
+}
+
 fn parse_seconds(s: &str) -> Result<Duration, std::num::ParseFloatError> {
     <f64 as std::str::FromStr>::from_str(s).map(Duration::from_secs_f64)
 }
@@ -360,27 +360,37 @@ async fn poll_open_interest(
         let val = tokio::task::spawn_blocking(move || {
             let mut r = vec![0i64; st.zo_state.total_markets as usize];
 
-            crate::utils::load_program_accounts::<zo_abi::Control>(&st.rpc)
-                .unwrap()
-                .into_iter()
-                .for_each(|(_, a)| {
-                    for (i, e) in r.iter_mut().enumerate() {
-                        let x = a.open_orders_agg[i].pos_size;
-                        if x > 0 {
-                            *e += x;
-                        }
+            crate::utils::load_program_accounts::<zo_abi::Control>(
+                &st.rpc,
+                &st.account_sets,
+                CommitmentConfig::finalized(),
+                st.max_program_accounts,
+            )?
+            .into_iter()
+            .for_each(|(_, a)| {
+                for (i, e) in r.iter_mut().enumerate() {
+                    let x = a.open_orders_agg[i].pos_size;
+                    if x > 0 {
+                        *e += x;
                     }
-                });
+                }
+            });
 
-            st.iter_markets()
-                .enumerate()
-                .map(|(i, m)| (m.symbol.into(), r[i]))
-                .collect::<HashMap<String, i64>>()
+            Ok::<_, Error>(
+                st.iter_markets()
+                    .enumerate()
+                    .map(|(i, m)| (m.symbol.into(), r[i]))
+                    .collect::<HashMap<String, i64>>(),
+            )
         })
         .await;
 
         let val = match val {
-            Ok(x) => x,
+            Ok(Ok(x)) => x,
+            Ok(Err(e)) => {
+                warn!("Failed to load controls for open interest: {}", e);
+                continue;
+            }
             Err(e) => {
                 warn!("{}", e);
                 continue;
@@ -17,10 +17,20 @@ pub struct AppState {
     pub zo_state_pubkey: Pubkey,
     pub zo_cache_pubkey: Pubkey,
     pub zo_state_signer_pubkey: Pubkey,
+    /// Most accounts a program account fetch may return. Larger sets
+    /// are counted and refused before their data is fetched.
+    pub max_program_accounts: Option<usize>,
+    /// Program account sets shared between subsystems.
+    pub account_sets: crate::AccountSetCache,
 }
 
 impl AppState {
-    pub fn new(cluster: Cluster, payer: Keypair) -> Self {
+    pub fn new(
+        cluster: Cluster,
+        payer: Keypair,
+        max_program_accounts: Option<usize>,
+        account_cache_ttl: std::time::Duration,
+    ) -> Self {
         let program = Client::new_with_options(
             cluster.clone(),
             std::rc::Rc::new(Keypair::from_bytes(&payer.to_bytes()).unwrap()),
@@ -49,6 +59,8 @@ impl AppState {
             zo_state_pubkey,
             zo_cache_pubkey: zo_state.cache,
             zo_state_signer_pubkey,
+            max_program_accounts,
+            account_sets: crate::AccountSetCache::new(account_cache_ttl),
         }
     }
 
@@ -57,6 +69,10 @@ impl AppState {
         self.payer.pubkey()
     }
 
+    pub fn payer_keypair(&self) -> Keypair {
+        Keypair::from_bytes(&self.payer.to_bytes()).unwrap()
+    }
+
     pub fn client(&self) -> Client {
         Client::new_with_options(
             self.cluster.clone(),
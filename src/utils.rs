@@ -1,7 +1,8 @@
-use crate::Error;
+use crate::{liquidator::ErrorCode, Error};
 use anchor_client::{
     anchor_lang::{prelude::AccountLoader, Owner, ZeroCopy},
     solana_client::{
+        client_error::ClientError,
         rpc_client::RpcClient,
         rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
         rpc_filter::{Memcmp, MemcmpEncodedBytes, RpcFilterType},
@@ -11,7 +12,128 @@ use anchor_client::{
         commitment_config::CommitmentConfig, pubkey::Pubkey,
     },
 };
-use solana_account_decoder::UiAccountEncoding;
+use solana_account_decoder::{UiAccountEncoding, UiDataSliceConfig};
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+type AccountSet = Arc<dyn Any + Send + Sync>;
+
+/// Account sets fetched recently, keyed by program, commitment and
+/// account type, so subsystems sharing the cache share a single fetch.
+pub struct AccountSetCache {
+    /// How long a fetched set is reused. Zero disables the cache.
+    ttl: Duration,
+    sets: Mutex<
+        HashMap<(Pubkey, CommitmentConfig, TypeId), (Instant, AccountSet)>,
+    >,
+}
+
+impl AccountSetCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            sets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the account set of type `T` under `program` at
+    /// `commitment`, calling `fetch` only if no set younger than the TTL
+    /// is cached. The lock is held while fetching, so concurrent callers
+    /// wait for the first fetch instead of issuing their own.
+    pub(crate) fn get_or_fetch<T, E>(
+        &self,
+        program: &Pubkey,
+        commitment: CommitmentConfig,
+        fetch: impl FnOnce() -> Result<Vec<(Pubkey, T)>, E>,
+    ) -> Result<Vec<(Pubkey, T)>, E>
+    where
+        T: Clone + Send + Sync + 'static,
+    {
+        if self.ttl.is_zero() {
+            return fetch();
+        }
+
+        let mut sets = self.sets.lock().unwrap();
+        let key = (*program, commitment, TypeId::of::<T>());
+
+        if let Some((fetched_at, set)) = sets.get(&key) {
+            if fetched_at.elapsed() < self.ttl {
+                if let Some(set) = set.downcast_ref::<Vec<(Pubkey, T)>>() {
+                    return Ok(set.clone());
+                }
+            }
+        }
+
+        let set = fetch()?;
+        sets.insert(key, (Instant::now(), Arc::new(set.clone())));
+        Ok(set)
+    }
+}
+
+/// Returns the number of accounts of type `T` under `program` and the
+/// limit `max`, if they exceed it. Only their keys are requested, with
+/// an empty data slice, so an overly broad set is refused before its
+/// data is fetched and doesn't exhaust memory. Callers without a limit
+/// skip the request altogether.
+pub(crate) fn exceeded_account_set_len<T>(
+    client: &RpcClient,
+    program: &Pubkey,
+    commitment: CommitmentConfig,
+    max: usize,
+) -> Result<Option<(usize, usize)>, ClientError>
+where
+    T: ZeroCopy + Owner + 'static,
+{
+    let config = RpcProgramAccountsConfig {
+        filters: Some(account_filters::<T>()),
+        account_config: RpcAccountInfoConfig {
+            encoding: Some(UiAccountEncoding::Base64),
+            data_slice: Some(UiDataSliceConfig {
+                offset: 0,
+                length: 0,
+            }),
+            commitment: Some(commitment),
+        },
+        with_context: Some(false),
+    };
+    let len = client
+        .get_program_accounts_with_config(program, config)?
+        .len();
+    Ok((len > max).then(|| (len, max)))
+}
+
+/// Program account filters by account type. They only depend on the
+/// type, so each is built once.
+static ACCOUNT_FILTERS: Mutex<Option<HashMap<TypeId, Vec<RpcFilterType>>>> =
+    Mutex::new(None);
+
+/// Returns the filters matching accounts of type `T`: its size and its
+/// anchor discriminator.
+pub(crate) fn account_filters<T>() -> Vec<RpcFilterType>
+where
+    T: ZeroCopy + Owner + 'static,
+{
+    ACCOUNT_FILTERS
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .entry(TypeId::of::<T>())
+        .or_insert_with(|| {
+            vec![
+                RpcFilterType::DataSize((8 + std::mem::size_of::<T>()) as u64),
+                RpcFilterType::Memcmp(Memcmp {
+                    offset: 0,
+                    bytes: MemcmpEncodedBytes::Bytes(T::discriminator().into()),
+                    encoding: None,
+                }),
+            ]
+        })
+        .clone()
+}
 
 fn load_account<'a, T>(key: &'a Pubkey, account: &'a mut Account) -> T
 where
@@ -26,33 +148,119 @@ where
 
 pub fn load_program_accounts<T>(
     client: &RpcClient,
+    sets: &AccountSetCache,
+    commitment: CommitmentConfig,
+    max_len: Option<usize>,
 ) -> Result<Vec<(Pubkey, T)>, Error>
 where
-    T: ZeroCopy + Owner,
+    T: ZeroCopy + Owner + Send + Sync + 'static,
 {
     let config = RpcProgramAccountsConfig {
-        filters: Some(vec![
-            RpcFilterType::DataSize((8 + std::mem::size_of::<T>()) as u64),
-            RpcFilterType::Memcmp(Memcmp {
-                offset: 0,
-                bytes: MemcmpEncodedBytes::Bytes(T::discriminator().into()),
-                encoding: None,
-            }),
-        ]),
+        filters: Some(account_filters::<T>()),
         account_config: RpcAccountInfoConfig {
             encoding: Some(UiAccountEncoding::Base64),
             data_slice: None,
-            commitment: Some(CommitmentConfig::finalized()),
+            commitment: Some(commitment),
         },
         with_context: Some(false),
     };
 
-    client
-        .get_program_accounts_with_config(&zo_abi::ID, config)
-        .map(|v| {
-            v.into_iter()
-                .map(|(k, mut a)| (k, load_account::<T>(&k, &mut a)))
-                .collect()
+    sets.get_or_fetch(&zo_abi::ID, commitment, || {
+        if let Some(max) = max_len {
+            if let Some((len, max)) = exceeded_account_set_len::<T>(
+                client,
+                &zo_abi::ID,
+                commitment,
+                max,
+            )? {
+                return Err(ErrorCode::ResultSetTooLarge(len, max).into());
+            }
+        }
+        let accounts =
+            client.get_program_accounts_with_config(&zo_abi::ID, config)?;
+
+        Ok(accounts
+            .into_iter()
+            .map(|(k, mut a)| (k, load_account::<T>(&k, &mut a)))
+            .collect())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn scans_within_the_ttl_share_a_fetch() {
+        let program = Pubkey::new_unique();
+        let fetches = Cell::new(0);
+        let fetch = |value: u8| {
+            fetches.set(fetches.get() + 1);
+            Ok::<_, ()>(vec![(Pubkey::default(), value)])
+        };
+        let confirmed = CommitmentConfig::confirmed();
+
+        // The cache is off without a TTL.
+        let off = AccountSetCache::new(Duration::ZERO);
+        off.get_or_fetch(&program, confirmed, || fetch(1)).unwrap();
+        off.get_or_fetch(&program, confirmed, || fetch(1)).unwrap();
+        assert_eq!(fetches.get(), 2);
+
+        let sets = AccountSetCache::new(Duration::from_secs(60));
+        let first = sets.get_or_fetch(&program, confirmed, || fetch(1));
+        let second = sets.get_or_fetch(&program, confirmed, || fetch(2));
+        assert_eq!(fetches.get(), 3);
+        assert_eq!(first, second);
+
+        // Sets are kept apart by commitment and by account type.
+        let finalized = CommitmentConfig::finalized();
+        sets.get_or_fetch(&program, finalized, || fetch(3)).unwrap();
+        sets.get_or_fetch(&program, confirmed, || {
+            fetches.set(fetches.get() + 1);
+            Ok::<_, ()>(vec![(Pubkey::default(), 4u16)])
         })
-        .map_err(Into::into)
+        .unwrap();
+        assert_eq!(fetches.get(), 5);
+
+        let short = AccountSetCache::new(Duration::from_millis(1));
+        short
+            .get_or_fetch(&program, confirmed, || fetch(5))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        let refetched = short.get_or_fetch(&program, confirmed, || fetch(6));
+        assert_eq!(refetched, Ok(vec![(Pubkey::default(), 6)]));
+        assert_eq!(fetches.get(), 7);
+    }
+
+    #[test]
+    fn cached_filters_match_freshly_built_ones() {
+        use anchor_client::anchor_lang::Discriminator;
+
+        fn fresh<T: ZeroCopy + Owner>() -> Vec<RpcFilterType> {
+            vec![
+                RpcFilterType::DataSize((8 + std::mem::size_of::<T>()) as u64),
+                RpcFilterType::Memcmp(Memcmp {
+                    offset: 0,
+                    bytes: MemcmpEncodedBytes::Bytes(T::discriminator().into()),
+                    encoding: None,
+                }),
+            ]
+        }
+
+        for _ in 0..2 {
+            assert_eq!(
+                account_filters::<zo_abi::Margin>(),
+                fresh::<zo_abi::Margin>()
+            );
+            assert_eq!(
+                account_filters::<zo_abi::Control>(),
+                fresh::<zo_abi::Control>()
+            );
+        }
+        assert_ne!(
+            account_filters::<zo_abi::Margin>(),
+            account_filters::<zo_abi::Control>()
+        );
+    }
 }
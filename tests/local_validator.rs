@@ -0,0 +1,124 @@
+/*
+ * This file runs a single liquidator scan against a local test
+ * validator and checks that it liquidated the fixture account. It is
+ * only built with the `local-validator` feature, and needs
+ * `solana-test-validator` on the path and the following variables:
+ *
+ * ZO_FIXTURES_DIR   programs as <PROGRAM_ID>.so and accounts as
+ *                   <PUBKEY>.json, as for scripts/test-validator.sh
+ * ZO_PAYER          keypair file of a funded payer with a margin account
+ *                   in the fixtures
+ * ZO_LIQEE_CONTROL  control account of a margin account in the fixtures
+ *                   that is below maintenance with a perp position
+ *
+ *     $ cargo test --features local-validator --test local_validator
+*/
+#![cfg(feature = "local-validator")]
+
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey};
+
+use std::{
+    env,
+    path::Path,
+    process::{Child, Command},
+    str::FromStr,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+const RPC_URL: &str = "http://127.0.0.1:8899";
+const WS_URL: &str = "ws://127.0.0.1:8900";
+
+/// Kills the validator when the test ends, including by a panic.
+struct Validator(Child);
+
+impl Drop for Validator {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn start_validator(fixtures: &Path) -> Validator {
+    let ledger = env::temp_dir().join("zo-keeper-test-ledger");
+    let mut cmd = Command::new("solana-test-validator");
+    cmd.arg("--reset")
+        .arg("--quiet")
+        .arg("--ledger")
+        .arg(&ledger);
+
+    for entry in std::fs::read_dir(fixtures).expect("Unreadable fixtures") {
+        let path = entry.unwrap().path();
+        let name = path.file_stem().unwrap().to_string_lossy().to_string();
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("so") => cmd.arg("--bpf-program").arg(name).arg(&path),
+            Some("json") => cmd.arg("--account").arg(name).arg(&path),
+            _ => continue,
+        };
+    }
+
+    Validator(cmd.spawn().expect("Failed to start solana-test-validator"))
+}
+
+fn wait_until_healthy(rpc: &RpcClient) {
+    let start = Instant::now();
+    while rpc.get_health().is_err() {
+        assert!(
+            start.elapsed() < Duration::from_secs(60),
+            "Validator didn't come up"
+        );
+        sleep(Duration::from_millis(500));
+    }
+}
+
+/// Sizes of the account's perp positions by market.
+fn positions(rpc: &RpcClient, control_key: &Pubkey) -> Vec<i64> {
+    let data = rpc.get_account_data(control_key).unwrap();
+    let size = std::mem::size_of::<zo_abi::Control>();
+    let control: &zo_abi::Control = bytemuck::from_bytes(&data[8..8 + size]);
+    let orders = { control.open_orders_agg };
+    orders.iter().map(|oo| oo.pos_size).collect()
+}
+
+#[test]
+fn single_scan_liquidates_the_fixture_account() {
+    let var =
+        |name| env::var(name).unwrap_or_else(|_| panic!("${} unset", name));
+    let fixtures = var("ZO_FIXTURES_DIR");
+    let payer = var("ZO_PAYER");
+    let liqee_control = Pubkey::from_str(&var("ZO_LIQEE_CONTROL")).unwrap();
+
+    let _validator = start_validator(Path::new(&fixtures));
+    let rpc = RpcClient::new_with_commitment(
+        RPC_URL.to_string(),
+        CommitmentConfig::confirmed(),
+    );
+    wait_until_healthy(&rpc);
+
+    let before = positions(&rpc, &liqee_control);
+    assert!(
+        before.iter().any(|&size| size != 0),
+        "The fixture account has no perp position to liquidate"
+    );
+
+    let status = Command::new(env!("CARGO_BIN_EXE_zo-keeper"))
+        .args(["--rpc-url", RPC_URL, "--ws-url", WS_URL])
+        .args(["--payer", &payer])
+        .args(["liquidator", "--single-scan"])
+        .status()
+        .expect("Failed to run the keeper");
+    assert!(status.success(), "Keeper exited with {}", status);
+
+    // Liquidations only reduce positions, and some must have been taken.
+    let after = positions(&rpc, &liqee_control);
+    let total = |sizes: &[i64]| sizes.iter().map(|s| s.unsigned_abs()).sum();
+    let (before_total, after_total): (u64, u64) =
+        (total(&before), total(&after));
+    assert!(
+        after_total < before_total,
+        "Positions went from {:?} to {:?}",
+        before,
+        after
+    );
+}